@@ -0,0 +1,57 @@
+;; Standard macros, loaded at startup by slosh and sl-compiler (disable with --no-prelude).
+;; These follow the sl-sh versions so scripts can move between the two.
+
+(def defmacro
+  (macro (name args &rest body)
+    `(eval-when (:compile :load :execute)
+       (def ,name (macro ,args ,@body)))))
+
+(defmacro defn (name args &rest body)
+  `(def ,name (fn ,args ,@body)))
+
+(defmacro when (test &rest body)
+  `(if ,test (do ,@body) nil))
+
+(defmacro unless (test &rest body)
+  `(if ,test nil (do ,@body)))
+
+;; (for bind in-list body*) evaluate body with bind set to each element of in-list.
+(defmacro for (bind in-list &rest body)
+  `(loop ((lst# ,in-list))
+     (if lst#
+       (let ((,bind (car lst#)))
+         ,@body
+         (recur (cdr lst#)))
+       nil)))
+
+;; (for-i idx-bind bind in-list body*) like for with idx-bind set to the index of bind.
+(defmacro for-i (idx-bind bind in-list &rest body)
+  `(loop ((,idx-bind 0) (lst# ,in-list))
+     (if lst#
+       (let ((,bind (car lst#)))
+         ,@body
+         (recur (+ ,idx-bind 1) (cdr lst#)))
+       nil)))
+
+;; (-> x (f a) g) thread x through the forms as their first arg, (g (f x a)).  A form that
+;; is not a list is called with the value as its only arg.
+(defmacro -> (init &rest forms)
+  (loop ((acc init) (forms forms))
+    (if forms
+      (let ((form (car forms)))
+        (recur (if (eq? (type form) :Pair)
+                 `(,(car form) ,acc ,@(cdr form))
+                 `(,form ,acc))
+               (cdr forms)))
+      acc)))
+
+;; (->> x (f a) g) like -> but x is the last arg of each form, (g (f a x)).
+(defmacro ->> (init &rest forms)
+  (loop ((acc init) (forms forms))
+    (if forms
+      (let ((form (car forms)))
+        (recur (if (eq? (type form) :Pair)
+                 `(,@form ,acc)
+                 `(,form ,acc))
+               (cdr forms)))
+      acc)))
@@ -1,9 +1,14 @@
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::time::{SystemTime, UNIX_EPOCH};
+
 use slvm::error::*;
 use slvm::interner::Interned;
 use slvm::value::*;
 use slvm::vm::*;
 
 use crate::compile::*;
+use crate::error::*;
+use crate::roots::*;
 use crate::state::*;
 
 macro_rules! is_tag {
@@ -39,31 +44,27 @@ macro_rules! get_data {
                     if ncdr.is_nil() {
                         return Ok(ncar);
                     } else {
-                        let (line, col) = (
-                            $vm.get_heap_property(handle, "dbg-line"),
-                            $vm.get_heap_property(handle, "dbg-col"),
-                        );
-                        if let (Some(Value::UInt(line)), Some(Value::UInt(col))) = (line, col) {
-                            return Err(VMError::new_compile(format!(
-                                "Invalid tag at {}:{}, takes one expression.",
-                                line, col
-                            )));
-                        } else {
-                            return Err(VMError::new_compile("Invalid tag, takes one expression."));
-                        }
+                        return Err(CompileError::invalid_quote(
+                            "Invalid tag, takes one expression.",
+                        )
+                        .with_form($vm, $exp));
                     }
                 }
             }
             Value::Vector(handle) => {
                 let v = $vm.get_vector(handle);
                 if v.len() != 2 {
-                    return Err(VMError::new_compile("Invalid tag, takes one expression."));
+                    return Err(CompileError::invalid_quote(
+                        "Invalid tag, takes one expression.",
+                    ));
                 }
                 return Ok(v[1]);
             }
             _ => {}
         }
-        Err(VMError::new_compile("Invalid tag, takes one expression."))
+        Err(CompileError::invalid_quote(
+            "Invalid tag, takes one expression.",
+        ))
     }};
 }
 
@@ -100,21 +101,21 @@ impl Tag {
         is_tag!(vm, exp, self.splice_bang)
     }
 
-    pub fn data(vm: &Vm, exp: Value) -> VMResult<Value> {
+    pub fn data(vm: &Vm, exp: Value) -> CompileResult<Value> {
         get_data!(vm, exp)
     }
 }
 
 fn quote(vm: &mut Vm, exp: Value) -> Value {
-    let cdr = vm.alloc_pair_ro(exp, Value::Nil);
+    let cdr = alloc_pair_rooted(vm, exp, Value::Nil);
     let q_i = vm.intern_static("quote");
-    vm.alloc_pair_ro(Value::Symbol(q_i), cdr)
+    alloc_pair_rooted(vm, Value::Symbol(q_i), cdr)
 }
 
 fn list(vm: &mut Vm, exp: Value) -> Value {
-    let cdr = vm.alloc_pair_ro(exp, Value::Nil);
+    let cdr = alloc_pair_rooted(vm, exp, Value::Nil);
     let q_i = vm.intern_static("list");
-    vm.alloc_pair_ro(Value::Symbol(q_i), cdr)
+    alloc_pair_rooted(vm, Value::Symbol(q_i), cdr)
 }
 
 fn vec(vm: &mut Vm, v: &[Value]) -> Value {
@@ -122,31 +123,31 @@ fn vec(vm: &mut Vm, v: &[Value]) -> Value {
     if !v.is_empty() {
         let mut i = v.len();
         while i > 0 {
-            last_pair = vm.alloc_pair_ro(v[i - 1], last_pair);
+            last_pair = alloc_pair_rooted(vm, v[i - 1], last_pair);
             i -= 1;
         }
     }
     let q_i = vm.intern_static("vec");
-    vm.alloc_pair_ro(Value::Symbol(q_i), last_pair)
+    alloc_pair_rooted(vm, Value::Symbol(q_i), last_pair)
 }
 
 fn list2(vm: &mut Vm, exp: Value) -> Value {
     let q_i = vm.intern_static("list");
-    vm.alloc_pair_ro(Value::Symbol(q_i), exp)
+    alloc_pair_rooted(vm, Value::Symbol(q_i), exp)
 }
 
 fn append(vm: &mut Vm, exp1: Value, exp2: Value) -> Value {
-    let cdr1 = vm.alloc_pair_ro(exp2, Value::Nil);
-    let cdr2 = vm.alloc_pair_ro(exp1, cdr1);
+    let cdr1 = alloc_pair_rooted(vm, exp2, Value::Nil);
+    let cdr2 = alloc_pair_rooted(vm, exp1, cdr1);
     let q_i = vm.intern_static("list-append");
-    vm.alloc_pair_ro(Value::Symbol(q_i), cdr2)
+    alloc_pair_rooted(vm, Value::Symbol(q_i), cdr2)
 }
 
 fn rewrap(vm: &mut Vm, exp: Value, sym: &'static str) -> Value {
-    let cdr = vm.alloc_pair_ro(exp, Value::Nil);
+    let cdr = alloc_pair_rooted(vm, exp, Value::Nil);
     let q_i = vm.intern_static(sym);
     let car = quote(vm, Value::Symbol(q_i));
-    let cdr = vm.alloc_pair_ro(car, cdr);
+    let cdr = alloc_pair_rooted(vm, car, cdr);
     list2(vm, cdr)
 }
 
@@ -166,49 +167,125 @@ fn back_quote(vm: &mut Vm, exp: Value) -> Value {
     rewrap(vm, exp, "back-quote")
 }
 
+/// If exp is an auto-gensym symbol (name#) add it to gensyms and return true.  The expansion
+/// refers to it as a local that backquote binds to a fresh symbol.
+fn auto_gensym(vm: &Vm, exp: Value, gensyms: &mut Vec<Interned>) -> bool {
+    if let Value::Symbol(i) = exp {
+        let name = vm.get_interned(i);
+        if name.len() > 1 && name.ends_with('#') {
+            if !gensyms.contains(&i) {
+                gensyms.push(i);
+            }
+            return true;
+        }
+    }
+    false
+}
+
+/// Wrap expand in a let that binds each auto-gensym to a new symbol, this happens each time the
+/// back quote is evaluated so every expansion gets its own symbols.
+fn bind_gensyms(vm: &mut Vm, expand: Value, gensyms: &[Interned]) -> Value {
+    let gensym_i = vm.intern_static("gensym");
+    let mut bindings = Value::Nil;
+    for sym in gensyms.iter().rev() {
+        let name = vm.get_interned(*sym);
+        let prefix = vm.intern(&name[..name.len() - 1]);
+        let call = alloc_pair_rooted(vm, Value::StringConst(prefix), Value::Nil);
+        let call = alloc_pair_rooted(vm, Value::Symbol(gensym_i), call);
+        let binding = alloc_pair_rooted(vm, call, Value::Nil);
+        let binding = alloc_pair_rooted(vm, Value::Symbol(*sym), binding);
+        bindings = alloc_pair_rooted(vm, binding, bindings);
+    }
+    let let_i = vm.intern_static("let");
+    let body = alloc_pair_rooted(vm, expand, Value::Nil);
+    let body = alloc_pair_rooted(vm, bindings, body);
+    alloc_pair_rooted(vm, Value::Symbol(let_i), body)
+}
+
+static GENSYM_COUNT: AtomicUsize = AtomicUsize::new(0);
+/// Salt for the gensym names of this process, 0 until the first gensym.
+static GENSYM_SALT: AtomicU64 = AtomicU64::new(0);
+
+/// The gensym salt of this process, made from the time and process id the first time.
+fn gensym_salt() -> u64 {
+    let salt = GENSYM_SALT.load(Ordering::Relaxed);
+    if salt != 0 {
+        return salt;
+    }
+    let nanos = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_nanos() as u64)
+        .unwrap_or(0);
+    let salt = (nanos ^ ((std::process::id() as u64) << 32)) | 1;
+    match GENSYM_SALT.compare_exchange(0, salt, Ordering::Relaxed, Ordering::Relaxed) {
+        Ok(_) => salt,
+        Err(salt) => salt,
+    }
+}
+
+/// Builtin (gensym) or (gensym prefix), returns a new unique symbol.  The name starts with #:
+/// so the reader can never produce it and it will not clash with symbols in source.  Symbols
+/// are interned so the name also carries a salt for the process that made it, a .slc file
+/// compiled by one process never reuses the gensyms of the process that loads it.
+pub fn gensym(vm: &mut Vm, registers: &[Value]) -> VMResult<Value> {
+    let prefix = match registers {
+        [] => "G".to_string(),
+        [Value::StringConst(i)] | [Value::Symbol(i)] => vm.get_interned(*i).to_string(),
+        [Value::String(h)] => vm.get_string(*h).to_string(),
+        [_] => return Err(VMError::new_vm("gensym: prefix must be a string or symbol")),
+        _ => {
+            return Err(VMError::new_vm(
+                "gensym: wrong number of args, expected zero or one",
+            ))
+        }
+    };
+    let count = GENSYM_COUNT.fetch_add(1, Ordering::Relaxed);
+    let sym = vm.intern(&format!("#:{}{}-{:x}", prefix, count, gensym_salt()));
+    Ok(Value::Symbol(sym))
+}
+
 // Algorithm initially from
 // https://3e8.org/pub/scheme/doc/Quasiquotation%20in%20Lisp%20(Bawden).pdf
-fn qq_expand(vm: &mut Vm, exp: Value, line: u32, depth: u32) -> VMResult<Value> {
+fn qq_expand(
+    vm: &mut Vm,
+    exp: Value,
+    depth: u32,
+    gensyms: &mut Vec<Interned>,
+) -> CompileResult<Value> {
     let tag = Tag::new(vm);
     if tag.is_unquote(vm, exp) {
         if depth == 0 {
             Ok(Tag::data(vm, exp)?)
         } else {
-            let expand = qq_expand(vm, Tag::data(vm, exp)?, line, depth - 1)?;
+            let expand = qq_expand(vm, Tag::data(vm, exp)?, depth - 1, gensyms)?;
             Ok(unquote(vm, expand))
         }
     } else if tag.is_splice(vm, exp) {
         if depth == 0 {
-            Err(VMError::new_compile(format!(
-                ",@ not valid here: line {}",
-                line
-            )))
+            Err(CompileError::invalid_quote(",@ not valid here").with_form(vm, exp))
         } else {
-            let expand = qq_expand(vm, Tag::data(vm, exp)?, line, depth - 1)?;
+            let expand = qq_expand(vm, Tag::data(vm, exp)?, depth - 1, gensyms)?;
             Ok(splice(vm, expand))
         }
     } else if tag.is_splice_bang(vm, exp) {
         if depth == 0 {
-            Err(VMError::new_compile(format!(
-                ",. not valid here: line {}",
-                line
-            )))
+            Err(CompileError::invalid_quote(",. not valid here").with_form(vm, exp))
         } else {
-            let expand = qq_expand(vm, Tag::data(vm, exp)?, line, depth - 1)?;
+            let expand = qq_expand(vm, Tag::data(vm, exp)?, depth - 1, gensyms)?;
             Ok(splice_bang(vm, expand))
         }
     } else if tag.is_backquote(vm, exp) {
-        let inner = qq_expand(vm, Tag::data(vm, exp)?, line, depth + 1)?;
+        let inner = qq_expand(vm, Tag::data(vm, exp)?, depth + 1, gensyms)?;
         Ok(back_quote(vm, inner))
     } else {
         match exp {
             Value::Pair(handle) => {
                 let (car, cdr) = vm.get_pair(handle);
-                let l1 = qq_expand_list(vm, car, line, depth)?;
+                let l1 = qq_expand_list(vm, car, depth, gensyms)?;
                 if cdr.is_nil() {
                     Ok(l1)
                 } else {
-                    let l2 = qq_expand(vm, cdr, line, depth)?;
+                    let l2 = qq_expand(vm, cdr, depth, gensyms)?;
                     Ok(append(vm, l1, l2))
                 }
             }
@@ -216,23 +293,34 @@ fn qq_expand(vm: &mut Vm, exp: Value, line: u32, depth: u32) -> VMResult<Value>
                 let vector = vm.get_vector(handle);
                 let mut new_vec: Vec<Value> = vector.to_vec();
                 for i in &mut new_vec {
-                    *i = qq_expand(vm, *i, line, depth)?;
+                    *i = qq_expand(vm, *i, depth, gensyms)?;
                 }
                 Ok(vec(vm, &new_vec[..]))
             }
-            _ => Ok(quote(vm, exp)),
+            _ => {
+                if depth == 0 && auto_gensym(vm, exp, gensyms) {
+                    Ok(exp)
+                } else {
+                    Ok(quote(vm, exp))
+                }
+            }
         }
     }
 }
 
-fn qq_expand_list(vm: &mut Vm, exp: Value, line: u32, depth: u32) -> VMResult<Value> {
+fn qq_expand_list(
+    vm: &mut Vm,
+    exp: Value,
+    depth: u32,
+    gensyms: &mut Vec<Interned>,
+) -> CompileResult<Value> {
     let tag = Tag::new(vm);
     if tag.is_unquote(vm, exp) {
         if depth == 0 {
             let data = Tag::data(vm, exp)?;
             Ok(list(vm, data))
         } else {
-            let expand = qq_expand(vm, Tag::data(vm, exp)?, line, depth - 1)?;
+            let expand = qq_expand(vm, Tag::data(vm, exp)?, depth - 1, gensyms)?;
             let inner = unquote(vm, expand);
             Ok(list(vm, inner))
         }
@@ -240,7 +328,7 @@ fn qq_expand_list(vm: &mut Vm, exp: Value, line: u32, depth: u32) -> VMResult<Va
         if depth == 0 {
             Ok(Tag::data(vm, exp)?)
         } else {
-            let expand = qq_expand(vm, Tag::data(vm, exp)?, line, depth - 1)?;
+            let expand = qq_expand(vm, Tag::data(vm, exp)?, depth - 1, gensyms)?;
             let inner = splice(vm, expand);
             Ok(list(vm, inner))
         }
@@ -248,23 +336,23 @@ fn qq_expand_list(vm: &mut Vm, exp: Value, line: u32, depth: u32) -> VMResult<Va
         if depth == 0 {
             Ok(Tag::data(vm, exp)?)
         } else {
-            let expand = qq_expand(vm, Tag::data(vm, exp)?, line, depth - 1)?;
+            let expand = qq_expand(vm, Tag::data(vm, exp)?, depth - 1, gensyms)?;
             let inner = splice_bang(vm, expand);
             Ok(list(vm, inner))
         }
     } else if tag.is_backquote(vm, exp) {
-        let inner = qq_expand(vm, Tag::data(vm, exp)?, line, depth + 1)?;
+        let inner = qq_expand(vm, Tag::data(vm, exp)?, depth + 1, gensyms)?;
         let inner = back_quote(vm, inner);
         Ok(list(vm, inner))
     } else {
         match exp {
             Value::Pair(handle) => {
                 let (car, cdr) = vm.get_pair(handle);
-                let l1 = qq_expand_list(vm, car, line, depth)?;
+                let l1 = qq_expand_list(vm, car, depth, gensyms)?;
                 if cdr.is_nil() {
                     Ok(list(vm, l1))
                 } else {
-                    let l2 = qq_expand(vm, cdr, line, depth)?;
+                    let l2 = qq_expand(vm, cdr, depth, gensyms)?;
                     let app = append(vm, l1, l2);
                     Ok(list(vm, app))
                 }
@@ -273,14 +361,18 @@ fn qq_expand_list(vm: &mut Vm, exp: Value, line: u32, depth: u32) -> VMResult<Va
                 let vector = vm.get_vector(handle);
                 let mut new_vec: Vec<Value> = vector.to_vec();
                 for i in &mut new_vec {
-                    *i = qq_expand(vm, *i, line, depth)?;
+                    *i = qq_expand(vm, *i, depth, gensyms)?;
                 }
                 let vv = vec(vm, &new_vec[..]);
                 Ok(list(vm, vv))
             }
             _ => {
-                let q = quote(vm, exp);
-                Ok(list(vm, q))
+                if depth == 0 && auto_gensym(vm, exp, gensyms) {
+                    Ok(list(vm, exp))
+                } else {
+                    let q = quote(vm, exp);
+                    Ok(list(vm, q))
+                }
             }
         }
     }
@@ -292,15 +384,65 @@ pub fn backquote(
     exp: Value,
     result: usize,
     line: &mut Option<&mut u32>,
-) -> VMResult<()> {
-    let line_num = match line {
-        Some(l) => **l,
-        None => 0,
-    };
-    vm.pause_gc();
-    let result = qq_expand(vm, exp, line_num, 0).and_then(|expand| {
-        pass1(vm, state, expand).and_then(|_| compile(vm, state, expand, result, &mut None))
+) -> CompileResult<()> {
+    // Each pair of the expansion is rooted as it is built, once the expansion is rooted the
+    // pieces are kept by it.
+    let mark = roots_mark();
+    let mut gensyms = Vec::new();
+    let expand = qq_expand(vm, exp, 0, &mut gensyms).map(|expand| {
+        if gensyms.is_empty() {
+            expand
+        } else {
+            bind_gensyms(vm, expand, &gensyms)
+        }
     });
-    vm.unpause_gc();
-    result
+    release_roots(vm, mark);
+    let expand = expand?;
+    root(vm, expand);
+    pass1(vm, state, expand)?;
+    compile(vm, state, expand, result, line)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::test_util::*;
+
+    #[test]
+    fn test_auto_gensym() {
+        let mut vm = new_vm();
+        eval(
+            &mut vm,
+            "(defmacro twice (x) `(let ((v# ,x)) (+ v# v#)))
+             (defmacro names () `(list 'a# 'a# 'b#))",
+        );
+        // v# does not capture a v in the caller.
+        assert_eq!(eval(&mut vm, "(let ((v 3)) (twice (+ v 1)))"), "8");
+        // The same name# is one symbol in an expansion, a different name another.
+        assert_eq!(
+            eval(&mut vm, "(let ((n (names))) (eq? (car n) (car (cdr n))))"),
+            "true"
+        );
+        assert_eq!(
+            eval(
+                &mut vm,
+                "(let ((n (names))) (eq? (car n) (car (cdr (cdr n)))))"
+            ),
+            "false"
+        );
+        // Each expansion gets new symbols.
+        assert_eq!(eval(&mut vm, "(eq? (car (names)) (car (names)))"), "false");
+    }
+
+    #[test]
+    fn test_gensym_salted() {
+        let mut vm = Vm::new();
+        let a = gensym(&mut vm, &[]).unwrap();
+        let b = gensym(&mut vm, &[]).unwrap();
+        assert_ne!(a, b);
+        let name = a.display_value(&vm);
+        let salt = format!("-{:x}", gensym_salt());
+        assert!(name.starts_with("#:G") && name.ends_with(&salt), "{}", name);
+    }
 }
@@ -0,0 +1,496 @@
+//! Compile the IR (see ir.rs) to bytecode.
+//!
+//! Each top level form is lowered then compiled from its IrFn: macros are already expanded,
+//! globals resolved and constants folded.  The special forms the IR has nodes for share their
+//! compilers with compile (they take their args as Exp).  A Form node, a form the IR does not
+//! lower (loops, match, destructuring, etc), is compiled from its source by compile, so the
+//! codegen binds each local by name in the state's symbols where compile would and the names
+//! there mean the locals the IR resolved them to.
+
+use std::cell::RefCell;
+use std::rc::Rc;
+
+use slvm::interner::*;
+use slvm::opcodes::*;
+use slvm::value::*;
+use slvm::vm::*;
+
+use crate::compile::*;
+use crate::error::*;
+use crate::fold::*;
+use crate::inline::*;
+use crate::ir::*;
+use crate::state::*;
+
+/// A node of the IrFn ir, the IrFn names its locals.
+#[derive(Copy, Clone)]
+pub struct IrExp<'a> {
+    pub ir: &'a IrFn,
+    pub node: &'a Node,
+}
+
+impl<'a> IrExp<'a> {
+    fn of(self, node: &'a Node) -> Self {
+        IrExp { ir: self.ir, node }
+    }
+
+    fn all(self, nodes: &'a [Node]) -> Vec<IrExp<'a>> {
+        nodes.iter().map(|node| self.of(node)).collect()
+    }
+
+    fn name(self, id: LocalId) -> Interned {
+        self.ir.locals[id.0].name
+    }
+}
+
+impl Exp for IrExp<'_> {
+    fn compile(
+        self,
+        vm: &mut Vm,
+        state: &mut CompileState,
+        result: usize,
+        line: &mut Option<&mut u32>,
+    ) -> CompileResult<()> {
+        compile_node(vm, state, self, result, line)
+    }
+
+    fn local(self) -> Option<Interned> {
+        if let NodeKind::Local(id) = self.node.kind {
+            Some(self.name(id))
+        } else {
+            None
+        }
+    }
+
+    fn is_simple(self) -> bool {
+        matches!(
+            self.node.kind,
+            NodeKind::Const(_) | NodeKind::Local(_) | NodeKind::Global(_)
+        )
+    }
+
+    fn const_value(self, _state: &CompileState) -> Option<Value> {
+        if let NodeKind::Const(val) = self.node.kind {
+            Some(val)
+        } else {
+            None
+        }
+    }
+}
+
+/// Lower the top level form exp and compile its IR into result.  A top level do is lowered a
+/// form at a time, what compiling one does (define a macro in an eval-when, switch namespace,
+/// declare a fn inline) applies to lowering the forms after it.
+pub fn compile_lowered(
+    vm: &mut Vm,
+    state: &mut CompileState,
+    exp: Value,
+    result: usize,
+    line: &mut Option<&mut u32>,
+) -> CompileResult<()> {
+    if let Value::Pair(handle) = exp {
+        let (car, cdr) = vm.get_pair(handle);
+        if car == Value::Symbol(state.specials.do_) {
+            let body: Vec<Value> = cdr.iter(vm).collect();
+            if body.is_empty() {
+                return mkconst(vm, state, Value::Nil, result, line);
+            }
+            for exp in body {
+                compile_lowered(vm, state, exp, result, line)?;
+            }
+            return Ok(());
+        }
+    }
+    let ir = lower(vm, state, exp)?;
+    pass1_forms(vm, state, &ir.body)?;
+    for node in &ir.body {
+        state.top_level = true;
+        IrExp { ir: &ir, node }.compile(vm, state, result, line)?;
+    }
+    Ok(())
+}
+
+/// Run pass1 on the Form nodes in nodes (not in inner fns, they have their own state).  This
+/// captures the locals of outer fns they use before the body takes any registers.
+fn pass1_forms(vm: &mut Vm, state: &mut CompileState, nodes: &[Node]) -> CompileResult<()> {
+    for node in nodes {
+        if let NodeKind::Form(form) = node.kind {
+            pass1(vm, state, form)?;
+        }
+        for sub in node.subnodes() {
+            pass1_forms(vm, state, std::slice::from_ref(sub))?;
+        }
+    }
+    Ok(())
+}
+
+/// The index of the local name in state.
+fn local_index(vm: &Vm, state: &CompileState, name: Interned) -> CompileResult<usize> {
+    state.get_symbol(name).ok_or_else(|| {
+        CompileError::malformed(format!("local {} is not bound", vm.get_interned(name)))
+    })
+}
+
+fn compile_node(
+    vm: &mut Vm,
+    state: &mut CompileState,
+    exp: IrExp,
+    result: usize,
+    line: &mut Option<&mut u32>,
+) -> CompileResult<()> {
+    if state.max_regs < result {
+        state.max_regs = result;
+    }
+    let node = exp.node;
+    // An atom has the span of the form it is in, that may not be the line being compiled.
+    if !exp.is_simple() {
+        if let (Some(node_line), Some(line)) = (node.span.line, line.as_mut()) {
+            **line = node_line;
+        }
+    }
+    // Only the forms that keep a top level form top level get it for their subforms.
+    let top_level = state.top_level;
+    state.top_level = false;
+    // The node's op has read its operands once it is compiled.
+    let temps = state.temps_mark();
+    let res = compile_node_kind(vm, state, exp, result, line, top_level);
+    state.release_temps(temps);
+    res.map_err(|e| e.with_form(vm, node.form).with_file(state.chunk.file_name))
+}
+
+fn compile_node_kind(
+    vm: &mut Vm,
+    state: &mut CompileState,
+    exp: IrExp,
+    result: usize,
+    line: &mut Option<&mut u32>,
+    top_level: bool,
+) -> CompileResult<()> {
+    match &exp.node.kind {
+        NodeKind::Const(val) => mkconst(vm, state, *val, result, line),
+        NodeKind::Local(id) => {
+            let name = exp.name(*id);
+            let idx = local_index(vm, state, name)?;
+            state.read_local(name);
+            if result != idx + 1 {
+                state.encode2(MOV, result as u16, (idx + 1) as u16, own_line(line))?;
+            }
+            Ok(())
+        }
+        NodeKind::Global(global) => {
+            state.warn_undefined(vm, *global, "reference to", own_line(line));
+            state.encode_refi(vm, result as u16, *global, own_line(line))?;
+            Ok(())
+        }
+        NodeKind::Def(global, val) => {
+            state.tail = false;
+            state.warnings.borrow_mut().defined(*global);
+            vm.reserve_index(*global);
+            warn_inline_redefinition(vm, state, *global, "def", own_line(line));
+            exp.of(val).compile(vm, state, result + 1, line)?;
+            state.encode_refi(vm, result as u16, *global, own_line(line))?;
+            state.encode2(DEF, result as u16, (result + 1) as u16, own_line(line))?;
+            Ok(())
+        }
+        NodeKind::SetLocal(id, val) => {
+            state.tail = false;
+            let name = exp.name(*id);
+            let idx = local_index(vm, state, name)?;
+            exp.of(val).compile(vm, state, result, line)?;
+            state.set_local(name, own_line(line));
+            state.encode2(SET, (idx + 1) as u16, result as u16, own_line(line))?;
+            Ok(())
+        }
+        NodeKind::SetGlobal(global, val) => {
+            state.tail = false;
+            state.warn_undefined(vm, *global, "set! of", own_line(line));
+            warn_inline_redefinition(vm, state, *global, "set!", own_line(line));
+            exp.of(val).compile(vm, state, result + 1, line)?;
+            state.encode_refi(vm, result as u16, *global, own_line(line))?;
+            state.encode2(DEF, result as u16, (result + 1) as u16, own_line(line))?;
+            Ok(())
+        }
+        NodeKind::Do(body) => {
+            if body.is_empty() {
+                return mkconst(vm, state, Value::Nil, result, line);
+            }
+            let last_thing = body.len() - 1;
+            let old_tail = state.tail;
+            state.tail = false;
+            for (i, node) in body.iter().enumerate() {
+                if i == last_thing {
+                    state.tail = old_tail;
+                }
+                state.top_level = top_level;
+                exp.of(node).compile(vm, state, result, line)?;
+            }
+            Ok(())
+        }
+        NodeKind::If(args) => compile_if(vm, state, &exp.all(args), result, line),
+        NodeKind::And(args) => compile_and(vm, state, &exp.all(args), result, line),
+        NodeKind::Or(args) => compile_or(vm, state, &exp.all(args), result, line),
+        NodeKind::Let { .. } => compile_let_node(vm, state, exp, result, line),
+        NodeKind::Fn(ir) => compile_fn_node(vm, state, ir, result, line),
+        NodeKind::Prim(op, args) => {
+            compile_prim(vm, state, Value::Symbol(*op), &exp.all(args), result, line)?;
+            Ok(())
+        }
+        NodeKind::Call(callee, args) => {
+            let args = exp.all(args);
+            match callee.kind {
+                NodeKind::Local(id) => {
+                    let name = exp.name(id);
+                    let idx = local_index(vm, state, name)?;
+                    state.read_local(name);
+                    compile_call_reg(vm, state, (idx + 1) as u16, &args, result, line)
+                }
+                NodeKind::Const(callable)
+                    if matches!(callable, Value::Lambda(_) | Value::Builtin(_)) =>
+                {
+                    compile_call(vm, state, callable, &args, result, line)
+                }
+                _ => {
+                    // Only the call is in tail position, not the form giving the callee.
+                    let tail = state.tail;
+                    state.tail = false;
+                    exp.of(callee).compile(vm, state, result, line)?;
+                    state.tail = tail;
+                    compile_call_reg(vm, state, result as u16, &args, result, line)
+                }
+            }
+        }
+        NodeKind::CallGlobal(global, args) => {
+            vm.reserve_index(*global);
+            state.warn_undefined(vm, *global, "call to", own_line(line));
+            compile_callg(vm, state, *global, &exp.all(args), result, line)
+        }
+        NodeKind::Recur(args) => {
+            if !state.tail {
+                return Err(CompileError::malformed("recur: not in tail position"));
+            }
+            compile_call_myself(vm, state, &exp.all(args), result, line, true)
+        }
+        NodeKind::ThisFn(args) => {
+            compile_call_myself(vm, state, &exp.all(args), result, line, false)
+        }
+        NodeKind::Form(form) => {
+            fold_constants(vm, state, *form);
+            state.top_level = top_level;
+            compile(vm, state, *form, result, line)
+        }
+    }
+}
+
+/// Compile the let (or let*) node of exp like compile_let.  Each local is named once its value
+/// is compiled, so the value of a let* binding still sees what its name meant before.
+fn compile_let_node(
+    vm: &mut Vm,
+    state: &mut CompileState,
+    exp: IrExp,
+    result: usize,
+    line: &mut Option<&mut u32>,
+) -> CompileResult<()> {
+    fn inner(
+        vm: &mut Vm,
+        state: &mut CompileState,
+        exp: IrExp,
+        result: usize,
+        line: &mut Option<&mut u32>,
+        old_tail: bool,
+    ) -> CompileResult<()> {
+        let (star, bindings, body) = match &exp.node.kind {
+            NodeKind::Let {
+                star,
+                bindings,
+                body,
+            } => (*star, bindings, body),
+            _ => return Err(CompileError::malformed("let: expected a let node")),
+        };
+        let start_defers = state.defers;
+        let symbols = Rc::new(RefCell::new(Symbols::with_let(
+            state.symbols.clone(),
+            result,
+        )));
+        if star {
+            state.symbols = symbols.clone();
+        }
+        for (id, val) in bindings {
+            let name = exp.name(*id);
+            let idx = symbols.borrow().data.borrow_mut().add_anon();
+            exp.of(val).compile(vm, state, idx + 1, line)?;
+            symbols.borrow_mut().name_index(name, idx);
+            state.add_binding(vm, &symbols.borrow(), name, false, own_line(line));
+            if let Some(dbg_args) = state.chunk.dbg_args.as_mut() {
+                dbg_args.push(name);
+            }
+        }
+        if !star {
+            state.symbols = symbols;
+        }
+        let used_regs = bindings.len();
+        if body.is_empty() {
+            mkconst(vm, state, Value::Nil, result + used_regs, line)?;
+        }
+        let last_thing = body.len().saturating_sub(1);
+        for (i, node) in body.iter().enumerate() {
+            if i == last_thing {
+                state.tail = old_tail;
+            }
+            exp.of(node).compile(vm, state, result + used_regs, line)?;
+        }
+        if used_regs > 0 {
+            state.encode2(
+                MOV,
+                result as u16,
+                (result + used_regs) as u16,
+                own_line(line),
+            )?;
+        }
+        for _ in start_defers..state.defers {
+            state.encode0(DFRPOP, own_line(line))?;
+        }
+        count_popped_defers(state, state.defers - start_defers, own_line(line))
+    }
+
+    let old_symbols = state.symbols.clone();
+    let old_tail = state.tail;
+    state.tail = false;
+    let old_defers = state.defers;
+    let old_defers_known = state.defers_known;
+    let res = inner(vm, state, exp, result, line, old_tail);
+    state.tail = old_tail;
+    state.symbols = old_symbols;
+    // The let popped its defers on the way out so the outer count holds again.
+    state.defers = old_defers;
+    state.defers_known = old_defers_known;
+    res
+}
+
+/// Compile the fn ir to a lambda in result, closed over if it captures locals.
+fn compile_fn_node(
+    vm: &mut Vm,
+    state: &mut CompileState,
+    ir: &IrFn,
+    result: usize,
+    line: &mut Option<&mut u32>,
+) -> CompileResult<()> {
+    let mut new_state = fn_state(vm, state, line);
+    // The rest list is counted as the last arg.
+    for id in ir.params.iter().chain(ir.rest.iter()) {
+        add_arg(vm, &mut new_state, ir.locals[id.0].name, line);
+        new_state.chunk.args += 1;
+    }
+    new_state.chunk.rest = ir.rest.is_some();
+    for (id, _) in &ir.captures {
+        let name = ir.locals[id.0].name;
+        new_state.symbols.borrow().insert_capture(vm, name);
+        if let Some(dbg_args) = new_state.chunk.dbg_args.as_mut() {
+            dbg_args.push(name);
+        }
+    }
+    pass1_forms(vm, &mut new_state, &ir.body)?;
+    let reserved = new_state.reserved_regs();
+    let last_thing = ir.body.len().saturating_sub(1);
+    for (i, node) in ir.body.iter().enumerate() {
+        if i == last_thing {
+            new_state.tail = true;
+        }
+        IrExp { ir, node }.compile(vm, &mut new_state, reserved, line)?;
+    }
+    new_state.encode1(SRET, reserved as u16, own_line(line))?;
+    let (lambda, closure) = alloc_lambda(vm, state, new_state, reserved, false)?;
+    load_lambda(state, lambda, closure, result, line)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::test_util::*;
+
+    #[test]
+    fn test_locals_and_closures() {
+        let mut vm = new_vm();
+        assert_eq!(
+            eval(&mut vm, "(let (x 1 y 2) (let (x y y x) (list x y)))"),
+            "(2 1)"
+        );
+        assert_eq!(eval(&mut vm, "(let* (x 1 y (+ x 1)) (list x y))"), "(1 2)");
+        assert_eq!(eval(&mut vm, "(let (x 1) (let* (x (+ x 1)) x))"), "2");
+        assert_eq!(eval(&mut vm, "(let (x 1) (let () x))"), "1");
+        assert_eq!(eval(&mut vm, "(let (x 1))"), "nil");
+        assert_eq!(
+            eval(&mut vm, "(defn adder (n) (fn (x) (+ x n))) ((adder 2) 3)"),
+            "5"
+        );
+        assert_eq!(
+            eval(
+                &mut vm,
+                "(let (a 1) ((fn () (let (b 2) ((fn () (+ a b)))))))"
+            ),
+            "3"
+        );
+        assert_eq!(
+            eval(&mut vm, "((fn (x &rest r) (list x r)) 1 2 3)"),
+            "(1 (2 3))"
+        );
+    }
+
+    #[test]
+    fn test_set_local_and_tail() {
+        let mut vm = new_vm();
+        assert_eq!(eval(&mut vm, "(let (x 1) (set! x (+ x 1)) x)"), "2");
+        assert_eq!(
+            eval(
+                &mut vm,
+                "(defn inc-by (n) (fn () (set! n (+ n 1)) n)) (let (f (inc-by 1)) (f) (f))"
+            ),
+            "3"
+        );
+        // A call in an op in tail position is not a tail call.
+        assert_eq!(
+            eval(&mut vm, "(defn g (x) x) (defn f (x) (+ (g x) 1)) (f 1)"),
+            "2"
+        );
+        assert_eq!(
+            eval(
+                &mut vm,
+                "(defn h (x) (if (> x 0) (recur (- x 1)) :done)) (h 3)"
+            ),
+            ":done"
+        );
+    }
+
+    #[test]
+    fn test_form_sees_locals() {
+        let mut vm = new_vm();
+        // loop and cond are not lowered, they are compiled from their forms inside IR locals.
+        assert_eq!(
+            eval(
+                &mut vm,
+                "(defn sum (n)
+                   (let (acc 0)
+                     (loop ((i n)) (if (= i 0) acc (do (set! acc (+ acc i)) (recur (- i 1)))))))
+                 (sum 4)"
+            ),
+            "10"
+        );
+        assert_eq!(
+            eval(
+                &mut vm,
+                "(let (x 2) ((fn () (cond ((= x 1) :one) ((= x 2) :two) (#t :many)))))"
+            ),
+            ":two"
+        );
+    }
+
+    #[test]
+    fn test_top_level_do() {
+        let mut vm = new_vm();
+        // Each form of a top level do is lowered after the ones before it are compiled.
+        assert_eq!(
+            eval(&mut vm, "(do (defmacro twice (x) `(list ,x ,x)) (twice 1))"),
+            "(1 1)"
+        );
+        assert_eq!(eval(&mut vm, "(do (def cg-a 1) (+ cg-a 1))"), "2");
+        assert_eq!(eval(&mut vm, "(do)"), "nil");
+    }
+}
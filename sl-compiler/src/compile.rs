@@ -1,102 +1,177 @@
 use std::cell::RefCell;
+use std::cmp::Ordering;
 use std::rc::Rc;
 use std::sync::Arc;
 
+use slvm::chunk::*;
 use slvm::error::*;
+use slvm::interner::*;
 use slvm::opcodes::*;
 use slvm::value::*;
 use slvm::vm::*;
 use slvm::Handle;
 
 use crate::backquote::*;
+use crate::codegen::*;
+use crate::error::*;
+use crate::fold::*;
+use crate::inline::*;
+use crate::namespace::*;
+use crate::peephole::*;
+use crate::regalloc::*;
+use crate::roots::*;
 use crate::state::*;
+use crate::warning::*;
 
-fn compile_params(
+fn compile_params<E: Exp>(
     vm: &mut Vm,
     state: &mut CompileState,
-    cdr: &[Value],
+    cdr: &[E],
     result: usize,
     tail: bool,
     line: &mut Option<&mut u32>,
-) -> VMResult<()> {
+) -> CompileResult<()> {
     for (i, r) in cdr.iter().enumerate() {
-        compile(vm, state, *r, result + i, line)?;
+        r.compile(vm, state, result + i, line)?;
     }
     let line = own_line(line);
     if tail {
-        state
-            .chunk
-            .encode3(BMOV, 1, result as u16, cdr.len() as u16, line)?;
+        // Only recur is a tail call with defers active (a call waits for them), the defers of
+        // this pass through the fn run before it starts again.
+        for _ in 0..state.tail_defers().unwrap_or(0) {
+            state.encode0(DFRPOP, line)?;
+        }
+        state.encode3(BMOV, 1, result as u16, cdr.len() as u16, line)?;
     }
     Ok(())
 }
 
-fn compile_call(
+/// Call the constant callable (a lambda or builtin) with args cdr.
+pub fn compile_call<E: Exp>(
     vm: &mut Vm,
     state: &mut CompileState,
     callable: Value,
-    cdr: &[Value],
+    cdr: &[E],
     result: usize,
     line: &mut Option<&mut u32>,
-) -> VMResult<()> {
+) -> CompileResult<()> {
+    if compile_inline(vm, state, callable, cdr, result, line)? {
+        return Ok(());
+    }
     let b_reg = result + cdr.len() + 1;
     let const_i = state.add_constant(callable);
-    let tail = state.tail && state.defers == 0;
+    let tail = state.tail_call();
     state.tail = false;
     compile_params(vm, state, cdr, result + 1, tail, line)?;
     let line = own_line(line);
-    state
-        .chunk
-        .encode2(CONST, b_reg as u16, const_i as u16, line)?;
+    state.encode2(CONST, b_reg as u16, const_i as u16, line)?;
     if tail {
-        state
-            .chunk
-            .encode2(TCALL, b_reg as u16, cdr.len() as u16, line)?;
+        state.encode2(TCALL, b_reg as u16, cdr.len() as u16, line)?;
     } else {
-        state
-            .chunk
-            .encode3(CALL, b_reg as u16, cdr.len() as u16, result as u16, line)?;
+        state.encode3(CALL, b_reg as u16, cdr.len() as u16, result as u16, line)?;
     }
     Ok(())
 }
 
-fn compile_callg(
+/// If chunk can not take nargs args describe what it expects.
+fn arity_mismatch(chunk: &Chunk, nargs: usize) -> Option<String> {
+    let args = chunk.args as usize;
+    let opt_args = chunk.opt_args as usize;
+    if chunk.rest {
+        // The rest list is counted as the last arg.
+        let min = if opt_args > 0 {
+            args
+        } else {
+            args.saturating_sub(1)
+        };
+        if nargs < min {
+            return Some(format!("expected at least {} args, got {}", min, nargs));
+        }
+    } else if opt_args > 0 {
+        if nargs < args || nargs > args + opt_args {
+            return Some(format!(
+                "expected {} to {} args, got {}",
+                args,
+                args + opt_args,
+                nargs
+            ));
+        }
+    } else if nargs != args {
+        return Some(format!("expected {} args, got {}", args, nargs));
+    }
+    None
+}
+
+/// If global is currently a lambda check nargs against it, only a warning since the global
+/// can be redefined before the call runs.
+fn check_global_arity(
+    vm: &Vm,
+    state: &CompileState,
+    global: Interned,
+    nargs: usize,
+    line: Option<u32>,
+) {
+    let chunk = match vm
+        .global_intern_slot(global)
+        .map(|slot| vm.get_global(slot))
+    {
+        Some(Value::Lambda(h)) => vm.get_lambda(h),
+        Some(Value::Closure(h)) => vm.get_closure(h).0,
+        _ => return,
+    };
+    if let Some(message) = arity_mismatch(&chunk, nargs) {
+        state.warn(
+            WarningKind::ArgCount,
+            format!("{} {}", vm.get_interned(global), message),
+            line,
+        );
+    }
+}
+
+/// Call the global with args cdr, inlined if it is an inline fn.
+pub fn compile_callg<E: Exp>(
     vm: &mut Vm,
     state: &mut CompileState,
-    global: u32,
-    cdr: &[Value],
+    global: Interned,
+    cdr: &[E],
     result: usize,
     line: &mut Option<&mut u32>,
-) -> VMResult<()> {
-    let tail = state.tail && state.defers == 0;
+) -> CompileResult<()> {
+    check_global_arity(vm, state, global, cdr.len(), own_line(line));
+    if let Some(lambda @ Value::Lambda(_)) = vm
+        .global_intern_slot(global)
+        .map(|slot| vm.get_global(slot))
+    {
+        if compile_inline(vm, state, lambda, cdr, result, line)? {
+            return Ok(());
+        }
+    }
+    let tail = state.tail_call();
     state.tail = false;
     compile_params(vm, state, cdr, result + 1, tail, line)?;
     let line = own_line(line);
     if tail {
-        state.chunk.encode_tcallg(global, cdr.len() as u16, line)?;
+        state.encode_tcallg(vm, global, cdr.len() as u16, line)?;
     } else {
-        state
-            .chunk
-            .encode_callg(global, cdr.len() as u16, result as u16, line)?;
+        state.encode_callg(vm, global, cdr.len() as u16, result as u16, line)?;
     }
     Ok(())
 }
 
-fn compile_call_reg(
+/// Call the callable in register reg with args cdr.
+pub fn compile_call_reg<E: Exp>(
     vm: &mut Vm,
     state: &mut CompileState,
     reg: u16,
-    cdr: &[Value],
+    cdr: &[E],
     result: usize,
     line: &mut Option<&mut u32>,
-) -> VMResult<()> {
-    let tail = state.tail && state.defers == 0;
+) -> CompileResult<()> {
+    let tail = state.tail_call();
     state.tail = false;
     let b_reg = if tail {
         let b_reg = result + cdr.len() + 2;
-        state
-            .chunk
-            .encode2(MOV, b_reg as u16, reg as u16, own_line(line))?;
+        state.encode2(MOV, b_reg as u16, reg as u16, own_line(line))?;
         b_reg
     } else {
         0
@@ -104,35 +179,35 @@ fn compile_call_reg(
     compile_params(vm, state, cdr, result + 1, tail, line)?;
     let line = own_line(line);
     if tail {
-        state
-            .chunk
-            .encode2(TCALL, b_reg as u16, cdr.len() as u16, line)?;
+        state.encode2(TCALL, b_reg as u16, cdr.len() as u16, line)?;
     } else {
-        state
-            .chunk
-            .encode3(CALL, reg, cdr.len() as u16, result as u16, line)?;
+        state.encode3(CALL, reg, cdr.len() as u16, result as u16, line)?;
     }
     Ok(())
 }
 
-fn compile_call_myself(
+/// Call the fn being compiled with args cdr, recur if force_tail else this-fn.
+pub fn compile_call_myself<E: Exp>(
     vm: &mut Vm,
     state: &mut CompileState,
-    cdr: &[Value],
+    cdr: &[E],
     result: usize,
     line: &mut Option<&mut u32>,
     force_tail: bool,
-) -> VMResult<()> {
-    let tail = force_tail || (state.tail && state.defers == 0);
+) -> CompileResult<()> {
+    // The fn being compiled is the callee so its arity is known for sure.
+    if let Some(message) = arity_mismatch(&state.chunk, cdr.len()) {
+        let name = if force_tail { "recur" } else { "this-fn" };
+        return Err(CompileError::arg_count(format!("{}: {}", name, message)));
+    }
+    let tail = force_tail || state.tail_call();
     state.tail = false;
     compile_params(vm, state, cdr, result + 1, tail, line)?;
     let line = own_line(line);
     if tail {
-        state.chunk.encode1(TCALLM, cdr.len() as u16, line)?;
+        state.encode1(TCALLM, cdr.len() as u16, line)?;
     } else {
-        state
-            .chunk
-            .encode2(CALLM, cdr.len() as u16, result as u16, line)?;
+        state.encode2(CALLM, cdr.len() as u16, result as u16, line)?;
     }
     Ok(())
 }
@@ -149,7 +224,7 @@ fn get_args_iter<'vm>(
     args: Value,
     name: &str,
     line: &mut Option<&mut u32>,
-) -> VMResult<Box<dyn Iterator<Item = Value> + 'vm>> {
+) -> CompileResult<Box<dyn Iterator<Item = Value> + 'vm>> {
     match args {
         Value::Pair(handle) => {
             let (_, _) = vm.get_pair(handle);
@@ -159,38 +234,190 @@ fn get_args_iter<'vm>(
         Value::Vector(_v) => Ok(args.iter(vm)),
         Value::Nil => Ok(args.iter(vm)),
         _ => {
-            return Err(VMError::new_compile(format!("{}, invalid args", name)));
+            return Err(CompileError::invalid_args(format!(
+                "{}, invalid args",
+                name
+            )));
         }
     }
 }
 
-fn mk_state(
-    vm: &mut Vm,
-    state: &mut CompileState,
-    args: Value,
-    line: &mut Option<&mut u32>,
-) -> VMResult<(CompileState, Option<Vec<Value>>)> {
+/// True if arg (from a fn arg list after &optional or &key) is (name) or (name default).
+fn is_opt_arg(vm: &Vm, arg: Value) -> bool {
+    if let Value::Pair(handle) = arg {
+        let (car, cdr) = vm.get_pair(handle);
+        if let Value::Symbol(_) = car {
+            return match cdr {
+                Value::Nil => true,
+                Value::Pair(handle) => vm.get_pair(handle).1.is_nil(),
+                _ => false,
+            };
+        }
+    }
+    false
+}
+
+/// True if arg is (name default) with a default that is not a pattern (a literal or quoted
+/// datum), the optional arg syntax from before args were destructured.
+fn is_old_opt_arg(vm: &Vm, state: &CompileState, arg: Value) -> bool {
+    if !is_opt_arg(vm, arg) {
+        return false;
+    }
+    let default = match arg {
+        Value::Pair(handle) => match vm.get_pair(handle).1 {
+            Value::Pair(handle) => vm.get_pair(handle).0,
+            _ => return false,
+        },
+        _ => return false,
+    };
+    match default {
+        Value::Symbol(_) | Value::Vector(_) => false,
+        Value::Pair(handle) => vm.get_pair(handle).0 == Value::Symbol(state.specials.quote),
+        _ => true,
+    }
+}
+
+/// The parts of a fn arg list that are compiled at the start of the body.
+#[derive(Default)]
+struct ArgComps {
+    /// (register, default) of the optional args with a default value.
+    opt_comps: Vec<(usize, Value)>,
+    /// (register, pattern) of destructured args.
+    patterns: Vec<(usize, Value)>,
+    /// The register of the rest list the &key args are parsed from.
+    key_rest: usize,
+    /// (name, register, default) of each &key arg.
+    keys: Vec<(Interned, usize, Value)>,
+}
+
+/// Add a named arg to new_state, returns its register.
+pub fn add_arg(
+    vm: &Vm,
+    new_state: &mut CompileState,
+    name: Interned,
+    line: &Option<&mut u32>,
+) -> usize {
+    let idx = new_state.symbols.borrow().data.borrow().count();
+    new_state
+        .symbols
+        .borrow_mut()
+        .data
+        .borrow_mut()
+        .add_sym(name);
+    new_state.add_binding(vm, &new_state.symbols.borrow(), name, true, own_line(line));
+    if let Some(dbg_args) = new_state.chunk.dbg_args.as_mut() {
+        dbg_args.push(name);
+    }
+    idx + 1
+}
+
+/// Add an unnamed arg to new_state (shown as _ in the debug args), returns its register.
+fn add_anon_arg(new_state: &mut CompileState) -> usize {
+    let idx = new_state.symbols.borrow().data.borrow_mut().add_anon();
+    let wildcard = new_state.specials.wildcard;
+    if let Some(dbg_args) = new_state.chunk.dbg_args.as_mut() {
+        dbg_args.push(wildcard);
+    }
+    idx + 1
+}
+
+/// Make the state for compiling a fn inside state, it has no args yet.
+pub fn fn_state(vm: &mut Vm, state: &CompileState, line: &Option<&mut u32>) -> CompileState {
     let mut new_state = CompileState::new_state(
         vm,
         state.chunk.file_name,
         own_line(line).unwrap_or(1),
         Some(state.symbols.clone()),
     );
+    new_state.warnings = state.warnings.clone();
+    new_state.declarations = state.declarations.clone();
+    new_state.expansions = state.expansions.clone();
+    new_state.peephole = state.peephole;
+    new_state.mode = state.mode;
+    new_state.chunk.dbg_args = Some(Vec::new());
+    new_state
+}
+
+/// Make the state for a fn with args, if rest_as_arg the &rest arg is an ordinary arg (the
+/// arities of a multi-arity fn are passed their rest list).  A list or vector arg is a
+/// destructuring pattern, after &optional the args are optional and may be (name default).
+fn mk_state(
+    vm: &mut Vm,
+    state: &mut CompileState,
+    args: Value,
+    line: &mut Option<&mut u32>,
+    rest_as_arg: bool,
+) -> CompileResult<(CompileState, ArgComps)> {
+    let mut new_state = fn_state(vm, state, line);
     let args_iter = get_args_iter(vm, args, "fn", line)?;
     let mut opt = false;
     let mut rest = false;
-    let mut opt_comps = Vec::new();
-    new_state.chunk.dbg_args = Some(Vec::new());
+    let mut key = false;
+    let mut comps = ArgComps::default();
     for a in args_iter {
+        if key {
+            let (name, default) = match a {
+                Value::Symbol(i) if i == new_state.specials.rest => {
+                    return Err(CompileError::invalid_args(
+                        "Malformed fn, can not use &rest and &key together.",
+                    ))
+                }
+                Value::Symbol(i) => (i, Value::Nil),
+                Value::Pair(_) if is_opt_arg(vm, a) => {
+                    let mut args_iter = get_args_iter(vm, a, "fn", line)?;
+                    if let Some(Value::Symbol(name)) = args_iter.next() {
+                        (name, args_iter.next().unwrap_or(Value::Nil))
+                    } else {
+                        // is_opt_arg checked for (name default?).
+                        unreachable!()
+                    }
+                }
+                _ => {
+                    return Err(CompileError::invalid_args(
+                        "Malformed fn, &key args must be symbols or (symbol default).",
+                    ))
+                }
+            };
+            if comps.keys.iter().any(|(k, _, _)| *k == name) {
+                return Err(CompileError::invalid_args(format!(
+                    "Malformed fn, duplicate &key arg {}.",
+                    vm.get_interned(name)
+                )));
+            }
+            let reg = add_arg(vm, &mut new_state, name, line);
+            comps.keys.push((name, reg, default));
+            continue;
+        }
         match a {
+            Value::Symbol(i) if i == new_state.specials.key => {
+                if rest {
+                    return Err(CompileError::invalid_args(
+                        "Malformed fn, can not use &rest and &key together.",
+                    ));
+                }
+                // The keyword/value args are collected in an unnamed rest list.
+                rest = true;
+                key = true;
+                comps.key_rest = add_anon_arg(&mut new_state);
+                if opt {
+                    new_state.chunk.opt_args += 1;
+                } else {
+                    new_state.chunk.args += 1;
+                }
+            }
+            Value::Symbol(i) if i == new_state.specials.optional => {
+                if rest {
+                    return Err(CompileError::invalid_args(
+                        "Malformed fn, &optional must come before &rest.",
+                    ));
+                }
+                opt = true;
+            }
             Value::Symbol(i) => {
                 if i == new_state.specials.rest {
-                    rest = true;
+                    rest = !rest_as_arg;
                 } else {
-                    new_state.symbols.borrow_mut().data.borrow_mut().add_sym(i);
-                    if let Some(dbg_args) = state.chunk.dbg_args.as_mut() {
-                        dbg_args.push(i);
-                    }
+                    add_arg(vm, &mut new_state, i, line);
                     if opt {
                         new_state.chunk.opt_args += 1;
                     } else {
@@ -198,68 +425,176 @@ fn mk_state(
                     }
                 }
             }
-            Value::Pair(_) | Value::Vector(_) => {
+            Value::Pair(_) if opt && is_opt_arg(vm, a) => {
                 let mut args_iter = get_args_iter(vm, a, "fn", line)?;
-                opt = true;
                 if let Some(Value::Symbol(i)) = args_iter.next() {
-                    new_state.symbols.borrow_mut().data.borrow_mut().add_sym(i);
-                    if let Some(dbg_args) = state.chunk.dbg_args.as_mut() {
-                        dbg_args.push(i);
-                    }
+                    let reg = add_arg(vm, &mut new_state, i, line);
                     new_state.chunk.opt_args += 1;
                     if let Some(r) = args_iter.next() {
-                        opt_comps.push(r);
+                        comps.opt_comps.push((reg, r));
                     }
-                    // XXX Check to make sure only two elements...
+                }
+            }
+            Value::Pair(_) if is_old_opt_arg(vm, &new_state, a) => {
+                return Err(CompileError::invalid_args(format!(
+                    "Malformed fn, {} is not a pattern, put an optional arg with a default after \
+                     &optional: (fn (a &optional (b 2)) ...).",
+                    a.display_value(vm)
+                )));
+            }
+            Value::Pair(_) | Value::Vector(_) => {
+                // Destructured, the arg is unnamed and its pattern's symbols follow the args.
+                let reg = add_anon_arg(&mut new_state);
+                comps.patterns.push((reg, a));
+                if opt {
+                    new_state.chunk.opt_args += 1;
+                } else {
+                    new_state.chunk.args += 1;
                 }
             }
             _ => {
-                return Err(VMError::new_compile(
+                return Err(CompileError::invalid_args(
                     "Malformed fn, invalid args, must be symbols.",
                 ))
             }
         }
     }
+    for (_, pattern) in &comps.patterns {
+        let mut binds = Vec::new();
+        pattern_bindings(vm, &new_state, *pattern, &mut binds);
+        for b in binds {
+            add_arg(vm, &mut new_state, b, line);
+        }
+    }
     new_state.chunk.rest = rest;
-    if !opt_comps.is_empty() {
-        Ok((new_state, Some(opt_comps)))
-    } else {
-        Ok((new_state, None))
+    Ok((new_state, comps))
+}
+
+/// Compile default into target_reg if it is still unset (an optional arg that was not passed).
+fn compile_arg_default(
+    vm: &mut Vm,
+    state: &mut CompileState,
+    target_reg: usize,
+    default: Value,
+    scratch: usize,
+    line: &mut Option<&mut u32>,
+) -> CompileResult<()> {
+    state.encode1(JMPNU, target_reg as u16, own_line(line))?;
+    let encode_offset = state.chunk.code.len();
+    state.encode_jump_offset(0)?;
+    let start_offset = state.chunk.code.len();
+    compile(vm, state, default, scratch, line)?;
+    state.encode2(MOV, target_reg as u16, scratch as u16, own_line(line))?;
+    state.reencode_jump_offset(
+        encode_offset,
+        (state.chunk.code.len() - start_offset) as i32,
+    )?;
+    Ok(())
+}
+
+/// Set the &key args from the keyword/value list in register rest then compile the defaults of
+/// the ones not passed.  An odd length list, an unknown keyword or a keyword passed twice is
+/// an error.
+fn compile_key_args(
+    vm: &mut Vm,
+    state: &mut CompileState,
+    rest: usize,
+    keys: &[(Interned, usize, Value)],
+    scratch: usize,
+    line: &mut Option<&mut u32>,
+) -> CompileResult<()> {
+    // scratch is the rest of the list, then the keyword, a temp and the value.
+    let (cur, key, tmp, val) = (scratch, scratch + 1, scratch + 2, scratch + 3);
+    use_regs(state, val);
+    for (_, reg, _) in keys {
+        state.encode1(REGC, *reg as u16, own_line(line))?;
+    }
+    state.encode2(MOV, cur as u16, rest as u16, own_line(line))?;
+    let start = state.chunk.code.len();
+    state.encode1(REGN, key as u16, own_line(line))?;
+    state.encode3(EQ, key as u16, cur as u16, key as u16, own_line(line))?;
+    let done = encode_forward_jump(state, JMPT, Some(key), own_line(line))?;
+    state.encode2(CAR, key as u16, cur as u16, own_line(line))?;
+    state.encode2(CDR, cur as u16, cur as u16, own_line(line))?;
+    state.encode1(REGN, tmp as u16, own_line(line))?;
+    state.encode3(EQ, tmp as u16, cur as u16, tmp as u16, own_line(line))?;
+    let missing = encode_forward_jump(state, JMPT, Some(tmp), own_line(line))?;
+    state.encode2(CAR, val as u16, cur as u16, own_line(line))?;
+    state.encode2(CDR, cur as u16, cur as u16, own_line(line))?;
+    let mut duplicates = Vec::new();
+    for (name, reg, _) in keys {
+        mkconst(vm, state, Value::Keyword(*name), tmp, line)?;
+        state.encode3(EQ, tmp as u16, key as u16, tmp as u16, own_line(line))?;
+        let next = encode_forward_jump(state, JMPF, Some(tmp), own_line(line))?;
+        duplicates.push(encode_forward_jump(
+            state,
+            JMPNU,
+            Some(*reg),
+            own_line(line),
+        )?);
+        state.encode2(MOV, *reg as u16, val as u16, own_line(line))?;
+        encode_jump_back(state, start, own_line(line))?;
+        patch_jump(state, next)?;
+    }
+    encode_error(vm, state, "fn: unknown keyword arg ", key, tmp, line)?;
+    patch_jump(state, missing)?;
+    encode_error(vm, state, "fn: no value for keyword arg ", key, tmp, line)?;
+    for jump in duplicates {
+        patch_jump(state, jump)?;
     }
+    encode_error(vm, state, "fn: duplicate keyword arg ", key, tmp, line)?;
+    patch_jump(state, done)?;
+    for (_, reg, default) in keys {
+        compile_arg_default(vm, state, *reg, *default, scratch, line)?;
+    }
+    Ok(())
 }
 
-fn compile_fn(
+/// Compile a fn or macro to a lambda, returns it and true if it needs to be closed over.
+fn mk_lambda(
     vm: &mut Vm,
     state: &mut CompileState,
     args: Value,
     cdr: &[Value],
-    result: usize,
     line: &mut Option<&mut u32>,
     is_macro: bool,
-) -> VMResult<()> {
-    let (mut new_state, opt_comps) = mk_state(vm, state, args, line)?;
+) -> CompileResult<(Value, bool)> {
+    let (new_state, comps) = mk_state(vm, state, args, line, false)?;
+    compile_lambda(vm, state, new_state, comps, cdr, line, is_macro)
+}
+
+/// Compile the body cdr of a fn with the state and args from mk_state to a lambda.
+fn compile_lambda(
+    vm: &mut Vm,
+    state: &mut CompileState,
+    mut new_state: CompileState,
+    comps: ArgComps,
+    cdr: &[Value],
+    line: &mut Option<&mut u32>,
+    is_macro: bool,
+) -> CompileResult<(Value, bool)> {
+    for r in cdr.iter() {
+        pass1(vm, &mut new_state, *r)?;
+    }
     for r in cdr.iter() {
-        pass1(vm, &mut new_state, *r).unwrap();
+        fold_constants(vm, &mut new_state, *r);
     }
     let reserved = new_state.reserved_regs();
-    if let Some(opt_comps) = opt_comps {
-        for (i, r) in opt_comps.into_iter().enumerate() {
-            let target_reg = new_state.chunk.args as usize + i + 1;
-            new_state
-                .chunk
-                .encode1(JMPNU, target_reg as u16, own_line(line))?;
-            let encode_offset = new_state.chunk.code.len();
-            new_state.chunk.encode_jump_offset(0)?;
-            let start_offset = new_state.chunk.code.len();
-            compile(vm, &mut new_state, r, reserved, line)?;
-            new_state
-                .chunk
-                .encode2(MOV, target_reg as u16, reserved as u16, own_line(line))?;
-            new_state.chunk.reencode_jump_offset(
-                encode_offset,
-                (new_state.chunk.code.len() - start_offset) as i32,
-            )?;
-        }
+    for (target_reg, r) in comps.opt_comps {
+        compile_arg_default(vm, &mut new_state, target_reg, r, reserved, line)?;
+    }
+    if !comps.keys.is_empty() {
+        compile_key_args(
+            vm,
+            &mut new_state,
+            comps.key_rest,
+            &comps.keys,
+            reserved,
+            line,
+        )?;
+    }
+    for (reg, pattern) in comps.patterns {
+        compile_destructure(vm, &mut new_state, "fn", pattern, reg, reserved, line)?;
     }
     let last_thing = cdr.len() - 1;
     for (i, r) in cdr.iter().enumerate() {
@@ -269,9 +604,21 @@ fn compile_fn(
         compile(vm, &mut new_state, *r, reserved, line)?;
     }
     new_state
-        .chunk
         .encode1(SRET, reserved as u16, own_line(line))
         .unwrap();
+    alloc_lambda(vm, state, new_state, reserved, is_macro)
+}
+
+/// Allocate the lambda for the compiled chunk of new_state (with reserved input registers),
+/// returns it and true if it needs to be closed over.
+pub fn alloc_lambda(
+    vm: &mut Vm,
+    state: &mut CompileState,
+    mut new_state: CompileState,
+    reserved: usize,
+    is_macro: bool,
+) -> CompileResult<(Value, bool)> {
+    peephole(&mut new_state)?;
     let mut closure = false;
     if !new_state.symbols.borrow().captures.borrow().is_empty() {
         let mut caps = Vec::new();
@@ -283,42 +630,299 @@ fn compile_fn(
     }
     new_state.chunk.input_regs = reserved;
     new_state.chunk.extra_regs = new_state.max_regs - reserved;
-    vm.pause_gc();
-    let lambda = vm.alloc_lambda(Arc::new(new_state.chunk));
-    vm.unpause_gc();
+    let chunk = Arc::new(new_state.chunk);
+    let lambda = vm.alloc_lambda(chunk);
+    // Only the chunk being compiled references it until that runs.
+    let lambda = root_new(vm, lambda);
+    let id = new_chunk_id(vm, lambda);
+    record_chunk_refs(
+        id,
+        ChunkRefs {
+            global_refs: new_state.global_refs,
+            jumps: new_state.jumps,
+        },
+    );
     if is_macro {
         // Unwrap safe since we just allocated lambda on the heap.
         vm.set_heap_property(lambda.get_handle().unwrap(), ":macro", Value::True);
     }
+    Ok((lambda, closure))
+}
+
+fn compile_fn(
+    vm: &mut Vm,
+    state: &mut CompileState,
+    args: Value,
+    cdr: &[Value],
+    result: usize,
+    line: &mut Option<&mut u32>,
+    is_macro: bool,
+) -> CompileResult<()> {
+    let (lambda, closure) = if !is_macro && is_multi_arity(state, args) {
+        mk_multi_lambda(vm, state, cdr, line)?
+    } else {
+        mk_lambda(vm, state, args, cdr, line, is_macro)?
+    };
+    load_lambda(state, lambda, closure, result, line)
+}
+
+/// Load lambda from mk_lambda into result, closing over its captures if closure.
+pub fn load_lambda(
+    state: &mut CompileState,
+    lambda: Value,
+    closure: bool,
+    result: usize,
+    line: &mut Option<&mut u32>,
+) -> CompileResult<()> {
     let const_i = state.add_constant(lambda);
-    state
-        .chunk
-        .encode2(CONST, result as u16, const_i as u16, own_line(line))?;
+    state.encode2(CONST, result as u16, const_i as u16, own_line(line))?;
     if closure {
-        state
-            .chunk
-            .encode2(CLOSE, result as u16, result as u16, own_line(line))?;
+        state.encode2(CLOSE, result as u16, result as u16, own_line(line))?;
     }
     Ok(())
 }
 
-fn make_math_comp(
+/// True if args is the :arities marker of a multi-arity fn, (fn :arities (arg-list body+)+).
+/// Without a marker (fn ((x) ...) ((x y) ...)) is already a fn with two destructured args.
+fn is_multi_arity(state: &CompileState, args: Value) -> bool {
+    args == Value::Keyword(state.specials.arities)
+}
+
+/// One arity of a multi-arity fn.
+struct Arity {
+    /// Number of args before &rest.
+    fixed: usize,
+    rest: bool,
+    lambda: Value,
+    closure: bool,
+}
+
+/// The number of fixed args in an arity arg list and if it has a &rest arg.  Optional and &key
+/// args are not allowed, the arity is picked by arg count alone.
+fn arity_args(vm: &Vm, state: &CompileState, args: Value) -> CompileResult<(usize, bool)> {
+    let mut fixed = 0;
+    let mut rest = false;
+    for a in args.iter(vm) {
+        match a {
+            Value::Symbol(i) if i == state.specials.rest => rest = true,
+            Value::Symbol(i) if i == state.specials.key => {
+                return Err(CompileError::invalid_args(
+                    "Malformed fn, an arity can not have &key args.",
+                ))
+            }
+            Value::Symbol(i) if i == state.specials.optional => {
+                return Err(CompileError::invalid_args(
+                    "Malformed fn, an arity can not have optional args.",
+                ))
+            }
+            _ if rest => {}
+            _ => fixed += 1,
+        }
+    }
+    Ok((fixed, rest))
+}
+
+/// Compile a multi-arity fn, (fn :arities (arg-list body+)+).  Each arity is its own lambda (so
+/// recur and this-fn call that arity) and the returned lambda dispatches to the first arity that
+/// takes the number of args passed.  An arity with &rest is passed its rest list as an ordinary
+/// arg so a recur in it passes the rest as a list.
+fn mk_multi_lambda(
     vm: &mut Vm,
     state: &mut CompileState,
     cdr: &[Value],
+    line: &mut Option<&mut u32>,
+) -> CompileResult<(Value, bool)> {
+    let mut clauses = Vec::new();
+    for clause in cdr {
+        let clause: Vec<Value> = match clause {
+            Value::Pair(_) => clause.iter(vm).collect(),
+            _ => Vec::new(),
+        };
+        if clause.len() < 2 || !matches!(clause[0], Value::Pair(_) | Value::Nil) {
+            return Err(CompileError::malformed(
+                "Malformed fn, each arity must be (arg-list body+).",
+            ));
+        }
+        let (fixed, rest) = arity_args(vm, state, clause[0])?;
+        clauses.push((clause, fixed, rest));
+    }
+    let min_fixed = clauses
+        .iter()
+        .map(|(_, fixed, _)| *fixed)
+        .min()
+        .unwrap_or(0);
+    let max_fixed = clauses
+        .iter()
+        .map(|(_, fixed, _)| *fixed)
+        .max()
+        .unwrap_or(0);
+    let has_rest = clauses.iter().any(|(_, _, rest)| *rest);
+    let mut new_state = CompileState::new_state(
+        vm,
+        state.chunk.file_name,
+        own_line(line).unwrap_or(1),
+        Some(state.symbols.clone()),
+    );
+    new_state.warnings = state.warnings.clone();
+    new_state.declarations = state.declarations.clone();
+    new_state.expansions = state.expansions.clone();
+    new_state.peephole = state.peephole;
+    new_state.mode = state.mode;
+    new_state.chunk.dbg_args = Some(Vec::new());
+    // Registers 1..=max_fixed hold the args passed (unset past the count passed) then a list of
+    // any more.  The shortest arity's args are required so too few args is an arg count error.
+    for _ in 0..max_fixed + has_rest as usize {
+        add_anon_arg(&mut new_state);
+    }
+    if max_fixed == min_fixed {
+        new_state.chunk.args = (min_fixed + has_rest as usize) as u16;
+    } else {
+        new_state.chunk.args = min_fixed as u16;
+        new_state.chunk.opt_args = (max_fixed - min_fixed + has_rest as usize) as u16;
+    }
+    new_state.chunk.rest = has_rest;
+    let mut arities = Vec::new();
+    for (clause, fixed, rest) in clauses {
+        let (arity_state, comps) = mk_state(vm, &mut new_state, clause[0], line, true)?;
+        if rest && arity_state.chunk.args as usize != fixed + 1 {
+            return Err(CompileError::invalid_args(
+                "Malformed fn, &rest must be followed by one arg.",
+            ));
+        }
+        let (lambda, closure) = compile_lambda(
+            vm,
+            &mut new_state,
+            arity_state,
+            comps,
+            &clause[1..],
+            line,
+            false,
+        )?;
+        arities.push(Arity {
+            fixed,
+            rest,
+            lambda,
+            closure,
+        });
+    }
+    // Captures for the arities were added by compile_lambda so reserve after them.
+    let scratch = new_state.reserved_regs();
+    let more = if has_rest {
+        Some(encode_forward_jump(
+            &mut new_state,
+            JMPT,
+            Some(max_fixed + 1),
+            own_line(line),
+        )?)
+    } else {
+        None
+    };
+    let mut counts = Vec::new();
+    for n in (min_fixed + 1..=max_fixed).rev() {
+        counts.push((
+            n,
+            encode_forward_jump(&mut new_state, JMPNU, Some(n), own_line(line))?,
+        ));
+    }
+    compile_arity_call(
+        vm,
+        &mut new_state,
+        &arities,
+        min_fixed,
+        false,
+        scratch,
+        line,
+    )?;
+    for (n, jump) in counts {
+        patch_jump(&mut new_state, jump)?;
+        compile_arity_call(vm, &mut new_state, &arities, n, false, scratch, line)?;
+    }
+    if let Some(jump) = more {
+        patch_jump(&mut new_state, jump)?;
+        compile_arity_call(vm, &mut new_state, &arities, max_fixed, true, scratch, line)?;
+    }
+    alloc_lambda(vm, state, new_state, scratch, false)
+}
+
+/// Tail call the first arity that takes n args (or more than n if more, with the extra args in
+/// a list in register n + 1) from the args in registers 1..=n, error if there is none.
+fn compile_arity_call(
+    vm: &mut Vm,
+    state: &mut CompileState,
+    arities: &[Arity],
+    n: usize,
+    more: bool,
+    scratch: usize,
+    line: &mut Option<&mut u32>,
+) -> CompileResult<()> {
+    let arity = arities.iter().find(|a| {
+        if more {
+            a.rest
+        } else {
+            a.fixed == n || (a.rest && a.fixed <= n)
+        }
+    });
+    let arity = if let Some(arity) = arity {
+        arity
+    } else {
+        use_regs(state, scratch + 3);
+        mkconst(vm, state, Value::Int(n as i64), scratch + 3, line)?;
+        let message = if more {
+            "fn: no arity for more args than "
+        } else {
+            "fn: no arity for arg count "
+        };
+        return encode_error(vm, state, message, scratch + 3, scratch, line);
+    };
+    let mut nargs = n;
+    if arity.rest {
+        // Gather the args after the fixed ones into the rest list arg.
+        let first = arity.fixed + 1;
+        use_regs(state, scratch + 1);
+        if first <= n {
+            state.encode3(LIST, scratch as u16, first as u16, n as u16, own_line(line))?;
+        } else {
+            state.encode1(REGN, scratch as u16, own_line(line))?;
+        }
+        if more {
+            state.encode2(MOV, (scratch + 1) as u16, (n + 1) as u16, own_line(line))?;
+            state.encode3(
+                APND,
+                scratch as u16,
+                scratch as u16,
+                (scratch + 1) as u16,
+                own_line(line),
+            )?;
+        }
+        state.encode2(MOV, first as u16, scratch as u16, own_line(line))?;
+        nargs = first;
+    }
+    let const_i = state.add_constant(arity.lambda);
+    state.encode2(CONST, scratch as u16, const_i as u16, own_line(line))?;
+    if arity.closure {
+        state.encode2(CLOSE, scratch as u16, scratch as u16, own_line(line))?;
+    }
+    state.encode2(TCALL, scratch as u16, nargs as u16, own_line(line))?;
+    Ok(())
+}
+
+fn make_math_comp<E: Exp>(
+    vm: &mut Vm,
+    state: &mut CompileState,
+    cdr: &[E],
     result: usize,
     line: &mut Option<&mut u32>,
     op: u8,
-) -> VMResult<()> {
+) -> CompileResult<()> {
     if cdr.len() <= 1 {
-        return Err(VMError::new_compile("Requires at least two arguments."));
+        return Err(CompileError::arg_count("Requires at least two arguments."));
     } else {
         let mut max = 0;
         for (i, v) in cdr.iter().enumerate() {
             max = result + i + 1;
-            compile(vm, state, *v, max, line)?;
+            v.compile(vm, state, max, line)?;
         }
-        state.chunk.encode3(
+        state.encode3(
             op,
             result as u16,
             (result + 1) as u16,
@@ -329,193 +933,225 @@ fn make_math_comp(
     Ok(())
 }
 
-fn compile_math(
+/// Compile (inc! symbol amount?) or (dec! symbol amount?), false if car is neither.
+fn compile_inc_dec(
     vm: &mut Vm,
     state: &mut CompileState,
     car: Value,
     cdr: &[Value],
     result: usize,
     line: &mut Option<&mut u32>,
-) -> VMResult<bool> {
+) -> CompileResult<bool> {
     match car {
         Value::Symbol(i) if i == state.specials.inc => {
             let dest = if let Value::Symbol(si) = cdr[0] {
                 if let Some(idx) = state.get_symbol(si) {
+                    state.read_local(si);
                     idx + 1
                 } else {
-                    let const_i = vm.reserve_index(si);
-                    state
-                        .chunk
-                        .encode_refi(result as u16, const_i, own_line(line))?;
+                    let si = resolve_global(vm, si);
+                    state.encode_refi(vm, result as u16, si, own_line(line))?;
                     result
                 }
             } else {
-                return Err(VMError::new_compile("inc!: expected symbol"));
+                return Err(CompileError::expected_symbol("inc!: expected symbol"));
             };
             if cdr.len() == 1 {
-                state.chunk.encode2(INC, dest as u16, 1, own_line(line))?;
+                state.encode2(INC, dest as u16, 1, own_line(line))?;
             } else if cdr.len() == 2 {
                 let amount = match cdr[1] {
                     Value::Byte(i) => i as u16,
                     Value::Int(i) if i >= 0 && i <= u16::MAX as i64 => i as u16,
-                    Value::Int(_) => return Err(VMError::new_compile("inc!: second arg to large")),
+                    Value::Int(_) => {
+                        return Err(CompileError::malformed("inc!: second arg to large"))
+                    }
                     Value::UInt(i) if i <= u16::MAX as u64 => i as u16,
                     Value::UInt(_) => {
-                        return Err(VMError::new_compile("inc!: second arg < 0 or to large"))
+                        return Err(CompileError::malformed("inc!: second arg < 0 or to large"))
                     }
-                    _ => return Err(VMError::new_compile("inc!: second arg must be integer")),
+                    _ => return Err(CompileError::malformed("inc!: second arg must be integer")),
                 };
-                state
-                    .chunk
-                    .encode2(INC, dest as u16, amount, own_line(line))?;
+                state.encode2(INC, dest as u16, amount, own_line(line))?;
             } else {
-                return Err(VMError::new_compile("inc!: malformed"));
+                return Err(CompileError::malformed("inc!: malformed"));
             }
         }
         Value::Symbol(i) if i == state.specials.dec => {
             let dest = if let Value::Symbol(si) = cdr[0] {
                 if let Some(idx) = state.get_symbol(si) {
+                    state.read_local(si);
                     idx + 1
                 } else {
-                    let const_i = vm.reserve_index(si);
-                    state
-                        .chunk
-                        .encode_refi(result as u16, const_i, own_line(line))?;
+                    let si = resolve_global(vm, si);
+                    state.encode_refi(vm, result as u16, si, own_line(line))?;
                     result
                 }
             } else {
-                return Err(VMError::new_compile("dec!: expected symbol"));
+                return Err(CompileError::expected_symbol("dec!: expected symbol"));
             };
             if cdr.len() == 1 {
-                state.chunk.encode2(DEC, dest as u16, 1, own_line(line))?;
+                state.encode2(DEC, dest as u16, 1, own_line(line))?;
             } else if cdr.len() == 2 {
                 let amount = match cdr[1] {
                     Value::Byte(i) => i as u16,
                     Value::Int(i) if i >= 0 && i <= u16::MAX as i64 => i as u16,
-                    Value::Int(_) => return Err(VMError::new_compile("inc!: second arg to large")),
+                    Value::Int(_) => {
+                        return Err(CompileError::malformed("inc!: second arg to large"))
+                    }
                     Value::UInt(i) if i <= u16::MAX as u64 => i as u16,
                     Value::UInt(_) => {
-                        return Err(VMError::new_compile("inc!: second arg < 0 or to large"))
+                        return Err(CompileError::malformed("inc!: second arg < 0 or to large"))
                     }
-                    _ => return Err(VMError::new_compile("inc!: second arg must be integer")),
+                    _ => return Err(CompileError::malformed("inc!: second arg must be integer")),
                 };
-                state
-                    .chunk
-                    .encode2(DEC, dest as u16, amount, own_line(line))?;
+                state.encode2(DEC, dest as u16, amount, own_line(line))?;
             } else {
-                return Err(VMError::new_compile("dec!: malformed"));
+                return Err(CompileError::malformed("dec!: malformed"));
             }
         }
+        _ => return Ok(false),
+    }
+    Ok(true)
+}
+
+fn compile_math<E: Exp>(
+    vm: &mut Vm,
+    state: &mut CompileState,
+    car: Value,
+    cdr: &[E],
+    result: usize,
+    line: &mut Option<&mut u32>,
+) -> CompileResult<bool> {
+    match car {
         Value::Symbol(i) if i == state.specials.add => {
+            state.tail = false;
             if cdr.is_empty() {
                 compile(vm, state, Value::Int(0), result, line)?;
             } else if cdr.len() == 1 {
-                compile(vm, state, cdr[0], result, line)?;
+                cdr[0].compile(vm, state, result, line)?;
             } else {
                 for (i, v) in cdr.iter().enumerate() {
                     if i > 0 {
-                        compile(vm, state, *v, result + 1, line)?;
-                        state.chunk.encode3(
+                        let temps = state.temps_mark();
+                        let arg = Operands::new(result + 1).operand(vm, state, *v, &[], line)?;
+                        state.encode3(
                             ADDM,
                             result as u16,
                             result as u16,
-                            (result + 1) as u16,
+                            arg as u16,
                             own_line(line),
                         )?;
+                        state.release_temps(temps);
                     } else {
-                        compile(vm, state, *v, result, line)?;
+                        v.compile(vm, state, result, line)?;
                     }
                 }
             }
         }
         Value::Symbol(i) if i == state.specials.sub => {
+            state.tail = false;
             if cdr.is_empty() {
-                return Err(VMError::new_compile(
+                return Err(CompileError::arg_count(
                     "Malformed -, requires at least one argument.",
                 ));
             } else if cdr.len() == 1 {
-                if let Ok(i) = cdr[0].get_int() {
+                let arg = cdr[0].const_value(state).unwrap_or(Value::Nil);
+                if let Ok(i) = arg.get_int() {
                     compile(vm, state, Value::Int(-i), result, line)?;
-                } else if let Ok(f) = cdr[0].get_float() {
+                } else if let Ok(f) = arg.get_float() {
                     compile(vm, state, Value::float(-f), result, line)?;
                 }
             } else {
                 for (i, v) in cdr.iter().enumerate() {
                     if i > 0 {
-                        compile(vm, state, *v, result + 1, line)?;
-                        state.chunk.encode3(
+                        let temps = state.temps_mark();
+                        let arg = Operands::new(result + 1).operand(vm, state, *v, &[], line)?;
+                        state.encode3(
                             SUBM,
                             result as u16,
                             result as u16,
-                            (result + 1) as u16,
+                            arg as u16,
                             own_line(line),
                         )?;
+                        state.release_temps(temps);
                     } else {
-                        compile(vm, state, *v, result, line)?;
+                        v.compile(vm, state, result, line)?;
                     }
                 }
             }
         }
         Value::Symbol(i) if i == state.specials.mul => {
+            state.tail = false;
             if cdr.is_empty() {
                 compile(vm, state, Value::Int(1), result, line)?;
             } else if cdr.len() == 1 {
-                compile(vm, state, cdr[0], result, line)?;
+                cdr[0].compile(vm, state, result, line)?;
             } else {
                 for (i, v) in cdr.iter().enumerate() {
                     if i > 0 {
-                        compile(vm, state, *v, result + 1, line)?;
-                        state.chunk.encode3(
+                        let temps = state.temps_mark();
+                        let arg = Operands::new(result + 1).operand(vm, state, *v, &[], line)?;
+                        state.encode3(
                             MULM,
                             result as u16,
                             result as u16,
-                            (result + 1) as u16,
+                            arg as u16,
                             own_line(line),
                         )?;
+                        state.release_temps(temps);
                     } else {
-                        compile(vm, state, *v, result, line)?;
+                        v.compile(vm, state, result, line)?;
                     }
                 }
             }
         }
         Value::Symbol(i) if i == state.specials.div => {
+            state.tail = false;
             if cdr.len() <= 1 {
-                return Err(VMError::new_compile(
+                return Err(CompileError::arg_count(
                     "Malformed /, requires at least two arguments.",
                 ));
             } else {
                 for (i, v) in cdr.iter().enumerate() {
                     if i > 0 {
-                        compile(vm, state, *v, result + 1, line)?;
-                        state.chunk.encode3(
+                        let temps = state.temps_mark();
+                        let arg = Operands::new(result + 1).operand(vm, state, *v, &[], line)?;
+                        state.encode3(
                             DIVM,
                             result as u16,
                             result as u16,
-                            (result + 1) as u16,
+                            arg as u16,
                             own_line(line),
                         )?;
+                        state.release_temps(temps);
                     } else {
-                        compile(vm, state, *v, result, line)?;
+                        v.compile(vm, state, result, line)?;
                     }
                 }
             }
         }
         Value::Symbol(i) if i == state.specials.numeq => {
+            state.tail = false;
             make_math_comp(vm, state, cdr, result, line, NUMEQ)?;
         }
         Value::Symbol(i) if i == state.specials.numneq => {
+            state.tail = false;
             make_math_comp(vm, state, cdr, result, line, NUMNEQ)?;
         }
         Value::Symbol(i) if i == state.specials.numlt => {
+            state.tail = false;
             make_math_comp(vm, state, cdr, result, line, NUMLT)?;
         }
         Value::Symbol(i) if i == state.specials.numlte => {
+            state.tail = false;
             make_math_comp(vm, state, cdr, result, line, NUMLTE)?;
         }
         Value::Symbol(i) if i == state.specials.numgt => {
+            state.tail = false;
             make_math_comp(vm, state, cdr, result, line, NUMGT)?;
         }
         Value::Symbol(i) if i == state.specials.numgte => {
+            state.tail = false;
             make_math_comp(vm, state, cdr, result, line, NUMGTE)?;
         }
         _ => return Ok(false),
@@ -523,23 +1159,23 @@ fn compile_math(
     Ok(true)
 }
 
-fn compile_cons(
+fn compile_cons<E: Exp>(
     vm: &mut Vm,
     state: &mut CompileState,
     car: Value,
-    cdr: &[Value],
+    cdr: &[E],
     result: usize,
     line: &mut Option<&mut u32>,
-) -> VMResult<bool> {
+) -> CompileResult<bool> {
     match car {
         Value::Symbol(i) if i == state.specials.list => {
             state.tail = false;
             let mut max = 0;
             for r in cdr {
-                compile(vm, state, *r, result + max + 1, line)?;
+                r.compile(vm, state, result + max + 1, line)?;
                 max += 1;
             }
-            state.chunk.encode3(
+            state.encode3(
                 LIST,
                 result as u16,
                 (result + 1) as u16,
@@ -551,10 +1187,10 @@ fn compile_cons(
             state.tail = false;
             let mut max = 0;
             for r in cdr {
-                compile(vm, state, *r, result + max + 1, line)?;
+                r.compile(vm, state, result + max + 1, line)?;
                 max += 1;
             }
-            state.chunk.encode3(
+            state.encode3(
                 APND,
                 result as u16,
                 (result + 1) as u16,
@@ -565,102 +1201,90 @@ fn compile_cons(
         Value::Symbol(i) if i == state.specials.cons => {
             state.tail = false;
             if cdr.len() != 2 {
-                return Err(VMError::new_compile(format!(
-                    "takes two arguments, got {}, line {}",
-                    cdr.len(),
-                    line_num(line)
+                return Err(CompileError::arg_count(format!(
+                    "takes two arguments, got {}",
+                    cdr.len()
                 )));
             }
-            compile(vm, state, cdr[0], result, line)?;
-            compile(vm, state, cdr[1], result + 1, line)?;
-            state.chunk.encode3(
+            let mut operands = Operands::new(result);
+            let car_reg = operands.operand(vm, state, cdr[0], &cdr[1..], line)?;
+            let cdr_reg = operands.operand(vm, state, cdr[1], &[], line)?;
+            state.encode3(
                 CONS,
                 result as u16,
-                result as u16,
-                (result + 1) as u16,
+                car_reg as u16,
+                cdr_reg as u16,
                 own_line(line),
             )?;
         }
         Value::Symbol(i) if i == state.specials.car => {
             state.tail = false;
             if cdr.len() != 1 {
-                return Err(VMError::new_compile(format!(
-                    "takes one argument, got {}, line {}",
-                    cdr.len(),
-                    line_num(line)
+                return Err(CompileError::arg_count(format!(
+                    "takes one argument, got {}",
+                    cdr.len()
                 )));
             }
-            compile(vm, state, cdr[0], result + 1, line)?;
-            state
-                .chunk
-                .encode2(CAR, result as u16, (result + 1) as u16, own_line(line))?;
+            let src = Operands::new(result).operand(vm, state, cdr[0], &[], line)?;
+            state.encode2(CAR, result as u16, src as u16, own_line(line))?;
         }
         Value::Symbol(i) if i == state.specials.cdr => {
             state.tail = false;
             if cdr.len() != 1 {
-                return Err(VMError::new_compile(format!(
-                    "takes one argument, got {}, line {}",
-                    cdr.len(),
-                    line_num(line)
+                return Err(CompileError::arg_count(format!(
+                    "takes one argument, got {}",
+                    cdr.len()
                 )));
             }
-            compile(vm, state, cdr[0], result + 1, line)?;
-            state
-                .chunk
-                .encode2(CDR, result as u16, (result + 1) as u16, own_line(line))?;
+            let src = Operands::new(result).operand(vm, state, cdr[0], &[], line)?;
+            state.encode2(CDR, result as u16, src as u16, own_line(line))?;
         }
         Value::Symbol(i) if i == state.specials.xar => {
             state.tail = false;
             if cdr.len() != 2 {
-                return Err(VMError::new_compile(format!(
-                    "takes two arguments, got {}, line {}",
-                    cdr.len(),
-                    line_num(line)
+                return Err(CompileError::arg_count(format!(
+                    "takes two arguments, got {}",
+                    cdr.len()
                 )));
             }
-            compile(vm, state, cdr[0], result, line)?;
-            compile(vm, state, cdr[1], result + 1, line)?;
-            state
-                .chunk
-                .encode2(XAR, result as u16, (result + 1) as u16, own_line(line))?;
+            cdr[0].compile(vm, state, result, line)?;
+            let val = Operands::new(result + 1).operand(vm, state, cdr[1], &[], line)?;
+            state.encode2(XAR, result as u16, val as u16, own_line(line))?;
         }
         Value::Symbol(i) if i == state.specials.xdr => {
             state.tail = false;
             if cdr.len() != 2 {
-                return Err(VMError::new_compile(format!(
-                    "takes two arguments, got {}, line {}",
-                    cdr.len(),
-                    line_num(line)
+                return Err(CompileError::arg_count(format!(
+                    "takes two arguments, got {}",
+                    cdr.len()
                 )));
             }
-            compile(vm, state, cdr[0], result, line)?;
-            compile(vm, state, cdr[1], result + 1, line)?;
-            state
-                .chunk
-                .encode2(XDR, result as u16, (result + 1) as u16, own_line(line))?;
+            cdr[0].compile(vm, state, result, line)?;
+            let val = Operands::new(result + 1).operand(vm, state, cdr[1], &[], line)?;
+            state.encode2(XDR, result as u16, val as u16, own_line(line))?;
         }
         _ => return Ok(false),
     }
     Ok(true)
 }
 
-fn compile_vec(
+fn compile_vec<E: Exp>(
     vm: &mut Vm,
     state: &mut CompileState,
     car: Value,
-    cdr: &[Value],
+    cdr: &[E],
     result: usize,
     line: &mut Option<&mut u32>,
-) -> VMResult<bool> {
+) -> CompileResult<bool> {
     match car {
         Value::Symbol(i) if i == state.specials.vec => {
             state.tail = false;
             let mut max = 0;
             for r in cdr {
-                compile(vm, state, *r, result + max + 1, line)?;
+                r.compile(vm, state, result + max + 1, line)?;
                 max += 1;
             }
-            state.chunk.encode3(
+            state.encode3(
                 VEC,
                 result as u16,
                 (result + 1) as u16,
@@ -671,7 +1295,7 @@ fn compile_vec(
         Value::Symbol(i) if i == state.specials.make_vec => {
             state.tail = false;
             if cdr.is_empty() {
-                state.chunk.encode3(
+                state.encode3(
                     VEC,
                     result as u16,
                     result as u16,
@@ -679,193 +1303,210 @@ fn compile_vec(
                     own_line(line),
                 )?;
             } else if cdr.len() == 1 {
-                compile(vm, state, cdr[0], result + 1, line)?;
-                state
-                    .chunk
-                    .encode2(VECMK, result as u16, (result + 1) as u16, own_line(line))?;
+                let src = Operands::new(result).operand(vm, state, cdr[0], &[], line)?;
+                state.encode2(VECMK, result as u16, src as u16, own_line(line))?;
             } else if cdr.len() == 2 {
-                compile(vm, state, cdr[0], result + 1, line)?;
-                compile(vm, state, cdr[1], result + 2, line)?;
-                state.chunk.encode3(
+                let mut operands = Operands::new(result);
+                let len = operands.operand(vm, state, cdr[0], &cdr[1..], line)?;
+                let default = operands.operand(vm, state, cdr[1], &[], line)?;
+                state.encode3(
                     VECMKD,
                     result as u16,
-                    (result + 1) as u16,
-                    (result + 2) as u16,
+                    len as u16,
+                    default as u16,
                     own_line(line),
                 )?;
             } else {
-                return Err(VMError::new_compile(format!(
-                    "takes up to two arguments, got {}, line {}",
-                    cdr.len(),
-                    line_num(line)
+                return Err(CompileError::arg_count(format!(
+                    "takes up to two arguments, got {}",
+                    cdr.len()
                 )));
             }
         }
         Value::Symbol(i) if i == state.specials.vec_push => {
             state.tail = false;
             if cdr.len() != 2 {
-                return Err(VMError::new_compile(format!(
-                    "takes two arguments, got {}, line {}",
-                    cdr.len(),
-                    line_num(line)
+                return Err(CompileError::arg_count(format!(
+                    "takes two arguments, got {}",
+                    cdr.len()
                 )));
             }
-            compile(vm, state, cdr[0], result, line)?;
-            compile(vm, state, cdr[1], result + 1, line)?;
-            state
-                .chunk
-                .encode2(VECPSH, result as u16, (result + 1) as u16, own_line(line))?;
+            cdr[0].compile(vm, state, result, line)?;
+            let val = Operands::new(result + 1).operand(vm, state, cdr[1], &[], line)?;
+            state.encode2(VECPSH, result as u16, val as u16, own_line(line))?;
         }
         Value::Symbol(i) if i == state.specials.vec_pop => {
             state.tail = false;
             if cdr.len() != 1 {
-                return Err(VMError::new_compile(format!(
-                    "takes one argument, got {}, line {}",
-                    cdr.len(),
-                    line_num(line)
+                return Err(CompileError::arg_count(format!(
+                    "takes one argument, got {}",
+                    cdr.len()
                 )));
             }
-            compile(vm, state, cdr[0], result + 1, line)?;
-            state
-                .chunk
-                .encode2(VECPOP, (result + 1) as u16, result as u16, own_line(line))?;
+            let vec = Operands::new(result).operand(vm, state, cdr[0], &[], line)?;
+            state.encode2(VECPOP, vec as u16, result as u16, own_line(line))?;
         }
         Value::Symbol(i) if i == state.specials.vec_nth => {
             state.tail = false;
             if cdr.len() != 2 {
-                return Err(VMError::new_compile(format!(
-                    "takes two arguments, got {}, line {}",
-                    cdr.len(),
-                    line_num(line)
+                return Err(CompileError::arg_count(format!(
+                    "takes two arguments, got {}",
+                    cdr.len()
                 )));
             }
-            compile(vm, state, cdr[0], result + 1, line)?;
-            compile(vm, state, cdr[1], result + 2, line)?;
-            state.chunk.encode3(
+            let mut operands = Operands::new(result);
+            let vec = operands.operand(vm, state, cdr[0], &cdr[1..], line)?;
+            let idx = operands.operand(vm, state, cdr[1], &[], line)?;
+            state.encode3(
                 VECNTH,
-                (result + 1) as u16,
+                vec as u16,
                 result as u16,
-                (result + 2) as u16,
+                idx as u16,
                 own_line(line),
             )?;
         }
         Value::Symbol(i) if i == state.specials.vec_set => {
             state.tail = false;
             if cdr.len() != 3 {
-                return Err(VMError::new_compile(format!(
-                    "takes three arguments, got {}, line {}",
-                    cdr.len(),
-                    line_num(line)
+                return Err(CompileError::arg_count(format!(
+                    "takes three arguments, got {}",
+                    cdr.len()
                 )));
             }
-            compile(vm, state, cdr[0], result, line)?;
-            compile(vm, state, cdr[1], result + 1, line)?;
-            compile(vm, state, cdr[2], result + 2, line)?;
-            state.chunk.encode3(
+            cdr[0].compile(vm, state, result, line)?;
+            let mut operands = Operands::new(result + 1);
+            let idx = operands.operand(vm, state, cdr[1], &cdr[2..], line)?;
+            let val = operands.operand(vm, state, cdr[2], &[], line)?;
+            state.encode3(
                 VECSTH,
                 result as u16,
-                (result + 2) as u16,
-                (result + 1) as u16,
+                val as u16,
+                idx as u16,
                 own_line(line),
             )?;
         }
         Value::Symbol(i) if i == state.specials.vec_len => {
             state.tail = false;
             if cdr.len() != 1 {
-                return Err(VMError::new_compile(format!(
-                    "takes one argument, got {}, line {}",
-                    cdr.len(),
-                    line_num(line)
+                return Err(CompileError::arg_count(format!(
+                    "takes one argument, got {}",
+                    cdr.len()
                 )));
             }
-            compile(vm, state, cdr[0], result + 1, line)?;
-            state
-                .chunk
-                .encode2(VECLEN, result as u16, (result + 1) as u16, own_line(line))?;
+            let src = Operands::new(result).operand(vm, state, cdr[0], &[], line)?;
+            state.encode2(VECLEN, result as u16, src as u16, own_line(line))?;
         }
         Value::Symbol(i) if i == state.specials.vec_clr => {
             state.tail = false;
             if cdr.len() != 1 {
-                return Err(VMError::new_compile(format!(
-                    "takes one argument, got {}, line {}",
-                    cdr.len(),
-                    line_num(line)
+                return Err(CompileError::arg_count(format!(
+                    "takes one argument, got {}",
+                    cdr.len()
                 )));
             }
-            compile(vm, state, cdr[0], result, line)?;
-            state.chunk.encode1(VECCLR, result as u16, own_line(line))?;
+            cdr[0].compile(vm, state, result, line)?;
+            state.encode1(VECCLR, result as u16, own_line(line))?;
         }
         _ => return Ok(false),
     }
     Ok(true)
 }
 
-fn compile_if(
+pub fn compile_if<E: Exp>(
     vm: &mut Vm,
     state: &mut CompileState,
-    cdr: &[Value],
+    cdr: &[E],
     result: usize,
     line: &mut Option<&mut u32>,
-) -> VMResult<()> {
+) -> CompileResult<()> {
     if cdr.is_empty() {
-        return Err(VMError::new_compile(format!(
-            "requires at least one argument, got 0, line {}",
-            line_num(line)
-        )));
+        return Err(CompileError::arg_count(
+            "requires at least one argument, got 0",
+        ));
     }
     let tail = state.tail;
     state.tail = false;
     let mut end_patches = Vec::new();
+    // Defers active at each way out of the if, a defer in one branch only is path dependent.
+    let mut exit_defers = Vec::new();
     let mut cdr_i = cdr.iter().peekable();
     while let Some(r) = cdr_i.next() {
         let next = cdr_i.next();
+        if let (Some(test), Some(then)) = (r.const_value(state), next) {
+            if is_truthy(test) {
+                // The rest of the if can never run.
+                state.tail = tail;
+                then.compile(vm, state, result, line)?;
+                state.tail = false;
+                break;
+            } else if cdr_i.peek().is_none() {
+                // Last clause so the if evaluates to the false test.
+                mkconst(vm, state, test, result, line)?;
+            }
+            continue;
+        }
         if next.is_none() {
             state.tail = tail;
         }
-        compile(vm, state, *r, result, line)?;
+        // The if only evaluates to a test when it is the last one, otherwise a local test is
+        // read in place.
+        let temps = state.temps_mark();
+        let test = if next.is_some() && cdr_i.peek().is_some() {
+            Operands::new(result).operand(vm, state, *r, &[], line)?
+        } else {
+            r.compile(vm, state, result, line)?;
+            result
+        };
+        let test_defers = state.defers;
         if let Some(r) = next {
             state.tail = tail;
-            state.chunk.encode1(JMPF, result as u16, own_line(line))?;
+            state.encode1(JMPF, test as u16, own_line(line))?;
+            state.release_temps(temps);
             let encode_offset = state.chunk.code.len();
-            state.chunk.encode_jump_offset(0)?;
+            state.encode_jump_offset(0)?;
             let tmp_start_ip = state.chunk.code.len();
-            compile(vm, state, *r, result, line)?;
+            r.compile(vm, state, result, line)?;
+            exit_defers.push(state.defers);
+            state.defers = test_defers;
             if cdr_i.peek().is_some() {
-                state.chunk.encode0(JMP, own_line(line))?;
-                state.chunk.encode_jump_offset(0)?;
+                state.encode0(JMP, own_line(line))?;
+                state.encode_jump_offset(0)?;
                 end_patches.push(state.chunk.code.len());
             }
-            state.chunk.reencode_jump_offset(
+            state.reencode_jump_offset(
                 encode_offset,
                 (state.chunk.code.len() - tmp_start_ip) as i32,
             )?;
         }
         state.tail = false;
     }
+    // The else (or falling off the last test) is the last way out.
+    exit_defers.push(state.defers);
+    state.merge_defers(&exit_defers);
     let end_ip = state.chunk.code.len();
     for i in end_patches {
         let jmp_forward = (end_ip - i) as i32;
-        state.chunk.reencode_jump_offset(i - 3, jmp_forward)?;
+        state.reencode_jump_offset(i - 3, jmp_forward)?;
     }
     Ok(())
 }
 
-fn compile_and(
+pub fn compile_and<E: Exp>(
     vm: &mut Vm,
     state: &mut CompileState,
-    cdr: &[Value],
+    cdr: &[E],
     result: usize,
     line: &mut Option<&mut u32>,
-) -> VMResult<()> {
+) -> CompileResult<()> {
     if cdr.is_empty() {
-        return Err(VMError::new_compile(format!(
-            "requires at least one argument, got 0, line {}",
-            line_num(line)
-        )));
+        return Err(CompileError::arg_count(
+            "requires at least one argument, got 0",
+        ));
     }
     let tail = state.tail;
     state.tail = false;
     let mut end_patches = Vec::new();
+    let mut exit_defers = Vec::new();
     let mut cdr_i = cdr.iter().peekable();
     let mut next = cdr_i.next();
     while let Some(r) = next {
@@ -873,38 +1514,40 @@ fn compile_and(
         if next.is_none() {
             state.tail = tail;
         }
-        compile(vm, state, *r, result, line)?;
+        r.compile(vm, state, result, line)?;
+        exit_defers.push(state.defers);
         if cdr_i.peek().is_some() {
-            state.chunk.encode1(JMPF, result as u16, own_line(line))?;
-            state.chunk.encode_jump_offset(0)?;
+            state.encode1(JMPF, result as u16, own_line(line))?;
+            state.encode_jump_offset(0)?;
             end_patches.push(state.chunk.code.len());
         }
         state.tail = false;
     }
+    state.merge_defers(&exit_defers);
     let end_ip = state.chunk.code.len();
     for i in end_patches {
         let jmp_forward = (end_ip - i) as i32;
-        state.chunk.reencode_jump_offset(i - 3, jmp_forward)?;
+        state.reencode_jump_offset(i - 3, jmp_forward)?;
     }
     Ok(())
 }
 
-fn compile_or(
+pub fn compile_or<E: Exp>(
     vm: &mut Vm,
     state: &mut CompileState,
-    cdr: &[Value],
+    cdr: &[E],
     result: usize,
     line: &mut Option<&mut u32>,
-) -> VMResult<()> {
+) -> CompileResult<()> {
     if cdr.is_empty() {
-        return Err(VMError::new_compile(format!(
-            "requires at least one argument, got 0, line {}",
-            line_num(line)
-        )));
+        return Err(CompileError::arg_count(
+            "requires at least one argument, got 0",
+        ));
     }
     let tail = state.tail;
     state.tail = false;
     let mut end_patches = Vec::new();
+    let mut exit_defers = Vec::new();
     let mut cdr_i = cdr.iter().peekable();
     let mut next = cdr_i.next();
     while let Some(r) = next {
@@ -912,18 +1555,20 @@ fn compile_or(
         if next.is_none() {
             state.tail = tail;
         }
-        compile(vm, state, *r, result, line)?;
+        r.compile(vm, state, result, line)?;
+        exit_defers.push(state.defers);
         if cdr_i.peek().is_some() {
-            state.chunk.encode1(JMPT, result as u16, own_line(line))?;
-            state.chunk.encode_jump_offset(0)?;
+            state.encode1(JMPT, result as u16, own_line(line))?;
+            state.encode_jump_offset(0)?;
             end_patches.push(state.chunk.code.len());
         }
         state.tail = false;
     }
+    state.merge_defers(&exit_defers);
     let end_ip = state.chunk.code.len();
     for i in end_patches {
         let jmp_forward = (end_ip - i) as i32;
-        state.chunk.reencode_jump_offset(i - 3, jmp_forward)?;
+        state.reencode_jump_offset(i - 3, jmp_forward)?;
     }
     Ok(())
 }
@@ -934,58 +1579,87 @@ fn compile_def(
     cdr: &[Value],
     result: usize,
     line: &mut Option<&mut u32>,
-) -> VMResult<()> {
+) -> CompileResult<()> {
     if cdr.len() == 2 {
-        if let Value::Symbol(si) = cdr[0] {
-            compile(vm, state, cdr[1], result + 1, line)?;
-            let si_const = vm.reserve_index(si);
-            state
-                .chunk
-                .encode_refi(result as u16, si_const, own_line(line))?;
-            state
-                .chunk
-                .encode2(DEF, result as u16, (result + 1) as u16, own_line(line))?;
+        if let Value::Symbol(name) = cdr[0] {
+            let si = def_name(vm, name);
+            state.warnings.borrow_mut().defined(si);
+            // Reserve first so the value (a fn calling itself say) resolves to this global.
+            vm.reserve_index(si);
+            warn_inline_redefinition(vm, state, si, "def", own_line(line));
+            compile_def_value(vm, state, si, name, cdr[1], result + 1, line)?;
+            state.encode_refi(vm, result as u16, si, own_line(line))?;
+            state.encode2(DEF, result as u16, (result + 1) as u16, own_line(line))?;
         } else {
-            return Err(VMError::new_compile("def: expected symbol"));
+            return Err(CompileError::expected_symbol("def: expected symbol"));
         }
     } else if cdr.len() == 3 {
         // XXX implement docstrings
-        if let Value::Symbol(si) = cdr[0] {
-            let si_const = vm.reserve_index(si);
+        if let Value::Symbol(name) = cdr[0] {
+            let si = def_name(vm, name);
+            state.warnings.borrow_mut().defined(si);
+            vm.reserve_index(si);
             // Set docstring
             let set_prop = vm.intern("set-prop");
-            if let Some(set_prop) = vm.global_intern_slot(set_prop) {
+            if vm.global_intern_slot(set_prop).is_some() {
                 let doc_const = state
                     .chunk
                     .add_constant(Value::Keyword(vm.intern("doc-string")));
-                state
-                    .chunk
-                    .encode_refi((result + 1) as u16, si_const, own_line(line))?;
-                state.chunk.encode2(
-                    CONST,
-                    (result + 2) as u16,
-                    doc_const as u16,
-                    own_line(line),
-                )?;
+                state.encode_refi(vm, (result + 1) as u16, si, own_line(line))?;
+                state.encode2(CONST, (result + 2) as u16, doc_const as u16, own_line(line))?;
                 compile(vm, state, cdr[1], result + 3, line)?;
-                state
-                    .chunk
-                    .encode_callg(set_prop as u32, 3, result as u16, own_line(line))?;
+                state.encode_callg(vm, set_prop, 3, result as u16, own_line(line))?;
             }
 
-            compile(vm, state, cdr[2], result + 1, line)?;
-            state
-                .chunk
-                .encode_refi(result as u16, si_const, own_line(line))?;
-            state
-                .chunk
-                .encode2(DEF, result as u16, (result + 1) as u16, own_line(line))?;
+            warn_inline_redefinition(vm, state, si, "def", own_line(line));
+            compile_def_value(vm, state, si, name, cdr[2], result + 1, line)?;
+            state.encode_refi(vm, result as u16, si, own_line(line))?;
+            state.encode2(DEF, result as u16, (result + 1) as u16, own_line(line))?;
         } else {
-            return Err(VMError::new_compile("def: expected symbol"));
+            return Err(CompileError::expected_symbol("def: expected symbol"));
+        }
+    } else {
+        return Err(CompileError::malformed("def: malformed"));
+    }
+    Ok(())
+}
+
+/// Compile the value of a def of global (named name in the def), a fn declared inline is
+/// marked so calls to it can be inlined.
+fn compile_def_value(
+    vm: &mut Vm,
+    state: &mut CompileState,
+    global: Interned,
+    name: Interned,
+    value: Value,
+    result: usize,
+    line: &mut Option<&mut u32>,
+) -> CompileResult<()> {
+    if !state.declarations.borrow().inline.contains(&global) {
+        return compile(vm, state, value, result, line);
+    }
+    if let Some(source) = inline_source(vm, state, global, name, value) {
+        // Unwrap safe, inline_source only returns a pair.
+        let (args, body) = vm.get_pair(source.get_handle().unwrap());
+        let body: Vec<Value> = body.iter(vm).collect();
+        let (lambda, closure) = mk_lambda(vm, state, args, &body, line, false)?;
+        if !closure {
+            mark_inline(vm, lambda, source);
+            return load_lambda(state, lambda, closure, result, line);
         }
+        load_lambda(state, lambda, closure, result, line)?;
     } else {
-        return Err(VMError::new_compile("def: malformed"));
+        compile(vm, state, value, result, line)?;
     }
+    state.warn(
+        WarningKind::Inline,
+        format!(
+            "{} is declared inline but is not a small non-recursive fn with fixed args and \
+             no macro calls",
+            vm.get_interned(global)
+        ),
+        own_line(line),
+    );
     Ok(())
 }
 
@@ -995,130 +1669,1765 @@ fn compile_set(
     cdr: &[Value],
     result: usize,
     line: &mut Option<&mut u32>,
-) -> VMResult<()> {
+) -> CompileResult<()> {
     if cdr.len() == 2 {
         if let Value::Symbol(si) = cdr[0] {
             if let Some(idx) = state.get_symbol(si) {
                 compile(vm, state, cdr[1], result, line)?;
-                state
-                    .chunk
-                    .encode2(SET, (idx + 1) as u16, result as u16, own_line(line))?;
+                state.set_local(si, own_line(line));
+                state.encode2(SET, (idx + 1) as u16, result as u16, own_line(line))?;
+            } else {
+                let si = resolve_global(vm, si);
+                state.warn_undefined(vm, si, "set! of", own_line(line));
+                warn_inline_redefinition(vm, state, si, "set!", own_line(line));
+                compile(vm, state, cdr[1], result + 1, line)?;
+                state.encode_refi(vm, result as u16, si, own_line(line))?;
+                state.encode2(DEF, result as u16, (result + 1) as u16, own_line(line))?;
+            }
+        } else {
+            return Err(CompileError::expected_symbol("set!: expected symbol"));
+        }
+    } else {
+        return Err(CompileError::malformed("set!: malformed"));
+    }
+    Ok(())
+}
+
+fn compile_let(
+    vm: &mut Vm,
+    state: &mut CompileState,
+    cdr: &[Value],
+    result: usize,
+    line: &mut Option<&mut u32>,
+    star: bool,
+) -> CompileResult<()> {
+    fn inner(
+        vm: &mut Vm,
+        state: &mut CompileState,
+        cdr: &[Value],
+        result: usize,
+        line: &mut Option<&mut u32>,
+        star: bool,
+        old_tail: bool,
+    ) -> CompileResult<()> {
+        let start_defers = state.defers;
+        let symbols = Rc::new(RefCell::new(Symbols::with_let(
+            state.symbols.clone(),
+            result,
+        )));
+        if star {
+            state.symbols = symbols.clone();
+        }
+        let mut cdr_iter = cdr.iter();
+        let args = cdr_iter.next().unwrap(); // unwrap safe, length is at least 1
+        let mut opt_comps: Vec<(usize, Value)> = Vec::new();
+        let mut used_regs = 0;
+        let args_iter = get_args_iter(vm, *args, "let", line)?;
+        // XXX fixme
+        //new_state.chunk.dbg_args = Some(Vec::new());
+        let mut patterns: Vec<(usize, Value)> = Vec::new();
+        for a in args_iter {
+            used_regs += 1;
+            let mut args_iter = get_args_iter(vm, a, "let", line)?;
+            match args_iter.next() {
+                Some(Value::Symbol(i)) => {
+                    let reg = symbols.borrow_mut().insert(i) + 1;
+                    state.add_binding(vm, &symbols.borrow(), i, false, own_line(line));
+                    if let Some(dbg_args) = state.chunk.dbg_args.as_mut() {
+                        dbg_args.push(i);
+                    }
+                    if let Some(r) = args_iter.next() {
+                        opt_comps.push((reg, r));
+                    } else {
+                        opt_comps.push((reg, Value::Nil));
+                    }
+                    // XXX Check to make sure only two elements...
+                }
+                Some(pattern) if matches!(pattern, Value::Pair(_) | Value::Vector(_)) => {
+                    // The value goes in an unnamed register and is destructured from there.
+                    let reg = symbols.borrow().data.borrow_mut().add_anon() + 1;
+                    let wildcard = state.specials.wildcard;
+                    if let Some(dbg_args) = state.chunk.dbg_args.as_mut() {
+                        dbg_args.push(wildcard);
+                    }
+                    opt_comps.push((reg, args_iter.next().unwrap_or(Value::Nil)));
+                    patterns.push((reg, pattern));
+                }
+                _ => {}
+            }
+        }
+        // The destructured locals follow the bindings.
+        for (_, pattern) in &patterns {
+            let mut binds = Vec::new();
+            pattern_bindings(vm, state, *pattern, &mut binds);
+            for b in binds {
+                used_regs += 1;
+                symbols.borrow_mut().insert(b);
+                state.add_binding(vm, &symbols.borrow(), b, false, own_line(line));
+                if let Some(dbg_args) = state.chunk.dbg_args.as_mut() {
+                    dbg_args.push(b);
+                }
+            }
+        }
+        let top = result + used_regs;
+        for (reg, val) in opt_comps {
+            if patterns.is_empty() {
+                compile(vm, state, val, reg, line)?;
+            } else {
+                // Compile above all the bindings so temporaries do not clobber destructured
+                // locals from earlier bindings.
+                compile(vm, state, val, top, line)?;
+                state.encode2(MOV, reg as u16, top as u16, own_line(line))?;
+            }
+            if let Some((_, pattern)) = patterns.iter().find(|(r, _)| *r == reg) {
+                let old_symbols = state.symbols.clone();
+                state.symbols = symbols.clone();
+                let res = compile_destructure(vm, state, "let", *pattern, reg, top, line);
+                state.symbols = old_symbols;
+                res?;
+            }
+        }
+        if !star {
+            state.symbols = symbols;
+        }
+        let last_thing = cdr.len() - 2;
+        for (i, r) in cdr_iter.enumerate() {
+            if i == last_thing {
+                state.tail = old_tail;
+            }
+            compile(vm, state, *r, result + used_regs, line)?;
+        }
+        if used_regs > 0 {
+            state.encode2(
+                MOV,
+                result as u16,
+                (result + used_regs) as u16,
+                own_line(line),
+            )?;
+        }
+        for _ in start_defers..state.defers {
+            state.encode0(DFRPOP, own_line(line))?;
+        }
+        count_popped_defers(state, state.defers - start_defers, own_line(line))?;
+        Ok(())
+    }
+
+    if cdr.is_empty() {
+        return Err(CompileError::arg_count(
+            "Too few arguments, need at least 1 got 0.",
+        ));
+    }
+    let old_symbols = state.symbols.clone();
+    let old_tail = state.tail;
+    state.tail = false;
+    let old_defers = state.defers;
+    let old_defers_known = state.defers_known;
+    let result = inner(vm, state, cdr, result, line, star, old_tail);
+    state.tail = old_tail;
+    state.symbols = old_symbols;
+    // The let popped its defers on the way out so the outer count holds again.
+    state.defers = old_defers;
+    state.defers_known = old_defers_known;
+    result
+}
+
+/// Emit a JMP back to start (the top of a loop) in the current chunk.
+fn encode_jump_back(
+    state: &mut CompileState,
+    start: usize,
+    line: Option<u32>,
+) -> CompileResult<()> {
+    state.encode0(JMP, line)?;
+    let encode_offset = state.chunk.code.len();
+    state.encode_jump_offset(0)?;
+    let offset = start as i32 - state.chunk.code.len() as i32;
+    state.reencode_jump_offset(encode_offset, offset)?;
+    Ok(())
+}
+
+/// Emit a forward jump (JMP or op on test) to be patched with patch_jump, returns the offset and
+/// jump start.
+fn encode_forward_jump(
+    state: &mut CompileState,
+    op: u8,
+    test: Option<usize>,
+    line: Option<u32>,
+) -> CompileResult<(usize, usize)> {
+    if let Some(test) = test {
+        state.encode1(op, test as u16, line)?;
+    } else {
+        state.encode0(op, line)?;
+    }
+    let encode_offset = state.chunk.code.len();
+    state.encode_jump_offset(0)?;
+    Ok((encode_offset, state.chunk.code.len()))
+}
+
+/// Make sure the chunk has room for registers up to reg, for registers used directly instead of
+/// as the result of compile.
+fn use_regs(state: &mut CompileState, reg: usize) {
+    if state.max_regs < reg {
+        state.max_regs = reg;
+    }
+}
+
+/// Point a jump from encode_forward_jump at the current end of the chunk.
+fn patch_jump(state: &mut CompileState, jump: (usize, usize)) -> CompileResult<()> {
+    let (encode_offset, start_ip) = jump;
+    state.reencode_jump_offset(encode_offset, (state.chunk.code.len() - start_ip) as i32)?;
+    Ok(())
+}
+
+/// Run f to compile a loop body, it is never in tail position and a defer in it may or may not
+/// have run when the loop exits.
+fn loop_body<F>(state: &mut CompileState, f: F) -> CompileResult<()>
+where
+    F: FnOnce(&mut CompileState) -> CompileResult<()>,
+{
+    let old_tail = state.tail;
+    let old_in_loop = state.in_loop;
+    let start_defers = state.defers;
+    state.tail = false;
+    state.in_loop = true;
+    let res = f(state);
+    state.tail = old_tail;
+    state.in_loop = old_in_loop;
+    state.merge_defers(&[start_defers, state.defers]);
+    res
+}
+
+/// (while test body*)
+/// Evaluate body while test is true, evaluates to nil.
+fn compile_while(
+    vm: &mut Vm,
+    state: &mut CompileState,
+    cdr: &[Value],
+    result: usize,
+    line: &mut Option<&mut u32>,
+) -> CompileResult<()> {
+    if cdr.is_empty() {
+        return Err(CompileError::arg_count("while: requires a test"));
+    }
+    loop_body(state, |state| {
+        let start = state.chunk.code.len();
+        compile(vm, state, cdr[0], result, line)?;
+        let end_jump = encode_forward_jump(state, JMPF, Some(result), own_line(line))?;
+        for r in &cdr[1..] {
+            compile(vm, state, *r, result, line)?;
+        }
+        encode_jump_back(state, start, own_line(line))?;
+        patch_jump(state, end_jump)?;
+        state.encode1(REGN, result as u16, own_line(line))?;
+        Ok(())
+    })
+}
+
+/// (dotimes times body*) and (dotimes-i idx times body*)
+/// Evaluate body times times, dotimes-i binds idx to 0 to times - 1 (a new binding each time
+/// so closures over it see their own value).  Evaluates to nil.
+fn compile_dotimes(
+    vm: &mut Vm,
+    state: &mut CompileState,
+    cdr: &[Value],
+    result: usize,
+    line: &mut Option<&mut u32>,
+    with_idx: bool,
+) -> CompileResult<()> {
+    let (idx, times, body) = if with_idx {
+        if cdr.len() < 2 {
+            return Err(CompileError::arg_count(
+                "dotimes-i: requires an index symbol and count",
+            ));
+        }
+        if let Value::Symbol(idx) = cdr[0] {
+            (Some(idx), cdr[1], &cdr[2..])
+        } else {
+            return Err(CompileError::expected_symbol("dotimes-i: expected symbol"));
+        }
+    } else {
+        if cdr.is_empty() {
+            return Err(CompileError::arg_count("dotimes: requires a count"));
+        }
+        (None, cdr[0], &cdr[1..])
+    };
+    // result is idx, result + 1 the counter, result + 2 times and result + 3 the test.
+    let (counter, limit, test) = (result + 1, result + 2, result + 3);
+    use_regs(state, test);
+    compile(vm, state, times, limit, line)?;
+    let old_symbols = state.symbols.clone();
+    if let Some(idx) = idx {
+        let symbols = Rc::new(RefCell::new(Symbols::with_let(
+            state.symbols.clone(),
+            result,
+        )));
+        symbols.borrow_mut().insert(idx);
+        state.add_binding(vm, &symbols.borrow(), idx, false, own_line(line));
+        if let Some(dbg_args) = state.chunk.dbg_args.as_mut() {
+            dbg_args.push(idx);
+        }
+        state.symbols = symbols;
+    }
+    let res = loop_body(state, |state| {
+        state.encode2(REGI, counter as u16, 0, own_line(line))?;
+        let start = state.chunk.code.len();
+        state.encode3(
+            NUMLT,
+            test as u16,
+            counter as u16,
+            limit as u16,
+            own_line(line),
+        )?;
+        let end_jump = encode_forward_jump(state, JMPF, Some(test), own_line(line))?;
+        if idx.is_some() {
+            state.encode2(MOV, result as u16, counter as u16, own_line(line))?;
+        }
+        for r in body {
+            compile(vm, state, *r, test + 1, line)?;
+        }
+        state.encode2(INC, counter as u16, 1, own_line(line))?;
+        encode_jump_back(state, start, own_line(line))?;
+        patch_jump(state, end_jump)?;
+        state.encode1(REGN, result as u16, own_line(line))?;
+        Ok(())
+    });
+    state.symbols = old_symbols;
+    res
+}
+
+/// (for-each bind vector body*)
+/// Evaluate body with bind set to each element of vector in turn.  Evaluates to nil.
+fn compile_for_each(
+    vm: &mut Vm,
+    state: &mut CompileState,
+    cdr: &[Value],
+    result: usize,
+    line: &mut Option<&mut u32>,
+) -> CompileResult<()> {
+    if cdr.len() < 2 {
+        return Err(CompileError::arg_count(
+            "for-each: requires a symbol and vector",
+        ));
+    }
+    let bind = if let Value::Symbol(bind) = cdr[0] {
+        bind
+    } else {
+        return Err(CompileError::expected_symbol("for-each: expected symbol"));
+    };
+    // result is bind, result + 1 the vector, result + 2 the index, result + 3 the length and
+    // result + 4 the test.
+    let (vector, idx, len, test) = (result + 1, result + 2, result + 3, result + 4);
+    use_regs(state, test);
+    compile(vm, state, cdr[1], vector, line)?;
+    let old_symbols = state.symbols.clone();
+    let symbols = Rc::new(RefCell::new(Symbols::with_let(
+        state.symbols.clone(),
+        result,
+    )));
+    symbols.borrow_mut().insert(bind);
+    state.add_binding(vm, &symbols.borrow(), bind, false, own_line(line));
+    if let Some(dbg_args) = state.chunk.dbg_args.as_mut() {
+        dbg_args.push(bind);
+    }
+    state.symbols = symbols;
+    let res = loop_body(state, |state| {
+        state.encode2(VECLEN, len as u16, vector as u16, own_line(line))?;
+        state.encode2(REGI, idx as u16, 0, own_line(line))?;
+        let start = state.chunk.code.len();
+        state.encode3(NUMLT, test as u16, idx as u16, len as u16, own_line(line))?;
+        let end_jump = encode_forward_jump(state, JMPF, Some(test), own_line(line))?;
+        state.encode3(
+            VECNTH,
+            vector as u16,
+            result as u16,
+            idx as u16,
+            own_line(line),
+        )?;
+        for r in &cdr[2..] {
+            compile(vm, state, *r, test + 1, line)?;
+        }
+        state.encode2(INC, idx as u16, 1, own_line(line))?;
+        encode_jump_back(state, start, own_line(line))?;
+        patch_jump(state, end_jump)?;
+        state.encode1(REGN, result as u16, own_line(line))?;
+        Ok(())
+    });
+    state.symbols = old_symbols;
+    res
+}
+
+/// (loop ((name init)*) body*)
+/// Bind each name to its init (like let) then evaluate body, a recur in body assigns the names
+/// to its args and jumps back to the top of body instead of calling the fn again.  Evaluates to
+/// the last form of body.
+fn compile_loop(
+    vm: &mut Vm,
+    state: &mut CompileState,
+    cdr: &[Value],
+    result: usize,
+    line: &mut Option<&mut u32>,
+) -> CompileResult<()> {
+    if cdr.is_empty() {
+        return Err(CompileError::arg_count("loop: requires a binding list"));
+    }
+    let symbols = Rc::new(RefCell::new(Symbols::with_let(
+        state.symbols.clone(),
+        result,
+    )));
+    let mut inits = Vec::new();
+    for binding in get_args_iter(vm, cdr[0], "loop", line)?.collect::<Vec<Value>>() {
+        let mut binding = get_args_iter(vm, binding, "loop", line)?;
+        if let Some(Value::Symbol(i)) = binding.next() {
+            let reg = symbols.borrow_mut().insert(i) + 1;
+            state.add_binding(vm, &symbols.borrow(), i, false, own_line(line));
+            if let Some(dbg_args) = state.chunk.dbg_args.as_mut() {
+                dbg_args.push(i);
+            }
+            inits.push((reg, binding.next().unwrap_or(Value::Nil)));
+        } else {
+            return Err(CompileError::expected_symbol(
+                "loop: bindings must be (symbol init)",
+            ));
+        }
+    }
+    // Counts the defers pushed by this pass through the body.
+    let defer_reg = symbols.borrow().data.borrow_mut().add_anon() + 1;
+    use_regs(state, defer_reg);
+    let wildcard = state.specials.wildcard;
+    if let Some(dbg_args) = state.chunk.dbg_args.as_mut() {
+        dbg_args.push(wildcard);
+    }
+    let old_tail = state.tail;
+    let old_loop_tail = state.loop_tail;
+    state.tail = false;
+    for (reg, init) in &inits {
+        compile(vm, state, *init, *reg, line)?;
+    }
+    let body_reg = defer_reg + 1;
+    let old_symbols = state.symbols.clone();
+    let old_target = state.loop_target.take();
+    let old_in_loop = state.in_loop;
+    state.symbols = symbols;
+    state.in_loop = true;
+    let mut defer_regs = old_target
+        .as_ref()
+        .map(|target| target.defer_regs.clone())
+        .unwrap_or_default();
+    defer_regs.push(defer_reg);
+    state.loop_target = Some(LoopTarget {
+        start: state.chunk.code.len(),
+        regs: inits.iter().map(|(reg, _)| *reg).collect(),
+        defers: state.defers,
+        defer_regs,
+    });
+    let mut res = state.encode2(REGI, defer_reg as u16, 0, own_line(line));
+    // The end of the body is where recur can jump back from, it is only a tail call position if
+    // the loop is.
+    state.loop_tail = !old_tail || old_loop_tail;
+    let body = &cdr[1..];
+    if body.is_empty() && res.is_ok() {
+        res = compile(vm, state, Value::Nil, body_reg, line);
+    }
+    for (i, r) in body.iter().enumerate() {
+        if res.is_err() {
+            break;
+        }
+        state.tail = i == body.len() - 1;
+        res = compile(vm, state, *r, body_reg, line);
+    }
+    state.tail = old_tail;
+    state.loop_tail = old_loop_tail;
+    state.symbols = old_symbols;
+    state.loop_target = old_target;
+    state.in_loop = old_in_loop;
+    res?;
+    if body_reg != result {
+        state.encode2(MOV, result as u16, body_reg as u16, own_line(line))?;
+    }
+    Ok(())
+}
+
+/// (recur arg*) inside a loop, assign the loop bindings and jump back to the top of the loop.
+fn compile_loop_recur(
+    vm: &mut Vm,
+    state: &mut CompileState,
+    target: LoopTarget,
+    cdr: &[Value],
+    result: usize,
+    line: &mut Option<&mut u32>,
+) -> CompileResult<()> {
+    if !state.tail {
+        return Err(CompileError::malformed(
+            "recur: not in tail position of the loop",
+        ));
+    }
+    if cdr.len() != target.regs.len() {
+        return Err(CompileError::arg_count(format!(
+            "recur: loop has {} bindings, got {} arguments",
+            target.regs.len(),
+            cdr.len()
+        )));
+    }
+    state.tail = false;
+    // Evaluate all the args before assigning any so they see the old bindings.
+    for (i, r) in cdr.iter().enumerate() {
+        compile(vm, state, *r, result + i + 1, line)?;
+    }
+    for (i, reg) in target.regs.iter().enumerate() {
+        state.encode2(MOV, *reg as u16, (result + i + 1) as u16, own_line(line))?;
+    }
+    // Defers from this pass through the loop.
+    let line = own_line(line);
+    if let Some(defers) = state.tail_defers() {
+        for _ in target.defers..defers {
+            state.encode0(DFRPOP, line)?;
+        }
+        count_popped_defers(state, defers - target.defers, line)?;
+    } else {
+        // Depends on the path taken, pop while this loop's count is above 0.
+        let (zero, test) = (result, result + 1);
+        use_regs(state, test);
+        // Unwrap safe, the loop's own register is always last.
+        let defer_reg = *target.defer_regs.last().unwrap();
+        state.encode2(REGI, zero as u16, 0, line)?;
+        let top = state.chunk.code.len();
+        state.encode3(NUMLT, test as u16, zero as u16, defer_reg as u16, line)?;
+        let done = encode_forward_jump(state, JMPF, Some(test), line)?;
+        state.encode0(DFRPOP, line)?;
+        for reg in &target.defer_regs {
+            state.encode2(DEC, *reg as u16, 1, line)?;
+        }
+        encode_jump_back(state, top, line)?;
+        patch_jump(state, done)?;
+    }
+    encode_jump_back(state, target.start, line)
+}
+
+/// Keep the defer counts of the loops being compiled right after count defers were popped.
+pub fn count_popped_defers(
+    state: &mut CompileState,
+    count: usize,
+    line: Option<u32>,
+) -> CompileResult<()> {
+    if count == 0 {
+        return Ok(());
+    }
+    if let Some(target) = state.loop_target.clone() {
+        for reg in target.defer_regs {
+            state.encode2(DEC, reg as u16, count as u16, line)?;
+        }
+    }
+    Ok(())
+}
+
+/// Compile the forms of a clause into result, the last one in tail position if tail.  No forms
+/// evaluates to nil.
+fn compile_clause_body(
+    vm: &mut Vm,
+    state: &mut CompileState,
+    body: &[Value],
+    result: usize,
+    line: &mut Option<&mut u32>,
+    tail: bool,
+) -> CompileResult<()> {
+    if body.is_empty() {
+        return compile(vm, state, Value::Nil, result, line);
+    }
+    for (i, r) in body.iter().enumerate() {
+        state.tail = tail && i == body.len() - 1;
+        compile(vm, state, *r, result, line)?;
+    }
+    state.tail = false;
+    Ok(())
+}
+
+/// (cond (test form*)* (else form*)?)
+/// Evaluate the forms of the first clause with a true test.  A clause with no forms evaluates to
+/// its test, (test => f) calls f with the value of test and else always matches.  Nil if no
+/// clause matches.
+fn compile_cond(
+    vm: &mut Vm,
+    state: &mut CompileState,
+    cdr: &[Value],
+    result: usize,
+    line: &mut Option<&mut u32>,
+) -> CompileResult<()> {
+    let tail = state.tail;
+    state.tail = false;
+    let mut end_jumps = Vec::new();
+    let mut exit_defers = Vec::new();
+    let mut has_else = false;
+    for (i, clause) in cdr.iter().enumerate() {
+        let clause: Vec<Value> = get_args_iter(vm, *clause, "cond", line)?.collect();
+        if clause.is_empty() {
+            return Err(CompileError::malformed("cond: empty clause"));
+        }
+        if clause[0] == Value::Symbol(state.specials.else_) {
+            if i != cdr.len() - 1 {
+                return Err(CompileError::malformed(
+                    "cond: else must be the last clause",
+                ));
+            }
+            compile_clause_body(vm, state, &clause[1..], result, line, tail)?;
+            has_else = true;
+            break;
+        }
+        compile(vm, state, clause[0], result, line)?;
+        let test_defers = state.defers;
+        let next = encode_forward_jump(state, JMPF, Some(result), own_line(line))?;
+        if clause.len() == 3 && clause[1] == Value::Symbol(state.specials.arrow) {
+            // Call the receiver with the test value as its only arg.
+            state.encode2(MOV, (result + 1) as u16, result as u16, own_line(line))?;
+            compile(vm, state, clause[2], result + 2, line)?;
+            state.tail = false;
+            state.encode3(CALL, (result + 2) as u16, 1, result as u16, own_line(line))?;
+        } else if clause.len() > 1 {
+            compile_clause_body(vm, state, &clause[1..], result, line, tail)?;
+        }
+        exit_defers.push(state.defers);
+        state.defers = test_defers;
+        end_jumps.push(encode_forward_jump(state, JMP, None, own_line(line))?);
+        patch_jump(state, next)?;
+    }
+    if !has_else {
+        // The last test failed, the cond is nil not the false test value.
+        state.encode1(REGN, result as u16, own_line(line))?;
+    }
+    exit_defers.push(state.defers);
+    state.merge_defers(&exit_defers);
+    for jump in end_jumps {
+        patch_jump(state, jump)?;
+    }
+    Ok(())
+}
+
+/// Number of int keys in a case before they are dispatched with a binary search.
+const CASE_SEARCH_MIN_KEYS: usize = 4;
+
+/// Forward jumps from the dispatch code of a case to patch once the clauses are placed.
+struct CaseJumps {
+    clauses: Vec<Vec<(usize, usize)>>,
+    default: Vec<(usize, usize)>,
+}
+
+/// Emit a binary search of the sorted int keys for the value in register val.  val + 1 and
+/// val + 2 are scratch.
+fn compile_case_search(
+    vm: &mut Vm,
+    state: &mut CompileState,
+    keys: &[(i64, usize)],
+    val: usize,
+    line: &mut Option<&mut u32>,
+    jumps: &mut CaseJumps,
+) -> CompileResult<()> {
+    let (key_reg, test) = (val + 1, val + 2);
+    if keys.len() < CASE_SEARCH_MIN_KEYS {
+        for (key, clause) in keys {
+            mkconst(vm, state, Value::Int(*key), key_reg, line)?;
+            state.encode3(EQ, test as u16, val as u16, key_reg as u16, own_line(line))?;
+            let jump = encode_forward_jump(state, JMPT, Some(test), own_line(line))?;
+            jumps.clauses[*clause].push(jump);
+        }
+        let jump = encode_forward_jump(state, JMP, None, own_line(line))?;
+        jumps.default.push(jump);
+    } else {
+        let mid = keys.len() / 2;
+        mkconst(vm, state, Value::Int(keys[mid].0), key_reg, line)?;
+        state.encode3(
+            NUMLT,
+            test as u16,
+            val as u16,
+            key_reg as u16,
+            own_line(line),
+        )?;
+        let upper = encode_forward_jump(state, JMPF, Some(test), own_line(line))?;
+        compile_case_search(vm, state, &keys[..mid], val, line, jumps)?;
+        patch_jump(state, upper)?;
+        compile_case_search(vm, state, &keys[mid..], val, line, jumps)?;
+    }
+    Ok(())
+}
+
+/// Jump to clause if the value in register val is eq? to key.  val + 1 and val + 2 are
+/// scratch.
+fn compile_case_eq(
+    vm: &mut Vm,
+    state: &mut CompileState,
+    key: Value,
+    clause: usize,
+    val: usize,
+    line: &mut Option<&mut u32>,
+    jumps: &mut CaseJumps,
+) -> CompileResult<()> {
+    mkconst(vm, state, key, val + 1, line)?;
+    state.encode3(
+        EQ,
+        (val + 2) as u16,
+        val as u16,
+        (val + 1) as u16,
+        own_line(line),
+    )?;
+    let jump = encode_forward_jump(state, JMPT, Some(val + 2), own_line(line))?;
+    jumps.clauses[clause].push(jump);
+    Ok(())
+}
+
+/// Rank of a case key for case_key_cmp, the keys case-index can find are below 3.
+fn case_key_rank(key: Value) -> u8 {
+    match key {
+        Value::CodePoint(_) => 0,
+        Value::Keyword(_) => 1,
+        Value::Symbol(_) => 2,
+        _ => 3,
+    }
+}
+
+/// Order of the keys case-index searches: chars by code point, then keywords and then symbols
+/// by name.
+fn case_key_cmp(vm: &Vm, a: Value, b: Value) -> Ordering {
+    match (a, b) {
+        (Value::CodePoint(a), Value::CodePoint(b)) => a.cmp(&b),
+        (Value::Keyword(a), Value::Keyword(b)) | (Value::Symbol(a), Value::Symbol(b)) => {
+            vm.get_interned(a).cmp(vm.get_interned(b))
+        }
+        _ => case_key_rank(a).cmp(&case_key_rank(b)),
+    }
+}
+
+/// Builtin (case-index val keys), the position of val in keys (a vector sorted by
+/// case_key_cmp) or -1.  Compiled case forms call it to dispatch char, keyword and symbol keys.
+pub fn case_index(vm: &mut Vm, registers: &[Value]) -> VMResult<Value> {
+    if registers.len() != 2 {
+        return Err(VMError::new_vm(
+            "case-index: wrong number of args, expected two",
+        ));
+    }
+    let (val, keys) = if let Value::Vector(h) = registers[1] {
+        (registers[0], vm.get_vector(h))
+    } else {
+        return Err(VMError::new_vm("case-index: keys must be a vector"));
+    };
+    if case_key_rank(val) > 2 {
+        return Ok(Value::Int(-1));
+    }
+    match keys.binary_search_by(|key| case_key_cmp(vm, *key, val)) {
+        Ok(i) => Ok(Value::Int(i as i64)),
+        Err(_) => Ok(Value::Int(-1)),
+    }
+}
+
+/// (case expr (key form*)* (else form*)?)
+/// key is a literal (int, keyword, char, symbol, true, false or nil) or a list of them.
+/// Evaluate the forms of the first clause with a key eq? to expr, nil if none match and there
+/// is no else.  Int keys are found with a binary search when there are enough of them, enough
+/// char, keyword and symbol keys are looked up with case-index and its position searched the
+/// same way.  Fewer keys and the others (true, false, nil, etc) are tested in turn.
+fn compile_case(
+    vm: &mut Vm,
+    state: &mut CompileState,
+    cdr: &[Value],
+    result: usize,
+    line: &mut Option<&mut u32>,
+) -> CompileResult<()> {
+    if cdr.is_empty() {
+        return Err(CompileError::arg_count("case: requires an expression"));
+    }
+    let tail = state.tail;
+    state.tail = false;
+    // result + 1 holds the value, result + 2 and result + 3 are scratch.
+    let val = result + 1;
+    use_regs(state, val + 2);
+    compile(vm, state, cdr[0], val, line)?;
+    let mut bodies = Vec::new();
+    let mut else_body = None;
+    let mut int_keys: Vec<(i64, usize)> = Vec::new();
+    let mut other_keys: Vec<(Value, usize)> = Vec::new();
+    for (i, clause) in cdr[1..].iter().enumerate() {
+        let clause: Vec<Value> = get_args_iter(vm, *clause, "case", line)?.collect();
+        if clause.is_empty() {
+            return Err(CompileError::malformed("case: empty clause"));
+        }
+        if clause[0] == Value::Symbol(state.specials.else_) {
+            if i != cdr.len() - 2 {
+                return Err(CompileError::malformed(
+                    "case: else must be the last clause",
+                ));
+            }
+            else_body = Some(clause[1..].to_vec());
+            break;
+        }
+        let keys: Vec<Value> = if let Value::Pair(_) = clause[0] {
+            clause[0].iter(vm).collect()
+        } else {
+            vec![clause[0]]
+        };
+        let clause_idx = bodies.len();
+        for key in keys {
+            match key {
+                Value::Int(k) => {
+                    if !int_keys.iter().any(|(i, _)| *i == k) {
+                        int_keys.push((k, clause_idx));
+                    }
+                }
+                Value::Keyword(_)
+                | Value::Symbol(_)
+                | Value::CodePoint(_)
+                | Value::CharCluster(_, _)
+                | Value::Byte(_)
+                | Value::UInt(_)
+                | Value::True
+                | Value::False
+                | Value::Nil => {
+                    if !other_keys.iter().any(|(k, _)| *k == key) {
+                        other_keys.push((key, clause_idx));
+                    }
+                }
+                _ => {
+                    return Err(CompileError::malformed(format!(
+                        "case: invalid key {}, must be a literal",
+                        key.display_value(vm)
+                    )))
+                }
+            }
+        }
+        bodies.push(clause[1..].to_vec());
+    }
+    let mut jumps = CaseJumps {
+        clauses: vec![Vec::new(); bodies.len()],
+        default: Vec::new(),
+    };
+    if int_keys.len() >= CASE_SEARCH_MIN_KEYS {
+        // Only search ints, anything else would error in the compares.
+        let int_type = Value::Keyword(state.specials.int_type);
+        state.encode2(TYPE, (val + 1) as u16, val as u16, own_line(line))?;
+        mkconst(vm, state, int_type, val + 2, line)?;
+        state.encode3(
+            EQ,
+            (val + 2) as u16,
+            (val + 1) as u16,
+            (val + 2) as u16,
+            own_line(line),
+        )?;
+        let not_int = encode_forward_jump(state, JMPF, Some(val + 2), own_line(line))?;
+        int_keys.sort_unstable_by_key(|(k, _)| *k);
+        compile_case_search(vm, state, &int_keys, val, line, &mut jumps)?;
+        patch_jump(state, not_int)?;
+    } else {
+        other_keys.extend(int_keys.iter().map(|(k, c)| (Value::Int(*k), *c)));
+    }
+    let (mut index_keys, other_keys): (Vec<(Value, usize)>, Vec<(Value, usize)>) = other_keys
+        .into_iter()
+        .partition(|(key, _)| case_key_rank(*key) < 3);
+    if index_keys.len() < CASE_SEARCH_MIN_KEYS {
+        // Not worth a call, test them with the others.
+        index_keys.extend(other_keys);
+        for (key, clause) in index_keys.drain(..) {
+            compile_case_eq(vm, state, key, clause, val, line, &mut jumps)?;
+        }
+    } else {
+        for (key, clause) in other_keys {
+            compile_case_eq(vm, state, key, clause, val, line, &mut jumps)?;
+        }
+    }
+    if index_keys.is_empty() {
+        let default = encode_forward_jump(state, JMP, None, own_line(line))?;
+        jumps.default.push(default);
+    } else {
+        // (case-index val keys) into val + 2 then search its position, -1 goes to the default.
+        index_keys.sort_unstable_by(|(a, _), (b, _)| case_key_cmp(vm, *a, *b));
+        let keys = vm.alloc_vector_ro(index_keys.iter().map(|(key, _)| *key).collect());
+        root_new(vm, keys);
+        vm.set_global("case-index", Value::Builtin(CallFunc { func: case_index }));
+        let pos = val + 2;
+        use_regs(state, pos + 2);
+        state.encode2(MOV, (pos + 1) as u16, val as u16, own_line(line))?;
+        mkconst(vm, state, keys, pos + 2, line)?;
+        let case_index = state.specials.case_index;
+        state.encode_callg(vm, case_index, 2, pos as u16, own_line(line))?;
+        let positions: Vec<(i64, usize)> = index_keys
+            .iter()
+            .enumerate()
+            .map(|(i, (_, clause))| (i as i64, *clause))
+            .collect();
+        compile_case_search(vm, state, &positions, pos, line, &mut jumps)?;
+    }
+
+    let start_defers = state.defers;
+    let mut exit_defers = Vec::new();
+    let mut end_jumps = Vec::new();
+    for (body, clause_jumps) in bodies.iter().zip(jumps.clauses) {
+        for jump in clause_jumps {
+            patch_jump(state, jump)?;
+        }
+        compile_clause_body(vm, state, body, result, line, tail)?;
+        exit_defers.push(state.defers);
+        state.defers = start_defers;
+        end_jumps.push(encode_forward_jump(state, JMP, None, own_line(line))?);
+    }
+    for jump in jumps.default {
+        patch_jump(state, jump)?;
+    }
+    compile_clause_body(
+        vm,
+        state,
+        &else_body.unwrap_or_default(),
+        result,
+        line,
+        tail,
+    )?;
+    exit_defers.push(state.defers);
+    state.merge_defers(&exit_defers);
+    for jump in end_jumps {
+        patch_jump(state, jump)?;
+    }
+    Ok(())
+}
+
+/// Add the symbols pattern binds to binds (in order, without duplicates).
+fn pattern_bindings(vm: &Vm, state: &CompileState, pattern: Value, binds: &mut Vec<Interned>) {
+    match pattern {
+        Value::Symbol(i) => {
+            if i != state.specials.wildcard && i != state.specials.rest && !binds.contains(&i) {
+                binds.push(i);
+            }
+        }
+        Value::Pair(handle) => {
+            let (car, _) = vm.get_pair(handle);
+            if car != Value::Symbol(state.specials.quote) {
+                let mut cur = pattern;
+                while let Value::Pair(handle) = cur {
+                    let (car, cdr) = vm.get_pair(handle);
+                    pattern_bindings(vm, state, car, binds);
+                    cur = cdr;
+                }
+                // The tail of a dotted list pattern.
+                pattern_bindings(vm, state, cur, binds);
+            }
+        }
+        Value::Vector(handle) => {
+            for p in vm.get_vector(handle).iter() {
+                pattern_bindings(vm, state, *p, binds);
+            }
+        }
+        _ => {}
+    }
+}
+
+/// Jump to fail unless the value in register src has type (a type keyword).  scratch and
+/// scratch + 1 are clobbered.
+fn match_type(
+    vm: &mut Vm,
+    state: &mut CompileState,
+    type_: Interned,
+    src: usize,
+    scratch: usize,
+    line: &mut Option<&mut u32>,
+    fails: &mut Vec<(usize, usize)>,
+) -> CompileResult<()> {
+    use_regs(state, scratch + 1);
+    state.encode2(TYPE, scratch as u16, src as u16, own_line(line))?;
+    mkconst(vm, state, Value::Keyword(type_), scratch + 1, line)?;
+    state.encode3(
+        EQ,
+        scratch as u16,
+        scratch as u16,
+        (scratch + 1) as u16,
+        own_line(line),
+    )?;
+    fails.push(encode_forward_jump(
+        state,
+        JMPF,
+        Some(scratch),
+        own_line(line),
+    )?);
+    Ok(())
+}
+
+/// Emit code matching the value in register src against pattern, binding its symbols (already
+/// in the current scope) and jumping to one of fails if it does not match.  Registers from
+/// scratch up are free to use.
+fn compile_pattern(
+    vm: &mut Vm,
+    state: &mut CompileState,
+    pattern: Value,
+    src: usize,
+    scratch: usize,
+    line: &mut Option<&mut u32>,
+    fails: &mut Vec<(usize, usize)>,
+) -> CompileResult<()> {
+    let literal = match pattern {
+        Value::Symbol(i) if i == state.specials.wildcard => return Ok(()),
+        Value::Symbol(i) => {
+            // Unwrap safe, pattern_bindings put it in scope.
+            let reg = state.get_symbol(i).unwrap() + 1;
+            if reg != src {
+                state.encode2(MOV, reg as u16, src as u16, own_line(line))?;
+            }
+            return Ok(());
+        }
+        Value::Pair(handle) => {
+            let (car, cdr) = vm.get_pair(handle);
+            if car == Value::Symbol(state.specials.quote) {
+                if let Value::Pair(cdr_h) = cdr {
+                    vm.get_pair(cdr_h).0
+                } else {
+                    return Err(CompileError::invalid_quote("match: invalid quoted pattern"));
+                }
+            } else {
+                return compile_list_pattern(vm, state, pattern, src, scratch, line, fails);
+            }
+        }
+        Value::Vector(handle) => {
+            let elements = vm.get_vector(handle).to_vec();
+            if elements.contains(&Value::Symbol(state.specials.rest)) {
+                return Err(CompileError::malformed(
+                    "match: &rest is not supported in vector patterns",
+                ));
+            }
+            let vector_type = state.specials.vector_type;
+            match_type(vm, state, vector_type, src, scratch, line, fails)?;
+            let (len, idx, elem) = (scratch, scratch + 1, scratch + 2);
+            use_regs(state, elem);
+            state.encode2(VECLEN, len as u16, src as u16, own_line(line))?;
+            mkconst(vm, state, Value::Int(elements.len() as i64), idx, line)?;
+            state.encode3(EQ, len as u16, len as u16, idx as u16, own_line(line))?;
+            fails.push(encode_forward_jump(state, JMPF, Some(len), own_line(line))?);
+            for (i, p) in elements.iter().enumerate() {
+                mkconst(vm, state, Value::Int(i as i64), idx, line)?;
+                state.encode3(VECNTH, src as u16, elem as u16, idx as u16, own_line(line))?;
+                compile_pattern(vm, state, *p, elem, elem + 1, line, fails)?;
+            }
+            return Ok(());
+        }
+        Value::True
+        | Value::False
+        | Value::Nil
+        | Value::Int(_)
+        | Value::UInt(_)
+        | Value::Byte(_)
+        | Value::Float(_)
+        | Value::Keyword(_)
+        | Value::StringConst(_)
+        | Value::CodePoint(_)
+        | Value::CharCluster(_, _)
+        | Value::CharClusterLong(_) => pattern,
+        _ => {
+            return Err(CompileError::malformed(format!(
+                "match: invalid pattern {}",
+                pattern.display_value(vm)
+            )))
+        }
+    };
+    use_regs(state, scratch + 1);
+    state.encode2(MOV, scratch as u16, src as u16, own_line(line))?;
+    mkconst(vm, state, literal, scratch + 1, line)?;
+    state.encode3(
+        EQUAL,
+        scratch as u16,
+        scratch as u16,
+        (scratch + 1) as u16,
+        own_line(line),
+    )?;
+    fails.push(encode_forward_jump(
+        state,
+        JMPF,
+        Some(scratch),
+        own_line(line),
+    )?);
+    Ok(())
+}
+
+/// Split a list pattern into its element patterns and the pattern for the rest of the list
+/// (after &rest or the dot of a dotted list) if any.
+fn list_pattern_parts(
+    vm: &Vm,
+    state: &CompileState,
+    pattern: Value,
+) -> CompileResult<(Vec<Value>, Option<Value>)> {
+    let mut elements = Vec::new();
+    let mut cur = pattern;
+    while let Value::Pair(handle) = cur {
+        let (car, cdr) = vm.get_pair(handle);
+        elements.push(car);
+        cur = cdr;
+    }
+    let dotted = if cur.is_nil() { None } else { Some(cur) };
+    let rest_marker = Value::Symbol(state.specials.rest);
+    match elements.iter().position(|e| *e == rest_marker) {
+        Some(pos) if pos + 2 == elements.len() && dotted.is_none() => {
+            let rest = elements[pos + 1];
+            elements.truncate(pos);
+            Ok((elements, Some(rest)))
+        }
+        Some(_) => Err(CompileError::malformed(
+            "invalid pattern, &rest must be followed by one pattern at the end of a list",
+        )),
+        None => Ok((elements, dotted)),
+    }
+}
+
+/// Match a list pattern (p* &rest rest?) or (p+ . rest) against the value in register src.
+fn compile_list_pattern(
+    vm: &mut Vm,
+    state: &mut CompileState,
+    pattern: Value,
+    src: usize,
+    scratch: usize,
+    line: &mut Option<&mut u32>,
+    fails: &mut Vec<(usize, usize)>,
+) -> CompileResult<()> {
+    let (fixed, rest) = list_pattern_parts(vm, state, pattern)?;
+    // scratch is the rest of the list still to match, scratch + 1 the current element.
+    let (cur, elem) = (scratch, scratch + 1);
+    use_regs(state, elem);
+    state.encode2(MOV, cur as u16, src as u16, own_line(line))?;
+    let pair_type = state.specials.pair_type;
+    for p in &fixed {
+        match_type(vm, state, pair_type, cur, elem, line, fails)?;
+        state.encode2(CAR, elem as u16, cur as u16, own_line(line))?;
+        compile_pattern(vm, state, *p, elem, elem + 1, line, fails)?;
+        state.encode2(CDR, cur as u16, cur as u16, own_line(line))?;
+    }
+    if let Some(rest) = rest {
+        compile_pattern(vm, state, rest, cur, elem, line, fails)?;
+    } else {
+        state.encode1(REGN, elem as u16, own_line(line))?;
+        state.encode3(EQ, elem as u16, cur as u16, elem as u16, own_line(line))?;
+        fails.push(encode_forward_jump(
+            state,
+            JMPF,
+            Some(elem),
+            own_line(line),
+        )?);
+    }
+    Ok(())
+}
+
+/// Destructure the value in register src with pattern for a let or fn binding, the symbols in
+/// pattern must already be in scope.  If the value does not have the shape of pattern raise an
+/// error naming form, the pattern and the value.
+fn compile_destructure(
+    vm: &mut Vm,
+    state: &mut CompileState,
+    form: &str,
+    pattern: Value,
+    src: usize,
+    scratch: usize,
+    line: &mut Option<&mut u32>,
+) -> CompileResult<()> {
+    let mut fails = Vec::new();
+    compile_pattern(vm, state, pattern, src, scratch, line, &mut fails)?;
+    if fails.is_empty() {
+        return Ok(());
+    }
+    let done = encode_forward_jump(state, JMP, None, own_line(line))?;
+    for jump in fails {
+        patch_jump(state, jump)?;
+    }
+    let message = format!(
+        "{}: value does not match the pattern {}: ",
+        form,
+        pattern.display_value(vm)
+    );
+    encode_error(vm, state, &message, src, scratch, line)?;
+    patch_jump(state, done)
+}
+
+/// Raise an :error with message followed by the value in register val.  Registers from scratch
+/// up are free to use.
+fn encode_error(
+    vm: &mut Vm,
+    state: &mut CompileState,
+    message: &str,
+    val: usize,
+    scratch: usize,
+    line: &mut Option<&mut u32>,
+) -> CompileResult<()> {
+    let error = vm.intern("error");
+    let message = vm.intern(message);
+    use_regs(state, scratch + 2);
+    mkconst(vm, state, Value::Keyword(error), scratch, line)?;
+    mkconst(vm, state, Value::StringConst(message), scratch + 1, line)?;
+    state.encode2(MOV, (scratch + 2) as u16, val as u16, own_line(line))?;
+    state.encode3(
+        STR,
+        (scratch + 1) as u16,
+        (scratch + 1) as u16,
+        (scratch + 2) as u16,
+        own_line(line),
+    )?;
+    state.encode2(ERR, scratch as u16, (scratch + 1) as u16, own_line(line))?;
+    Ok(())
+}
+
+/// (match expr (pattern (:when guard)? form*)*)
+/// Evaluate the forms of the first clause whose pattern matches expr, with the symbols in the
+/// pattern bound to the matching parts.  A pattern is _ (matches anything), a symbol (matches
+/// anything and binds it), a literal or 'datum (matches if equal?), a list of patterns with an
+/// optional &rest pattern (or dotted tail) for the rest of the list or a vector of patterns.
+/// The same patterns destructure let bindings and fn args.  With :when guard the clause
+/// only matches if guard (evaluated with the bindings) is true.  Nil if no clause matches.
+fn compile_match(
+    vm: &mut Vm,
+    state: &mut CompileState,
+    cdr: &[Value],
+    result: usize,
+    line: &mut Option<&mut u32>,
+) -> CompileResult<()> {
+    if cdr.is_empty() {
+        return Err(CompileError::arg_count("match: requires an expression"));
+    }
+    let tail = state.tail;
+    state.tail = false;
+    let val = result + 1;
+    compile(vm, state, cdr[0], val, line)?;
+    let old_symbols = state.symbols.clone();
+    let start_defers = state.defers;
+    let mut exit_defers = Vec::new();
+    let mut end_jumps = Vec::new();
+    let guard = Value::Keyword(state.specials.guard);
+    for clause in &cdr[1..] {
+        let clause: Vec<Value> = get_args_iter(vm, *clause, "match", line)?.collect();
+        if clause.is_empty() {
+            return Err(CompileError::malformed("match: empty clause"));
+        }
+        let mut binds = Vec::new();
+        pattern_bindings(vm, state, clause[0], &mut binds);
+        // The bindings start at result + 2, then scratch registers for matching and the body.
+        let symbols = Rc::new(RefCell::new(Symbols::with_let(
+            old_symbols.clone(),
+            val + 1,
+        )));
+        for b in &binds {
+            symbols.borrow_mut().insert(*b);
+            state.add_binding(vm, &symbols.borrow(), *b, false, own_line(line));
+            if let Some(dbg_args) = state.chunk.dbg_args.as_mut() {
+                dbg_args.push(*b);
+            }
+        }
+        state.symbols = symbols;
+        let scratch = val + 1 + binds.len();
+        use_regs(state, scratch);
+        let mut fails = Vec::new();
+        let res = compile_pattern(vm, state, clause[0], val, scratch, line, &mut fails);
+        let res = res.and_then(|_| {
+            let body = if clause.len() > 2 && clause[1] == guard {
+                compile(vm, state, clause[2], scratch, line)?;
+                fails.push(encode_forward_jump(
+                    state,
+                    JMPF,
+                    Some(scratch),
+                    own_line(line),
+                )?);
+                &clause[3..]
+            } else {
+                &clause[1..]
+            };
+            compile_clause_body(vm, state, body, scratch, line, tail)?;
+            state.encode2(MOV, result as u16, scratch as u16, own_line(line))?;
+            Ok(())
+        });
+        state.symbols = old_symbols.clone();
+        res?;
+        exit_defers.push(state.defers);
+        state.defers = start_defers;
+        end_jumps.push(encode_forward_jump(state, JMP, None, own_line(line))?);
+        for jump in fails {
+            patch_jump(state, jump)?;
+        }
+    }
+    state.encode1(REGN, result as u16, own_line(line))?;
+    exit_defers.push(state.defers);
+    state.merge_defers(&exit_defers);
+    for jump in end_jumps {
+        patch_jump(state, jump)?;
+    }
+    Ok(())
+}
+
+/// Expand the macro mac with args cdr and compile the expansion into result.
+fn compile_macro_call(
+    vm: &mut Vm,
+    state: &mut CompileState,
+    mac: Value,
+    cdr: &[Value],
+    result: usize,
+) -> CompileResult<()> {
+    let exp = expand_macro(vm, mac, cdr)?;
+    let exp = root_new(vm, exp);
+    pass1(vm, state, exp)?;
+    fold_constants(vm, state, exp);
+    compile(vm, state, exp, result, &mut None)
+}
+
+/// (macrolet ((name (args) body*)*) body*)
+/// Compile body with the local macros, they are expanded like global macros but only in the
+/// lexical scope of body.  A macro's body can use the local macros of an outer macrolet but not
+/// the others defined by the same macrolet or any locals (it runs at compile time).
+fn compile_macrolet(
+    vm: &mut Vm,
+    state: &mut CompileState,
+    cdr: &[Value],
+    result: usize,
+    line: &mut Option<&mut u32>,
+) -> CompileResult<()> {
+    fn inner(
+        vm: &mut Vm,
+        state: &mut CompileState,
+        cdr: &[Value],
+        result: usize,
+        line: &mut Option<&mut u32>,
+    ) -> CompileResult<()> {
+        let symbols = Rc::new(RefCell::new(Symbols::with_let(
+            state.symbols.clone(),
+            result,
+        )));
+        let defs: Vec<Value> = get_args_iter(vm, cdr[0], "macrolet", line)?.collect();
+        for def in defs {
+            let def: Vec<Value> = def.iter(vm).collect();
+            if def.len() < 3 {
+                return Err(CompileError::malformed(
+                    "macrolet: each macro needs a name, args and body",
+                ));
+            }
+            let name = if let Value::Symbol(name) = def[0] {
+                name
+            } else {
+                return Err(CompileError::expected_symbol("macrolet: expected symbol"));
+            };
+            // Macros run at compile time so can not close over the locals around them.
+            let mut mac_state = CompileState::new_state(
+                vm,
+                state.chunk.file_name,
+                own_line(line).unwrap_or(0),
+                None,
+            );
+            mac_state.warnings = state.warnings.clone();
+            mac_state.declarations = state.declarations.clone();
+            mac_state.expansions = state.expansions.clone();
+            mac_state.peephole = state.peephole;
+            for (outer_name, outer_mac) in state.symbols.borrow().visible_macros() {
+                mac_state
+                    .symbols
+                    .borrow_mut()
+                    .insert_macro(outer_name, outer_mac);
+            }
+            let (mac, _) = mk_lambda(vm, &mut mac_state, def[1], &def[2..], line, true)?;
+            // Keep the macro alive while it is only referenced by the compiler.
+            root(vm, mac);
+            symbols.borrow_mut().insert_macro(name, mac);
+        }
+        state.symbols = symbols;
+        let old_tail = state.tail;
+        if cdr.len() == 1 {
+            return compile(vm, state, Value::Nil, result, line);
+        }
+        let last_thing = cdr.len() - 2;
+        for (i, r) in cdr[1..].iter().enumerate() {
+            state.tail = i == last_thing && old_tail;
+            compile(vm, state, *r, result, line)?;
+        }
+        Ok(())
+    }
+
+    if cdr.is_empty() {
+        return Err(CompileError::arg_count(
+            "macrolet: requires a list of macros",
+        ));
+    }
+    let old_symbols = state.symbols.clone();
+    let old_tail = state.tail;
+    let res = inner(vm, state, cdr, result, line);
+    state.tail = old_tail;
+    state.symbols = old_symbols;
+    res
+}
+
+/// (ns name), (in-ns name) and (import name*)
+/// These take effect when compiled so the forms following them are compiled in the namespace.
+/// ns creates (or resets the imports of) name and switches to it, in-ns switches to name (nil
+/// for the root namespace) and import adds namespaces the current one resolves names from.
+fn compile_ns(
+    vm: &mut Vm,
+    state: &mut CompileState,
+    form: Interned,
+    cdr: &[Value],
+    result: usize,
+    line: &mut Option<&mut u32>,
+) -> CompileResult<()> {
+    if form == state.specials.import {
+        for import in cdr {
+            let import = namespace_arg("import", *import)?;
+            import_namespace(vm, import);
+        }
+        return mkconst(vm, state, Value::Nil, result, line);
+    }
+    if cdr.len() != 1 {
+        return Err(CompileError::arg_count(format!(
+            "{}: requires one argument",
+            vm.get_interned(form)
+        )));
+    }
+    if form == state.specials.in_ns && cdr[0].is_nil() {
+        set_namespace(vm, None);
+        return mkconst(vm, state, Value::Nil, result, line);
+    }
+    let ns = namespace_arg(vm.get_interned(form), cdr[0])?;
+    if form == state.specials.ns {
+        new_namespace(vm, ns);
+    } else {
+        set_namespace(vm, Some(ns));
+    }
+    mkconst(vm, state, Value::Symbol(ns), result, line)
+}
+
+/// (declare declaration*), compile time settings for the rest of the file.
+/// (ignore-undefined name*) suppresses undefined global warnings for the names in the file.
+fn compile_declare(
+    vm: &mut Vm,
+    state: &mut CompileState,
+    cdr: &[Value],
+    result: usize,
+    line: &mut Option<&mut u32>,
+) -> CompileResult<()> {
+    for declaration in cdr {
+        let declaration: Vec<Value> = declaration.iter(vm).collect();
+        match declaration.first() {
+            Some(Value::Symbol(i)) if *i == state.specials.ignore_undefined => {
+                for name in &declaration[1..] {
+                    if let Value::Symbol(name) = name {
+                        let global = resolve_global(vm, *name);
+                        state
+                            .declarations
+                            .borrow_mut()
+                            .ignore_undefined
+                            .insert(global);
+                        state.warnings.borrow_mut().forget_undefined(global);
+                    } else {
+                        return Err(CompileError::expected_symbol(
+                            "declare: ignore-undefined takes symbols",
+                        ));
+                    }
+                }
+            }
+            Some(Value::Symbol(i)) if *i == state.specials.inline => {
+                for name in &declaration[1..] {
+                    if let Value::Symbol(name) = name {
+                        let global = def_name(vm, *name);
+                        state.declarations.borrow_mut().inline.insert(global);
+                    } else {
+                        return Err(CompileError::expected_symbol(
+                            "declare: inline takes symbols",
+                        ));
+                    }
+                }
+            }
+            _ => {
+                return Err(CompileError::malformed(
+                    "declare: expected (ignore-undefined name*) or (inline name*)",
+                ))
+            }
+        }
+    }
+    mkconst(vm, state, Value::Nil, result, line)
+}
+
+/// Compile (eval-when (phase*) body*), phases are :compile, :load and :execute.
+///
+/// When compiling to run in this process (CompileMode::Eval) the body is compiled if :execute
+/// is a phase.  When compiling for another process to load (CompileMode::File) the body is run
+/// now if :compile is a phase and compiled if :load is.  An eval-when that is not at the top
+/// level of a form (only do, eval-when and macro expansions keep a form top level) runs with
+/// the code around it so only :execute counts.  Evaluates to the last form of the body if it
+/// was compiled, else nil.
+fn compile_eval_when(
+    vm: &mut Vm,
+    state: &mut CompileState,
+    cdr: &[Value],
+    result: usize,
+    line: &mut Option<&mut u32>,
+    top_level: bool,
+) -> CompileResult<()> {
+    let (phases, body) = if let Some((phases, body)) = cdr.split_first() {
+        (*phases, body)
+    } else {
+        return Err(CompileError::arg_count(
+            "eval-when: expected a list of phases then the body",
+        ));
+    };
+    if !matches!(phases, Value::Nil | Value::Pair(_)) {
+        return Err(CompileError::malformed(
+            "eval-when: expected a list of phases (:compile, :load or :execute)",
+        ));
+    }
+    let mut compile_phase = false;
+    let mut load_phase = false;
+    let mut execute_phase = false;
+    for phase in phases.iter(vm) {
+        match phase {
+            Value::Keyword(i) if i == state.specials.compile_phase => compile_phase = true,
+            Value::Keyword(i) if i == state.specials.load_phase => load_phase = true,
+            Value::Keyword(i) if i == state.specials.execute_phase => execute_phase = true,
+            _ => {
+                return Err(CompileError::malformed(format!(
+                    "eval-when: unknown phase {}, expected :compile, :load or :execute",
+                    phase.display_value(vm)
+                )))
+            }
+        }
+    }
+    let mode = if top_level {
+        state.mode
+    } else {
+        CompileMode::Eval
+    };
+    let emit = match mode {
+        CompileMode::Eval => execute_phase,
+        CompileMode::File => {
+            if compile_phase {
+                eval_now(vm, state, body, line)?;
+            }
+            load_phase
+        }
+    };
+    if !emit || body.is_empty() {
+        return mkconst(vm, state, Value::Nil, result, line);
+    }
+    let last_thing = body.len() - 1;
+    let old_tail = state.tail;
+    state.tail = false;
+    for (i, r) in body.iter().enumerate() {
+        if i == last_thing {
+            state.tail = old_tail;
+        }
+        state.top_level = top_level;
+        compile(vm, state, *r, result, line)?;
+    }
+    Ok(())
+}
+
+/// Compile and run each of body in this process now, like top level forms that are run as they
+/// are compiled.  Nested eval-whens run as in CompileMode::Eval.
+fn eval_now(
+    vm: &mut Vm,
+    state: &CompileState,
+    body: &[Value],
+    line: &mut Option<&mut u32>,
+) -> CompileResult<()> {
+    for exp in body {
+        let mut eval_state =
+            CompileState::new_state(vm, state.chunk.file_name, own_line(line).unwrap_or(1), None);
+        eval_state.warnings = state.warnings.clone();
+        eval_state.declarations = state.declarations.clone();
+        eval_state.expansions = state.expansions.clone();
+        eval_state.peephole = state.peephole;
+        eval_state.chunk.dbg_args = Some(Vec::new());
+        compile_top_level(vm, &mut eval_state, *exp, line)?;
+        gc_point(vm);
+        vm.do_call(Arc::new(eval_state.chunk), &[Value::Nil], None)?;
+    }
+    Ok(())
+}
+
+/// Call the macro mac (a lambda or closure) with args and return the expansion.  The GC can run
+/// while the macro does, args must be rooted and the caller roots the expansion if it allocates
+/// before it is done with it.
+pub fn expand_macro(vm: &mut Vm, mac: Value, args: &[Value]) -> VMResult<Value> {
+    gc_point(vm);
+    match mac {
+        Value::Lambda(h) => {
+            let mac = vm.get_lambda(h);
+            vm.do_call(mac, args, None)
+        }
+        Value::Closure(h) => {
+            let (mac, caps) = vm.get_closure(h);
+            let caps = caps.to_vec();
+            vm.do_call(mac, args, Some(&caps))
+        }
+        _ => Err(VMError::new_compile("Invalid macro!")),
+    }
+}
+
+/// If form is a call to a global macro expand it once.  Returns the expansion and true or form
+/// and false if it is not a macro call.
+pub fn macroexpand_once(vm: &mut Vm, form: Value) -> VMResult<(Value, bool)> {
+    if let Value::Pair(handle) = form {
+        let (car, _) = vm.get_pair(handle);
+        if let Value::Symbol(i) = car {
+            let i = resolve_global(vm, i);
+            if let Some(slot) = vm.global_intern_slot(i) {
+                let global = vm.get_global(slot);
+                if is_macro(vm, global) {
+                    let args: Vec<Value> = form.iter(vm).skip(1).collect();
+                    return Ok((expand_macro(vm, global, &args)?, true));
+                }
+            }
+        }
+    }
+    Ok((form, false))
+}
+
+/// Builtin (macroexpand-1 form), expand form once if it is a macro call.
+pub fn macroexpand_1(vm: &mut Vm, registers: &[Value]) -> VMResult<Value> {
+    if registers.len() != 1 {
+        return Err(VMError::new_vm(
+            "macroexpand-1: wrong number of args, expected one",
+        ));
+    }
+    Ok(macroexpand_once(vm, registers[0])?.0)
+}
+
+/// Builtin (macroexpand form), expand form until it is no longer a macro call.
+pub fn macroexpand(vm: &mut Vm, registers: &[Value]) -> VMResult<Value> {
+    if registers.len() != 1 {
+        return Err(VMError::new_vm(
+            "macroexpand: wrong number of args, expected one",
+        ));
+    }
+    let mut form = registers[0];
+    loop {
+        let (exp, expanded) = macroexpand_once(vm, form)?;
+        if !expanded {
+            return Ok(exp);
+        }
+        form = exp;
+    }
+}
+
+/// True if val is a lambda or closure made by macro.
+pub fn is_macro(vm: &Vm, val: Value) -> bool {
+    match val {
+        Value::Lambda(h) => matches!(vm.get_heap_property(h, ":macro"), Some(Value::True)),
+        Value::Closure(h) => matches!(vm.get_heap_property(h, ":macro"), Some(Value::True)),
+        _ => false,
+    }
+}
+
+/// Compile (car cdr*) if car is one of the specials the compiler turns into an op (math, list
+/// and vector ops, comparisons, etc), false if it is not.
+pub fn compile_prim<E: Exp>(
+    vm: &mut Vm,
+    state: &mut CompileState,
+    car: Value,
+    cdr: &[E],
+    result: usize,
+    line: &mut Option<&mut u32>,
+) -> CompileResult<bool> {
+    Ok(compile_math(vm, state, car, cdr, result, line)?
+        || compile_cons(vm, state, car, cdr, result, line)?
+        || compile_vec(vm, state, car, cdr, result, line)?
+        || compile_op(vm, state, car, cdr, result, line)?)
+}
+
+fn compile_op<E: Exp>(
+    vm: &mut Vm,
+    state: &mut CompileState,
+    car: Value,
+    cdr: &[E],
+    result: usize,
+    line: &mut Option<&mut u32>,
+) -> CompileResult<bool> {
+    match car {
+        Value::Symbol(i) if i == state.specials.eq => {
+            state.tail = false;
+            if cdr.len() <= 1 {
+                return Err(CompileError::arg_count("Requires at least two arguments."));
+            } else {
+                let mut max = 0;
+                for (i, v) in cdr.iter().enumerate() {
+                    v.compile(vm, state, result + i + 1, line)?;
+                    max = result + i + 1;
+                }
+                state.encode3(
+                    EQ,
+                    result as u16,
+                    (result + 1) as u16,
+                    max as u16,
+                    own_line(line),
+                )?;
+            }
+        }
+        Value::Symbol(i) if i == state.specials.equal => {
+            state.tail = false;
+            if cdr.len() <= 1 {
+                return Err(CompileError::arg_count(
+                    "Requires at least two arguments. 2",
+                ));
+            } else {
+                let mut max = 0;
+                for (i, v) in cdr.iter().enumerate() {
+                    v.compile(vm, state, result + i + 1, line)?;
+                    max = result + i + 1;
+                }
+                state.encode3(
+                    EQUAL,
+                    result as u16,
+                    (result + 1) as u16,
+                    max as u16,
+                    own_line(line),
+                )?;
+            }
+        }
+        Value::Symbol(i) if i == state.specials.type_ => {
+            state.tail = false;
+            if cdr.len() != 1 {
+                return Err(CompileError::arg_count("Requires one argument."));
             } else {
-                compile(vm, state, cdr[1], result + 1, line)?;
-                let si_const = vm.reserve_index(si);
-                state
-                    .chunk
-                    .encode_refi(result as u16, si_const, own_line(line))?;
-                state
-                    .chunk
-                    .encode2(DEF, result as u16, (result + 1) as u16, own_line(line))?;
+                let src = Operands::new(result).operand(vm, state, cdr[0], &[], line)?;
+                state.encode2(TYPE, result as u16, src as u16, own_line(line))?;
             }
-        } else {
-            return Err(VMError::new_compile("set!: expected symbol"));
         }
-    } else {
-        return Err(VMError::new_compile("set!: malformed"));
-    }
-    Ok(())
-}
-
-fn compile_let(
-    vm: &mut Vm,
-    state: &mut CompileState,
-    cdr: &[Value],
-    result: usize,
-    line: &mut Option<&mut u32>,
-    star: bool,
-) -> VMResult<()> {
-    fn inner(
-        vm: &mut Vm,
-        state: &mut CompileState,
-        cdr: &[Value],
-        result: usize,
-        line: &mut Option<&mut u32>,
-        star: bool,
-        old_tail: bool,
-    ) -> VMResult<()> {
-        let start_defers = state.defers;
-        let symbols = Rc::new(RefCell::new(Symbols::with_let(
-            state.symbols.clone(),
-            result,
-        )));
-        if star {
-            state.symbols = symbols.clone();
+        Value::Symbol(i) if i == state.specials.not => {
+            state.tail = false;
+            if cdr.len() != 1 {
+                return Err(CompileError::arg_count("Requires one argument."));
+            } else {
+                let src = Operands::new(result).operand(vm, state, cdr[0], &[], line)?;
+                state.encode2(NOT, result as u16, src as u16, own_line(line))?;
+            }
         }
-        let mut cdr_iter = cdr.iter();
-        let args = cdr_iter.next().unwrap(); // unwrap safe, length is at least 1
-        let mut opt_comps: Vec<(usize, Value)> = Vec::new();
-        let mut used_regs = 0;
-        let args_iter = get_args_iter(vm, *args, "let", line)?;
-        // XXX fixme
-        //new_state.chunk.dbg_args = Some(Vec::new());
-        for a in args_iter {
-            used_regs += 1;
-            let mut args_iter = get_args_iter(vm, a, "let", line)?;
-            if let Some(Value::Symbol(i)) = args_iter.next() {
-                let reg = symbols.borrow_mut().insert(i) + 1;
-                if let Some(dbg_args) = state.chunk.dbg_args.as_mut() {
-                    dbg_args.push(i);
-                }
-                if let Some(r) = args_iter.next() {
-                    opt_comps.push((reg, r));
+        Value::Symbol(i) if i == state.specials.err => {
+            state.tail = false;
+            let len = cdr.len();
+            if len != 1 && len != 2 {
+                return Err(CompileError::arg_count("Requires one or two arguments."));
+            } else {
+                if len == 2 {
+                    cdr[0].compile(vm, state, result, line)?;
+                    cdr[1].compile(vm, state, result + 1, line)?;
                 } else {
-                    opt_comps.push((reg, Value::Nil));
+                    let error = vm.intern("error");
+                    compile(vm, state, Value::Keyword(error), result, line)?;
+                    cdr[0].compile(vm, state, result + 1, line)?;
                 }
-                // XXX Check to make sure only two elements...
+                state.encode2(ERR, result as u16, (result + 1) as u16, own_line(line))?;
             }
         }
-        for (reg, val) in opt_comps {
-            compile(vm, state, val, reg, line)?;
-        }
-        if !star {
-            state.symbols = symbols;
-        }
-        let last_thing = cdr.len() - 2;
-        for (i, r) in cdr_iter.enumerate() {
-            if i == last_thing {
-                state.tail = old_tail;
+        Value::Symbol(i) if i == state.specials.str_ => {
+            state.tail = false;
+            let mut max = 0;
+            for (i, v) in cdr.iter().enumerate() {
+                v.compile(vm, state, result + i + 1, line)?;
+                max = result + i + 1;
             }
-            compile(vm, state, *r, result + used_regs, line)?;
-        }
-        if used_regs > 0 {
-            state.chunk.encode2(
-                MOV,
+            state.encode3(
+                STR,
                 result as u16,
-                (result + used_regs) as u16,
+                (result + 1) as u16,
+                max as u16,
                 own_line(line),
             )?;
         }
-        for _ in start_defers..state.defers {
-            state.chunk.encode0(DFRPOP, own_line(line))?;
+        Value::Symbol(i) if i == state.specials.call_cc => {
+            state.tail = false;
+            if cdr.len() != 1 {
+                return Err(CompileError::arg_count("Requires one argument."));
+            }
+            cdr[0].compile(vm, state, result, line)?;
+            state.encode2(CCC, result as u16, result as u16, own_line(line))?;
         }
-        Ok(())
-    }
-
-    if cdr.is_empty() {
-        return Err(VMError::new_compile(
-            "Too few arguments, need at least 1 got 0.",
-        ));
-    }
-    let old_symbols = state.symbols.clone();
-    let old_tail = state.tail;
-    state.tail = false;
-    let old_defers = state.defers;
-    let result = inner(vm, state, cdr, result, line, star, old_tail);
-    state.tail = old_tail;
-    state.symbols = old_symbols;
-    state.defers = old_defers;
-    result
-}
-
-fn is_macro(vm: &Vm, val: Value) -> bool {
-    match val {
-        Value::Lambda(h) => matches!(vm.get_heap_property(h, ":macro"), Some(Value::True)),
-        Value::Closure(h) => matches!(vm.get_heap_property(h, ":macro"), Some(Value::True)),
-        _ => false,
+        _ => return Ok(false),
     }
+    Ok(true)
 }
 
 fn compile_list(
@@ -1128,24 +3437,26 @@ fn compile_list(
     cdr: &[Value],
     result: usize,
     line: &mut Option<&mut u32>,
-) -> VMResult<()> {
-    if !(compile_math(vm, state, car, cdr, result, line)?
-        || compile_cons(vm, state, car, cdr, result, line)?
-        || compile_vec(vm, state, car, cdr, result, line)?)
+) -> CompileResult<()> {
+    // Only the forms that keep a top level form top level get it for their subforms.
+    let top_level = state.top_level;
+    state.top_level = false;
+    if !(compile_inc_dec(vm, state, car, cdr, result, line)?
+        || compile_prim(vm, state, car, cdr, result, line)?)
     {
         match car {
             Value::Symbol(i) if i == state.specials.fn_ => {
                 if cdr.len() > 1 {
                     compile_fn(vm, state, cdr[0], &cdr[1..], result, line, false)?
                 } else {
-                    return Err(VMError::new_compile("Malformed fn form."));
+                    return Err(CompileError::malformed("Malformed fn form."));
                 }
             }
             Value::Symbol(i) if i == state.specials.mac_ => {
                 if cdr.len() > 1 {
                     compile_fn(vm, state, cdr[0], &cdr[1..], result, line, true)?
                 } else {
-                    return Err(VMError::new_compile("Malformed macro form."));
+                    return Err(CompileError::malformed("Malformed macro form."));
                 }
             }
             Value::Symbol(i) if i == state.specials.if_ => {
@@ -1159,6 +3470,7 @@ fn compile_list(
                     if i == last_thing {
                         state.tail = old_tail;
                     }
+                    state.top_level = top_level;
                     compile(vm, state, *r, result, line)?;
                 }
             }
@@ -1173,10 +3485,9 @@ fn compile_list(
             Value::Symbol(i) if i == state.specials.quote => {
                 state.tail = false;
                 if cdr.len() != 1 {
-                    return Err(VMError::new_compile(format!(
-                        "quote takes one argument, got {}, line {}",
-                        cdr.len(),
-                        line_num(line)
+                    return Err(CompileError::arg_count(format!(
+                        "quote takes one argument, got {}",
+                        cdr.len()
                     )));
                 }
                 mkconst(vm, state, cdr[0], result, line)?;
@@ -1184,190 +3495,121 @@ fn compile_list(
             Value::Symbol(i) if i == state.specials.backquote => {
                 state.tail = false;
                 if cdr.len() != 1 {
-                    return Err(VMError::new_compile(format!(
-                        "backquote takes one argument, got {}, line {}",
-                        cdr.len(),
-                        line_num(line)
+                    return Err(CompileError::arg_count(format!(
+                        "backquote takes one argument, got {}",
+                        cdr.len()
                     )));
                 }
                 backquote(vm, state, cdr[0], result, line)?;
             }
+            Value::Symbol(i) if i == state.specials.recur && state.loop_target.is_some() => {
+                let target = state.loop_target.clone().unwrap(); // unwrap safe, checked above
+                compile_loop_recur(vm, state, target, cdr, result, line)?
+            }
             Value::Symbol(i) if i == state.specials.recur => {
-                /*if !state.tail {
-                    return Err(VMError::new_compile(format!(
-                        "recur not in tail position, line {}",
-                        line
-                    )));
-                }*/
+                if !state.tail {
+                    return Err(CompileError::malformed("recur: not in tail position"));
+                }
                 compile_call_myself(vm, state, cdr, result, line, true)?
             }
             Value::Symbol(i) if i == state.specials.this_fn => {
                 compile_call_myself(vm, state, cdr, result, line, false)?
             }
-            Value::Symbol(i) if i == state.specials.eq => {
-                if cdr.len() <= 1 {
-                    return Err(VMError::new_compile("Requires at least two arguments."));
-                } else {
-                    let mut max = 0;
-                    for (i, v) in cdr.iter().enumerate() {
-                        compile(vm, state, *v, result + i + 1, line)?;
-                        max = result + i + 1;
-                    }
-                    state.chunk.encode3(
-                        EQ,
-                        result as u16,
-                        (result + 1) as u16,
-                        max as u16,
-                        own_line(line),
-                    )?;
-                }
-            }
-            Value::Symbol(i) if i == state.specials.equal => {
-                if cdr.len() <= 1 {
-                    return Err(VMError::new_compile("Requires at least two arguments. 2"));
-                } else {
-                    let mut max = 0;
-                    for (i, v) in cdr.iter().enumerate() {
-                        compile(vm, state, *v, result + i + 1, line)?;
-                        max = result + i + 1;
-                    }
-                    state.chunk.encode3(
-                        EQUAL,
-                        result as u16,
-                        (result + 1) as u16,
-                        max as u16,
-                        own_line(line),
-                    )?;
-                }
-            }
-            Value::Symbol(i) if i == state.specials.type_ => {
-                if cdr.len() != 1 {
-                    return Err(VMError::new_compile("Requires one argument."));
-                } else {
-                    compile(vm, state, cdr[0], result + 1, line)?;
-                    state.chunk.encode2(
-                        TYPE,
-                        result as u16,
-                        (result + 1) as u16,
-                        own_line(line),
-                    )?;
-                }
-            }
-            Value::Symbol(i) if i == state.specials.not => {
-                if cdr.len() != 1 {
-                    return Err(VMError::new_compile("Requires one argument."));
-                } else {
-                    compile(vm, state, cdr[0], result + 1, line)?;
-                    state
-                        .chunk
-                        .encode2(NOT, result as u16, (result + 1) as u16, own_line(line))?;
-                }
-            }
-            Value::Symbol(i) if i == state.specials.err => {
-                let len = cdr.len();
-                if len != 1 && len != 2 {
-                    return Err(VMError::new_compile("Requires one or two arguments."));
-                } else {
-                    if len == 2 {
-                        compile(vm, state, cdr[0], result, line)?;
-                        compile(vm, state, cdr[1], result + 1, line)?;
-                    } else {
-                        let error = vm.intern("error");
-                        compile(vm, state, Value::Keyword(error), result, line)?;
-                        compile(vm, state, cdr[0], result + 1, line)?;
-                    }
-                    state
-                        .chunk
-                        .encode2(ERR, result as u16, (result + 1) as u16, own_line(line))?;
-                }
-            }
             Value::Symbol(i) if i == state.specials.and => {
                 compile_and(vm, state, cdr, result, line)?;
             }
             Value::Symbol(i) if i == state.specials.or => {
                 compile_or(vm, state, cdr, result, line)?;
             }
-            Value::Symbol(i) if i == state.specials.str_ => {
-                let mut max = 0;
-                for (i, v) in cdr.iter().enumerate() {
-                    compile(vm, state, *v, result + i + 1, line)?;
-                    max = result + i + 1;
-                }
-                state.chunk.encode3(
-                    STR,
-                    result as u16,
-                    (result + 1) as u16,
-                    max as u16,
-                    own_line(line),
-                )?;
-            }
             Value::Symbol(i) if i == state.specials.let_ => {
                 compile_let(vm, state, cdr, result, line, false)?;
             }
             Value::Symbol(i) if i == state.specials.letstar => {
                 compile_let(vm, state, cdr, result, line, true)?;
             }
-            Value::Symbol(i) if i == state.specials.call_cc => {
-                if cdr.len() != 1 {
-                    return Err(VMError::new_compile("Requires one argument."));
-                }
-                compile(vm, state, cdr[0], result, line)?;
-                state
-                    .chunk
-                    .encode2(CCC, result as u16, result as u16, own_line(line))?;
-            }
             Value::Symbol(i) if i == state.specials.defer => {
                 if !cdr.is_empty() {
                     compile_fn(vm, state, Value::Nil, &cdr[0..], result, line, false)?;
-                    state.chunk.encode1(DFR, result as u16, own_line(line))?;
+                    state.encode1(DFR, result as u16, own_line(line))?;
                     state.defers += 1;
+                    if let Some(target) = state.loop_target.clone() {
+                        for reg in target.defer_regs {
+                            state.encode2(INC, reg as u16, 1, own_line(line))?;
+                        }
+                    }
                 } else {
-                    return Err(VMError::new_compile(
+                    return Err(CompileError::malformed(
                         "Malformed defer form, need at least one form.",
                     ));
                 }
             }
             Value::Symbol(i) if i == state.specials.on_error => {
                 if cdr.len() != 1 {
-                    return Err(VMError::new_compile("Requires one argument."));
+                    return Err(CompileError::arg_count("Requires one argument."));
                 }
                 compile(vm, state, cdr[0], result, line)?;
-                state.chunk.encode1(ONERR, result as u16, own_line(line))?;
+                state.encode1(ONERR, result as u16, own_line(line))?;
+            }
+            Value::Symbol(i) if i == state.specials.while_ => {
+                compile_while(vm, state, cdr, result, line)?;
+            }
+            Value::Symbol(i) if i == state.specials.loop_ => {
+                compile_loop(vm, state, cdr, result, line)?;
+            }
+            Value::Symbol(i) if i == state.specials.dotimes => {
+                compile_dotimes(vm, state, cdr, result, line, false)?;
+            }
+            Value::Symbol(i) if i == state.specials.dotimes_i => {
+                compile_dotimes(vm, state, cdr, result, line, true)?;
+            }
+            Value::Symbol(i) if i == state.specials.for_each => {
+                compile_for_each(vm, state, cdr, result, line)?;
+            }
+            Value::Symbol(i) if i == state.specials.cond => {
+                compile_cond(vm, state, cdr, result, line)?;
+            }
+            Value::Symbol(i) if i == state.specials.case => {
+                compile_case(vm, state, cdr, result, line)?;
+            }
+            Value::Symbol(i) if i == state.specials.match_ => {
+                compile_match(vm, state, cdr, result, line)?;
+            }
+            Value::Symbol(i) if i == state.specials.macrolet => {
+                compile_macrolet(vm, state, cdr, result, line)?;
+            }
+            Value::Symbol(i)
+                if i == state.specials.ns
+                    || i == state.specials.in_ns
+                    || i == state.specials.import =>
+            {
+                compile_ns(vm, state, i, cdr, result, line)?;
+            }
+            Value::Symbol(i) if i == state.specials.declare => {
+                compile_declare(vm, state, cdr, result, line)?;
+            }
+            Value::Symbol(i) if i == state.specials.eval_when => {
+                compile_eval_when(vm, state, cdr, result, line, top_level)?;
             }
             Value::Symbol(i) => {
-                if let Some(idx) = state.get_symbol(i) {
+                let local_macro = state.symbols.borrow().get_macro(i);
+                if let Some(mac) = local_macro {
+                    state.top_level = top_level;
+                    compile_macro_call(vm, state, mac, cdr, result)?
+                } else if let Some(idx) = state.get_symbol(i) {
+                    state.read_local(i);
                     compile_call_reg(vm, state, (idx + 1) as u16, cdr, result, line)?
                 } else {
+                    let i = resolve_global(vm, i);
                     let slot = vm.reserve_index(i);
                     // Is a global so set up a call and will error at runtime if
                     // not callable (dynamic is fun).
                     let global = vm.get_global(slot);
-                    if let Value::Undefined = global {
-                        eprintln!("Warning: {} not defined.", vm.get_interned(i));
-                    }
+                    state.warn_undefined(vm, i, "call to", own_line(line));
                     if is_macro(vm, global) {
-                        match global {
-                            Value::Lambda(h) => {
-                                let mac = vm.get_lambda(h);
-                                vm.pause_gc();
-                                let exp = vm.do_call(mac, cdr, None)?;
-                                vm.unpause_gc();
-                                pass1(vm, state, exp)?;
-                                compile(vm, state, exp, result, &mut None)?
-                            }
-                            Value::Closure(h) => {
-                                let (mac, caps) = vm.get_closure(h);
-                                let caps = caps.to_vec();
-                                vm.pause_gc();
-                                let exp = vm.do_call(mac, cdr, Some(&caps))?;
-                                vm.unpause_gc();
-                                pass1(vm, state, exp)?;
-                                compile(vm, state, exp, result, &mut None)?
-                            }
-                            _ => panic!("Invalid macro!"),
-                        }
+                        state.top_level = top_level;
+                        compile_macro_call(vm, state, global, cdr, result)?
                     } else {
-                        compile_callg(vm, state, slot as u32, cdr, result, line)?
+                        compile_callg(vm, state, i, cdr, result, line)?
                     }
                 }
             }
@@ -1383,27 +3625,28 @@ fn compile_list(
                 compile_call_reg(vm, state, result as u16, cdr, result, line)?
             }
             Value::Vector(h) => {
-                let v = vm.get_vector(h);
-                if let Some(ncar) = v.get(0) {
-                    let ncar = *ncar;
-                    if v.len() > 1 {
-                        let ncdr = make_vec_cdr(&v[1..]);
-                        compile_list(vm, state, ncar, ncdr, result, line)?;
-                    } else {
-                        compile_list(vm, state, ncar, &[], result, line)?;
-                    }
+                // Copied out so compiling (that can run macros that allocate) does not hold a
+                // borrow of the heap.
+                let v = vm.get_vector(h).to_vec();
+                if let Some((ncar, ncdr)) = v.split_first() {
+                    compile_list(vm, state, *ncar, ncdr, result, line)?;
                     compile_call_reg(vm, state, result as u16, cdr, result, line)?
                 }
             }
             _ => {
-                println!("Boo, {}", car.display_value(vm));
+                return Err(CompileError::not_callable(format!(
+                    "{} {} can not be called",
+                    car.display_type(vm),
+                    car.display_value(vm)
+                )));
             }
         }
     }
+    state.top_level = false;
     Ok(())
 }
 
-pub fn pass1(vm: &mut Vm, state: &mut CompileState, exp: Value) -> VMResult<()> {
+pub fn pass1(vm: &mut Vm, state: &mut CompileState, exp: Value) -> CompileResult<()> {
     let fn_ = vm.intern("fn");
     let mac_ = vm.intern("macro");
     match exp {
@@ -1459,128 +3702,688 @@ pub fn pass1(vm: &mut Vm, state: &mut CompileState, exp: Value) -> VMResult<()>
     Ok(())
 }
 
-// Need to break the cdr lifetime away from the vm for a call or we have
-// to reallocate stuff for no reason.
-// Should be safe because compiling code should not be manipulating values on
-// the heap (where the underlying vector lives).
-// XXX double check this invariant....
-fn make_vec_cdr(cdr: &[Value]) -> &'static [Value] {
-    unsafe { &*(cdr as *const [Value]) }
-}
-
 pub fn mkconst(
     _vm: &mut Vm,
     state: &mut CompileState,
     exp: Value,
     result: usize,
     line: &mut Option<&mut u32>,
-) -> VMResult<()> {
+) -> CompileResult<()> {
     match exp {
-        Value::True => state.chunk.encode1(REGT, result as u16, own_line(line))?,
-        Value::False => state.chunk.encode1(REGF, result as u16, own_line(line))?,
-        Value::Nil => state.chunk.encode1(REGN, result as u16, own_line(line))?,
-        Value::Undefined => state.chunk.encode1(REGC, result as u16, own_line(line))?,
-        Value::Byte(i) => state
-            .chunk
-            .encode2(REGB, result as u16, i as u16, own_line(line))?,
+        Value::True => state.encode1(REGT, result as u16, own_line(line))?,
+        Value::False => state.encode1(REGF, result as u16, own_line(line))?,
+        Value::Nil => state.encode1(REGN, result as u16, own_line(line))?,
+        Value::Undefined => state.encode1(REGC, result as u16, own_line(line))?,
+        Value::Byte(i) => state.encode2(REGB, result as u16, i as u16, own_line(line))?,
         Value::Int(i) if i >= 0 && i <= u16::MAX as i64 => {
-            state
-                .chunk
-                .encode2(REGI, result as u16, i as u16, own_line(line))?;
+            state.encode2(REGI, result as u16, i as u16, own_line(line))?;
         }
         Value::UInt(i) if i <= u16::MAX as u64 => {
-            state
-                .chunk
-                .encode2(REGU, result as u16, i as u16, own_line(line))?;
+            state.encode2(REGU, result as u16, i as u16, own_line(line))?;
         }
         _ => {
             let const_i = state.add_constant(exp);
-            state
-                .chunk
-                .encode2(CONST, result as u16, const_i as u16, own_line(line))?;
+            state.encode2(CONST, result as u16, const_i as u16, own_line(line))?;
         }
     }
     Ok(())
 }
 
+/// Compile the top level form exp into state's chunk: the form lowered to the IR and compiled
+/// from that (see compile_lowered), a RET and the peephole pass.  The chunk is then ready to
+/// execute or write out.
+pub fn compile_top_level(
+    vm: &mut Vm,
+    state: &mut CompileState,
+    exp: Value,
+    line: &mut Option<&mut u32>,
+) -> CompileResult<()> {
+    compile_lowered(vm, state, exp, 0, line)?;
+    state.encode0(RET, own_line(line))?;
+    peephole(state)?;
+    state.chunk.extra_regs = state.max_regs;
+    Ok(())
+}
+
+/// A form to compile, a source form or a node of the IR (see codegen.rs).  The compilers of the
+/// special forms the IR has nodes for (if, calls, ops, etc) take their args as a slice of Exp
+/// so the codegen uses them as well.
+pub trait Exp: Copy {
+    /// Compile self into result.
+    fn compile(
+        self,
+        vm: &mut Vm,
+        state: &mut CompileState,
+        result: usize,
+        line: &mut Option<&mut u32>,
+    ) -> CompileResult<()>;
+
+    /// The name of the local self reads if it is just a local.
+    fn local(self) -> Option<Interned>;
+
+    /// True if self has no subforms, evaluating it can not set! a local.
+    fn is_simple(self) -> bool;
+
+    /// The value of self if it is a constant.
+    fn const_value(self, state: &CompileState) -> Option<Value>;
+}
+
+impl Exp for Value {
+    fn compile(
+        self,
+        vm: &mut Vm,
+        state: &mut CompileState,
+        result: usize,
+        line: &mut Option<&mut u32>,
+    ) -> CompileResult<()> {
+        compile(vm, state, self, result, line)
+    }
+
+    fn local(self) -> Option<Interned> {
+        if let Value::Symbol(i) = self {
+            Some(i)
+        } else {
+            None
+        }
+    }
+
+    fn is_simple(self) -> bool {
+        !matches!(self, Value::Pair(_) | Value::Vector(_))
+    }
+
+    fn const_value(self, state: &CompileState) -> Option<Value> {
+        const_value(state, self)
+    }
+}
+
 pub fn compile(
     vm: &mut Vm,
     state: &mut CompileState,
     exp: Value,
     result: usize,
     line: &mut Option<&mut u32>,
-) -> VMResult<()> {
+) -> CompileResult<()> {
     if state.max_regs < result {
         state.max_regs = result;
     }
     match exp {
         Value::Pair(handle) => {
-            let (car, cdr) = vm.get_pair(handle);
             set_line(vm, handle, line);
+            if let Some(val) = state.folds.get(&exp).copied() {
+                return compile(vm, state, val, result, line);
+            }
+            let expansion = state.expansions.borrow().get(&exp).copied();
+            if let Some(expansion) = expansion {
+                // Lowered already, compile the expansion lower used instead of running the
+                // macro again.
+                pass1(vm, state, expansion)?;
+                fold_constants(vm, state, expansion);
+                return compile(vm, state, expansion, result, line);
+            }
+            let (car, cdr) = vm.get_pair(handle);
             let cdr: Vec<Value> = cdr.iter(vm).collect();
-            compile_list(vm, state, car, &cdr[..], result, line)?;
+            // The form's op has read its operands once it is compiled.
+            let temps = state.temps_mark();
+            let res = compile_list(vm, state, car, &cdr[..], result, line);
+            state.release_temps(temps);
+            res.map_err(|e| e.with_form(vm, exp).with_file(state.chunk.file_name))?;
         }
         Value::Vector(handle) => {
-            let v = vm.get_vector(handle);
-            if let Some(car) = v.get(0) {
-                let car = *car;
-                if v.len() > 1 {
-                    let cdr = make_vec_cdr(&v[1..]);
-                    compile_list(vm, state, car, cdr, result, line)?;
-                } else {
-                    compile_list(vm, state, car, &[], result, line)?;
-                }
+            let v = vm.get_vector(handle).to_vec();
+            if let Some((car, cdr)) = v.split_first() {
+                let temps = state.temps_mark();
+                let res = compile_list(vm, state, *car, cdr, result, line);
+                state.release_temps(temps);
+                res.map_err(|e| e.with_form(vm, exp).with_file(state.chunk.file_name))?;
             }
         }
         Value::Symbol(i) => {
             if let Some(idx) = state.get_symbol(i) {
+                state.read_local(i);
                 if result != idx + 1 {
-                    state
-                        .chunk
-                        .encode2(MOV, result as u16, (idx + 1) as u16, own_line(line))?;
+                    state.encode2(MOV, result as u16, (idx + 1) as u16, own_line(line))?;
                 }
             } else {
-                let const_i = vm.reserve_index(i);
-                state
-                    .chunk
-                    .encode_refi(result as u16, const_i, own_line(line))?;
+                let i = resolve_global(vm, i);
+                state.warn_undefined(vm, i, "reference to", own_line(line));
+                state.encode_refi(vm, result as u16, i, own_line(line))?;
             }
         }
-        Value::True => state.chunk.encode1(REGT, result as u16, own_line(line))?,
-        Value::False => state.chunk.encode1(REGF, result as u16, own_line(line))?,
-        Value::Nil => state.chunk.encode1(REGN, result as u16, own_line(line))?,
-        Value::Undefined => state.chunk.encode1(REGC, result as u16, own_line(line))?,
-        Value::Byte(i) => state
-            .chunk
-            .encode2(REGB, result as u16, i as u16, own_line(line))?,
+        Value::True => state.encode1(REGT, result as u16, own_line(line))?,
+        Value::False => state.encode1(REGF, result as u16, own_line(line))?,
+        Value::Nil => state.encode1(REGN, result as u16, own_line(line))?,
+        Value::Undefined => state.encode1(REGC, result as u16, own_line(line))?,
+        Value::Byte(i) => state.encode2(REGB, result as u16, i as u16, own_line(line))?,
         Value::Int(i) if i >= 0 && i <= u16::MAX as i64 => {
-            state
-                .chunk
-                .encode2(REGI, result as u16, i as u16, own_line(line))?
+            state.encode2(REGI, result as u16, i as u16, own_line(line))?
         }
         Value::UInt(i) if i <= u16::MAX as u64 => {
-            state
-                .chunk
-                .encode2(REGU, result as u16, i as u16, own_line(line))?
+            state.encode2(REGU, result as u16, i as u16, own_line(line))?
         }
         _ => {
             // XXX this used to ignore References but now does not, is that good?
             let const_i = state.add_constant(exp);
-            state
-                .chunk
-                .encode2(CONST, result as u16, const_i as u16, own_line(line))?;
+            state.encode2(CONST, result as u16, const_i as u16, own_line(line))?;
         }
     }
     Ok(())
 }
 
-fn own_line(line: &Option<&mut u32>) -> Option<u32> {
+/// The line being compiled, None if lines are not tracked.
+pub fn own_line(line: &Option<&mut u32>) -> Option<u32> {
     line.as_ref().map(|l| **l)
 }
 
-fn line_num(line: &mut Option<&mut u32>) -> u32 {
-    match line {
-        Some(l) => **l,
-        None => 0,
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::prelude::*;
+    use crate::test_util::*;
+
+    #[test]
+    fn test_not_callable() {
+        let mut vm = new_vm();
+        let e = compile_err(&mut vm, "(1 2)");
+        assert!(matches!(e.kind, CompileErrorKind::NotCallable));
+        let e = compile_err(&mut vm, "(\"s\" 2)");
+        assert!(matches!(e.kind, CompileErrorKind::NotCallable));
+        assert_eq!(eval(&mut vm, "((fn (x) (+ x 1)) 2)"), "3");
+    }
+
+    const DEFER_LOG: &str = "(def log '())
+        (defn note (x) (set! log (cons x log)) x)
+        (defn use-it (x) (note :use) x)";
+
+    #[test]
+    fn test_defer_tail_call() {
+        let mut vm = new_vm();
+        eval(&mut vm, DEFER_LOG);
+        eval(
+            &mut vm,
+            "(defn in-let () (let ((a 1)) (defer (note :close)) (use-it a)))
+             (defn in-if (t) (let ((a 1)) (defer (note :close)) (if t (use-it a) (use-it 2))))
+             (defn in-do () (do (defer (note :close)) (use-it 1)))",
+        );
+        // The call in tail position runs before the defer.
+        for call in &["(in-let)", "(in-if #t)", "(in-if #f)", "(in-do)"] {
+            eval(&mut vm, "(set! log '())");
+            eval(&mut vm, call);
+            assert_eq!(eval(&mut vm, "log"), "(:close :use)", "{}", call);
+        }
+        // The defer of a let that is done does not stop a tail call after it.
+        assert_eq!(
+            eval(
+                &mut vm,
+                "(defn down (n) (let ((a n)) (defer (note a))) (if (= n 0) :done (down (- n 1))))
+                 (down 100000)"
+            ),
+            ":done"
+        );
+    }
+
+    #[test]
+    fn test_loops() {
+        let mut vm = new_vm();
+        assert_eq!(
+            eval(
+                &mut vm,
+                "(def i 0) (def s 0) (while (< i 5) (set! s (+ s i)) (set! i (+ i 1))) s"
+            ),
+            "10"
+        );
+        assert_eq!(eval(&mut vm, "(while #f 1)"), "nil");
+        assert_eq!(
+            eval(&mut vm, "(set! s 0) (dotimes 4 (set! s (+ s 1))) s"),
+            "4"
+        );
+        assert_eq!(
+            eval(&mut vm, "(set! s 0) (dotimes-i x 4 (set! s (+ s x))) s"),
+            "6"
+        );
+        assert_eq!(
+            eval(
+                &mut vm,
+                "(set! s 0) (for-each x '#(1 2 3) (set! s (+ s x))) s"
+            ),
+            "6"
+        );
+        assert_eq!(
+            eval(
+                &mut vm,
+                "(loop ((i 0) (acc '())) (if (< i 3) (recur (+ i 1) (cons i acc)) acc))"
+            ),
+            "(2 1 0)"
+        );
+        // A loop that is not in tail position still evaluates to its last form.
+        assert_eq!(
+            eval(
+                &mut vm,
+                "(defn g () (+ 1 (loop ((i 0)) (if (< i 3) (recur (+ i 1)) (+ i 0)))))
+                 (g)"
+            ),
+            "4"
+        );
+    }
+
+    #[test]
+    fn test_recur_not_tail() {
+        let mut vm = new_vm();
+        compile_err(&mut vm, "(loop ((i 0)) (recur (+ i 1)) 1)");
+        compile_err(&mut vm, "(loop ((i 0)) (+ 1 (recur i)))");
+        compile_err(&mut vm, "(loop ((i 0)) (while #t (recur i)))");
+        compile_err(&mut vm, "(defn f (n) (+ 1 (recur n)))");
+    }
+
+    #[test]
+    fn test_loop_defer() {
+        let mut vm = new_vm();
+        eval(&mut vm, DEFER_LOG);
+        // Each pass through the loop runs its defer before the next one starts.
+        eval(
+            &mut vm,
+            "(defn each-pass ()
+               (loop ((i 0))
+                 (note i)
+                 (defer (note :d))
+                 (if (< i 1) (recur (+ i 1)) i)))",
+        );
+        assert_eq!(eval(&mut vm, "(each-pass)"), "1");
+        assert_eq!(eval(&mut vm, "log"), "(:d 1 :d 0)");
+        // A defer on only some paths is popped by the count kept at runtime.
+        eval(
+            &mut vm,
+            "(set! log '())
+             (defn some-passes ()
+               (loop ((i 0))
+                 (note i)
+                 (if (= i 1) (defer (note :d)))
+                 (if (< i 2) (recur (+ i 1)) i)))",
+        );
+        assert_eq!(eval(&mut vm, "(some-passes)"), "2");
+        assert_eq!(eval(&mut vm, "log"), "(2 :d 1 0)");
+        // An inner loop's defers are popped by its own recur, not again by the outer one.
+        eval(
+            &mut vm,
+            "(set! log '())
+             (defn nested ()
+               (loop ((i 0))
+                 (let ((x (loop ((j 0))
+                            (if (= j 0) (defer (note :inner)))
+                            (if (< j 1) (recur (+ j 1)) j))))
+                   (note x))
+                 (if (< i 1) (recur (+ i 1)) i)))",
+        );
+        assert_eq!(eval(&mut vm, "(nested)"), "1");
+        assert_eq!(eval(&mut vm, "log"), "(1 :inner 1 :inner)");
+    }
+
+    #[test]
+    fn test_cond() {
+        let mut vm = new_vm();
+        eval(
+            &mut vm,
+            "(defn c (x) (cond ((= x 1) :one) ((car x) => (fn (v) (+ v 1))) (else :other)))",
+        );
+        assert_eq!(eval(&mut vm, "(c 1)"), ":one");
+        assert_eq!(eval(&mut vm, "(c '(41))"), "42");
+        assert_eq!(eval(&mut vm, "(c '(nil))"), ":other");
+        assert_eq!(eval(&mut vm, "(cond (#f 1))"), "nil");
+    }
+
+    #[test]
+    fn test_case() {
+        let mut vm = new_vm();
+        // Enough int keys for a binary search, with a keyword tested after it.
+        eval(
+            &mut vm,
+            "(defn c5 (n) (case n (1 :one) ((3 5) :odd) (7 :seven) (9 :nine) (:a :key) (else :other)))",
+        );
+        let expected = [
+            ":other", ":one", ":other", ":odd", ":other", ":odd", ":other", ":seven", ":other",
+            ":nine", ":other",
+        ];
+        // Each key, between keys and past both ends.
+        for (n, expected) in expected.iter().enumerate() {
+            assert_eq!(eval(&mut vm, &format!("(c5 {})", n)), *expected, "{}", n);
+        }
+        assert_eq!(eval(&mut vm, "(c5 -100)"), ":other");
+        assert_eq!(eval(&mut vm, "(c5 :a)"), ":key");
+        assert_eq!(eval(&mut vm, "(c5 \"1\")"), ":other");
+        // Few keys are tested in turn, the first clause with a key wins.
+        eval(
+            &mut vm,
+            "(defn k (x) (case x (:a 1) ((:b sym 2) 2) ((:a 3) 3)))",
+        );
+        assert_eq!(eval(&mut vm, "(k :a)"), "1");
+        assert_eq!(eval(&mut vm, "(k 'sym)"), "2");
+        assert_eq!(eval(&mut vm, "(k 2)"), "2");
+        assert_eq!(eval(&mut vm, "(k 3)"), "3");
+        assert_eq!(eval(&mut vm, "(k :z)"), "nil");
+        // Enough char, keyword and symbol keys are found with case-index, nil is tested first.
+        eval(
+            &mut vm,
+            "(defn kind (c)
+               (case c ((#\\a #\\e #\\i #\\o #\\u) :vowel) ((#\\y #\\w) :semi) (:x :key) (x :sym)
+                 (nil :nil) (else :other)))",
+        );
+        for c in &["a", "e", "i", "o", "u"] {
+            assert_eq!(
+                eval(&mut vm, &format!("(kind #\\{})", c)),
+                ":vowel",
+                "{}",
+                c
+            );
+        }
+        assert_eq!(eval(&mut vm, "(kind #\\y)"), ":semi");
+        assert_eq!(eval(&mut vm, "(kind #\\w)"), ":semi");
+        assert_eq!(eval(&mut vm, "(kind #\\b)"), ":other");
+        assert_eq!(eval(&mut vm, "(kind #\\z)"), ":other");
+        assert_eq!(eval(&mut vm, "(kind :x)"), ":key");
+        assert_eq!(eval(&mut vm, "(kind 'x)"), ":sym");
+        assert_eq!(eval(&mut vm, "(kind 'y)"), ":other");
+        assert_eq!(eval(&mut vm, "(kind nil)"), ":nil");
+        assert_eq!(eval(&mut vm, "(kind 1)"), ":other");
+        assert_eq!(eval(&mut vm, "(kind \"a\")"), ":other");
+        assert_eq!(eval(&mut vm, "(case-index :b '#(:a :b :c))"), "1");
+        assert_eq!(eval(&mut vm, "(case-index :z '#(:a :b :c))"), "-1");
+        assert_eq!(eval(&mut vm, "(case-index 1 '#(:a :b :c))"), "-1");
+    }
+
+    #[test]
+    fn test_match_guard() {
+        let mut vm = new_vm();
+        eval(
+            &mut vm,
+            "(defn m (x) (match x
+                ((a b) :when (< a b) (list :lt a b))
+                ((a b) (list :ge a b))
+                (n :when (= n 0) :zero)
+                (_ :other)))",
+        );
+        assert_eq!(eval(&mut vm, "(m '(1 2))"), "(:lt 1 2)");
+        assert_eq!(eval(&mut vm, "(m '(2 1))"), "(:ge 2 1)");
+        assert_eq!(eval(&mut vm, "(m 0)"), ":zero");
+        assert_eq!(eval(&mut vm, "(m 5)"), ":other");
+    }
+
+    #[test]
+    fn test_destructure_args() {
+        let mut vm = new_vm();
+        assert_eq!(eval(&mut vm, "((fn ((a b)) (+ a b)) '(1 2))"), "3");
+        assert_eq!(eval(&mut vm, "((fn ((a)) a) '(5))"), "5");
+        assert_eq!(
+            eval(&mut vm, "((fn (#(x y) z) (list x y z)) #(1 2) 3)"),
+            "(1 2 3)"
+        );
+        assert_eq!(
+            eval(&mut vm, "((fn (a &optional b (c 3)) (list a b c)) 1)"),
+            "(1 nil 3)"
+        );
+        assert_eq!(
+            eval(&mut vm, "((fn (a &optional (b 2) c) (list a b c)) 1 5 6)"),
+            "(1 5 6)"
+        );
+        // The fn's args are its own, _ marks the unnamed register of a destructured arg.
+        eval(&mut vm, "(def f (fn ((a b) c) c))");
+        let f = vm.intern("f");
+        let chunk = match vm.get_global(vm.global_intern_slot(f).unwrap()) {
+            Value::Lambda(h) => vm.get_lambda(h),
+            _ => panic!("f is not a lambda"),
+        };
+        let names: Vec<&str> = chunk
+            .dbg_args
+            .as_ref()
+            .unwrap()
+            .iter()
+            .map(|i| vm.get_interned(*i))
+            .collect();
+        assert_eq!(names, vec!["_", "c", "a", "b"]);
+    }
+
+    #[test]
+    fn test_old_optional_args() {
+        let mut vm = new_vm();
+        // (name default) was an optional arg before destructuring, now it needs &optional.
+        for text in &[
+            "(fn (a (b 2)) b)",
+            "(fn (a (b :x)) b)",
+            "(fn (a (b '(1))) b)",
+        ] {
+            let e = compile_err(&mut vm, text);
+            assert!(matches!(e.kind, CompileErrorKind::InvalidArgs), "{}", text);
+            assert!(e.message.contains("&optional"), "{}: {}", text, e.message);
+        }
+        assert_eq!(eval(&mut vm, "((fn (a &optional (b 2)) (+ a b)) 1)"), "3");
+        // A pattern of symbols is still destructured.
+        assert_eq!(
+            eval(&mut vm, "((fn (a (b c)) (list a b c)) 1 '(2 3))"),
+            "(1 2 3)"
+        );
+    }
+
+    #[test]
+    fn test_key_args() {
+        let mut vm = new_vm();
+        eval(&mut vm, "(defn k (a &key b (c (+ a 1))) (list a b c))");
+        assert_eq!(eval(&mut vm, "(k 1)"), "(1 nil 2)");
+        assert_eq!(eval(&mut vm, "(k 1 :b 5)"), "(1 5 2)");
+        assert_eq!(eval(&mut vm, "(k 1 :c 7 :b 5)"), "(1 5 7)");
+        // A key passed as nil is passed, its default is not used.
+        assert_eq!(eval(&mut vm, "(k 1 :c nil)"), "(1 nil nil)");
+        for (text, message) in &[
+            ("(k 1 :b)", "no value for keyword arg"),
+            ("(k 1 :b 2 :c)", "no value for keyword arg"),
+            ("(k 1 :d 2)", "unknown keyword arg"),
+            ("(k 1 :b 2 :b 3)", "duplicate keyword arg"),
+        ] {
+            let e = compile_err(&mut vm, text);
+            assert!(e.message.contains(message), "{}: {}", text, e);
+        }
+        // &optional args come before the keys.
+        eval(
+            &mut vm,
+            "(defn ok (a &optional (b 2) &key (c 3)) (list a b c))",
+        );
+        assert_eq!(eval(&mut vm, "(ok 1)"), "(1 2 3)");
+        assert_eq!(eval(&mut vm, "(ok 1 5)"), "(1 5 3)");
+        assert_eq!(eval(&mut vm, "(ok 1 5 :c 6)"), "(1 5 6)");
+        let e = compile_err(&mut vm, "(fn (a &key b &rest c) a)");
+        assert!(matches!(e.kind, CompileErrorKind::InvalidArgs));
+        let e = compile_err(&mut vm, "(fn (a &key b b) a)");
+        assert!(matches!(e.kind, CompileErrorKind::InvalidArgs));
+    }
+
+    #[test]
+    fn test_destructure_mismatch() {
+        let mut vm = new_vm();
+        for text in [
+            "((fn ((a b)) a) '(1))",
+            "((fn ((a b)) a) '(1 2 3))",
+            "((fn (#(a b)) a) '(1 2))",
+            "(let (((a b) 5)) a)",
+            "(let ((#(a) #(1 2))) a)",
+        ] {
+            let e = compile_err(&mut vm, text);
+            assert!(matches!(e.kind, CompileErrorKind::Vm(_)), "{}", text);
+            assert!(e.message.contains("does not match"), "{}: {}", text, e);
+        }
+    }
+
+    #[test]
+    fn test_multi_arity() {
+        let mut vm = new_vm();
+        eval(
+            &mut vm,
+            "(def f (fn :arities
+                (() :none)
+                ((a) (list :one a))
+                ((a b) (list :two a b))
+                ((a b &rest more) (list :many a b more))))",
+        );
+        assert_eq!(eval(&mut vm, "(f)"), ":none");
+        assert_eq!(eval(&mut vm, "(f 1)"), "(:one 1)");
+        assert_eq!(eval(&mut vm, "(f 1 2)"), "(:two 1 2)");
+        assert_eq!(eval(&mut vm, "(f 1 2 3 4)"), "(:many 1 2 (3 4))");
+        // Without the marker a list of lists is a fn with destructured args.
+        eval(&mut vm, "(defn get-f () (fn (x) (* x 10)))");
+        assert_eq!(eval(&mut vm, "((fn ((a b) c) ((get-f) a)) '(1 2) 3)"), "10");
+        compile_err(&mut vm, "(fn :arities (x) ((y) y))");
+    }
+
+    #[test]
+    fn test_multi_arity_recur() {
+        let mut vm = new_vm();
+        eval(
+            &mut vm,
+            "(def count-down (fn :arities
+                ((n) (count-down n 0))
+                ((n acc) (if (= n 0) acc (recur (- n 1) (+ acc 1))))))
+             (def sum (fn :arities
+                ((acc) acc)
+                ((acc x &rest xs) (if xs (recur (+ acc x) (car xs) (cdr xs)) (+ acc x)))))",
+        );
+        assert_eq!(eval(&mut vm, "(count-down 5)"), "5");
+        assert_eq!(eval(&mut vm, "(count-down 5 10)"), "15");
+        assert_eq!(eval(&mut vm, "(sum 1)"), "1");
+        assert_eq!(eval(&mut vm, "(sum 1 2 3 4)"), "10");
+    }
+
+    #[test]
+    fn test_multi_arity_too_few_args() {
+        let mut vm = new_vm();
+        eval(
+            &mut vm,
+            "(def f (fn :arities ((a b) :two) ((a b c) :three)))",
+        );
+        compile_err(&mut vm, "(f 1)");
+        let warnings = lint_str(&mut vm, "test", "(defn g () (f 1))").unwrap();
+        assert!(warnings
+            .iter()
+            .any(|w| matches!(w.kind, WarningKind::ArgCount)));
+        let warnings = lint_str(&mut vm, "test", "(defn h () (f 1 2 3))").unwrap();
+        assert!(!warnings
+            .iter()
+            .any(|w| matches!(w.kind, WarningKind::ArgCount)));
+    }
+
+    #[test]
+    fn test_macrolet() {
+        let mut vm = new_vm();
+        assert_eq!(
+            eval(&mut vm, "(macrolet ((sq (x) `(* ,x ,x))) (sq 3))"),
+            "9"
+        );
+        // Only in scope in the macrolet body.
+        compile_err(&mut vm, "(macrolet ((one () 1)) (one)) (one)");
+        assert_eq!(eval(&mut vm, "(def one (fn () :global)) (one)"), ":global");
+        // A local binding hides the local macro.
+        assert_eq!(
+            eval(&mut vm, "(macrolet ((m () 1)) (let ((m (fn () 2))) (m)))"),
+            "2"
+        );
+        // A macro's body can use an outer macrolet's macros.
+        assert_eq!(
+            eval(
+                &mut vm,
+                "(macrolet ((two () 2)) (macrolet ((four () (* (two) 2))) (four)))"
+            ),
+            "4"
+        );
+    }
+
+    #[test]
+    fn test_macroexpand() {
+        let mut vm = new_vm();
+        vm.set_global(
+            "macroexpand-1",
+            Value::Builtin(CallFunc {
+                func: macroexpand_1,
+            }),
+        );
+        vm.set_global(
+            "macroexpand",
+            Value::Builtin(CallFunc { func: macroexpand }),
+        );
+        eval(&mut vm, "(defmacro my-when (&rest r) `(when ,@r))");
+        assert_eq!(
+            eval(&mut vm, "(macroexpand-1 '(my-when a b))"),
+            "(when a b)"
+        );
+        assert_eq!(
+            eval(&mut vm, "(macroexpand '(my-when a b))"),
+            "(if a (do b) nil)"
+        );
+        // Not a macro call, returned as is.
+        assert_eq!(eval(&mut vm, "(macroexpand '(+ 1 2))"), "(+ 1 2)");
+        // A closure macro expands with its captures.
+        eval(&mut vm, "(def add-n (let ((n 10)) (macro (x) `(+ ,x ,n))))");
+        assert_eq!(eval(&mut vm, "(macroexpand-1 '(add-n 1))"), "(+ 1 10)");
+        assert_eq!(eval(&mut vm, "(add-n 1)"), "11");
+    }
+
+    /// Compile each form of text as sl-compiler -o does, without running it.
+    fn compile_file(vm: &mut Vm, text: &str) {
+        let mut reader_state = crate::reader::ReaderState::new();
+        let exps = crate::reader::read_all(vm, &mut reader_state, text).unwrap();
+        for exp in exps {
+            let mut linenum = 1;
+            let mut state = CompileState::new_state(vm, "test", 1, None);
+            state.chunk.dbg_args = Some(Vec::new());
+            state.mode = CompileMode::File;
+            compile_top_level(vm, &mut state, exp, &mut Some(&mut linenum)).unwrap();
+        }
+    }
+
+    #[test]
+    fn test_eval_when_eval_mode() {
+        let mut vm = new_vm();
+        assert_eq!(eval(&mut vm, "(eval-when (:execute) 1 2)"), "2");
+        assert_eq!(eval(&mut vm, "(eval-when (:compile :load) 1)"), "nil");
+        assert_eq!(
+            eval(&mut vm, "(let ((x 1)) (eval-when (:compile :execute) x))"),
+            "1"
+        );
+    }
+
+    #[test]
+    fn test_eval_when_file_mode() {
+        let mut vm = new_vm();
+        eval(&mut vm, "(def runs 0)");
+        compile_file(&mut vm, "(eval-when (:compile) (set! runs (+ runs 1)))");
+        assert_eq!(eval(&mut vm, "runs"), "1");
+        // Only written, not run until the file is loaded.
+        compile_file(&mut vm, "(eval-when (:load) (set! runs (+ runs 10)))");
+        compile_file(&mut vm, "(eval-when (:execute) (set! runs (+ runs 10)))");
+        compile_file(&mut vm, "(set! runs (+ runs 10))");
+        assert_eq!(eval(&mut vm, "runs"), "1");
+        // do and macro expansions keep a form top level.
+        compile_file(
+            &mut vm,
+            "(do 1 (eval-when (:compile) (set! runs (+ runs 1))))",
+        );
+        eval(&mut vm, "(defmacro body (&rest b) `(do ,@b))");
+        compile_file(
+            &mut vm,
+            "(body (eval-when (:compile) (set! runs (+ runs 1))))",
+        );
+        assert_eq!(eval(&mut vm, "runs"), "2");
+        // Inside a let (even one binding nothing) it runs with the code around it.
+        compile_file(
+            &mut vm,
+            "(let () (eval-when (:compile) (set! runs (+ runs 1))))",
+        );
+        assert_eq!(eval(&mut vm, "runs"), "2");
+    }
+
+    #[test]
+    fn test_eval_when_defmacro_file_mode() {
+        let mut vm = new_vm();
+        // The defmacro is not run but its macro is defined when it is compiled.
+        compile_file(
+            &mut vm,
+            "(defmacro twice (x) `(+ ,x ,x)) (def four (twice 2))",
+        );
+        assert_eq!(eval(&mut vm, "(twice 3)"), "6");
     }
 }
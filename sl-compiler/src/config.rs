@@ -8,6 +8,13 @@ pub struct Config {
     pub run: bool,
     pub globals_pre: bool,
     pub globals_post: bool,
+    pub prelude: bool,
+    pub output: Option<String>,
+    pub warnings_error: bool,
+    pub lint: bool,
+    pub peephole: bool,
+    pub ir: bool,
+    pub gc_stress: bool,
 }
 
 pub const VERSION_STRING: &str = env!("VERSION_STRING");
@@ -27,6 +34,24 @@ OPTIONS:
     -d, --dump         Compile and dump the bytecode for the script.
     -g1, --global_pre  Compile and dump the globals before running.
     -g2, --global_post Compile and dump the globals before running.
+    -n, --no-prelude   Do not load the bundled prelude macros (defn, when, cond, etc).
+    -o, --output <file>
+                       Write the compiled bytecode to file (.slc) for slosh to load.
+                       Forms are written, not run.  (eval-when (:compile) ...) bodies
+                       are run but not written, (eval-when (:load) ...) bodies are
+                       written.  defmacro is run and written so its macro works in
+                       later forms, put the fns the macros call in
+                       (eval-when (:compile :load) ...) too.
+    -l, --lint         Also report unused locals and args (unless named _name), locals
+                       shadowing another local or arg and set! of an arg never read after.
+    -W error           Treat warnings as errors, exit with an error (and write no output
+                       file) if compiling the script warned.
+    --ir               Print the IR of each form (after macro expansion) as it is compiled.
+    --no-peephole      Do not run the peephole pass over the compiled bytecode, compare
+                       --dump output with and without it.
+    --gc-stress        Collect garbage after each compiler allocation and before each macro
+                       call (same as SLOSH_GC_STRESS=1), finds heap values the compiler
+                       uses unrooted.
 
 ARGS:
     <args>...      Script to run with arguments."#;
@@ -62,6 +87,13 @@ pub fn get_config() -> Option<Config> {
     let mut dump = false;
     let mut globals_pre = false;
     let mut globals_post = false;
+    let mut prelude = true;
+    let mut output = None;
+    let mut warnings_error = false;
+    let mut lint = false;
+    let mut peephole = true;
+    let mut ir = false;
+    let mut gc_stress = false;
     while !args.is_empty() {
         if let Some(argument) = args.pop() {
             if let Ok(arg) = argument.into_string() {
@@ -76,6 +108,19 @@ pub fn get_config() -> Option<Config> {
                         globals_post = true;
                         run = true;
                     }
+                    "-n" | "--no-prelude" => prelude = false,
+                    "-o" | "--output" => output = Some(get_arg(&exe_name, &mut args)?),
+                    "-l" | "--lint" => lint = true,
+                    "--no-peephole" => peephole = false,
+                    "--ir" => ir = true,
+                    "--gc-stress" => gc_stress = true,
+                    "-W" => match &get_arg(&exe_name, &mut args)?[..] {
+                        "error" => warnings_error = true,
+                        _ => {
+                            help(&exe_name);
+                            return None;
+                        }
+                    },
                     "-v" | "--version" => {
                         version();
                         return None;
@@ -108,6 +153,13 @@ pub fn get_config() -> Option<Config> {
         dump,
         globals_pre,
         globals_post,
+        prelude,
+        output,
+        warnings_error,
+        lint,
+        peephole,
+        ir,
+        gc_stress,
         script: script.unwrap(),
         args: command_args,
     })
@@ -0,0 +1,208 @@
+use std::error::Error;
+use std::fmt;
+
+use slvm::error::*;
+use slvm::value::*;
+use slvm::vm::*;
+use slvm::Handle;
+
+use crate::reader::ReadError;
+
+/// The category of a compile error, tooling can match on this instead of the message text.
+#[derive(Debug)]
+pub enum CompileErrorKind {
+    /// A special form had the wrong shape (missing body, bad binding list, etc).
+    Malformed,
+    /// Wrong number of arguments to a special or compiled form.
+    ArgCount,
+    /// A symbol was required (def, set!, inc!, etc).
+    ExpectedSymbol,
+    /// Invalid fn/macro/let argument list.
+    InvalidArgs,
+    /// Invalid quote, back-quote or unquote usage.
+    InvalidQuote,
+    /// The head of a call is a value that can not be called (a number, string, etc).
+    NotCallable,
+    /// An error raised by the VM while compiling (encoding or running a macro).
+    Vm(VMError),
+    /// An error reading source before compiling it.
+    Read(ReadError),
+    /// An invalid or unsupported compiled (.slc) file.
+    Bytecode,
+}
+
+impl fmt::Display for CompileErrorKind {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            CompileErrorKind::Malformed => write!(f, "malformed form"),
+            CompileErrorKind::ArgCount => write!(f, "wrong number of arguments"),
+            CompileErrorKind::ExpectedSymbol => write!(f, "expected symbol"),
+            CompileErrorKind::InvalidArgs => write!(f, "invalid arguments"),
+            CompileErrorKind::InvalidQuote => write!(f, "invalid quote"),
+            CompileErrorKind::NotCallable => write!(f, "not callable"),
+            CompileErrorKind::Vm(_) => write!(f, "vm error"),
+            CompileErrorKind::Read(_) => write!(f, "read error"),
+            CompileErrorKind::Bytecode => write!(f, "invalid compiled file"),
+        }
+    }
+}
+
+/// An error from the compiler with the source position of the offending form.
+///
+/// Errors are created with just a kind and message, the position and form are filled in by
+/// compile as the error passes back through the innermost form with debug info.
+#[derive(Debug)]
+pub struct CompileError {
+    pub kind: CompileErrorKind,
+    pub message: String,
+    pub file_name: Option<&'static str>,
+    pub line: Option<u32>,
+    pub col: Option<u32>,
+    pub form: Option<String>,
+}
+
+pub type CompileResult<T> = Result<T, CompileError>;
+
+impl CompileError {
+    pub fn new<S: Into<String>>(kind: CompileErrorKind, message: S) -> Self {
+        CompileError {
+            kind,
+            message: message.into(),
+            file_name: None,
+            line: None,
+            col: None,
+            form: None,
+        }
+    }
+
+    pub fn malformed<S: Into<String>>(message: S) -> Self {
+        Self::new(CompileErrorKind::Malformed, message)
+    }
+
+    pub fn arg_count<S: Into<String>>(message: S) -> Self {
+        Self::new(CompileErrorKind::ArgCount, message)
+    }
+
+    pub fn expected_symbol<S: Into<String>>(message: S) -> Self {
+        Self::new(CompileErrorKind::ExpectedSymbol, message)
+    }
+
+    pub fn invalid_args<S: Into<String>>(message: S) -> Self {
+        Self::new(CompileErrorKind::InvalidArgs, message)
+    }
+
+    pub fn invalid_quote<S: Into<String>>(message: S) -> Self {
+        Self::new(CompileErrorKind::InvalidQuote, message)
+    }
+
+    pub fn not_callable<S: Into<String>>(message: S) -> Self {
+        Self::new(CompileErrorKind::NotCallable, message)
+    }
+
+    /// True if this error has a source position attached.
+    pub fn has_position(&self) -> bool {
+        self.line.is_some()
+    }
+
+    /// Attach the position and text of form (from its dbg-line/dbg-col properties) if this
+    /// error does not already have one.  The first (innermost) form to call this wins.
+    pub fn with_form(mut self, vm: &Vm, form: Value) -> Self {
+        if self.has_position() {
+            return self;
+        }
+        if let Some(handle) = form.get_handle() {
+            let (line, col) = form_position(vm, handle);
+            if line.is_none() {
+                return self;
+            }
+            self.line = line;
+            self.col = col;
+            self.form = Some(form.display_value(vm));
+        }
+        self
+    }
+
+    /// Attach the file name if not already set.
+    pub fn with_file(mut self, file_name: &'static str) -> Self {
+        if self.file_name.is_none() {
+            self.file_name = Some(file_name);
+        }
+        self
+    }
+
+    /// Attach a file and line if no position is known yet.  Used at the top level for forms
+    /// that carry no debug info (atoms for instance).
+    pub fn with_line(mut self, file_name: &'static str, line: Option<u32>) -> Self {
+        if self.line.is_none() {
+            self.line = line;
+        }
+        self.with_file(file_name)
+    }
+}
+
+/// Return the (line, column) the reader recorded for the form at handle.
+pub fn form_position(vm: &Vm, handle: Handle) -> (Option<u32>, Option<u32>) {
+    let line = match vm.get_heap_property(handle, "dbg-line") {
+        Some(Value::UInt(line)) => Some(line as u32),
+        _ => None,
+    };
+    let col = match vm.get_heap_property(handle, "dbg-col") {
+        Some(Value::UInt(col)) => Some(col as u32),
+        _ => None,
+    };
+    (line, col)
+}
+
+impl Error for CompileError {}
+
+impl fmt::Display for CompileError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if let Some(file_name) = self.file_name {
+            write!(f, "{}:", file_name)?;
+        }
+        match (self.line, self.col) {
+            (Some(line), Some(col)) => write!(f, "{}:{}: ", line, col)?,
+            (Some(line), None) => write!(f, "{}: ", line)?,
+            _ => {
+                if self.file_name.is_some() {
+                    write!(f, " ")?;
+                }
+            }
+        }
+        write!(f, "{}", self.message)?;
+        if let Some(form) = &self.form {
+            write!(f, " [{}]", form)?;
+        }
+        Ok(())
+    }
+}
+
+impl From<VMError> for CompileError {
+    fn from(err: VMError) -> Self {
+        let message = err.to_string();
+        CompileError::new(CompileErrorKind::Vm(err), message)
+    }
+}
+
+impl From<ReadError> for CompileError {
+    fn from(err: ReadError) -> Self {
+        let message = err.kind.to_string();
+        let line = Some(err.line as u32);
+        let col = Some(err.column as u32);
+        let mut err = CompileError::new(CompileErrorKind::Read(err), message);
+        err.line = line;
+        err.col = col;
+        err
+    }
+}
+
+impl From<CompileError> for VMError {
+    fn from(err: CompileError) -> Self {
+        if !err.has_position() {
+            if let CompileErrorKind::Vm(err) = err.kind {
+                return err;
+            }
+        }
+        VMError::new_compile(err.to_string())
+    }
+}
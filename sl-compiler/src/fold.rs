@@ -0,0 +1,344 @@
+use std::cmp::Ordering;
+
+use slvm::value::*;
+use slvm::vm::*;
+
+use crate::state::*;
+
+#[derive(Copy, Clone, Debug)]
+enum Num {
+    Int(i64),
+    Float(f64),
+}
+
+impl Num {
+    fn from_value(val: Value) -> Option<Num> {
+        match val {
+            Value::Int(i) => Some(Num::Int(i)),
+            Value::Float(_) => val.get_float().ok().map(Num::Float),
+            _ => None,
+        }
+    }
+
+    fn to_value(self) -> Value {
+        match self {
+            Num::Int(i) => Value::Int(i),
+            Num::Float(f) => Value::float(f),
+        }
+    }
+
+    fn as_f64(self) -> f64 {
+        match self {
+            Num::Int(i) => i as f64,
+            Num::Float(f) => f,
+        }
+    }
+
+    fn cmp(self, other: Num) -> Option<Ordering> {
+        match (self, other) {
+            (Num::Int(a), Num::Int(b)) => Some(a.cmp(&b)),
+            _ => self.as_f64().partial_cmp(&other.as_f64()),
+        }
+    }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+enum MathOp {
+    Add,
+    Sub,
+    Mul,
+    Div,
+}
+
+/// Apply op to a and b, None if the result is not exact (overflow, divide by zero, integer
+/// division with a remainder) so the form is left for the VM to handle (or error on) at runtime.
+fn arith(op: MathOp, a: Num, b: Num) -> Option<Num> {
+    match (a, b) {
+        (Num::Int(a), Num::Int(b)) => match op {
+            MathOp::Add => a.checked_add(b),
+            MathOp::Sub => a.checked_sub(b),
+            MathOp::Mul => a.checked_mul(b),
+            MathOp::Div => match a.checked_rem(b) {
+                Some(0) => a.checked_div(b),
+                _ => None,
+            },
+        }
+        .map(Num::Int),
+        _ => {
+            let (a, b) = (a.as_f64(), b.as_f64());
+            match op {
+                MathOp::Add => Some(a + b),
+                MathOp::Sub => Some(a - b),
+                MathOp::Mul => Some(a * b),
+                MathOp::Div if b == 0.0 => None,
+                MathOp::Div => Some(a / b),
+            }
+            .map(Num::Float)
+        }
+    }
+}
+
+/// True if val is a constant that is not nil or false.
+pub fn is_truthy(val: Value) -> bool {
+    !matches!(val, Value::Nil | Value::False)
+}
+
+/// The constant value of exp if it is a literal or a form folded by fold_constants.
+pub fn const_value(state: &CompileState, exp: Value) -> Option<Value> {
+    match exp {
+        Value::Pair(_) => state.folds.get(&exp).copied(),
+        Value::True
+        | Value::False
+        | Value::Nil
+        | Value::Int(_)
+        | Value::Float(_)
+        | Value::Keyword(_)
+        | Value::StringConst(_)
+        | Value::CodePoint(_)
+        | Value::CharCluster(_, _) => Some(exp),
+        _ => None,
+    }
+}
+
+/// Constant folding pass, run after pass1 and before compile.
+///
+/// Forms that evaluate to a constant (literal arithmetic, comparisons, not, eq?, equal? and if
+/// with constant tests) are recorded in state.folds and compile emits the constant in their
+/// place.  Forms that would error (wrong arg count, divide by zero, overflow) are not folded so
+/// they still error the same way.
+pub fn fold_constants(vm: &Vm, state: &mut CompileState, exp: Value) {
+    if let Value::Pair(handle) = exp {
+        let (car, _) = vm.get_pair(handle);
+        if let Value::Symbol(i) = car {
+            // fn forms are folded with their own state, quoted forms are data.
+            if i == state.specials.fn_
+                || i == state.specials.mac_
+                || i == state.specials.quote
+                || i == state.specials.backquote
+            {
+                return;
+            }
+        }
+        for r in exp.iter(vm) {
+            fold_constants(vm, state, r);
+        }
+        if let Some(val) = fold_form(vm, state, car, exp) {
+            state.folds.insert(exp, val);
+        }
+    }
+}
+
+fn fold_form(vm: &Vm, state: &CompileState, car: Value, exp: Value) -> Option<Value> {
+    let op = if let Value::Symbol(i) = car {
+        i
+    } else {
+        return None;
+    };
+    let specials = &state.specials;
+    let args: Vec<Value> = exp.iter(vm).skip(1).collect();
+    if op == specials.if_ {
+        return fold_if(state, &args);
+    }
+    let args = args
+        .iter()
+        .map(|a| const_value(state, *a))
+        .collect::<Option<Vec<Value>>>()?;
+    if op == specials.add {
+        fold_math(MathOp::Add, &args, Some(Num::Int(0)))
+    } else if op == specials.sub {
+        if args.len() == 1 {
+            match Num::from_value(args[0])? {
+                Num::Int(i) => i.checked_neg().map(Value::Int),
+                Num::Float(f) => Some(Value::float(-f)),
+            }
+        } else {
+            fold_math(MathOp::Sub, &args, None)
+        }
+    } else if op == specials.mul {
+        fold_math(MathOp::Mul, &args, Some(Num::Int(1)))
+    } else if op == specials.div {
+        if args.len() < 2 {
+            None
+        } else {
+            fold_math(MathOp::Div, &args, None)
+        }
+    } else if op == specials.numeq {
+        fold_compare(&args, |o| o == Ordering::Equal)
+    } else if op == specials.numneq {
+        // Only the simple case, leave the chained semantics to the VM.
+        if args.len() == 2 {
+            fold_compare(&args, |o| o != Ordering::Equal)
+        } else {
+            None
+        }
+    } else if op == specials.numlt {
+        fold_compare(&args, |o| o == Ordering::Less)
+    } else if op == specials.numlte {
+        fold_compare(&args, |o| o != Ordering::Greater)
+    } else if op == specials.numgt {
+        fold_compare(&args, |o| o == Ordering::Greater)
+    } else if op == specials.numgte {
+        fold_compare(&args, |o| o != Ordering::Less)
+    } else if op == specials.not {
+        if args.len() == 1 {
+            Some(bool_value(!is_truthy(args[0])))
+        } else {
+            None
+        }
+    } else if op == specials.eq || op == specials.equal {
+        if args.len() == 2 {
+            fold_eq(args[0], args[1])
+        } else {
+            None
+        }
+    } else {
+        None
+    }
+}
+
+fn fold_math(op: MathOp, args: &[Value], empty: Option<Num>) -> Option<Value> {
+    let mut args = args.iter();
+    let mut acc = match args.next() {
+        Some(a) => Num::from_value(*a)?,
+        None => return empty.map(Num::to_value),
+    };
+    for a in args {
+        acc = arith(op, acc, Num::from_value(*a)?)?;
+    }
+    Some(acc.to_value())
+}
+
+fn fold_compare(args: &[Value], test: fn(Ordering) -> bool) -> Option<Value> {
+    if args.len() < 2 {
+        return None;
+    }
+    let nums = args
+        .iter()
+        .map(|a| Num::from_value(*a))
+        .collect::<Option<Vec<Num>>>()?;
+    let mut res = true;
+    for pair in nums.windows(2) {
+        res = res && test(pair[0].cmp(pair[1])?);
+    }
+    Some(bool_value(res))
+}
+
+/// eq?/equal? for immediate literals where both agree, floats and strings are left alone.
+fn fold_eq(a: Value, b: Value) -> Option<Value> {
+    let simple = |v: Value| {
+        matches!(
+            v,
+            Value::True
+                | Value::False
+                | Value::Nil
+                | Value::Int(_)
+                | Value::Keyword(_)
+                | Value::CodePoint(_)
+        )
+    };
+    if simple(a) && simple(b) {
+        Some(bool_value(a == b))
+    } else {
+        None
+    }
+}
+
+/// Fold (if test then test then ... else) when the tests up to the taken branch are constant.
+fn fold_if(state: &CompileState, args: &[Value]) -> Option<Value> {
+    if args.is_empty() {
+        return None;
+    }
+    let mut args = args;
+    loop {
+        match args {
+            [] => return Some(Value::Nil),
+            [else_] => return const_value(state, *else_),
+            [test, then, rest @ ..] => {
+                let test = const_value(state, *test)?;
+                if is_truthy(test) {
+                    return const_value(state, *then);
+                } else if rest.is_empty() {
+                    return Some(test);
+                }
+                args = rest;
+            }
+        }
+    }
+}
+
+fn bool_value(b: bool) -> Value {
+    if b {
+        Value::True
+    } else {
+        Value::False
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::reader::*;
+    use crate::test_util::*;
+
+    /// The value text folds to, None if it is not folded.
+    fn folded(vm: &mut Vm, text: &str) -> Option<String> {
+        let mut reader_state = ReaderState::new();
+        let exp = read(vm, &mut reader_state, text, false).unwrap();
+        let mut state = CompileState::new(vm);
+        fold_constants(vm, &mut state, exp);
+        const_value(&state, exp).map(|v| v.display_value(vm))
+    }
+
+    #[test]
+    fn test_fold_math() {
+        let mut vm = Vm::new();
+        assert_eq!(folded(&mut vm, "(* 60 60 24)"), Some("86400".to_string()));
+        assert_eq!(folded(&mut vm, "(+ 1 (* 2 3))"), Some("7".to_string()));
+        assert_eq!(folded(&mut vm, "(- 5)"), Some("-5".to_string()));
+        assert_eq!(folded(&mut vm, "(< 1 2 3)"), Some("true".to_string()));
+        assert_eq!(folded(&mut vm, "(+ 1 x)"), None);
+    }
+
+    #[test]
+    fn test_fold_logic() {
+        let mut vm = Vm::new();
+        assert_eq!(folded(&mut vm, "(not nil)"), Some("true".to_string()));
+        assert_eq!(folded(&mut vm, "(not 1)"), Some("false".to_string()));
+        assert_eq!(folded(&mut vm, "(eq? :a :a)"), Some("true".to_string()));
+        assert_eq!(folded(&mut vm, "(equal? 1 2)"), Some("false".to_string()));
+        // Strings are left to the VM.
+        assert_eq!(folded(&mut vm, "(equal? \"a\" \"a\")"), None);
+    }
+
+    #[test]
+    fn test_fold_if() {
+        let mut vm = Vm::new();
+        assert_eq!(
+            folded(&mut vm, "(if (< 1 2) 10 20)"),
+            Some("10".to_string())
+        );
+        assert_eq!(folded(&mut vm, "(if #f 1 #f 2 3)"), Some("3".to_string()));
+        // The taken branch is not constant.
+        assert_eq!(folded(&mut vm, "(if #t x 1)"), None);
+    }
+
+    #[test]
+    fn test_folds_compiled() {
+        let mut vm = new_vm();
+        assert!(folded(&mut vm, "(* 1.5 2)").is_some());
+        assert_eq!(eval(&mut vm, "(defn day () (* 60 60 24)) (day)"), "86400");
+        // The branch not taken is dropped so the undefined call never runs.
+        assert_eq!(eval(&mut vm, "(if (< 1 2) 10 (no-such-fn))"), "10");
+        assert_eq!(eval(&mut vm, "(let ((x 2)) (+ x (* 3 4)))"), "14");
+    }
+
+    #[test]
+    fn test_errors_not_folded() {
+        let mut vm = new_vm();
+        assert_eq!(folded(&mut vm, "(/ 1 0)"), None);
+        assert_eq!(folded(&mut vm, "(+ 9223372036854775807 1)"), None);
+        // Still an error at runtime.
+        compile_err(&mut vm, "(/ 1 0)");
+    }
+}
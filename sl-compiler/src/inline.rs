@@ -0,0 +1,395 @@
+use std::cell::RefCell;
+use std::rc::Rc;
+
+use slvm::interner::*;
+use slvm::opcodes::*;
+use slvm::value::*;
+use slvm::vm::*;
+
+use crate::compile::*;
+use crate::error::*;
+use crate::namespace::*;
+use crate::state::*;
+use crate::warning::*;
+
+/// Property of an inlinable lambda, the (args body*) source it was compiled from.
+const INLINE_PROP: &str = ":inline";
+/// Property of an inlinable lambda, the namespace its body was compiled in.
+const INLINE_NS_PROP: &str = ":inline-ns";
+/// Property set on an inlinable lambda once a call to it has been inlined.
+const INLINED_PROP: &str = ":inlined";
+/// Most atoms (symbols and literals) the body of an inlinable fn can have.
+const MAX_INLINE_SIZE: usize = 24;
+
+/// The (args body*) of value if it is a (fn args body*) def'ed as global that can be inlined.
+///
+/// Only small fns with fixed args are inlined.  The body can not refer to the global (not
+/// recursive) or use fn, macro, macrolet, recur, this-fn, defer or on-error since those would
+/// mean something different in the caller's frame.  It can not call a macro either, the
+/// expansion could use any of them (and the macro could be changed before a call is inlined).
+pub fn inline_source(
+    vm: &mut Vm,
+    state: &CompileState,
+    global: Interned,
+    name: Interned,
+    value: Value,
+) -> Option<Value> {
+    let (car, source) = if let Value::Pair(h) = value {
+        vm.get_pair(h)
+    } else {
+        return None;
+    };
+    if car != Value::Symbol(state.specials.fn_) {
+        return None;
+    }
+    let (args, body) = if let Value::Pair(h) = source {
+        vm.get_pair(h)
+    } else {
+        return None;
+    };
+    if !matches!(args, Value::Nil | Value::Pair(_)) || !matches!(body, Value::Pair(_)) {
+        return None;
+    }
+    for arg in args.iter(vm) {
+        match arg {
+            Value::Symbol(i) if !vm.get_interned(i).starts_with('&') => {}
+            _ => return None,
+        }
+    }
+    let mut size = 0;
+    if inline_safe(vm, state, body, &[global, name], &mut size) {
+        Some(source)
+    } else {
+        None
+    }
+}
+
+fn inline_safe(
+    vm: &mut Vm,
+    state: &CompileState,
+    exp: Value,
+    names: &[Interned],
+    size: &mut usize,
+) -> bool {
+    match exp {
+        Value::Pair(h) => {
+            let (car, _) = vm.get_pair(h);
+            if is_macro_name(vm, state, car) {
+                return false;
+            }
+            let items: Vec<Value> = exp.iter(vm).collect();
+            items
+                .into_iter()
+                .all(|e| inline_safe(vm, state, e, names, size))
+        }
+        Value::Vector(h) => {
+            let items = vm.get_vector(h).to_vec();
+            items
+                .into_iter()
+                .all(|e| inline_safe(vm, state, e, names, size))
+        }
+        Value::Symbol(i) => {
+            let specials = &state.specials;
+            *size += 1;
+            *size <= MAX_INLINE_SIZE
+                && !names.contains(&i)
+                && i != specials.fn_
+                && i != specials.mac_
+                && i != specials.macrolet
+                && i != specials.recur
+                && i != specials.this_fn
+                && i != specials.defer
+                && i != specials.on_error
+        }
+        _ => {
+            *size += 1;
+            *size <= MAX_INLINE_SIZE
+        }
+    }
+}
+
+/// True if name is a local macro where the fn is defined or a global macro.
+fn is_macro_name(vm: &mut Vm, state: &CompileState, name: Value) -> bool {
+    if let Value::Symbol(i) = name {
+        if state.symbols.borrow().get_macro(i).is_some() {
+            return true;
+        }
+        let i = resolve_global(vm, i);
+        if let Some(slot) = vm.global_intern_slot(i) {
+            return is_macro(vm, vm.get_global(slot));
+        }
+    }
+    false
+}
+
+/// Mark lambda (compiled from source) so calls to it can be inlined.
+pub fn mark_inline(vm: &mut Vm, lambda: Value, source: Value) {
+    if let Value::Lambda(h) = lambda {
+        let ns = current_namespace(vm)
+            .map(Value::Symbol)
+            .unwrap_or(Value::Nil);
+        vm.set_heap_property(h, INLINE_PROP, source);
+        vm.set_heap_property(h, INLINE_NS_PROP, ns);
+    }
+}
+
+/// Warn if global is about to be changed by a def or set! (what) while calls to it can be
+/// inlined, those calls keep the old body whether they are compiled before or after this.  A
+/// def only warns if global already has an inline definition (the first def of a fn declared
+/// inline is how it gets one), a set! also warns if global is declared inline in this file.
+pub fn warn_inline_redefinition(
+    vm: &Vm,
+    state: &CompileState,
+    global: Interned,
+    what: &str,
+    line: Option<u32>,
+) {
+    let has_inline = match vm
+        .global_intern_slot(global)
+        .map(|slot| vm.get_global(slot))
+    {
+        Some(Value::Lambda(h)) => vm.get_heap_property(h, INLINE_PROP).is_some(),
+        _ => false,
+    };
+    let declared = what == "set!" && state.declarations.borrow().inline.contains(&global);
+    if has_inline || declared {
+        state.warn(
+            WarningKind::Inline,
+            format!(
+                "{} of {} which is declared inline, inlined calls to it keep the old definition",
+                what,
+                vm.get_interned(global)
+            ),
+            line,
+        );
+    }
+}
+
+/// Compile a call of lambda with args cdr by compiling its body in place if it was marked by
+/// mark_inline, returns false (and compiles nothing) if the call has to be made.
+///
+/// The args are evaluated in order into registers like a let, then the body is compiled in a
+/// scope that only has the params so the caller's locals and local macros can not change what
+/// its symbols mean.  A call with the wrong number of args is not inlined, it errors at runtime.
+pub fn compile_inline<E: Exp>(
+    vm: &mut Vm,
+    state: &mut CompileState,
+    lambda: Value,
+    cdr: &[E],
+    result: usize,
+    line: &mut Option<&mut u32>,
+) -> CompileResult<bool> {
+    let h = if let Value::Lambda(h) = lambda {
+        h
+    } else {
+        return Ok(false);
+    };
+    let source = if let Some(source @ Value::Pair(_)) = vm.get_heap_property(h, INLINE_PROP) {
+        source
+    } else {
+        return Ok(false);
+    };
+    // Globals in the body resolve in the namespace it was defined in.
+    let ns = current_namespace(vm)
+        .map(Value::Symbol)
+        .unwrap_or(Value::Nil);
+    if vm.get_heap_property(h, INLINE_NS_PROP) != Some(ns) {
+        return Ok(false);
+    }
+    let (args, body) = if let Value::Pair(sh) = source {
+        vm.get_pair(sh)
+    } else {
+        return Ok(false);
+    };
+    let params: Vec<Interned> = args
+        .iter(vm)
+        .filter_map(|a| {
+            if let Value::Symbol(i) = a {
+                Some(i)
+            } else {
+                None
+            }
+        })
+        .collect();
+    if params.len() != cdr.len() {
+        return Ok(false);
+    }
+    let body: Vec<Value> = body.iter(vm).collect();
+    vm.set_heap_property(h, INLINED_PROP, Value::True);
+
+    // Params go in result + 1.., above anything the caller has live.
+    let tail = state.tail;
+    state.tail = false;
+    for (i, arg) in cdr.iter().enumerate() {
+        arg.compile(vm, state, result + 1 + i, line)?;
+    }
+    let symbols = Rc::new(RefCell::new(Symbols::isolated(result)));
+    for p in &params {
+        symbols.borrow_mut().insert(*p);
+        if let Some(dbg_args) = state.chunk.dbg_args.as_mut() {
+            dbg_args.push(*p);
+        }
+    }
+    let old_symbols = std::mem::replace(&mut state.symbols, symbols);
+    let body_reg = result + 1 + params.len();
+    let res = inline_body(vm, state, &body, body_reg, tail);
+    state.tail = tail;
+    state.symbols = old_symbols;
+    res?;
+    let line = line.as_ref().map(|l| **l);
+    state.encode2(MOV, result as u16, body_reg as u16, line)?;
+    Ok(true)
+}
+
+fn inline_body(
+    vm: &mut Vm,
+    state: &mut CompileState,
+    body: &[Value],
+    result: usize,
+    tail: bool,
+) -> CompileResult<()> {
+    let last_thing = body.len() - 1;
+    for (i, exp) in body.iter().enumerate() {
+        state.tail = i == last_thing && tail;
+        // No line, the body's own lines are from the def, errors report the call's line.
+        compile(vm, state, *exp, result, &mut None)?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::prelude::*;
+    use crate::test_util::*;
+
+    /// True if global name is a lambda that had a call to it inlined.
+    fn inlined(vm: &mut Vm, name: &str) -> bool {
+        let global = vm.intern(name);
+        match vm
+            .global_intern_slot(global)
+            .map(|slot| vm.get_global(slot))
+        {
+            Some(Value::Lambda(h)) => vm.get_heap_property(h, INLINED_PROP) == Some(Value::True),
+            _ => false,
+        }
+    }
+
+    fn inline_warnings(vm: &mut Vm, text: &str) -> Vec<String> {
+        lint_str(vm, "test", text)
+            .unwrap()
+            .into_iter()
+            .filter(|w| matches!(w.kind, WarningKind::Inline))
+            .map(|w| w.message)
+            .collect()
+    }
+
+    #[test]
+    fn test_inline_small_fn() {
+        let mut vm = new_vm();
+        let warnings = inline_warnings(
+            &mut vm,
+            "(declare (inline sq)) (defn sq (x) (* x x)) (defn use-sq (y) (sq y))",
+        );
+        assert!(warnings.is_empty(), "{:?}", warnings);
+        assert!(inlined(&mut vm, "sq"));
+        assert_eq!(eval(&mut vm, "(use-sq 3)"), "9");
+    }
+
+    #[test]
+    fn test_inline_skips_recursive() {
+        let mut vm = new_vm();
+        let warnings = inline_warnings(
+            &mut vm,
+            "(declare (inline fact))
+             (defn fact (n) (if (< n 2) 1 (* n (fact (- n 1)))))
+             (defn use-fact () (fact 3))",
+        );
+        assert_eq!(warnings.len(), 1, "{:?}", warnings);
+        assert!(warnings[0].contains("fact is declared inline"));
+        assert!(!inlined(&mut vm, "fact"));
+        assert_eq!(eval(&mut vm, "(use-fact)"), "6");
+    }
+
+    #[test]
+    fn test_inline_skips_capturing() {
+        let mut vm = new_vm();
+        let warnings = inline_warnings(
+            &mut vm,
+            "(declare (inline add-n))
+             (let ((n 1)) (defn add-n (x) (+ x n)))
+             (defn use-add-n (y) (add-n y))",
+        );
+        assert_eq!(warnings.len(), 1, "{:?}", warnings);
+        assert!(warnings[0].contains("add-n is declared inline"));
+        assert!(!inlined(&mut vm, "add-n"));
+        assert_eq!(eval(&mut vm, "(use-add-n 2)"), "3");
+    }
+
+    #[test]
+    fn test_inline_skips_macro_calls() {
+        let mut vm = new_vm();
+        // The expansion defers, inlined it would run at the end of the caller.
+        let warnings = inline_warnings(
+            &mut vm,
+            "(defmacro with-note (x) `(do (defer (set! log :done)) ,x))
+             (def log nil)
+             (declare (inline noted))
+             (defn noted (x) (with-note (+ x 1)))
+             (defn use-noted (y) (noted y) log)",
+        );
+        assert_eq!(warnings.len(), 1, "{:?}", warnings);
+        assert!(warnings[0].contains("noted is declared inline"));
+        assert!(!inlined(&mut vm, "noted"));
+        assert_eq!(eval(&mut vm, "(use-noted 1)"), ":done");
+        // A local macro is a macro call too.
+        let warnings = inline_warnings(
+            &mut vm,
+            "(declare (inline twice))
+             (macrolet ((dbl (x) `(* 2 ,x))) (defn twice (x) (dbl x)))
+             (defn use-twice (y) (twice y))",
+        );
+        assert_eq!(warnings.len(), 1, "{:?}", warnings);
+        assert!(!inlined(&mut vm, "twice"));
+        assert_eq!(eval(&mut vm, "(use-twice 4)"), "8");
+    }
+
+    #[test]
+    fn test_inline_redefinition_warning() {
+        let mut vm = new_vm();
+        let warnings = inline_warnings(
+            &mut vm,
+            "(declare (inline sq))
+             (defn sq (x) (* x x))
+             (defn use-sq (y) (sq y))
+             (defn sq (x) (+ x x))",
+        );
+        assert_eq!(warnings.len(), 1, "{:?}", warnings);
+        assert!(warnings[0].contains("def of sq which is declared inline"));
+        // The inlined call keeps the old body.
+        assert_eq!(eval(&mut vm, "(use-sq 3)"), "9");
+        assert_eq!(eval(&mut vm, "(sq 3)"), "6");
+    }
+
+    #[test]
+    fn test_inline_set_warning() {
+        let mut vm = new_vm();
+        // Compiled before any call is inlined, or before the fn is defined.
+        let warnings = inline_warnings(
+            &mut vm,
+            "(declare (inline cube dbl))
+             (defn cube (x) (* x x x))
+             (defn reset-cube () (set! cube (fn (x) x)))
+             (defn reset-dbl () (set! dbl (fn (x) x)))
+             (defn dbl (x) (+ x x))
+             (defn use-both (y) (+ (cube y) (dbl y)))",
+        );
+        assert_eq!(warnings.len(), 2, "{:?}", warnings);
+        assert!(warnings[0].contains("set! of cube which is declared inline"));
+        assert!(warnings[1].contains("set! of dbl which is declared inline"));
+        // Not declared inline in this file or defined inline, no warning.
+        let warnings = inline_warnings(&mut vm, "(defn plain (x) x) (set! plain (fn (x) 1))");
+        assert!(warnings.is_empty(), "{:?}", warnings);
+    }
+}
@@ -0,0 +1,715 @@
+use std::collections::HashMap;
+
+use slvm::interner::*;
+use slvm::value::*;
+use slvm::vm::*;
+
+use crate::compile::*;
+use crate::error::*;
+use crate::fold::*;
+use crate::namespace::*;
+use crate::roots::*;
+use crate::state::*;
+
+/// Where a node came from in the source, from the reader's dbg-line and dbg-col.
+#[derive(Copy, Clone, Debug, Default, PartialEq)]
+pub struct Span {
+    pub line: Option<u32>,
+    pub col: Option<u32>,
+}
+
+/// A local of an IrFn, an index into its locals.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+pub struct LocalId(pub usize);
+
+#[derive(Clone, Debug)]
+pub struct Local {
+    pub name: Interned,
+    /// True for a fn arg.
+    pub param: bool,
+    /// True if an inner fn closes over this local.
+    pub captured: bool,
+    /// True if this local is set! somewhere.
+    pub set: bool,
+    pub span: Span,
+}
+
+/// A fn (or the top level form) with its locals resolved.
+#[derive(Clone, Debug, Default)]
+pub struct IrFn {
+    pub params: Vec<LocalId>,
+    /// The &rest param.
+    pub rest: Option<LocalId>,
+    pub locals: Vec<Local>,
+    /// Locals of the enclosing fn this fn closes over as (local here, local in the enclosing fn).
+    pub captures: Vec<(LocalId, LocalId)>,
+    pub body: Vec<Node>,
+    pub span: Span,
+}
+
+#[derive(Clone, Debug)]
+pub struct Node {
+    pub kind: NodeKind,
+    pub span: Span,
+    /// The form lowered to this node (the macro call for an expansion), for errors.
+    pub form: Value,
+}
+
+#[derive(Clone, Debug)]
+pub enum NodeKind {
+    /// A literal, quoted data or a form folded to a constant.
+    Const(Value),
+    Local(LocalId),
+    /// A global, resolved in the current namespace.
+    Global(Interned),
+    Def(Interned, Box<Node>),
+    SetLocal(LocalId, Box<Node>),
+    SetGlobal(Interned, Box<Node>),
+    Do(Vec<Node>),
+    /// (if test then test then ... else).
+    If(Vec<Node>),
+    And(Vec<Node>),
+    Or(Vec<Node>),
+    /// let or let* (star), each value is evaluated before its local is bound.
+    Let {
+        star: bool,
+        bindings: Bindings,
+        body: Vec<Node>,
+    },
+    Fn(Box<IrFn>),
+    /// A form the compiler turns into an op (+, car, vec-nth, etc), the special and its args.
+    Prim(Interned, Vec<Node>),
+    Call(Box<Node>, Vec<Node>),
+    CallGlobal(Interned, Vec<Node>),
+    Recur(Vec<Node>),
+    ThisFn(Vec<Node>),
+    /// A form that is not lowered (loops, match, macrolet, destructuring, etc), its locals and
+    /// captures are not resolved.  Codegen compiles it from the source form.
+    Form(Value),
+}
+
+/// The locals of a let and the values they are bound to.
+pub type Bindings = Vec<(LocalId, Node)>;
+
+impl Node {
+    fn new(kind: NodeKind, span: Span, form: Value) -> Self {
+        Node { kind, span, form }
+    }
+
+    /// The nodes directly under this one in evaluation order, the body of a fn is not.
+    pub fn subnodes(&self) -> Vec<&Node> {
+        match &self.kind {
+            NodeKind::Const(_)
+            | NodeKind::Local(_)
+            | NodeKind::Global(_)
+            | NodeKind::Fn(_)
+            | NodeKind::Form(_) => Vec::new(),
+            NodeKind::Def(_, val) | NodeKind::SetLocal(_, val) | NodeKind::SetGlobal(_, val) => {
+                vec![&**val]
+            }
+            NodeKind::Do(nodes)
+            | NodeKind::If(nodes)
+            | NodeKind::And(nodes)
+            | NodeKind::Or(nodes)
+            | NodeKind::Prim(_, nodes)
+            | NodeKind::CallGlobal(_, nodes)
+            | NodeKind::Recur(nodes)
+            | NodeKind::ThisFn(nodes) => nodes.iter().collect(),
+            NodeKind::Let { bindings, body, .. } => bindings
+                .iter()
+                .map(|(_, val)| val)
+                .chain(body.iter())
+                .collect(),
+            NodeKind::Call(callee, args) => std::iter::once(&**callee).chain(args.iter()).collect(),
+        }
+    }
+}
+
+/// Lower the top level form exp to the IR, exp is the body of the returned IrFn.
+///
+/// Macros are expanded once, the expansions are kept in state.expansions so lowering or
+/// compiling exp again with state (or a state sharing them) uses them.  Locals are resolved to
+/// the IrFn that binds them with captures recorded down to the fn that uses them, globals are
+/// resolved in the current namespace (a def reserves its global first so the value can refer to
+/// it) and forms that fold to a constant become constants.  The IR holds heap values (constants,
+/// expansions) that are rooted until release_roots is called with a mark from before lowering.
+pub fn lower(vm: &mut Vm, state: &mut CompileState, exp: Value) -> CompileResult<IrFn> {
+    fold_constants(vm, state, exp);
+    let span = span_of(vm, exp, Span::default());
+    let mut lower = Lower {
+        state,
+        fns: vec![FnScope::new(span)],
+    };
+    let node = lower.exp(vm, exp, span)?;
+    // Unwrap safe, fns always has the top level.
+    let mut ir = lower.fns.pop().unwrap().ir;
+    ir.body.push(node);
+    Ok(ir)
+}
+
+fn span_of(vm: &Vm, exp: Value, parent: Span) -> Span {
+    if let Value::Pair(h) = exp {
+        if let Some(Value::UInt(line)) = vm.get_heap_property(h, "dbg-line") {
+            let col = match vm.get_heap_property(h, "dbg-col") {
+                Some(Value::UInt(col)) => Some(col as u32),
+                _ => None,
+            };
+            return Span {
+                line: Some(line as u32),
+                col,
+            };
+        }
+    }
+    parent
+}
+
+struct FnScope {
+    ir: IrFn,
+    /// The let scopes in this fn, innermost last.
+    scopes: Vec<HashMap<Interned, LocalId>>,
+}
+
+impl FnScope {
+    fn new(span: Span) -> Self {
+        FnScope {
+            ir: IrFn {
+                span,
+                ..IrFn::default()
+            },
+            scopes: vec![HashMap::new()],
+        }
+    }
+
+    fn add_local(&mut self, name: Interned, param: bool, span: Span) -> LocalId {
+        let id = LocalId(self.ir.locals.len());
+        self.ir.locals.push(Local {
+            name,
+            param,
+            captured: false,
+            set: false,
+            span,
+        });
+        id
+    }
+
+    /// Bind name in the innermost scope.
+    fn bind(&mut self, name: Interned, param: bool, span: Span) -> LocalId {
+        let id = self.add_local(name, param, span);
+        // Unwrap safe, a fn always has its own scope.
+        self.scopes.last_mut().unwrap().insert(name, id);
+        id
+    }
+}
+
+struct Lower<'a> {
+    state: &'a mut CompileState,
+    /// The fns being lowered, innermost last.
+    fns: Vec<FnScope>,
+}
+
+impl Lower<'_> {
+    fn current(&mut self) -> &mut FnScope {
+        // Unwrap safe, fns always has the top level.
+        self.fns.last_mut().unwrap()
+    }
+
+    fn lookup(&mut self, name: Interned, span: Span) -> Option<LocalId> {
+        self.lookup_in(self.fns.len() - 1, name, span)
+    }
+
+    /// Find local name visible in fn f, a local of an enclosing fn is captured by each fn
+    /// between it and f.
+    fn lookup_in(&mut self, f: usize, name: Interned, span: Span) -> Option<LocalId> {
+        let scope = &self.fns[f];
+        if let Some(id) = scope.scopes.iter().rev().find_map(|s| s.get(&name)) {
+            return Some(*id);
+        }
+        if let Some((id, _)) = scope
+            .ir
+            .captures
+            .iter()
+            .find(|(id, _)| scope.ir.locals[id.0].name == name)
+        {
+            return Some(*id);
+        }
+        if f == 0 {
+            return None;
+        }
+        let outer = self.lookup_in(f - 1, name, span)?;
+        self.fns[f - 1].ir.locals[outer.0].captured = true;
+        let id = self.fns[f].add_local(name, false, span);
+        self.fns[f].ir.captures.push((id, outer));
+        Some(id)
+    }
+
+    fn exps(&mut self, vm: &mut Vm, exps: &[Value], span: Span) -> CompileResult<Vec<Node>> {
+        exps.iter().map(|e| self.exp(vm, *e, span)).collect()
+    }
+
+    fn exp(&mut self, vm: &mut Vm, exp: Value, parent: Span) -> CompileResult<Node> {
+        let span = span_of(vm, exp, parent);
+        if let Value::Pair(_) = exp {
+            if let Some(val) = const_value(self.state, exp) {
+                return Ok(Node::new(NodeKind::Const(val), span, exp));
+            }
+        }
+        let kind = match exp {
+            Value::Symbol(i) => {
+                if let Some(id) = self.lookup(i, span) {
+                    NodeKind::Local(id)
+                } else {
+                    NodeKind::Global(resolve_global(vm, i))
+                }
+            }
+            Value::Pair(h) => {
+                let (car, cdr) = vm.get_pair(h);
+                let cdr: Vec<Value> = cdr.iter(vm).collect();
+                self.list(vm, exp, car, &cdr, span)?
+            }
+            Value::Vector(h) => {
+                let v: Vec<Value> = vm.get_vector(h).to_vec();
+                if v.is_empty() {
+                    NodeKind::Form(exp)
+                } else {
+                    self.list(vm, exp, v[0], &v[1..], span)?
+                }
+            }
+            _ => NodeKind::Const(exp),
+        };
+        Ok(Node::new(kind, span, exp))
+    }
+
+    fn list(
+        &mut self,
+        vm: &mut Vm,
+        exp: Value,
+        car: Value,
+        cdr: &[Value],
+        span: Span,
+    ) -> CompileResult<NodeKind> {
+        let i = match car {
+            Value::Symbol(i) => i,
+            Value::Pair(_) | Value::Vector(_) | Value::Lambda(_) | Value::Builtin(_) => {
+                let callee = self.exp(vm, car, span)?;
+                let args = self.exps(vm, cdr, span)?;
+                return Ok(NodeKind::Call(Box::new(callee), args));
+            }
+            _ => return Ok(NodeKind::Form(exp)),
+        };
+        let specials = &self.state.specials;
+        if i == specials.quote {
+            if cdr.len() != 1 {
+                return Err(CompileError::arg_count(format!(
+                    "quote takes one argument, got {}",
+                    cdr.len()
+                )));
+            }
+            Ok(NodeKind::Const(cdr[0]))
+        } else if i == specials.fn_ {
+            self.fn_(vm, exp, cdr, span)
+        } else if i == specials.def {
+            match cdr {
+                [Value::Symbol(name), val] => {
+                    let global = def_name(vm, *name);
+                    if self.state.declarations.borrow().inline.contains(&global) {
+                        // Compiled from the source so it can be inlined.
+                        return Ok(NodeKind::Form(exp));
+                    }
+                    vm.reserve_index(global);
+                    Ok(NodeKind::Def(global, Box::new(self.exp(vm, *val, span)?)))
+                }
+                _ => Ok(NodeKind::Form(exp)),
+            }
+        } else if i == specials.set {
+            match cdr {
+                [Value::Symbol(name), val] => {
+                    let val = Box::new(self.exp(vm, *val, span)?);
+                    if let Some(id) = self.lookup(*name, span) {
+                        self.current().ir.locals[id.0].set = true;
+                        Ok(NodeKind::SetLocal(id, val))
+                    } else {
+                        Ok(NodeKind::SetGlobal(resolve_global(vm, *name), val))
+                    }
+                }
+                _ => Ok(NodeKind::Form(exp)),
+            }
+        } else if i == specials.do_ {
+            Ok(NodeKind::Do(self.exps(vm, cdr, span)?))
+        } else if i == specials.if_ {
+            Ok(NodeKind::If(self.exps(vm, cdr, span)?))
+        } else if i == specials.and {
+            Ok(NodeKind::And(self.exps(vm, cdr, span)?))
+        } else if i == specials.or {
+            Ok(NodeKind::Or(self.exps(vm, cdr, span)?))
+        } else if i == specials.let_ || i == specials.letstar {
+            self.let_(vm, exp, cdr, i == specials.letstar, span)
+        } else if i == specials.recur {
+            Ok(NodeKind::Recur(self.exps(vm, cdr, span)?))
+        } else if i == specials.this_fn {
+            Ok(NodeKind::ThisFn(self.exps(vm, cdr, span)?))
+        } else if is_prim(specials, i) {
+            Ok(NodeKind::Prim(i, self.exps(vm, cdr, span)?))
+        } else if is_special(specials, i) {
+            Ok(NodeKind::Form(exp))
+        } else if let Some(id) = self.lookup(i, span) {
+            let args = self.exps(vm, cdr, span)?;
+            Ok(NodeKind::Call(
+                Box::new(Node::new(NodeKind::Local(id), span, car)),
+                args,
+            ))
+        } else {
+            let cached = self.state.expansions.borrow().get(&exp).copied();
+            let (expansion, expanded) = match cached {
+                Some(expansion) => (expansion, true),
+                None => macroexpand_once(vm, exp)?,
+            };
+            if expanded {
+                if cached.is_none() {
+                    root_new(vm, expansion);
+                    self.state.expansions.borrow_mut().insert(exp, expansion);
+                }
+                fold_constants(vm, self.state, expansion);
+                Ok(self.exp(vm, expansion, span)?.kind)
+            } else {
+                let args = self.exps(vm, cdr, span)?;
+                Ok(NodeKind::CallGlobal(resolve_global(vm, i), args))
+            }
+        }
+    }
+
+    /// A fn with fixed args and an optional &rest, other arg lists are left as a Form.
+    fn fn_(
+        &mut self,
+        vm: &mut Vm,
+        exp: Value,
+        cdr: &[Value],
+        span: Span,
+    ) -> CompileResult<NodeKind> {
+        if cdr.len() < 2 || !matches!(cdr[0], Value::Nil | Value::Pair(_)) {
+            return Ok(NodeKind::Form(exp));
+        }
+        let args: Vec<Value> = cdr[0].iter(vm).collect();
+        let mut params = Vec::new();
+        let mut rest = None;
+        let mut args_iter = args.iter();
+        while let Some(arg) = args_iter.next() {
+            match arg {
+                Value::Symbol(i) if *i == self.state.specials.rest => {
+                    match (args_iter.next(), args_iter.next()) {
+                        (Some(Value::Symbol(r)), None) => rest = Some(*r),
+                        _ => return Ok(NodeKind::Form(exp)),
+                    }
+                }
+                Value::Symbol(i) if !vm.get_interned(*i).starts_with('&') => params.push(*i),
+                _ => return Ok(NodeKind::Form(exp)),
+            }
+        }
+        let mut scope = FnScope::new(span);
+        for p in params {
+            let id = scope.bind(p, true, span);
+            scope.ir.params.push(id);
+        }
+        scope.ir.rest = rest.map(|r| scope.bind(r, true, span));
+        // fold_constants leaves a fn's body to be folded with the fn.
+        for exp in &cdr[1..] {
+            fold_constants(vm, self.state, *exp);
+        }
+        self.fns.push(scope);
+        let body = self.exps(vm, &cdr[1..], span);
+        // Unwrap safe, pushed above.
+        let mut ir = self.fns.pop().unwrap().ir;
+        ir.body = body?;
+        Ok(NodeKind::Fn(Box::new(ir)))
+    }
+
+    /// A let with symbol (or (symbol value)) bindings, destructuring is left as a Form.
+    fn let_(
+        &mut self,
+        vm: &mut Vm,
+        exp: Value,
+        cdr: &[Value],
+        star: bool,
+        span: Span,
+    ) -> CompileResult<NodeKind> {
+        if cdr.is_empty() {
+            return Ok(NodeKind::Form(exp));
+        }
+        let mut binds = Vec::new();
+        for b in cdr[0].iter(vm) {
+            let b: Vec<Value> = match b {
+                Value::Symbol(_) => vec![b],
+                Value::Pair(_) => b.iter(vm).collect(),
+                _ => return Ok(NodeKind::Form(exp)),
+            };
+            match b[..] {
+                [Value::Symbol(name)] => binds.push((name, Value::Nil)),
+                [Value::Symbol(name), val] => binds.push((name, val)),
+                _ => return Ok(NodeKind::Form(exp)),
+            }
+        }
+        self.current().scopes.push(HashMap::new());
+        let res = self.let_body(vm, &binds, &cdr[1..], star, span);
+        self.current().scopes.pop();
+        let (bindings, body) = res?;
+        Ok(NodeKind::Let {
+            star,
+            bindings,
+            body,
+        })
+    }
+
+    fn let_body(
+        &mut self,
+        vm: &mut Vm,
+        binds: &[(Interned, Value)],
+        body: &[Value],
+        star: bool,
+        span: Span,
+    ) -> CompileResult<(Bindings, Vec<Node>)> {
+        let mut bindings = Vec::new();
+        let mut names = Vec::new();
+        for (name, val) in binds {
+            let val = self.exp(vm, *val, span)?;
+            let id = self.current().add_local(*name, false, span);
+            if star {
+                // Unwrap safe, let_ pushed a scope.
+                self.current().scopes.last_mut().unwrap().insert(*name, id);
+            } else {
+                names.push((*name, id));
+            }
+            bindings.push((id, val));
+        }
+        for (name, id) in names {
+            self.current().scopes.last_mut().unwrap().insert(name, id);
+        }
+        let body = self.exps(vm, body, span)?;
+        Ok((bindings, body))
+    }
+}
+
+/// True for the specials the compiler turns into ops, their args are evaluated like a call.
+fn is_prim(specials: &Specials, i: Interned) -> bool {
+    [
+        specials.add,
+        specials.sub,
+        specials.mul,
+        specials.div,
+        specials.list,
+        specials.list_append,
+        specials.cons,
+        specials.car,
+        specials.cdr,
+        specials.xar,
+        specials.xdr,
+        specials.vec,
+        specials.make_vec,
+        specials.vec_pop,
+        specials.vec_push,
+        specials.vec_nth,
+        specials.vec_set,
+        specials.numeq,
+        specials.numneq,
+        specials.numlt,
+        specials.numlte,
+        specials.numgt,
+        specials.numgte,
+        specials.eq,
+        specials.equal,
+        specials.type_,
+        specials.not,
+        specials.err,
+        specials.vec_len,
+        specials.vec_clr,
+        specials.str_,
+        specials.call_cc,
+    ]
+    .contains(&i)
+}
+
+/// True for the specials that are not lowered.
+fn is_special(specials: &Specials, i: Interned) -> bool {
+    [
+        specials.mac_,
+        specials.macrolet,
+        specials.while_,
+        specials.loop_,
+        specials.dotimes,
+        specials.dotimes_i,
+        specials.for_each,
+        specials.cond,
+        specials.case,
+        specials.match_,
+        specials.ns,
+        specials.in_ns,
+        specials.import,
+        specials.declare,
+        specials.eval_when,
+        specials.backquote,
+        specials.inc,
+        specials.dec,
+        specials.defer,
+        specials.on_error,
+    ]
+    .contains(&i)
+}
+
+impl IrFn {
+    /// The IR as an s-expression like listing, locals are name#index.
+    pub fn display(&self, vm: &Vm) -> String {
+        let mut out = String::new();
+        self.write(vm, &mut out, 0);
+        out
+    }
+
+    fn local_name(&self, vm: &Vm, id: LocalId) -> String {
+        format!("{}#{}", vm.get_interned(self.locals[id.0].name), id.0)
+    }
+
+    fn write(&self, vm: &Vm, out: &mut String, indent: usize) {
+        out.push_str("(fn (");
+        let params: Vec<String> = self
+            .params
+            .iter()
+            .map(|p| self.local_name(vm, *p))
+            .collect();
+        out.push_str(&params.join(" "));
+        if let Some(rest) = self.rest {
+            if !params.is_empty() {
+                out.push(' ');
+            }
+            out.push_str("&rest ");
+            out.push_str(&self.local_name(vm, rest));
+        }
+        out.push(')');
+        if !self.captures.is_empty() {
+            let captures: Vec<String> = self
+                .captures
+                .iter()
+                .map(|(id, _)| self.local_name(vm, *id))
+                .collect();
+            out.push_str(&format!(" :captures ({})", captures.join(" ")));
+        }
+        for node in &self.body {
+            out.push('\n');
+            out.push_str(&" ".repeat(indent + 2));
+            self.write_node(vm, node, out, indent + 2);
+        }
+        out.push(')');
+    }
+
+    fn write_nodes(&self, vm: &Vm, head: &str, nodes: &[Node], out: &mut String, indent: usize) {
+        out.push('(');
+        out.push_str(head);
+        for node in nodes {
+            out.push(' ');
+            self.write_node(vm, node, out, indent);
+        }
+        out.push(')');
+    }
+
+    fn write_node(&self, vm: &Vm, node: &Node, out: &mut String, indent: usize) {
+        match &node.kind {
+            NodeKind::Const(val) => {
+                out.push_str(&format!("(const {})", val.display_value(vm)));
+            }
+            NodeKind::Local(id) => out.push_str(&self.local_name(vm, *id)),
+            NodeKind::Global(g) => out.push_str(&format!("(global {})", vm.get_interned(*g))),
+            NodeKind::Def(g, val) => {
+                out.push_str(&format!("(def {} ", vm.get_interned(*g)));
+                self.write_node(vm, val, out, indent);
+                out.push(')');
+            }
+            NodeKind::SetLocal(id, val) => {
+                out.push_str(&format!("(set! {} ", self.local_name(vm, *id)));
+                self.write_node(vm, val, out, indent);
+                out.push(')');
+            }
+            NodeKind::SetGlobal(g, val) => {
+                out.push_str(&format!("(set! (global {}) ", vm.get_interned(*g)));
+                self.write_node(vm, val, out, indent);
+                out.push(')');
+            }
+            NodeKind::Do(nodes) => self.write_nodes(vm, "do", nodes, out, indent),
+            NodeKind::If(nodes) => self.write_nodes(vm, "if", nodes, out, indent),
+            NodeKind::And(nodes) => self.write_nodes(vm, "and", nodes, out, indent),
+            NodeKind::Or(nodes) => self.write_nodes(vm, "or", nodes, out, indent),
+            NodeKind::Let {
+                star,
+                bindings,
+                body,
+            } => {
+                out.push_str(if *star { "(let* (" } else { "(let (" });
+                for (i, (id, val)) in bindings.iter().enumerate() {
+                    if i > 0 {
+                        out.push(' ');
+                    }
+                    out.push_str(&format!("({} ", self.local_name(vm, *id)));
+                    self.write_node(vm, val, out, indent);
+                    out.push(')');
+                }
+                out.push(')');
+                for node in body {
+                    out.push(' ');
+                    self.write_node(vm, node, out, indent);
+                }
+                out.push(')');
+            }
+            NodeKind::Fn(ir) => ir.write(vm, out, indent),
+            NodeKind::Prim(op, args) => {
+                let head = format!("prim {}", vm.get_interned(*op));
+                self.write_nodes(vm, &head, args, out, indent);
+            }
+            NodeKind::Call(callee, args) => {
+                out.push_str("(call ");
+                self.write_node(vm, callee, out, indent);
+                for node in args {
+                    out.push(' ');
+                    self.write_node(vm, node, out, indent);
+                }
+                out.push(')');
+            }
+            NodeKind::CallGlobal(g, args) => {
+                let head = format!("callg {}", vm.get_interned(*g));
+                self.write_nodes(vm, &head, args, out, indent);
+            }
+            NodeKind::Recur(args) => self.write_nodes(vm, "recur", args, out, indent),
+            NodeKind::ThisFn(args) => self.write_nodes(vm, "this-fn", args, out, indent),
+            NodeKind::Form(form) => {
+                out.push_str(&format!("(form {})", form.display_value(vm)));
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::sync::Arc;
+
+    use crate::reader::*;
+    use crate::test_util::*;
+
+    #[test]
+    fn test_lower_expands_once() {
+        let mut vm = new_vm();
+        eval(
+            &mut vm,
+            "(def expansions 0)
+             (defmacro twice (x) (set! expansions (+ expansions 1)) `(+ ,x ,x))",
+        );
+        let mut reader_state = ReaderState::new();
+        let exp =
+            read_all(&mut vm, &mut reader_state, "(defn use-twice (y) (twice y))").unwrap()[0];
+        let mut state = CompileState::new_state(&mut vm, "test", 1, None);
+        let ir = lower(&mut vm, &mut state, exp).unwrap();
+        assert!(!ir.display(&vm).contains("twice y"));
+        assert_eq!(eval(&mut vm, "expansions"), "1");
+        // Compiling with the state lower used compiles its expansions.
+        let mut linenum = 1;
+        compile_top_level(&mut vm, &mut state, exp, &mut Some(&mut linenum)).unwrap();
+        vm.execute(Arc::new(state.chunk)).unwrap();
+        assert_eq!(eval(&mut vm, "(use-twice 3)"), "6");
+        assert_eq!(eval(&mut vm, "expansions"), "1");
+    }
+}
@@ -1,3 +1,9 @@
+pub mod error;
+pub use crate::error::*;
+
+pub mod warning;
+pub use crate::warning::*;
+
 pub mod reader;
 pub use crate::reader::*;
 
@@ -7,8 +13,41 @@ pub use crate::state::*;
 pub mod config;
 pub use crate::config::*;
 
+pub mod namespace;
+pub use crate::namespace::*;
+
+pub mod roots;
+pub use crate::roots::*;
+
+pub mod fold;
+pub use crate::fold::*;
+
+pub mod inline;
+pub use crate::inline::*;
+
 pub mod backquote;
 pub use crate::backquote::*;
 
 pub mod compile;
 pub use crate::compile::*;
+
+pub mod ir;
+pub use crate::ir::*;
+
+pub mod codegen;
+pub use crate::codegen::*;
+
+pub mod regalloc;
+pub use crate::regalloc::*;
+
+pub mod peephole;
+pub use crate::peephole::*;
+
+pub mod prelude;
+pub use crate::prelude::*;
+
+pub mod serialize;
+pub use crate::serialize::*;
+
+#[cfg(test)]
+mod test_util;
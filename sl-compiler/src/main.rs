@@ -1,14 +1,19 @@
 use std::sync::Arc;
 
 use slvm::error::*;
-use slvm::opcodes::*;
 use slvm::value::*;
 use slvm::vm::*;
 
+use sl_compiler::backquote::*;
 use sl_compiler::compile::*;
 use sl_compiler::config::*;
+use sl_compiler::ir::*;
+use sl_compiler::prelude::*;
 use sl_compiler::reader::*;
+use sl_compiler::roots::*;
+use sl_compiler::serialize::*;
 use sl_compiler::state::*;
+use sl_compiler::warning::*;
 
 fn line_num(line: &Option<&mut u32>) -> u32 {
     match line {
@@ -47,11 +52,16 @@ fn eval(vm: &mut Vm, registers: &[Value]) -> VMResult<Value> {
         let mut line = Some(&mut linenum);
         let mut state = CompileState::new_state(vm, "none", line_num(&line), None);
         state.chunk.dbg_args = Some(Vec::new());
-        pass1(vm, &mut state, *exp).unwrap();
-        compile(vm, &mut state, *exp, 0, &mut line).unwrap();
-        state.chunk.encode0(RET, own_line(&line)).unwrap();
-        let chunk = Arc::new(state.chunk.clone());
-        Ok(vm.do_call(chunk, &[Value::Nil], None)?)
+        let mark = roots_mark();
+        let res = compile_top_level(vm, &mut state, *exp, &mut line)
+            .map_err(VMError::from)
+            .and_then(|_| {
+                print_warnings(vm, &state.warnings);
+                let chunk = Arc::new(state.chunk.clone());
+                vm.do_call(chunk, &[Value::Nil], None)
+            });
+        release_roots(vm, mark);
+        res
     } else {
         Err(VMError::new_compile("boo"))
     }
@@ -63,18 +73,54 @@ fn main() {
     } else {
         return;
     };
+    if config.gc_stress {
+        set_gc_stress(true);
+    }
     let mut vm = Vm::new();
     vm.set_global("pr", Value::Builtin(CallFunc { func: pr }));
     vm.set_global("prn", Value::Builtin(CallFunc { func: prn }));
     vm.set_global("eval", Value::Builtin(CallFunc { func: eval }));
+    vm.set_global(
+        "macroexpand-1",
+        Value::Builtin(CallFunc {
+            func: macroexpand_1,
+        }),
+    );
+    vm.set_global(
+        "macroexpand",
+        Value::Builtin(CallFunc { func: macroexpand }),
+    );
+    vm.set_global("gensym", Value::Builtin(CallFunc { func: gensym }));
+    vm.set_global("case-index", Value::Builtin(CallFunc { func: case_index }));
+    if config.prelude {
+        if let Err(e) = load_prelude(&mut vm) {
+            eprintln!("{}", e);
+            return;
+        }
+    }
     let mut reader_state = ReaderState::new();
     //let mut state = CompileState::new();
     let txt = std::fs::read_to_string(&config.script).unwrap();
     let exps = read_all(&mut vm, &mut reader_state, &txt).unwrap();
+    // Running a form can GC, keep the forms not compiled yet alive.  The last is rooted first
+    // so each form's root (and what compiling it rooted) is released once it is done.
+    let mut marks = Vec::new();
+    for exp in exps.iter().rev() {
+        marks.push(roots_mark());
+        root(&mut vm, *exp);
+    }
+    marks.reverse();
     let mut linenum = 1;
     let mut line = Some(&mut linenum);
     let file_i = vm.intern(&config.script);
-    for exp in exps {
+    let mut writer = config.output.as_ref().map(|_| SlcWriter::new());
+    let warnings = if config.lint {
+        Warnings::with_lints()
+    } else {
+        Warnings::new_ref()
+    };
+    let declarations = Declarations::new_ref();
+    for (exp, mark) in exps.into_iter().zip(marks) {
         if let Value::Pair(h) = exp {
             let (_, _) = vm.get_pair(h);
             if let (Some(line), Some(Value::UInt(dline))) =
@@ -86,20 +132,59 @@ fn main() {
         let file_name = vm.get_interned(file_i);
         let mut state = CompileState::new_state(&mut vm, file_name, line_num(&line), None);
         state.chunk.dbg_args = Some(Vec::new());
-        pass1(&mut vm, &mut state, exp).unwrap();
-        compile(&mut vm, &mut state, exp, 0, &mut line).unwrap();
-        state.chunk.encode0(RET, own_line(&line)).unwrap();
+        state.warnings = warnings.clone();
+        state.declarations = declarations.clone();
+        state.peephole = config.peephole;
+        if writer.is_some() {
+            state.mode = CompileMode::File;
+        }
+        if config.ir {
+            match lower(&mut vm, &mut state, exp) {
+                Ok(ir) => println!("{}", ir.display(&vm)),
+                Err(e) => {
+                    eprintln!("{}", e.with_line(file_name, own_line(&line)));
+                    return;
+                }
+            }
+        }
+        if let Err(e) = compile_top_level(&mut vm, &mut state, exp, &mut line) {
+            eprintln!("{}", e.with_line(file_name, own_line(&line)));
+            return;
+        }
         if config.dump {
+            println!(
+                "Frame: {} args, {} optional, {} more registers",
+                state.chunk.args, state.chunk.opt_args, state.chunk.extra_regs
+            );
             state.chunk.disassemble_chunk(&vm, 0).unwrap();
         }
-        if config.run {
-            let chunk = Arc::new(state.chunk.clone());
+        let chunk = Arc::new(state.chunk.clone());
+        if let Some(writer) = writer.as_mut() {
+            if let Err(e) = writer.add_top_level(&vm, &chunk, &state) {
+                eprintln!("{}", e.with_line(file_name, own_line(&line)));
+                return;
+            }
+        }
+        // A form written to a .slc runs when it is loaded, what the compiler needs now is in
+        // (eval-when (:compile) ...) (defmacro is).
+        if config.run && writer.is_none() {
             if let Err(err) = vm.execute(chunk) {
                 println!("ERROR: {}", err);
                 vm.dump_globals();
                 //state.chunk.disassemble_chunk(&vm).unwrap();
             }
         }
+        release_roots(&mut vm, mark);
+    }
+    if print_warnings(&vm, &warnings) > 0 && config.warnings_error {
+        eprintln!("Warnings treated as errors (-W error).");
+        std::process::exit(1);
+    }
+    if let (Some(output), Some(writer)) = (&config.output, writer) {
+        if let Err(err) = std::fs::write(output, writer.to_bytes()) {
+            eprintln!("Error writing {}: {}", output, err);
+            return;
+        }
     }
     //state.chunk.encode0(RET, line).unwrap();
     //println!("Compile: {}", txt);
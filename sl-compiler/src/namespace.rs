@@ -0,0 +1,201 @@
+use slvm::interner::*;
+use slvm::value::*;
+use slvm::vm::*;
+
+use crate::error::*;
+use crate::roots::*;
+
+/// Separates a namespace from a name in a qualified symbol (ns::name).
+pub const NS_SEPARATOR: &str = "::";
+
+/// Global holding the current namespace symbol (nil for the root namespace).
+const CURRENT_NS: &str = "*ns*";
+/// Global (qualified with the namespace) holding the list of namespaces it imports.
+const IMPORTS: &str = "*imports*";
+
+fn global_value(vm: &Vm, sym: Interned) -> Value {
+    if let Some(slot) = vm.global_intern_slot(sym) {
+        vm.get_global(slot)
+    } else {
+        Value::Undefined
+    }
+}
+
+fn set_global_value(vm: &mut Vm, sym: Interned, val: Value) {
+    let name = vm.get_interned(sym);
+    vm.set_global(name, val);
+}
+
+fn qualify(vm: &mut Vm, ns: Interned, name: &str) -> Interned {
+    let qualified = format!("{}{}{}", vm.get_interned(ns), NS_SEPARATOR, name);
+    vm.intern(&qualified)
+}
+
+fn imports_sym(vm: &mut Vm, ns: Option<Interned>) -> Interned {
+    match ns {
+        Some(ns) => qualify(vm, ns, IMPORTS),
+        None => vm.intern_static(IMPORTS),
+    }
+}
+
+/// True if sym is already qualified with a namespace (ns::name).
+pub fn is_qualified(vm: &Vm, sym: Interned) -> bool {
+    vm.get_interned(sym).contains(NS_SEPARATOR)
+}
+
+/// The current namespace, None for the root namespace.
+pub fn current_namespace(vm: &mut Vm) -> Option<Interned> {
+    let current = vm.intern_static(CURRENT_NS);
+    match global_value(vm, current) {
+        Value::Symbol(ns) => Some(ns),
+        _ => None,
+    }
+}
+
+/// Switch to namespace ns (None for the root namespace).
+pub fn set_namespace(vm: &mut Vm, ns: Option<Interned>) {
+    let current = vm.intern_static(CURRENT_NS);
+    set_global_value(vm, current, ns.map(Value::Symbol).unwrap_or(Value::Nil));
+}
+
+/// Create (or reset the imports of) namespace ns and make it current.
+pub fn new_namespace(vm: &mut Vm, ns: Interned) {
+    let imports = imports_sym(vm, Some(ns));
+    set_global_value(vm, imports, Value::Nil);
+    set_namespace(vm, Some(ns));
+}
+
+/// The namespaces imported into ns in the order they were imported.
+pub fn namespace_imports(vm: &mut Vm, ns: Option<Interned>) -> Vec<Interned> {
+    let imports = imports_sym(vm, ns);
+    let imports = global_value(vm, imports);
+    imports
+        .iter(vm)
+        .filter_map(|i| {
+            if let Value::Symbol(i) = i {
+                Some(i)
+            } else {
+                None
+            }
+        })
+        .collect()
+}
+
+/// Add import to the imports of the current namespace.
+pub fn import_namespace(vm: &mut Vm, import: Interned) {
+    let ns = current_namespace(vm);
+    let mut imports = namespace_imports(vm, ns);
+    if imports.contains(&import) {
+        return;
+    }
+    imports.push(import);
+    let imports_sym = imports_sym(vm, ns);
+    // The list is rooted as it is built, the global references it once set.
+    let mark = roots_mark();
+    let mut list = Value::Nil;
+    for i in imports.iter().rev() {
+        list = alloc_pair_rooted(vm, Value::Symbol(*i), list);
+    }
+    set_global_value(vm, imports_sym, list);
+    release_roots(vm, mark);
+}
+
+/// The global a def of sym in the current namespace creates.
+pub fn def_name(vm: &mut Vm, sym: Interned) -> Interned {
+    if is_qualified(vm, sym) {
+        return sym;
+    }
+    match current_namespace(vm) {
+        Some(ns) => {
+            let name = vm.get_interned(sym);
+            qualify(vm, ns, name)
+        }
+        None => sym,
+    }
+}
+
+/// Resolve a reference to the global sym.  Qualified symbols are used as is, otherwise look in
+/// the current namespace (anything def'ed or referenced there so far, so a fn can call itself),
+/// then each imported namespace and the root namespace for a defined global.  A name not
+/// defined anywhere yet is a global of the current namespace, a def later in it defines it.
+pub fn resolve_global(vm: &mut Vm, sym: Interned) -> Interned {
+    if is_qualified(vm, sym) {
+        return sym;
+    }
+    let ns = if let Some(ns) = current_namespace(vm) {
+        ns
+    } else {
+        return sym;
+    };
+    let name = vm.get_interned(sym);
+    if let Some(local) = find_qualified(vm, ns, name) {
+        if vm.global_intern_slot(local).is_some() {
+            return local;
+        }
+    }
+    for import in namespace_imports(vm, Some(ns)) {
+        if let Some(imported) = find_qualified(vm, import, name) {
+            if is_defined(vm, imported) {
+                return imported;
+            }
+        }
+    }
+    if is_defined(vm, sym) {
+        return sym;
+    }
+    qualify(vm, ns, name)
+}
+
+/// ns::name if it is interned, unlike qualify this does not intern a name that is looked up.
+fn find_qualified(vm: &Vm, ns: Interned, name: &str) -> Option<Interned> {
+    let qualified = format!("{}{}{}", vm.get_interned(ns), NS_SEPARATOR, name);
+    vm.get_if_interned(&qualified)
+}
+
+fn is_defined(vm: &Vm, sym: Interned) -> bool {
+    !matches!(global_value(vm, sym), Value::Undefined)
+}
+
+/// The namespace symbol argument of ns, in-ns or import.
+pub fn namespace_arg(form: &str, arg: Value) -> CompileResult<Interned> {
+    if let Value::Symbol(ns) = arg {
+        Ok(ns)
+    } else {
+        Err(CompileError::expected_symbol(format!(
+            "{}: namespace must be a symbol",
+            form
+        )))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::test_util::*;
+
+    #[test]
+    fn test_forward_reference() {
+        let mut vm = new_vm();
+        assert_eq!(
+            eval(&mut vm, "(ns foo) (defn a () (b)) (defn b () 1) (a)"),
+            "1"
+        );
+        assert_eq!(eval(&mut vm, "(in-ns nil) (foo::a)"), "1");
+        // The root b was never defined.
+        let b = vm.intern("b");
+        assert!(!is_defined(&vm, b));
+    }
+
+    #[test]
+    fn test_imports_and_root() {
+        let mut vm = new_vm();
+        eval(&mut vm, "(def y 1) (def z 2) (ns lib) (def x 5) (def z 3)");
+        assert_eq!(
+            eval(&mut vm, "(ns app) (import lib) (list x y z)"),
+            "(5 1 3)"
+        );
+        // Looking y up in lib did not intern lib::y.
+        assert!(vm.get_if_interned("lib::y").is_none());
+    }
+}
@@ -0,0 +1,340 @@
+use std::collections::HashMap;
+
+use slvm::chunk::*;
+use slvm::error::*;
+use slvm::opcodes::*;
+
+use crate::state::*;
+
+/// Longest chain of jumps to jumps that is threaded, stops a loop of JMPs from hanging us.
+const MAX_HOPS: usize = 16;
+
+/// Peephole pass over the finished chunk of state, run once all its code is emitted.
+///
+/// Works on the instruction log the state records as code is encoded (the chunk bytes can not
+/// be decoded here).  Jumps to an unconditional JMP go straight to its target, a JMP to the
+/// next instruction, MOV of a register to itself and a constant load (CONST, REGN, REGT, etc)
+/// into a register that already holds that constant in the same basic block are removed.  The
+/// chunk is then rebuilt with the jumps and global refs moved to the new offsets.  If the log
+/// does not cover the code (something was encoded around it) the chunk is left as is.  Either
+/// way state.jumps is left with the jumps in the finished chunk.
+pub fn peephole(state: &mut CompileState) -> VMResult<()> {
+    let instrs = state.take_instrs();
+    state.jumps = instrs.iter().filter_map(|instr| instr.jump).collect();
+    if !state.peephole || !covers_code(&instrs, state.chunk.code.len()) {
+        return Ok(());
+    }
+    let code_len = state.chunk.code.len();
+    let by_pos: HashMap<usize, usize> = instrs
+        .iter()
+        .enumerate()
+        .map(|(i, instr)| (instr.pos, i))
+        .collect();
+    // The target of each jump as an index into instrs, instrs.len() is the end of the code.
+    let mut targets = vec![None; instrs.len()];
+    for (i, instr) in instrs.iter().enumerate() {
+        if let Some(jump) = instr.jump {
+            let target = jump.start_ip as i64 + jump.rel as i64;
+            let target = if target == code_len as i64 {
+                Some(instrs.len())
+            } else if target >= 0 {
+                by_pos.get(&(target as usize)).copied()
+            } else {
+                None
+            };
+            match target {
+                Some(target) => targets[i] = Some(target),
+                // Not to the start of an instruction, leave this chunk alone.
+                None => return Ok(()),
+            }
+        }
+    }
+    let leaders = leaders(&instrs, &targets);
+    thread_jumps(&instrs, &mut targets);
+    let mut keep = vec![true; instrs.len()];
+    remove_redundant_loads(&instrs, &leaders, &mut keep);
+    for (i, instr) in instrs.iter().enumerate() {
+        if instr.op == Some(MOV) && instr.args[0] == instr.args[1] {
+            keep[i] = false;
+        }
+    }
+    remove_jumps_to_next(&instrs, &targets, &mut keep);
+    rebuild(state, &instrs, &targets, &keep)
+}
+
+/// True if instrs are the code from 0 to code_len with no gaps.
+fn covers_code(instrs: &[Instr], code_len: usize) -> bool {
+    let mut pos = 0;
+    for instr in instrs {
+        if instr.pos != pos || instr.end <= instr.pos {
+            return false;
+        }
+        pos = instr.end;
+    }
+    pos == code_len
+}
+
+/// The instructions that start a basic block, the first one and each jump target.
+fn leaders(instrs: &[Instr], targets: &[Option<usize>]) -> Vec<bool> {
+    let mut leaders = vec![false; instrs.len() + 1];
+    leaders[0] = true;
+    for target in targets.iter().flatten() {
+        leaders[*target] = true;
+    }
+    leaders
+}
+
+/// Point jumps whose target is an unconditional JMP at that jump's target.
+fn thread_jumps(instrs: &[Instr], targets: &mut [Option<usize>]) {
+    for i in 0..instrs.len() {
+        let mut hops = 0;
+        while let Some(target) = targets[i] {
+            if hops == MAX_HOPS || target == instrs.len() || instrs[target].op != Some(JMP) {
+                break;
+            }
+            match targets[target] {
+                Some(next) if next != target => targets[i] = Some(next),
+                _ => break,
+            }
+            hops += 1;
+        }
+    }
+}
+
+/// Remove loads of a constant into a register that already holds it.  Only the loads and MOV
+/// are tracked, any other instruction (or the start of a basic block) forgets what is known.
+fn remove_redundant_loads(instrs: &[Instr], leaders: &[bool], keep: &mut [bool]) {
+    let mut loaded: HashMap<u16, (u8, u16)> = HashMap::new();
+    for (i, instr) in instrs.iter().enumerate() {
+        if leaders[i] {
+            loaded.clear();
+        }
+        let load = match (instr.op, instr.args.as_slice()) {
+            (Some(op), [dest, val]) if op == CONST || op == REGB || op == REGI => {
+                Some((*dest, (op, *val)))
+            }
+            (Some(op), [dest]) if op == REGN || op == REGT || op == REGF || op == REGC => {
+                Some((*dest, (op, 0)))
+            }
+            (Some(op), [dest, src]) if op == MOV => {
+                match loaded.get(src).copied() {
+                    Some(val) => {
+                        loaded.insert(*dest, val);
+                    }
+                    None => {
+                        loaded.remove(dest);
+                    }
+                }
+                None
+            }
+            _ => {
+                loaded.clear();
+                None
+            }
+        };
+        if let Some((dest, val)) = load {
+            if loaded.get(&dest) == Some(&val) {
+                keep[i] = false;
+            } else {
+                loaded.insert(dest, val);
+            }
+        }
+    }
+}
+
+/// The first kept instruction at or after i (instrs.len() for the end of the code).
+fn next_kept(keep: &[bool], i: usize) -> usize {
+    (i..keep.len()).find(|j| keep[*j]).unwrap_or(keep.len())
+}
+
+/// Remove each JMP that would land on the instruction after it, removing one can make the jump
+/// before it land on its next instruction so repeat until nothing changes.
+fn remove_jumps_to_next(instrs: &[Instr], targets: &[Option<usize>], keep: &mut [bool]) {
+    let mut changed = true;
+    while changed {
+        changed = false;
+        for (i, instr) in instrs.iter().enumerate() {
+            if !keep[i] || instr.op != Some(JMP) {
+                continue;
+            }
+            if let Some(target) = targets[i] {
+                if next_kept(keep, target) == next_kept(keep, i + 1) {
+                    keep[i] = false;
+                    changed = true;
+                }
+            }
+        }
+    }
+}
+
+/// Replace the chunk of state with one holding only the kept instructions.
+fn rebuild(
+    state: &mut CompileState,
+    instrs: &[Instr],
+    targets: &[Option<usize>],
+    keep: &[bool],
+) -> VMResult<()> {
+    // The new offset of each instruction, a removed one maps to the one after it.
+    let mut new_pos = Vec::with_capacity(instrs.len() + 1);
+    let mut pos = 0;
+    for (instr, keep) in instrs.iter().zip(keep) {
+        new_pos.push(pos);
+        if *keep {
+            pos += instr.end - instr.pos;
+        }
+    }
+    new_pos.push(pos);
+
+    let file_name = state.chunk.file_name;
+    let first_line = state.chunk.offset_to_line(0).unwrap_or(1) as u32;
+    let old = std::mem::replace(&mut state.chunk, Chunk::new(file_name, first_line));
+    let chunk = &mut state.chunk;
+    chunk.args = old.args;
+    chunk.opt_args = old.opt_args;
+    chunk.rest = old.rest;
+    chunk.input_regs = old.input_regs;
+    chunk.extra_regs = old.extra_regs;
+    chunk.captures = old.captures.clone();
+    chunk.dbg_args = old.dbg_args.clone();
+    for c in &old.constants {
+        chunk.add_constant(*c);
+    }
+    state.jumps.clear();
+    for (i, instr) in instrs.iter().enumerate() {
+        if !keep[i] {
+            continue;
+        }
+        // Replaying the bytes through encode0 lets the chunk build its own line table.
+        for offset in instr.pos..instr.end {
+            let line = old.offset_to_line(offset).map(|l| l as u32);
+            chunk.encode0(old.code[offset], line)?;
+        }
+        if let (Some(jump), Some(target)) = (instr.jump, targets[i]) {
+            let offset_pos = new_pos[i] + (jump.offset_pos - instr.pos);
+            let start_ip = new_pos[i] + (jump.start_ip - instr.pos);
+            let rel = new_pos[target] as i32 - start_ip as i32;
+            chunk.reencode_jump_offset(offset_pos, rel)?;
+            state.jumps.push(JumpOperand {
+                offset_pos,
+                start_ip,
+                rel,
+            });
+        }
+    }
+    let by_pos: HashMap<usize, usize> = instrs
+        .iter()
+        .enumerate()
+        .map(|(i, instr)| (instr.pos, i))
+        .collect();
+    for gref in state.global_refs.iter_mut() {
+        if let Some(i) = by_pos.get(&gref.start) {
+            let len = gref.end - gref.start;
+            gref.start = new_pos[*i];
+            gref.end = gref.start + len;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use slvm::value::*;
+    use slvm::vm::*;
+
+    use crate::test_util::*;
+
+    /// A logged instruction of len bytes at pos, a jump is given a placeholder operand.
+    fn instr(pos: usize, len: usize, op: u8, jump: bool) -> Instr {
+        Instr {
+            pos,
+            end: pos + len,
+            op: Some(op),
+            args: Vec::new(),
+            jump: if jump {
+                Some(JumpOperand {
+                    offset_pos: pos + 1,
+                    start_ip: pos + len,
+                    rel: 0,
+                })
+            } else {
+                None
+            },
+        }
+    }
+
+    #[test]
+    fn test_thread_jumps() {
+        // 0: JMP 2, 1: REGT, 2: JMP 3 (a jump to a jump), 3: JMP end.
+        let instrs = vec![
+            instr(0, 4, JMP, true),
+            instr(4, 2, REGT, false),
+            instr(6, 4, JMP, true),
+            instr(10, 4, JMP, true),
+        ];
+        let mut targets = vec![Some(2), None, Some(3), Some(4)];
+        thread_jumps(&instrs, &mut targets);
+        assert_eq!(targets, vec![Some(4), None, Some(4), Some(4)]);
+
+        // A loop of jumps stops threading instead of hanging.
+        let instrs = vec![instr(0, 4, JMP, true), instr(4, 4, JMP, true)];
+        let mut targets = vec![Some(1), Some(0)];
+        thread_jumps(&instrs, &mut targets);
+        assert!(targets.iter().all(|t| t.is_some()));
+    }
+
+    #[test]
+    fn test_remove_jumps_to_next() {
+        // 0: JMP 2, 1: JMP 2 (lands on its next), 2: RET.
+        let instrs = vec![
+            instr(0, 4, JMP, true),
+            instr(4, 4, JMP, true),
+            instr(8, 1, RET, false),
+        ];
+        let targets = vec![Some(2), Some(2), None];
+        let mut keep = vec![true; instrs.len()];
+        remove_jumps_to_next(&instrs, &targets, &mut keep);
+        // Removing the second makes the first land on its next as well.
+        assert_eq!(keep, vec![false, false, true]);
+    }
+
+    #[test]
+    fn test_rebuild_moves_lines_and_global_refs() {
+        let mut vm = Vm::new();
+        let mut state = CompileState::new(&mut vm);
+        let name = vm.intern("peephole-test-global");
+        state.encode2(REGI, 1, 5, Some(1)).unwrap();
+        // Redundant, register 1 already holds 5.
+        state.encode2(REGI, 1, 5, Some(2)).unwrap();
+        state.encode_refi(&mut vm, 2, name, Some(3)).unwrap();
+        // A JMP to the next instruction.
+        state.encode0(JMP, Some(4)).unwrap();
+        state.encode_jump_offset(0).unwrap();
+        state.encode0(RET, Some(5)).unwrap();
+        let old = state.instrs.clone();
+        let old_len = state.chunk.code.len();
+        let gref_len = state.global_refs[0].end - state.global_refs[0].start;
+
+        peephole(&mut state).unwrap();
+        let removed = (old[1].end - old[1].pos) + (old[3].end - old[3].pos);
+        assert_eq!(state.chunk.code.len(), old_len - removed);
+        let gref = &state.global_refs[0];
+        assert_eq!(gref.start, old[1].pos);
+        assert_eq!(gref.end - gref.start, gref_len);
+        assert_eq!(state.chunk.offset_to_line(0), Some(1));
+        assert_eq!(state.chunk.offset_to_line(gref.start), Some(3));
+        assert_eq!(state.chunk.offset_to_line(gref.end), Some(5));
+    }
+
+    #[test]
+    fn test_peephole_keeps_results() {
+        let mut vm = new_vm();
+        let res = eval(
+            &mut vm,
+            "(defn f (a b) (if a (if b 1 2) (do (if b 3 4))))
+             (list (f #t #t) (f #t #f) (f #f #t) (f #f #f))",
+        );
+        assert_eq!(res, "(1 2 3 4)");
+    }
+}
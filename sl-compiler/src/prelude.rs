@@ -0,0 +1,129 @@
+use std::sync::Arc;
+
+use slvm::value::*;
+use slvm::vm::*;
+
+use crate::backquote::*;
+use crate::compile::*;
+use crate::error::*;
+use crate::reader::*;
+use crate::roots::*;
+use crate::state::*;
+use crate::warning::*;
+
+/// Lisp source for the standard macros (defmacro, defn, when, unless, for, for-i, -> and ->>).
+pub const PRELUDE: &str = include_str!("../lisp/prelude.lisp");
+pub const PRELUDE_NAME: &str = "prelude.lisp";
+
+/// Compile and run the bundled prelude in vm.  Its macros use name# (expanded to calls of
+/// gensym) so this also defines the gensym builtin.
+pub fn load_prelude(vm: &mut Vm) -> CompileResult<()> {
+    vm.set_global("gensym", Value::Builtin(CallFunc { func: gensym }));
+    load_str(vm, PRELUDE_NAME, PRELUDE)
+}
+
+/// Read, compile and execute each top level form in text.
+pub fn load_str(vm: &mut Vm, name: &'static str, text: &str) -> CompileResult<()> {
+    let warnings = Warnings::new_ref();
+    let result = load_str_warnings(vm, name, text, &warnings);
+    print_warnings(vm, &warnings);
+    result
+}
+
+/// Like load_str but with the lints on, returns the warnings and lints instead of printing
+/// them.  The forms are still run so macros defined in text are used by later forms.
+pub fn lint_str(vm: &mut Vm, name: &'static str, text: &str) -> CompileResult<Vec<Warning>> {
+    let warnings = Warnings::with_lints();
+    load_str_warnings(vm, name, text, &warnings)?;
+    let mut warnings = warnings.borrow_mut();
+    warnings.finish(vm);
+    Ok(warnings.take())
+}
+
+fn load_str_warnings(
+    vm: &mut Vm,
+    name: &'static str,
+    text: &str,
+    warnings: &WarningsRef,
+) -> CompileResult<()> {
+    let mut reader_state = ReaderState::new();
+    let exps = read_all(vm, &mut reader_state, text)?;
+    // Running a form can GC, keep the forms not compiled yet alive.  The last is rooted first
+    // so each form's root (and what compiling it rooted) is released once it is done.
+    let mark = roots_mark();
+    let mut marks = Vec::new();
+    for exp in exps.iter().rev() {
+        marks.push(roots_mark());
+        root(vm, *exp);
+    }
+    marks.reverse();
+    let result = load_exps(vm, name, &exps, &marks, warnings);
+    release_roots(vm, mark);
+    result
+}
+
+fn load_exps(
+    vm: &mut Vm,
+    name: &'static str,
+    exps: &[Value],
+    marks: &[usize],
+    warnings: &WarningsRef,
+) -> CompileResult<()> {
+    let declarations = Declarations::new_ref();
+    for (exp, mark) in exps.iter().zip(marks) {
+        let mut linenum = match exp.get_handle() {
+            Some(handle) => form_position(vm, handle).0.unwrap_or(1),
+            None => 1,
+        };
+        let mut line = Some(&mut linenum);
+        let mut state = CompileState::new_state(vm, name, line_num(&line), None);
+        state.chunk.dbg_args = Some(Vec::new());
+        state.warnings = warnings.clone();
+        state.declarations = declarations.clone();
+        compile_top_level(vm, &mut state, *exp, &mut line)
+            .map_err(|e| e.with_line(name, Some(line_num(&line))))?;
+        vm.execute(Arc::new(state.chunk))?;
+        release_roots(vm, *mark);
+    }
+    Ok(())
+}
+
+/// Read, compile and execute each top level form in text and return the value of the last.
+pub fn eval_str(vm: &mut Vm, name: &'static str, text: &str) -> CompileResult<Value> {
+    let warnings = Warnings::new_ref();
+    load_str_warnings(vm, name, text, &warnings)?;
+    Ok(vm.get_stack(0))
+}
+
+fn line_num(line: &Option<&mut u32>) -> u32 {
+    match line {
+        Some(line) => **line,
+        None => 0,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::test_util::*;
+
+    #[test]
+    fn test_prelude_macros() {
+        let mut vm = new_vm();
+        assert_eq!(eval(&mut vm, "(defn add1 (x) (+ x 1)) (add1 2)"), "3");
+        assert_eq!(eval(&mut vm, "(when #t 1 2)"), "2");
+        assert_eq!(eval(&mut vm, "(when #f 1)"), "nil");
+        assert_eq!(eval(&mut vm, "(unless #f 1)"), "1");
+        assert_eq!(
+            eval(&mut vm, "(def s 0) (for x '(1 2 3) (set! s (+ s x))) s"),
+            "6"
+        );
+        assert_eq!(
+            eval(&mut vm, "(def s 0) (for-i i x '(5 6 7) (set! s (+ s i))) s"),
+            "3"
+        );
+        assert_eq!(eval(&mut vm, "(-> 10 (- 3) add1)"), "8");
+        assert_eq!(eval(&mut vm, "(->> 10 (- 3) add1)"), "-6");
+    }
+}
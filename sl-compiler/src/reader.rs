@@ -26,23 +26,130 @@ struct Meta {
     col: u64,
 }
 
+/// What went wrong while reading.
+#[derive(Clone, Debug, PartialEq)]
+pub enum ReadErrorKind {
+    /// A ')' with no matching '('.
+    UnexpectedCloseParen,
+    /// Input ended inside a string.
+    UnterminatedString,
+    /// Input ended inside a list.
+    UnterminatedList,
+    /// Input ended inside a vector.
+    UnterminatedVector,
+    /// Input ended inside a #| |# comment.
+    UnterminatedBlockComment,
+    /// Input ended where a form was required (after a quote for instance).
+    UnexpectedEof,
+    /// Invalid \x or \u escape.
+    BadCharEscape(String),
+    /// Invalid #\ char literal.
+    BadChar(String),
+    /// Invalid digit in a #x, #o or #b number.
+    BadRadixDigit { radix: u32, text: String },
+    /// Invalid dotted pair.
+    BadDottedPair(&'static str),
+    /// Unquote (,) or splice used outside of a back-quote.
+    UnquoteOutsideBackQuote,
+    /// A #< token, these are printed but can not be read.
+    UnreadableToken,
+    /// # followed by an unknown dispatch character.
+    BadDispatchChar(String),
+    /// Asked for a form but there was nothing to read.
+    Empty,
+}
+
+impl fmt::Display for ReadErrorKind {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ReadErrorKind::UnexpectedCloseParen => write!(f, "Unexpected ')'"),
+            ReadErrorKind::UnterminatedString => write!(f, "Unterminated string"),
+            ReadErrorKind::UnterminatedList => write!(f, "Unclosed list"),
+            ReadErrorKind::UnterminatedVector => write!(f, "Unclosed vector"),
+            ReadErrorKind::UnterminatedBlockComment => write!(f, "Unterminated block comment"),
+            ReadErrorKind::UnexpectedEof => write!(f, "Unexpected end of input"),
+            ReadErrorKind::BadCharEscape(reason) => write!(f, "{}", reason),
+            ReadErrorKind::BadChar(ch) => write!(f, "Not a valid char [{}]", ch),
+            ReadErrorKind::BadRadixDigit { radix, text } => {
+                write!(f, "Invalid base {} number [{}]", radix, text)
+            }
+            ReadErrorKind::BadDottedPair(reason) => {
+                write!(f, "Invalid dotted pair syntax ({})", reason)
+            }
+            ReadErrorKind::UnquoteOutsideBackQuote => write!(f, "Unquote outside of a back-quote"),
+            ReadErrorKind::UnreadableToken => write!(f, "Found an unreadable token"),
+            ReadErrorKind::BadDispatchChar(ch) => write!(f, "Found # with invalid char {}", ch),
+            ReadErrorKind::Empty => write!(f, "Empty value"),
+        }
+    }
+}
+
 #[derive(Clone, Debug)]
 pub struct ReadError {
-    pub reason: String,
+    pub kind: ReadErrorKind,
+    /// Line the error was found on (starts at 1).
+    pub line: usize,
+    /// Column the error was found on.
+    pub column: usize,
+    /// Byte offset into the input where the error was found, for unterminated forms this is
+    /// the start of the form.
+    pub offset: usize,
+}
+
+impl ReadError {
+    fn new(kind: ReadErrorKind, reader_state: &ReaderState) -> Self {
+        ReadError {
+            kind,
+            line: reader_state.line,
+            column: reader_state.column,
+            offset: reader_state.offset,
+        }
+    }
+
+    fn at(kind: ReadErrorKind, start: &Position) -> Self {
+        ReadError {
+            kind,
+            line: start.line,
+            column: start.column,
+            offset: start.offset,
+        }
+    }
+
+    /// True if this error is because the input ended before a form was complete, more input
+    /// may fix it (as opposed to a syntax error).
+    pub fn is_incomplete(&self) -> bool {
+        matches!(
+            self.kind,
+            ReadErrorKind::UnterminatedString
+                | ReadErrorKind::UnterminatedList
+                | ReadErrorKind::UnterminatedVector
+                | ReadErrorKind::UnterminatedBlockComment
+                | ReadErrorKind::UnexpectedEof
+        )
+    }
 }
 
 impl Error for ReadError {}
 
 impl fmt::Display for ReadError {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        write!(f, "{}", self.reason)
+        write!(f, "{}: line {}, col: {}", self.kind, self.line, self.column)
     }
 }
 
+// Saved reader position, used to report errors at the start of an unterminated form.
+struct Position {
+    line: usize,
+    column: usize,
+    offset: usize,
+}
+
 #[derive(Clone, Debug)]
 pub struct ReaderState {
     pub line: usize,
     pub column: usize,
+    /// Byte offset of the next char to read.
+    pub offset: usize,
     pub clear_state: bool,
     pub in_read: bool,
 }
@@ -55,9 +162,29 @@ impl ReaderState {
     pub fn clear(&mut self) {
         self.column = 0;
         self.line = 1;
+        self.offset = 0;
         self.clear_state = false;
         self.in_read = false;
     }
+
+    // Track the position after consuming ch.
+    fn advance(&mut self, ch: &str) {
+        self.offset += ch.len();
+        if ch == "\n" {
+            self.line += 1;
+            self.column = 0;
+        } else {
+            self.column += 1;
+        }
+    }
+
+    fn position(&self) -> Position {
+        Position {
+            line: self.line,
+            column: self.column,
+            offset: self.offset,
+        }
+    }
 }
 
 impl Default for ReaderState {
@@ -65,6 +192,7 @@ impl Default for ReaderState {
         ReaderState {
             column: 0,
             line: 1,
+            offset: 0,
             clear_state: false,
             in_read: false,
         }
@@ -84,7 +212,7 @@ fn is_whitespace(ch: &str) -> bool {
     matches!(ch, " " | "\t" | "\n")
 }
 
-fn char_to_hex_num(ch: &str) -> Result<u8, ReadError> {
+fn char_to_hex_num(ch: &str, reader_state: &ReaderState) -> Result<u8, ReadError> {
     if ("0"..="9").contains(&ch) {
         Ok(ch.chars().next().unwrap() as u8 - b'0')
     } else {
@@ -101,51 +229,66 @@ fn char_to_hex_num(ch: &str) -> Result<u8, ReadError> {
             "E" => Ok(14),
             "f" => Ok(15),
             "F" => Ok(15),
-            _ => Err(ReadError {
-                reason: format!("Invalid hex digit {}, expected 0-9 or A-F.", ch),
-            }),
+            _ => Err(ReadError::new(
+                ReadErrorKind::BadCharEscape(format!(
+                    "Invalid hex digit {}, expected 0-9 or A-F.",
+                    ch
+                )),
+                reader_state,
+            )),
         }
     }
 }
 
 fn escape_to_char(chars: &mut CharIter, reader_state: &mut ReaderState) -> Result<char, ReadError> {
     if let (Some(ch1), Some(ch2)) = (chars.next(), chars.next()) {
-        reader_state.column += 1;
-        let ch_n: u8 = (char_to_hex_num(&*ch1)? * 16) + (char_to_hex_num(&*ch2)?);
+        reader_state.advance(&ch1);
+        reader_state.advance(&ch2);
+        let ch_n: u8 =
+            (char_to_hex_num(&*ch1, reader_state)? * 16) + (char_to_hex_num(&*ch2, reader_state)?);
         if ch_n > 0x7f {
-            Err(ReadError {
-                reason: "Invalid hex ascii code, must be less then \\x7f.".to_string(),
-            })
+            Err(ReadError::new(
+                ReadErrorKind::BadCharEscape(
+                    "Invalid hex ascii code, must be less then \\x7f.".to_string(),
+                ),
+                reader_state,
+            ))
         } else {
             Ok(ch_n as char)
         }
     } else {
-        Err(ReadError {
-            reason: "Invalid hex ascii code, expected two digits.".to_string(),
-        })
+        Err(ReadError::new(
+            ReadErrorKind::BadCharEscape(
+                "Invalid hex ascii code, expected two digits.".to_string(),
+            ),
+            reader_state,
+        ))
     }
 }
 
 fn consume_line_comment(chars: &mut CharIter, reader_state: &mut ReaderState) {
     for ch in chars {
+        reader_state.advance(&ch);
         if ch == "\n" {
-            reader_state.line += 1;
-            reader_state.column = 0;
             return;
         }
     }
 }
 
-fn consume_block_comment(chars: &mut CharIter, reader_state: &mut ReaderState) {
+fn consume_block_comment(
+    chars: &mut CharIter,
+    reader_state: &mut ReaderState,
+) -> Result<(), ReadError> {
+    // The #| has already been consumed.
+    let start = Position {
+        line: reader_state.line,
+        column: reader_state.column.saturating_sub(1),
+        offset: reader_state.offset.saturating_sub(2),
+    };
     let mut depth = 1;
     let mut last_ch = Cow::Borrowed(" ");
     for ch in chars {
-        if ch == "\n" {
-            reader_state.line += 1;
-            reader_state.column = 0;
-        } else {
-            reader_state.column += 1;
-        }
+        reader_state.advance(&ch);
         if last_ch == "|" && ch == "#" {
             depth -= 1;
         }
@@ -154,9 +297,13 @@ fn consume_block_comment(chars: &mut CharIter, reader_state: &mut ReaderState) {
         }
         last_ch = ch;
         if depth == 0 {
-            break;
+            return Ok(());
         }
     }
+    Err(ReadError::at(
+        ReadErrorKind::UnterminatedBlockComment,
+        &start,
+    ))
 }
 
 fn end_symbol(ch: &str, read_table_term: &HashMap<&'static str, Value>) -> bool {
@@ -176,7 +323,7 @@ fn is_digit(ch: &str) -> bool {
 
 fn do_char(
     vm: &mut Vm,
-    reader_state: &mut ReaderState,
+    reader_state: &ReaderState,
     symbol: &str,
     //meta: Option<ExpMeta>,
 ) -> Result<Value, ReadError> {
@@ -203,21 +350,19 @@ fn do_char(
         if chars.peek().is_some() {
             match &*ch {
                 "u" => {
-                    let ch = read_utf_scalar(&mut chars, reader_state)?;
+                    let ch = read_utf_scalar(&mut chars, &mut reader_state.clone())?;
                     // XXX TODO- codepoint here?
                     return Ok(Value::CodePoint(ch));
                 }
                 "x" => {
-                    let ch = escape_to_char(&mut chars, reader_state)?;
+                    let ch = escape_to_char(&mut chars, &mut reader_state.clone())?;
                     return Ok(Value::CodePoint(ch));
                 }
                 _ => {
-                    let reader_state = reader_state;
-                    let reason = format!(
-                        "Not a valid char [{}]: line {}, col: {}",
-                        symbol, reader_state.line, reader_state.column
-                    );
-                    return Err(ReadError { reason });
+                    return Err(ReadError::new(
+                        ReadErrorKind::BadChar(symbol.to_string()),
+                        reader_state,
+                    ));
                 }
             }
         }
@@ -235,11 +380,10 @@ fn do_char(
             panic!("Invalid alloc_string!");
         }
     } else {
-        let reason = format!(
-            "Not a valid char [{}]: line {}, col: {}",
-            symbol, reader_state.line, reader_state.column
-        );
-        Err(ReadError { reason })
+        Err(ReadError::new(
+            ReadErrorKind::BadChar(symbol.to_string()),
+            reader_state,
+        ))
     }
 }
 
@@ -247,16 +391,17 @@ fn read_utf_scalar(
     chars: &mut CharIter,
     reader_state: &mut ReaderState,
 ) -> Result<char, ReadError> {
-    fn finish(char_u32: u32) -> Result<char, ReadError> {
+    fn finish(char_u32: u32, reader_state: &ReaderState) -> Result<char, ReadError> {
         if let Some(val) = std::char::from_u32(char_u32) {
             Ok(val)
         } else {
-            Err(ReadError {
-                reason: format!(
+            Err(ReadError::new(
+                ReadErrorKind::BadCharEscape(format!(
                     "Invalid unicode scalar, {:x} not a valid utf scalar.",
                     char_u32
-                ),
-            })
+                )),
+                reader_state,
+            ))
         }
     }
     let mut first = true;
@@ -264,18 +409,18 @@ fn read_utf_scalar(
     let mut char_u32 = 0;
     let mut nibbles = 0;
     while let Some(ch) = chars.next() {
+        reader_state.advance(&ch);
         if ch == "\n" {
-            reader_state.line += 1;
-            reader_state.column = 0;
             if has_bracket {
-                return Err(ReadError {
-                    reason: "Invalid unicode scalar, unexpected newline.".to_string(),
-                });
+                return Err(ReadError::new(
+                    ReadErrorKind::BadCharEscape(
+                        "Invalid unicode scalar, unexpected newline.".to_string(),
+                    ),
+                    reader_state,
+                ));
             } else {
-                return finish(char_u32);
+                return finish(char_u32, reader_state);
             }
-        } else {
-            reader_state.column += 1;
         }
         if first && ch == "{" {
             has_bracket = true;
@@ -284,28 +429,32 @@ fn read_utf_scalar(
         }
         first = false;
         if has_bracket && ch == "}" {
-            return finish(char_u32);
+            return finish(char_u32, reader_state);
         }
         if nibbles >= 8 {
-            return Err(ReadError {
-                reason: "Invalid unicode scalar, too many bytes (4 max).".to_string(),
-            });
+            return Err(ReadError::new(
+                ReadErrorKind::BadCharEscape(
+                    "Invalid unicode scalar, too many bytes (4 max).".to_string(),
+                ),
+                reader_state,
+            ));
         }
         nibbles += 1;
-        let nib = char_to_hex_num(&ch)?;
+        let nib = char_to_hex_num(&ch, reader_state)?;
         char_u32 = (char_u32 << 4) | nib as u32;
         if let Some(pch) = chars.peek() {
             if !has_bracket && is_whitespace(&*pch) {
-                return finish(char_u32);
+                return finish(char_u32, reader_state);
             }
         }
     }
     if has_bracket {
-        Err(ReadError {
-            reason: "Invalid unicode scalar, failed to parse.".to_string(),
-        })
+        Err(ReadError::new(
+            ReadErrorKind::BadCharEscape("Invalid unicode scalar, failed to parse.".to_string()),
+            reader_state,
+        ))
     } else {
-        finish(char_u32)
+        finish(char_u32, reader_state)
     }
 }
 /*
@@ -325,7 +474,14 @@ fn read_string<'sym>(
     read_table: &HashMap<&'static str, Chunk>,
 ) -> Result<(&'sym mut String, CharIter), (ReadError, CharIter)> {
     symbol.clear();
+    // The opening " has already been consumed.
+    let start = Position {
+        line: reader_state.line,
+        column: reader_state.column,
+        offset: reader_state.offset.saturating_sub(1),
+    };
     let mut last_ch_escape = false;
+    let mut closed = false;
     //let res_list: Option<Vec<Value>> = None;
     /*let meta = get_meta(
         environment.reader_state.file_name,
@@ -334,12 +490,7 @@ fn read_string<'sym>(
     );*/
 
     while let Some(ch) = chars.next() {
-        if ch == "\n" {
-            reader_state.line += 1;
-            reader_state.column = 0;
-        } else {
-            reader_state.column += 1;
-        }
+        reader_state.advance(&ch);
         if last_ch_escape {
             let mut do_match = true;
             if read_table.contains_key(&*ch) {
@@ -384,6 +535,7 @@ fn read_string<'sym>(
             last_ch_escape = false;
         } else {
             if ch == "\"" {
+                closed = true;
                 break;
             }
             let mut proc_ch = true;
@@ -454,6 +606,12 @@ fn read_string<'sym>(
         let fl = Expression::with_list_meta(list, meta);
         Ok((fl, chars))
     } else {*/
+    if !closed {
+        return Err((
+            ReadError::at(ReadErrorKind::UnterminatedString, &start),
+            chars,
+        ));
+    }
     Ok((
         symbol, //Value::Reference(vm.alloc(Object::String(symbol.clone().into()))),
         chars,
@@ -473,32 +631,33 @@ fn read_string_literal<'sym>(
         environment.reader_state.line,
         environment.reader_state.column,
     );*/
+    // The #" has already been consumed.
+    let start = Position {
+        line: reader_state.line,
+        column: reader_state.column.saturating_sub(1),
+        offset: reader_state.offset.saturating_sub(2),
+    };
     let end_ch = if let Some(ch) = chars.next() {
-        reader_state.column += 1;
+        reader_state.advance(&ch);
         ch
     } else {
         return Err((
-            ReadError {
-                reason: "Unexpected stream end on string literal".to_string(),
-            },
+            ReadError::at(ReadErrorKind::UnterminatedString, &start),
             chars,
         ));
     };
 
     while let Some(ch) = chars.next() {
-        if ch == "\n" {
-            reader_state.line += 1;
-            reader_state.column = 0;
-        } else {
-            reader_state.column += 1;
-        }
+        reader_state.advance(&ch);
         let peek = if let Some(pch) = chars.peek() {
             pch
         } else {
             ""
         };
         if ch == end_ch && peek == "\"" {
-            chars.next();
+            if let Some(ch) = chars.next() {
+                reader_state.advance(&ch);
+            }
             return Ok((
                 symbol,
                 //Value::Reference(vm.alloc(Object::String(symbol.clone().into()))),
@@ -508,9 +667,7 @@ fn read_string_literal<'sym>(
         symbol.push_str(&ch);
     }
     Err((
-        ReadError {
-            reason: "Unexpected end of string literal".to_string(),
-        },
+        ReadError::at(ReadErrorKind::UnterminatedString, &start),
         chars,
     ))
 }
@@ -586,7 +743,7 @@ fn read_symbol(
     let mut has_e = false;
     let mut last_e = false;
     if let Some(ch) = chars.peek() {
-        if end_symbol(ch, read_table_term) && !for_ch {
+        if end_symbol(ch, read_table_term) && !for_ch && (ch != "#" || is_number) {
             return buffer.len() == 1 && is_digit(&buffer[..]);
         }
     };
@@ -600,12 +757,7 @@ fn read_symbol(
             has_peek = false;
             " "
         };
-        if ch == "\n" {
-            reader_state.line += 1;
-            reader_state.column = 0;
-        } else {
-            reader_state.column += 1;
-        }
+        reader_state.advance(&ch);
         if ch == "\\" && has_peek && !for_ch {
             push_next = true;
         } else if !skip_underscore || ch != "_" {
@@ -616,12 +768,14 @@ fn read_symbol(
         }
         if push_next {
             let next_ch = chars.next().unwrap();
+            reader_state.advance(&next_ch);
             if is_number {
                 is_number = maybe_number(&ch, &mut has_e, &mut last_e, &mut has_decimal);
             }
             buffer.push_str(&next_ch);
             push_next = false;
-        } else if end_symbol(peek_ch, read_table_term) {
+        } else if end_symbol(peek_ch, read_table_term) && (peek_ch != "#" || is_number || for_ch) {
+            // A # after a symbol is part of it (foo# is an auto-gensym in back quotes).
             break;
         }
         next_ch = chars.next();
@@ -647,12 +801,7 @@ fn consume_whitespace(reader_state: &mut ReaderState, chars: &mut CharIter) {
     let mut ch = chars.peek();
     while ch.is_some() && is_whitespace(ch.unwrap()) {
         if let Some(ch) = ch {
-            if *ch == "\n" {
-                reader_state.line += 1;
-                reader_state.column = 0;
-            } else {
-                reader_state.column += 1;
-            }
+            reader_state.advance(ch);
             chars.next();
         }
         ch = chars.peek();
@@ -668,6 +817,12 @@ fn read_num_radix(
     read_table_term: &HashMap<&'static str, Value>,
 ) -> Result<(i64, CharIter), (ReadError, CharIter)> {
     buffer.clear();
+    // The #x, #o or #b has already been consumed.
+    let start = Position {
+        line: reader_state.line,
+        column: reader_state.column.saturating_sub(1),
+        offset: reader_state.offset.saturating_sub(2),
+    };
     read_symbol(
         buffer,
         &mut chars,
@@ -678,10 +833,14 @@ fn read_num_radix(
     );
     match i64::from_str_radix(buffer, radix) {
         Ok(n) => Ok((n, chars)),
-        Err(e) => Err((
-            ReadError {
-                reason: e.to_string(),
-            },
+        Err(_) => Err((
+            ReadError::at(
+                ReadErrorKind::BadRadixDigit {
+                    radix,
+                    text: buffer.clone(),
+                },
+                &start,
+            ),
             chars,
         )),
     }
@@ -700,6 +859,12 @@ fn read_vector(
         reader_state.line,
         reader_state.column,
     );*/
+    // The #( has already been consumed.
+    let start = Position {
+        line: reader_state.line,
+        column: reader_state.column.saturating_sub(1),
+        offset: reader_state.offset.saturating_sub(2),
+    };
     let mut cont = true;
 
     let close_intern = vm.intern(")");
@@ -727,9 +892,7 @@ fn read_vector(
         chars = ichars;
     }
     Err((
-        ReadError {
-            reason: "Unclosed vector".to_string(),
-        },
+        ReadError::at(ReadErrorKind::UnterminatedVector, &start),
         chars,
     ))
 }
@@ -790,6 +953,12 @@ fn read_list(
         line: reader_state.line as u64,
         col: reader_state.column as u64,
     };
+    // The ( has already been consumed.
+    let start = Position {
+        line: reader_state.line,
+        column: reader_state.column,
+        offset: reader_state.offset.saturating_sub(1),
+    };
     let mut cont = true;
     let mut dot = false;
     let mut dot_count = 0;
@@ -820,9 +989,10 @@ fn read_list(
             if let Value::Nil = head {
                 if dot {
                     return Err((
-                        ReadError {
-                            reason: "Invalid dotted pair syntax (nothing before dot).".to_string(),
-                        },
+                        ReadError::new(
+                            ReadErrorKind::BadDottedPair("nothing before dot"),
+                            reader_state,
+                        ),
                         ichars,
                     ));
                 }
@@ -831,10 +1001,10 @@ fn read_list(
             } else if dot {
                 if is_unquote_splice(vm, exp) {
                     return Err((
-                        ReadError {
-                            reason: "Invalid dotted pair syntax with unquote-splice (,@/,.)."
-                                .to_string(),
-                        },
+                        ReadError::new(
+                            ReadErrorKind::BadDottedPair("unquote-splice (,@/,.) after dot"),
+                            reader_state,
+                        ),
                         ichars,
                     ));
                 }
@@ -848,9 +1018,10 @@ fn read_list(
                     }
                     if i != 1 {
                         return Err((
-                            ReadError {
-                                reason: "Invalid dotted pair syntax with unquote.".to_string(),
-                            },
+                            ReadError::new(
+                                ReadErrorKind::BadDottedPair("unquote after dot takes one form"),
+                                reader_state,
+                            ),
                             ichars,
                         ));
                     }
@@ -879,18 +1050,16 @@ fn read_list(
         }
         if dot_count > 1 {
             return Err((
-                ReadError {
-                    reason: "Invalid dotted pair syntax (more than object follows dot)."
-                        .to_string(),
-                },
+                ReadError::new(
+                    ReadErrorKind::BadDottedPair("more than one object follows dot"),
+                    reader_state,
+                ),
                 chars,
             ));
         }
     }
     Err((
-        ReadError {
-            reason: "Unclosed list".to_string(),
-        },
+        ReadError::at(ReadErrorKind::UnterminatedList, &start),
         chars,
     ))
 }
@@ -940,7 +1109,7 @@ fn read_inner(
     let i_quote = vm.intern("quote");
     let i_backquote = vm.intern("back-quote");
     while let Some((ch, peek_ch)) = next2(&mut chars) {
-        reader_state.column += 1;
+        reader_state.advance(&ch);
         /*if read_table.contains_key(&*ch) {
             if let ExpEnum::Symbol(s) = read_table.get(&*ch).unwrap().get().data {
                 let res = prep_reader_macro(environment, chars, s, &ch);
@@ -988,9 +1157,7 @@ fn read_inner(
                 }
                 Ok((None, ichars)) => {
                     return Err((
-                        ReadError {
-                            reason: "Invalid quote".to_string(),
-                        },
+                        ReadError::new(ReadErrorKind::UnexpectedEof, reader_state),
                         ichars,
                     ));
                 }
@@ -1006,9 +1173,7 @@ fn read_inner(
                 }
                 Ok((None, ichars)) => {
                     return Err((
-                        ReadError {
-                            reason: "Invalid back-quote".to_string(),
-                        },
+                        ReadError::new(ReadErrorKind::UnexpectedEof, reader_state),
                         ichars,
                     ));
                 }
@@ -1019,9 +1184,11 @@ fn read_inner(
             "," if in_back_quote => {
                 let sym = if peek_ch == "@" {
                     chars.next();
+                    reader_state.advance(&peek_ch);
                     Value::Symbol(vm.intern("unquote-splice"))
                 } else if peek_ch == "." {
                     chars.next();
+                    reader_state.advance(&peek_ch);
                     Value::Symbol(vm.intern("unquote-splice!"))
                 } else {
                     Value::Symbol(vm.intern("unquote"))
@@ -1033,9 +1200,7 @@ fn read_inner(
                     }
                     Ok((None, ichars)) => {
                         return Err((
-                            ReadError {
-                                reason: "Invalid back-quote".to_string(),
-                            },
+                            ReadError::new(ReadErrorKind::UnexpectedEof, reader_state),
                             ichars,
                         ));
                     }
@@ -1046,16 +1211,24 @@ fn read_inner(
             }
             "," => {
                 return Err((
-                    ReadError {
-                        reason: "Unquote outside of a back-quote".to_string(),
-                    },
+                    ReadError::new(ReadErrorKind::UnquoteOutsideBackQuote, reader_state),
                     chars,
                 ))
             }
             "#" => {
-                chars.next();
+                if chars.next().is_none() {
+                    return Err((
+                        ReadError::new(ReadErrorKind::UnexpectedEof, reader_state),
+                        chars,
+                    ));
+                }
+                reader_state.advance(&peek_ch);
                 match &*peek_ch {
-                    "|" => consume_block_comment(&mut chars, reader_state),
+                    "|" => {
+                        if let Err(e) = consume_block_comment(&mut chars, reader_state) {
+                            return Err((e, chars));
+                        }
+                    }
                     "\\" => {
                         buffer.clear();
                         read_symbol(
@@ -1072,11 +1245,10 @@ fn read_inner(
                         };
                     }
                     "<" => {
-                        let reason = format!(
-                            "Found an unreadable token: line {}, col: {}",
-                            reader_state.line, reader_state.column
-                        );
-                        return Err((ReadError { reason }, chars));
+                        return Err((
+                            ReadError::new(ReadErrorKind::UnreadableToken, reader_state),
+                            chars,
+                        ));
                     }
                     "(" => {
                         let (exp, chars) =
@@ -1127,11 +1299,13 @@ fn read_inner(
                         }
                     }
                     _ => {
-                        let reason = format!(
-                            "Found # with invalid char {}: line {}, col: {}",
-                            peek_ch, reader_state.line, reader_state.column
-                        );
-                        return Err((ReadError { reason }, chars));
+                        return Err((
+                            ReadError::new(
+                                ReadErrorKind::BadDispatchChar(peek_ch.to_string()),
+                                reader_state,
+                            ),
+                            chars,
+                        ));
                     }
                 }
             }
@@ -1143,11 +1317,12 @@ fn read_inner(
                 if return_close_paren {
                     return Ok((Some(Value::Symbol(vm.intern(")"))), chars));
                 } else {
-                    let reason = format!(
-                        "Unexpected ')': line {} col {}",
-                        reader_state.line, reader_state.column
-                    );
-                    return Err((ReadError { reason }, chars));
+                    let mut pos = reader_state.position();
+                    pos.offset -= 1;
+                    return Err((
+                        ReadError::at(ReadErrorKind::UnexpectedCloseParen, &pos),
+                        chars,
+                    ));
                 }
             }
             ";" => {
@@ -1190,18 +1365,14 @@ fn read_form_state(
     reader_state.in_read = true;
     let res = match read_inner(vm, reader_state, chars, &mut buffer, false, false) {
         Ok((Some(exp), ichars)) => Ok((exp, ichars)),
-        Ok((None, ichars)) => Err((
-            ReadError {
-                reason: "Empty value".to_string(),
-            },
-            ichars,
-        )),
+        Ok((None, ichars)) => Err((ReadError::new(ReadErrorKind::Empty, reader_state), ichars)),
         Err((err, ichars)) => Err((err, ichars)),
     };
     reader_state.in_read = old_in_read;
     if let Some(old_state) = old_state {
         reader_state.line = old_state.line;
         reader_state.column = old_state.column;
+        reader_state.offset = old_state.offset;
         reader_state.clear_state = old_state.clear_state;
         reader_state.in_read = old_state.in_read;
     }
@@ -1261,11 +1432,10 @@ fn read_all_inner(
     }
     if chars.next().is_some() {
         reader_state.clear_state = true;
-        let reason = format!(
-            "Premature end (to many ')'?) line: {}, column: {}",
-            reader_state.line, reader_state.column
-        );
-        return Err(ReadError { reason });
+        return Err(ReadError::new(
+            ReadErrorKind::UnexpectedCloseParen,
+            reader_state,
+        ));
     }
     //let exp_meta = get_meta(environment.reader_state.file_name, 0, 0);
     reader_state.clear_state = true;
@@ -1302,9 +1472,7 @@ pub fn read(
                 _ => Ok(vm.alloc_vector_ro(exps)),
             }
         } else if exps.is_empty() {
-            Err(ReadError {
-                reason: "Empty value".to_string(),
-            })
+            Err(ReadError::new(ReadErrorKind::Empty, reader_state))
         } else {
             Ok(vm.alloc_vector_ro(exps))
         }
@@ -1506,6 +1674,10 @@ mod tests {
         assert!(tokens[1] == "Symbol:length");
         assert!(tokens[2] == "String:\"12345Σ\"");
         assert!(tokens[3] == ")");
+        let tokens = tokenize(&mut vm, &mut reader_state, "(let ((x# 1)) x#)", None);
+        assert!(tokens.len() == 10);
+        assert!(tokens[4] == "Symbol:x#");
+        assert!(tokens[8] == "Symbol:x#");
     }
 
     #[test]
@@ -1898,4 +2070,59 @@ two""#
         assert!(tokens[9] == "Symbol:0.23.123");
         assert!(tokens[10] == ")");
     }
+
+    #[test]
+    fn test_read_errors() {
+        let mut vm = build_def_vm();
+        let mut reader_state = ReaderState::new();
+        let err = tokenize_err(&mut vm, &mut reader_state, "(1 2", None);
+        assert_eq!(err.kind, ReadErrorKind::UnterminatedList);
+        assert!(err.is_incomplete());
+        let mut reader_state = ReaderState::new();
+        let err = tokenize_err(&mut vm, &mut reader_state, "(1 2))", None);
+        assert_eq!(err.kind, ReadErrorKind::UnexpectedCloseParen);
+        assert!(!err.is_incomplete());
+        assert_eq!(err.line, 1);
+        assert_eq!(err.column, 6);
+        assert_eq!(err.offset, 5);
+        let mut reader_state = ReaderState::new();
+        let err = tokenize_err(&mut vm, &mut reader_state, "(one\n \"two", None);
+        assert_eq!(err.kind, ReadErrorKind::UnterminatedString);
+        assert!(err.is_incomplete());
+        assert_eq!(err.line, 2);
+        assert_eq!(err.offset, 6);
+        let mut reader_state = ReaderState::new();
+        let err = tokenize_err(&mut vm, &mut reader_state, "#(1 2", None);
+        assert_eq!(err.kind, ReadErrorKind::UnterminatedVector);
+        assert!(err.is_incomplete());
+        let mut reader_state = ReaderState::new();
+        let err = tokenize_err(&mut vm, &mut reader_state, "1 #| 2 #| 3 |# 4", None);
+        assert_eq!(err.kind, ReadErrorKind::UnterminatedBlockComment);
+        assert!(err.is_incomplete());
+        assert_eq!(err.offset, 2);
+        let mut reader_state = ReaderState::new();
+        let err = tokenize_err(&mut vm, &mut reader_state, "'", None);
+        assert_eq!(err.kind, ReadErrorKind::UnexpectedEof);
+        assert!(err.is_incomplete());
+        let mut reader_state = ReaderState::new();
+        let err = tokenize_err(&mut vm, &mut reader_state, "\"\\xZZ\"", None);
+        assert!(matches!(err.kind, ReadErrorKind::BadCharEscape(_)));
+        assert!(!err.is_incomplete());
+        let mut reader_state = ReaderState::new();
+        let err = tokenize_err(&mut vm, &mut reader_state, "#\\ab", None);
+        assert_eq!(err.kind, ReadErrorKind::BadChar("ab".to_string()));
+        let mut reader_state = ReaderState::new();
+        let err = tokenize_err(&mut vm, &mut reader_state, "1 #b1112", None);
+        assert_eq!(
+            err.kind,
+            ReadErrorKind::BadRadixDigit {
+                radix: 2,
+                text: "1112".to_string()
+            }
+        );
+        assert_eq!(err.offset, 2);
+        let mut reader_state = ReaderState::new();
+        let err = tokenize_err(&mut vm, &mut reader_state, "'(1 ,2)", None);
+        assert_eq!(err.kind, ReadErrorKind::UnquoteOutsideBackQuote);
+    }
 }
@@ -0,0 +1,110 @@
+use slvm::vm::*;
+
+use crate::compile::*;
+use crate::error::*;
+use crate::state::*;
+
+/// Allocates the registers for the operands of one op from the temporaries of the state.
+///
+/// An operand is live from when it is set until the op reading it is emitted (compile releases
+/// the temps of a form once it is compiled) so the forms that follow reuse its register.  If the
+/// op reads its operands before writing its result the result register is dead until then and
+/// is the first temp handed out.  An operand that already lives in a register (a named local)
+/// is read in place instead of being copied to a temporary, that saves the move and the
+/// register.  Named locals keep their registers so dbg_args still maps them for the debugger.
+pub struct Operands {
+    base: usize,
+}
+
+impl Operands {
+    /// Temporaries start at base.
+    pub fn new(base: usize) -> Self {
+        Operands { base }
+    }
+
+    /// Compile exp, evaluated before the operands rest, and return the register holding it.
+    /// A local is only read in place if evaluating rest can not set! it before the op runs.
+    pub fn operand<E: Exp>(
+        &mut self,
+        vm: &mut Vm,
+        state: &mut CompileState,
+        exp: E,
+        rest: &[E],
+        line: &mut Option<&mut u32>,
+    ) -> CompileResult<usize> {
+        if let Some(i) = exp.local() {
+            if rest.iter().all(|r| r.is_simple()) {
+                if let Some(idx) = state.get_symbol(i) {
+                    state.read_local(i);
+                    return Ok(idx + 1);
+                }
+            }
+        }
+        let reg = state.alloc_temp(self.base);
+        exp.compile(vm, state, reg, line)?;
+        state.hold_temp(reg);
+        Ok(reg)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::reader::*;
+    use crate::test_util::*;
+
+    /// The registers the chunk for text needs past its result register.
+    fn frame_regs(vm: &mut Vm, text: &str) -> usize {
+        let mut reader_state = ReaderState::new();
+        let exp = read_all(vm, &mut reader_state, text).unwrap()[0];
+        let mut state = CompileState::new_state(vm, "test", 1, None);
+        let mut linenum = 1;
+        compile_top_level(vm, &mut state, exp, &mut Some(&mut linenum)).unwrap();
+        state.max_regs
+    }
+
+    #[test]
+    fn test_result_reg_reused() {
+        let mut vm = Vm::new();
+        // Each car and cdr reads its operand from its own result register.
+        assert_eq!(frame_regs(&mut vm, "(car (cdr (car (cdr '((1 2) 3)))))"), 0);
+        assert_eq!(
+            frame_regs(&mut vm, "(vec-nth (make-vec 2 (car '(1))) 1)"),
+            1
+        );
+        assert_eq!(
+            frame_regs(
+                &mut vm,
+                "(cons (vec-nth '#(1 2) 0) (cons (vec-nth '#(3 4) 1) nil))"
+            ),
+            2
+        );
+    }
+
+    #[test]
+    fn test_temps_released() {
+        let mut vm = new_vm();
+        // The arg of each add is dead once it is added, the next arg reuses its register.
+        assert_eq!(
+            frame_regs(&mut vm, "(+ 1 (car '(2)) (car '(3)) (car '(4)))"),
+            1
+        );
+        // The test is dead once the jump is emitted so the branch can use its register.
+        assert_eq!(
+            frame_regs(&mut vm, "(if (car '(1)) (car '(2)) (car '(3)) 4)"),
+            0
+        );
+        assert_eq!(
+            eval(&mut vm, "(+ 1 (car '(2)) (car '(3)) (car '(4)))"),
+            "10"
+        );
+        assert_eq!(
+            eval(
+                &mut vm,
+                "(cons (vec-nth '#(1 2) 0) (cons (vec-nth '#(3 4) 1) nil))"
+            ),
+            "(1 4)"
+        );
+    }
+}
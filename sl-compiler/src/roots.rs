@@ -0,0 +1,179 @@
+use std::cell::{Cell, RefCell};
+use std::collections::HashMap;
+
+use slvm::value::*;
+use slvm::vm::*;
+
+/// The heap values only the compiler references (the forms being compiled, macro expansions,
+/// lambdas in a chunk that has not run yet) in the order they were rooted.  Each is made sticky
+/// so the GC marks it, how many times it is rooted is counted here since the same value can be
+/// rooted more than once (a macro returning its arg) and sticky is not counted.
+#[derive(Default)]
+struct Roots {
+    stack: Vec<Value>,
+    counts: HashMap<Value, usize>,
+}
+
+thread_local! {
+    /// The roots of the compiles on this thread.
+    static ROOTS: RefCell<Roots> = RefCell::new(Roots::default());
+    /// The GC stress mode of the compiles on this thread.
+    static GC_STRESS: Cell<u8> = Cell::new(STRESS_UNKNOWN);
+}
+
+/// Environment variable that turns on the GC stress mode (any value but 0).
+const GC_STRESS_ENV: &str = "SLOSH_GC_STRESS";
+
+const STRESS_UNKNOWN: u8 = 0;
+const STRESS_OFF: u8 = 1;
+const STRESS_ON: u8 = 2;
+
+/// The current roots, pass to release_roots to drop the ones rooted after this.
+pub fn roots_mark() -> usize {
+    ROOTS.with(|roots| roots.borrow().stack.len())
+}
+
+/// Keep val from being collected until release_roots is called with a mark taken before now.
+pub fn root(vm: &mut Vm, val: Value) {
+    if let Some(handle) = val.get_handle() {
+        vm.heap_sticky(handle);
+        ROOTS.with(|roots| {
+            let mut roots = roots.borrow_mut();
+            roots.stack.push(val);
+            *roots.counts.entry(val).or_insert(0) += 1;
+        });
+    }
+}
+
+/// Drop the roots added since mark was taken, a value is no longer sticky once none of its
+/// roots are left.
+pub fn release_roots(vm: &mut Vm, mark: usize) {
+    let released = ROOTS.with(|roots| {
+        let mut roots = roots.borrow_mut();
+        let mut released = Vec::new();
+        while roots.stack.len() > mark {
+            // Unwrap safe, the stack is longer than mark.
+            let val = roots.stack.pop().unwrap();
+            if let Some(count) = roots.counts.get_mut(&val) {
+                *count -= 1;
+                if *count == 0 {
+                    roots.counts.remove(&val);
+                    released.push(val);
+                }
+            }
+        }
+        released
+    });
+    for val in released {
+        // Unwrap safe, only heap values are rooted.
+        vm.heap_unsticky(val.get_handle().unwrap());
+    }
+}
+
+/// Turn the GC stress mode on or off for this thread, overrides SLOSH_GC_STRESS.
+pub fn set_gc_stress(on: bool) {
+    GC_STRESS.with(|stress| stress.set(if on { STRESS_ON } else { STRESS_OFF }));
+}
+
+/// True if the GC stress mode is on, from set_gc_stress or else SLOSH_GC_STRESS.
+pub fn gc_stress() -> bool {
+    match GC_STRESS.with(|stress| stress.get()) {
+        STRESS_UNKNOWN => {
+            let on = std::env::var(GC_STRESS_ENV)
+                .map(|v| v != "0")
+                .unwrap_or(false);
+            set_gc_stress(on);
+            on
+        }
+        state => state == STRESS_ON,
+    }
+}
+
+/// A point where the compiler has allocated or is about to run a macro, collect now in the GC
+/// stress mode so a heap value the compiler still uses but did not root is freed right away
+/// instead of rarely.  Allocations the VM makes itself (while a macro or eval-when runs) are
+/// collected when the VM decides, the stress mode does not force those.
+pub fn gc_point(vm: &mut Vm) {
+    if gc_stress() {
+        vm.gc();
+    }
+}
+
+/// Root val, a heap value the compiler just allocated, then collect in the GC stress mode.
+pub fn root_new(vm: &mut Vm, val: Value) -> Value {
+    root(vm, val);
+    gc_point(vm);
+    val
+}
+
+/// Allocate the pair (car . cdr) rooted, see root_new.  Code building a form from several
+/// pairs uses this so each piece is kept until the whole form is referenced.
+pub fn alloc_pair_rooted(vm: &mut Vm, car: Value, cdr: Value) -> Value {
+    let pair = vm.alloc_pair_ro(car, cdr);
+    root_new(vm, pair)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::test_util::*;
+
+    #[test]
+    fn test_root_counted() {
+        let mut vm = Vm::new();
+        let pair = vm.alloc_pair_ro(Value::Int(1), Value::Nil);
+        let outer = roots_mark();
+        root(&mut vm, pair);
+        let inner = roots_mark();
+        // A macro returning its arg roots the same value again.
+        root(&mut vm, pair);
+        release_roots(&mut vm, inner);
+        assert_eq!(roots_mark(), inner);
+        vm.gc();
+        assert_eq!(pair.display_value(&vm), "(1)");
+        release_roots(&mut vm, outer);
+        assert_eq!(roots_mark(), outer);
+    }
+
+    #[test]
+    fn test_gc_stress() {
+        // Only this test's thread collects at each gc_point.
+        set_gc_stress(true);
+        let mut vm = new_vm();
+        assert_eq!(
+            eval(
+                &mut vm,
+                "(defmacro swap! (a b) `(let (tmp# ,a) (set! ,a ,b) (set! ,b tmp#)))
+                 (let (x 1 y 2) (swap! x y) (list x y))"
+            ),
+            "(2 1)"
+        );
+        assert_eq!(
+            eval(
+                &mut vm,
+                "(macrolet ((twice (x) `(do ,x ,x))) (let (n 0) (twice (inc! n)) n))"
+            ),
+            "2"
+        );
+        assert_eq!(
+            eval(&mut vm, "(defn adder (n) (fn (x) (+ x n))) ((adder 2) 3)"),
+            "5"
+        );
+        assert_eq!(
+            eval(
+                &mut vm,
+                "(defn c (n) (case n (1 :one) (2 :two) (3 :three) (4 :four) (else :other))) (c 3)"
+            ),
+            ":three"
+        );
+        assert_eq!(
+            eval(
+                &mut vm,
+                "(ns stress-lib) (def x 5) (ns stress-app) (import stress-lib) x"
+            ),
+            "5"
+        );
+        set_gc_stress(false);
+    }
+}
@@ -0,0 +1,799 @@
+use std::collections::HashMap;
+use std::sync::Arc;
+
+use slvm::chunk::*;
+use slvm::interner::*;
+use slvm::value::*;
+use slvm::vm::*;
+use slvm::Handle;
+
+use crate::error::*;
+use crate::state::*;
+
+/// First bytes of a compiled (.slc) file.
+pub const SLC_MAGIC: &[u8; 4] = b"SLC\0";
+/// Bumped whenever the layout below changes, older files must be recompiled.
+pub const SLC_VERSION: u32 = 2;
+
+// Value tags.
+const TAG_NIL: u8 = 0;
+const TAG_TRUE: u8 = 1;
+const TAG_FALSE: u8 = 2;
+const TAG_INT: u8 = 3;
+const TAG_UINT: u8 = 4;
+const TAG_FLOAT: u8 = 5;
+const TAG_BYTE: u8 = 6;
+const TAG_CODE_POINT: u8 = 7;
+const TAG_CHAR_CLUSTER: u8 = 8;
+const TAG_CHAR_CLUSTER_LONG: u8 = 9;
+const TAG_SYMBOL: u8 = 10;
+const TAG_KEYWORD: u8 = 11;
+const TAG_STRING_CONST: u8 = 12;
+const TAG_STRING: u8 = 13;
+const TAG_PAIR: u8 = 14;
+const TAG_VECTOR: u8 = 15;
+const TAG_LAMBDA: u8 = 16;
+const TAG_MACRO: u8 = 17;
+
+// Global ref tags.
+const TAG_REFI: u8 = 0;
+const TAG_CALLG: u8 = 1;
+const TAG_TCALLG: u8 = 2;
+
+/// Builds a compiled file from top level chunks.
+///
+/// The file is (all integers little endian):
+/// - magic and version
+/// - symbol table, every interned string used by the chunks
+/// - chunk table, nested lambdas come before the chunks that use them
+/// - the indexes of the top level chunks in the order they run
+///
+/// Each chunk has its code, line table, arg/register counts, captures, dbg_args, constants,
+/// the global refs needed to relink its code into the loading VM and its jumps (a relinked
+/// global can change size, moving the code after it).
+pub struct SlcWriter {
+    symbols: Vec<&'static str>,
+    symbol_idx: HashMap<Interned, u32>,
+    chunks: Vec<u8>,
+    num_chunks: u32,
+    /// Index of each lambda chunk written by chunk id.
+    chunk_idx: HashMap<u64, u32>,
+    top_level: Vec<u32>,
+}
+
+impl Default for SlcWriter {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl SlcWriter {
+    pub fn new() -> Self {
+        SlcWriter {
+            symbols: Vec::new(),
+            symbol_idx: HashMap::new(),
+            chunks: Vec::new(),
+            num_chunks: 0,
+            chunk_idx: HashMap::new(),
+            top_level: Vec::new(),
+        }
+    }
+
+    /// Add a compiled top level form.  state is the state it was compiled with (it holds the
+    /// global refs for the chunk), the refs of the lambdas it contains were recorded when they
+    /// were compiled.
+    pub fn add_top_level(
+        &mut self,
+        vm: &Vm,
+        chunk: &Arc<Chunk>,
+        state: &CompileState,
+    ) -> CompileResult<()> {
+        let idx = self.add_chunk(vm, chunk, &state.global_refs, &state.jumps)?;
+        self.top_level.push(idx);
+        Ok(())
+    }
+
+    /// The complete file contents.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut out = Vec::new();
+        out.extend_from_slice(SLC_MAGIC);
+        put_u32(&mut out, SLC_VERSION);
+        put_u32(&mut out, self.symbols.len() as u32);
+        for sym in &self.symbols {
+            put_str(&mut out, sym);
+        }
+        put_u32(&mut out, self.num_chunks);
+        out.extend_from_slice(&self.chunks);
+        put_u32(&mut out, self.top_level.len() as u32);
+        for idx in &self.top_level {
+            put_u32(&mut out, *idx);
+        }
+        out
+    }
+
+    fn symbol(&mut self, vm: &Vm, i: Interned) -> u32 {
+        if let Some(idx) = self.symbol_idx.get(&i) {
+            *idx
+        } else {
+            let idx = self.symbols.len() as u32;
+            self.symbols.push(vm.get_interned(i));
+            self.symbol_idx.insert(i, idx);
+            idx
+        }
+    }
+
+    fn add_chunk(
+        &mut self,
+        vm: &Vm,
+        chunk: &Arc<Chunk>,
+        global_refs: &[GlobalRef],
+        jumps: &[JumpOperand],
+    ) -> CompileResult<u32> {
+        let mut out = Vec::new();
+        put_str(&mut out, chunk.file_name);
+        put_u32(&mut out, chunk.code.len() as u32);
+        out.extend_from_slice(&chunk.code);
+        // Line table as runs of (start offset, line), 0 for no line.
+        let mut lines = Vec::new();
+        let mut last_line = None;
+        for offset in 0..chunk.code.len() {
+            let line = chunk.offset_to_line(offset).unwrap_or(0) as u32;
+            if last_line != Some(line) {
+                lines.push((offset as u32, line));
+                last_line = Some(line);
+            }
+        }
+        put_u32(&mut out, lines.len() as u32);
+        for (offset, line) in lines {
+            put_u32(&mut out, offset);
+            put_u32(&mut out, line);
+        }
+        put_u16(&mut out, chunk.args);
+        put_u16(&mut out, chunk.opt_args);
+        out.push(chunk.rest as u8);
+        put_u32(&mut out, chunk.input_regs as u32);
+        put_u32(&mut out, chunk.extra_regs as u32);
+        if let Some(captures) = &chunk.captures {
+            out.push(1);
+            put_u32(&mut out, captures.len() as u32);
+            for c in captures {
+                put_u32(&mut out, *c);
+            }
+        } else {
+            out.push(0);
+        }
+        if let Some(dbg_args) = &chunk.dbg_args {
+            out.push(1);
+            put_u32(&mut out, dbg_args.len() as u32);
+            for a in dbg_args {
+                let a = self.symbol(vm, *a);
+                put_u32(&mut out, a);
+            }
+        } else {
+            out.push(0);
+        }
+        put_u32(&mut out, chunk.constants.len() as u32);
+        for c in &chunk.constants {
+            self.put_value(vm, &mut out, *c)?;
+        }
+        put_u32(&mut out, global_refs.len() as u32);
+        for gref in global_refs {
+            put_u32(&mut out, gref.start as u32);
+            put_u32(&mut out, gref.end as u32);
+            let name = self.symbol(vm, gref.name);
+            put_u32(&mut out, name);
+            match gref.op {
+                GlobalRefOp::Refi(reg) => {
+                    out.push(TAG_REFI);
+                    put_u16(&mut out, reg);
+                }
+                GlobalRefOp::Callg(num_args, result) => {
+                    out.push(TAG_CALLG);
+                    put_u16(&mut out, num_args);
+                    put_u16(&mut out, result);
+                }
+                GlobalRefOp::Tcallg(num_args) => {
+                    out.push(TAG_TCALLG);
+                    put_u16(&mut out, num_args);
+                }
+            }
+        }
+        put_u32(&mut out, jumps.len() as u32);
+        for jump in jumps {
+            put_u32(&mut out, jump.offset_pos as u32);
+            put_u32(&mut out, jump.start_ip as u32);
+            out.extend_from_slice(&jump.rel.to_le_bytes());
+        }
+        // Any lambdas in the constants have been written by now so this chunk can refer to them.
+        let idx = self.num_chunks;
+        self.num_chunks += 1;
+        self.chunks.extend_from_slice(&out);
+        Ok(idx)
+    }
+
+    fn put_value(&mut self, vm: &Vm, out: &mut Vec<u8>, val: Value) -> CompileResult<()> {
+        match val {
+            Value::Nil => out.push(TAG_NIL),
+            Value::True => out.push(TAG_TRUE),
+            Value::False => out.push(TAG_FALSE),
+            Value::Int(i) => {
+                out.push(TAG_INT);
+                out.extend_from_slice(&i.to_le_bytes());
+            }
+            Value::UInt(i) => {
+                out.push(TAG_UINT);
+                out.extend_from_slice(&i.to_le_bytes());
+            }
+            Value::Float(_) => {
+                out.push(TAG_FLOAT);
+                out.extend_from_slice(&val.get_float()?.to_bits().to_le_bytes());
+            }
+            Value::Byte(b) => {
+                out.push(TAG_BYTE);
+                out.push(b);
+            }
+            Value::CodePoint(ch) => {
+                out.push(TAG_CODE_POINT);
+                put_u32(out, ch as u32);
+            }
+            Value::CharCluster(len, chars) => {
+                out.push(TAG_CHAR_CLUSTER);
+                out.push(len);
+                out.extend_from_slice(&chars);
+            }
+            Value::CharClusterLong(h) => {
+                out.push(TAG_CHAR_CLUSTER_LONG);
+                put_str(out, vm.get_string(h));
+            }
+            Value::Symbol(i) => {
+                out.push(TAG_SYMBOL);
+                let i = self.symbol(vm, i);
+                put_u32(out, i);
+            }
+            Value::Keyword(i) => {
+                out.push(TAG_KEYWORD);
+                let i = self.symbol(vm, i);
+                put_u32(out, i);
+            }
+            Value::StringConst(i) => {
+                out.push(TAG_STRING_CONST);
+                let i = self.symbol(vm, i);
+                put_u32(out, i);
+            }
+            Value::String(h) => {
+                out.push(TAG_STRING);
+                put_str(out, vm.get_string(h));
+            }
+            Value::Pair(h) => {
+                let (car, cdr) = vm.get_pair(h);
+                out.push(TAG_PAIR);
+                self.put_value(vm, out, car)?;
+                self.put_value(vm, out, cdr)?;
+            }
+            Value::Vector(h) => {
+                let v = vm.get_vector(h);
+                out.push(TAG_VECTOR);
+                put_u32(out, v.len() as u32);
+                for item in v {
+                    self.put_value(vm, out, *item)?;
+                }
+            }
+            Value::Lambda(h) => {
+                let id = chunk_id(vm, h);
+                let idx = match id.and_then(|id| self.chunk_idx.get(&id)) {
+                    Some(idx) => *idx,
+                    None => {
+                        // Without its refs the code would keep this VM's global slots and the
+                        // loading VM would call the wrong globals.
+                        let (id, refs) = match id.and_then(|id| Some((id, chunk_refs(id)?))) {
+                            Some(id_refs) => id_refs,
+                            None => {
+                                return Err(CompileError::new(
+                                    CompileErrorKind::Bytecode,
+                                    "can not write a lambda loaded from a compiled file to \
+                                     compiled output",
+                                ))
+                            }
+                        };
+                        let chunk = vm.get_lambda(h);
+                        let idx = self.add_chunk(vm, &chunk, &refs.global_refs, &refs.jumps)?;
+                        self.chunk_idx.insert(id, idx);
+                        idx
+                    }
+                };
+                if let Some(Value::True) = vm.get_heap_property(h, ":macro") {
+                    out.push(TAG_MACRO);
+                } else {
+                    out.push(TAG_LAMBDA);
+                }
+                put_u32(out, idx);
+            }
+            _ => {
+                return Err(CompileError::new(
+                    CompileErrorKind::Bytecode,
+                    format!(
+                        "can not write a {} constant to compiled output",
+                        val.display_type(vm)
+                    ),
+                ))
+            }
+        }
+        Ok(())
+    }
+}
+
+fn put_u16(out: &mut Vec<u8>, v: u16) {
+    out.extend_from_slice(&v.to_le_bytes());
+}
+
+fn put_u32(out: &mut Vec<u8>, v: u32) {
+    out.extend_from_slice(&v.to_le_bytes());
+}
+
+fn put_str(out: &mut Vec<u8>, s: &str) {
+    put_u32(out, s.len() as u32);
+    out.extend_from_slice(s.as_bytes());
+}
+
+/// True if bytes look like a compiled (.slc) file.
+pub fn is_slc(bytes: &[u8]) -> bool {
+    bytes.starts_with(SLC_MAGIC)
+}
+
+/// Read a compiled file and return its top level chunks in the order they should be run.
+///
+/// Global slots in the code are relinked by name against vm.  Heap values the chunks use
+/// (quoted lists, lambdas, etc) are left sticky so the caller can run the chunks without them
+/// being collected, pass the returned handles to vm.heap_unsticky when done.
+pub fn read_slc(vm: &mut Vm, bytes: &[u8]) -> CompileResult<(Vec<Arc<Chunk>>, Vec<Handle>)> {
+    let mut reader = SlcReader {
+        bytes,
+        pos: 0,
+        symbols: Vec::new(),
+        chunks: Vec::new(),
+        sticky: Vec::new(),
+    };
+    vm.pause_gc();
+    let res = reader.read(vm);
+    vm.unpause_gc();
+    match res {
+        Ok(top_level) => Ok((top_level, reader.sticky)),
+        Err(err) => {
+            for h in reader.sticky {
+                vm.heap_unsticky(h);
+            }
+            Err(err)
+        }
+    }
+}
+
+struct SlcReader<'a> {
+    bytes: &'a [u8],
+    pos: usize,
+    symbols: Vec<Interned>,
+    chunks: Vec<Arc<Chunk>>,
+    sticky: Vec<Handle>,
+}
+
+impl<'a> SlcReader<'a> {
+    fn read(&mut self, vm: &mut Vm) -> CompileResult<Vec<Arc<Chunk>>> {
+        if self.take(4)? != SLC_MAGIC {
+            return Err(bad_file("not a compiled file"));
+        }
+        let version = self.u32()?;
+        if version != SLC_VERSION {
+            return Err(bad_file(format!(
+                "compiled file version {} is not supported (expected {}), recompile it",
+                version, SLC_VERSION
+            )));
+        }
+        let num_symbols = self.u32()?;
+        for _ in 0..num_symbols {
+            let s = self.str()?;
+            let i = vm.intern(s);
+            self.symbols.push(i);
+        }
+        let num_chunks = self.u32()?;
+        for _ in 0..num_chunks {
+            let chunk = self.chunk(vm)?;
+            self.chunks.push(chunk);
+        }
+        let num_top = self.u32()?;
+        let mut top_level = Vec::new();
+        for _ in 0..num_top {
+            let idx = self.u32()? as usize;
+            if let Some(chunk) = self.chunks.get(idx) {
+                top_level.push(chunk.clone());
+            } else {
+                return Err(bad_file("invalid chunk index"));
+            }
+        }
+        if self.pos != self.bytes.len() {
+            return Err(bad_file("trailing data"));
+        }
+        Ok(top_level)
+    }
+
+    fn chunk(&mut self, vm: &mut Vm) -> CompileResult<Arc<Chunk>> {
+        let file_name = self.str()?;
+        let file_name = vm.intern(file_name);
+        let file_name = vm.get_interned(file_name);
+        let code_len = self.u32()? as usize;
+        let code = self.take(code_len)?;
+        let num_lines = self.u32()?;
+        let mut lines = Vec::new();
+        for _ in 0..num_lines {
+            let offset = self.u32()? as usize;
+            let line = self.u32()?;
+            lines.push((offset, line));
+        }
+        let first_line = lines.first().map(|(_, l)| *l).unwrap_or(1);
+        let mut chunk = Chunk::new(file_name, first_line);
+        chunk.args = self.u16()?;
+        chunk.opt_args = self.u16()?;
+        chunk.rest = self.u8()? != 0;
+        chunk.input_regs = self.u32()? as usize;
+        chunk.extra_regs = self.u32()? as usize;
+        if self.u8()? != 0 {
+            let len = self.u32()?;
+            let mut captures = Vec::new();
+            for _ in 0..len {
+                captures.push(self.u32()?);
+            }
+            chunk.captures = Some(captures);
+        }
+        if self.u8()? != 0 {
+            let len = self.u32()?;
+            let mut dbg_args = Vec::new();
+            for _ in 0..len {
+                dbg_args.push(self.symbol()?);
+            }
+            chunk.dbg_args = Some(dbg_args);
+        }
+        let num_constants = self.u32()?;
+        for _ in 0..num_constants {
+            let val = self.value(vm)?;
+            if let Some(h) = val.get_handle() {
+                vm.heap_sticky(h);
+                self.sticky.push(h);
+            }
+            chunk.add_constant(val);
+        }
+        let num_refs = self.u32()?;
+        let mut global_refs = Vec::new();
+        for _ in 0..num_refs {
+            let start = self.u32()? as usize;
+            let end = self.u32()? as usize;
+            let name = self.symbol()?;
+            let op = match self.u8()? {
+                TAG_REFI => GlobalRefOp::Refi(self.u16()?),
+                TAG_CALLG => GlobalRefOp::Callg(self.u16()?, self.u16()?),
+                TAG_TCALLG => GlobalRefOp::Tcallg(self.u16()?),
+                _ => return Err(bad_file("invalid global ref")),
+            };
+            global_refs.push(GlobalRef {
+                start,
+                end,
+                name,
+                op,
+            });
+        }
+        let num_jumps = self.u32()?;
+        let mut jumps = Vec::new();
+        for _ in 0..num_jumps {
+            let offset_pos = self.u32()? as usize;
+            let start_ip = self.u32()? as usize;
+            let rel = i32::from_le_bytes(self.array()?);
+            jumps.push(JumpOperand {
+                offset_pos,
+                start_ip,
+                rel,
+            });
+        }
+        relink(vm, &mut chunk, code, &lines, &global_refs, &jumps)?;
+        Ok(Arc::new(chunk))
+    }
+
+    fn value(&mut self, vm: &mut Vm) -> CompileResult<Value> {
+        Ok(match self.u8()? {
+            TAG_NIL => Value::Nil,
+            TAG_TRUE => Value::True,
+            TAG_FALSE => Value::False,
+            TAG_INT => Value::Int(i64::from_le_bytes(self.array()?)),
+            TAG_UINT => Value::UInt(u64::from_le_bytes(self.array()?)),
+            TAG_FLOAT => Value::float(f64::from_bits(u64::from_le_bytes(self.array()?))),
+            TAG_BYTE => Value::Byte(self.u8()?),
+            TAG_CODE_POINT => match std::char::from_u32(self.u32()?) {
+                Some(ch) => Value::CodePoint(ch),
+                None => return Err(bad_file("invalid char")),
+            },
+            TAG_CHAR_CLUSTER => {
+                let len = self.u8()?;
+                Value::CharCluster(len, self.array()?)
+            }
+            TAG_CHAR_CLUSTER_LONG => {
+                let s = self.str()?.to_string();
+                if let Value::String(h) = vm.alloc_string_ro(s) {
+                    Value::CharClusterLong(h)
+                } else {
+                    return Err(bad_file("invalid char"));
+                }
+            }
+            TAG_SYMBOL => Value::Symbol(self.symbol()?),
+            TAG_KEYWORD => Value::Keyword(self.symbol()?),
+            TAG_STRING_CONST => Value::StringConst(self.symbol()?),
+            TAG_STRING => {
+                let s = self.str()?.to_string();
+                vm.alloc_string_ro(s)
+            }
+            TAG_PAIR => {
+                let car = self.value(vm)?;
+                let cdr = self.value(vm)?;
+                vm.alloc_pair_ro(car, cdr)
+            }
+            TAG_VECTOR => {
+                let len = self.u32()?;
+                let mut v = Vec::new();
+                for _ in 0..len {
+                    v.push(self.value(vm)?);
+                }
+                vm.alloc_vector_ro(v)
+            }
+            tag @ TAG_LAMBDA | tag @ TAG_MACRO => {
+                let idx = self.u32()? as usize;
+                let chunk = if let Some(chunk) = self.chunks.get(idx) {
+                    chunk.clone()
+                } else {
+                    return Err(bad_file("invalid chunk index"));
+                };
+                let lambda = vm.alloc_lambda(chunk);
+                if tag == TAG_MACRO {
+                    // Unwrap safe since we just allocated lambda on the heap.
+                    vm.set_heap_property(lambda.get_handle().unwrap(), ":macro", Value::True);
+                }
+                lambda
+            }
+            _ => return Err(bad_file("invalid constant")),
+        })
+    }
+
+    fn take(&mut self, len: usize) -> CompileResult<&'a [u8]> {
+        if self.pos + len > self.bytes.len() {
+            return Err(bad_file("unexpected end of file"));
+        }
+        let bytes = self.bytes;
+        let res = &bytes[self.pos..self.pos + len];
+        self.pos += len;
+        Ok(res)
+    }
+
+    fn array<const N: usize>(&mut self) -> CompileResult<[u8; N]> {
+        let mut res = [0; N];
+        res.copy_from_slice(self.take(N)?);
+        Ok(res)
+    }
+
+    fn u8(&mut self) -> CompileResult<u8> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn u16(&mut self) -> CompileResult<u16> {
+        Ok(u16::from_le_bytes(self.array()?))
+    }
+
+    fn u32(&mut self) -> CompileResult<u32> {
+        Ok(u32::from_le_bytes(self.array()?))
+    }
+
+    fn str(&mut self) -> CompileResult<&'a str> {
+        let len = self.u32()? as usize;
+        std::str::from_utf8(self.take(len)?).map_err(|_| bad_file("invalid string"))
+    }
+
+    fn symbol(&mut self) -> CompileResult<Interned> {
+        let idx = self.u32()? as usize;
+        self.symbols
+            .get(idx)
+            .copied()
+            .ok_or_else(|| bad_file("invalid symbol index"))
+    }
+}
+
+/// Encode code into chunk with the line table, re-encoding each global ref with vm's slot.  A
+/// slot can take more or fewer bytes than it did in the compiling VM, the code after it moves
+/// and the jumps are re-encoded to their target's new offset.
+fn relink(
+    vm: &mut Vm,
+    chunk: &mut Chunk,
+    code: &[u8],
+    lines: &[(usize, u32)],
+    global_refs: &[GlobalRef],
+    jumps: &[JumpOperand],
+) -> CompileResult<()> {
+    let line_at = |offset: usize| -> Option<u32> {
+        match lines.iter().rev().find(|(start, _)| *start <= offset) {
+            Some((_, 0)) | None => None,
+            Some((_, line)) => Some(*line),
+        }
+    };
+    let mut offset = 0;
+    // The old and new end of each global ref, code after one is moved by the difference.
+    let mut moved: Vec<(usize, usize)> = Vec::new();
+    let mut refs = global_refs.to_vec();
+    refs.sort_by_key(|r| r.start);
+    for gref in refs {
+        if gref.start < offset || gref.end > code.len() {
+            return Err(bad_file("invalid global ref"));
+        }
+        // Replaying the bytes through encode0 lets the chunk build its own line table.
+        while offset < gref.start {
+            chunk.encode0(code[offset], line_at(offset))?;
+            offset += 1;
+        }
+        let line = line_at(offset);
+        let slot = vm.reserve_index(gref.name);
+        match gref.op {
+            GlobalRefOp::Refi(reg) => chunk.encode_refi(reg, slot, line)?,
+            GlobalRefOp::Callg(num_args, result) => {
+                chunk.encode_callg(slot, num_args, result, line)?
+            }
+            GlobalRefOp::Tcallg(num_args) => chunk.encode_tcallg(slot, num_args, line)?,
+        }
+        moved.push((gref.end, chunk.code.len()));
+        offset = gref.end;
+    }
+    while offset < code.len() {
+        chunk.encode0(code[offset], line_at(offset))?;
+        offset += 1;
+    }
+    if moved.iter().all(|(old, new)| old == new) {
+        return Ok(());
+    }
+    let new_offset = |old: usize| -> usize {
+        match moved.iter().rev().find(|(end, _)| *end <= old) {
+            Some((end, new_end)) => old - end + new_end,
+            None => old,
+        }
+    };
+    for jump in jumps {
+        let target = jump.start_ip as i64 + jump.rel as i64;
+        if jump.offset_pos >= jump.start_ip || jump.start_ip > code.len() {
+            return Err(bad_file("invalid jump"));
+        }
+        if target < 0 || target > code.len() as i64 {
+            return Err(bad_file("invalid jump"));
+        }
+        let start_ip = new_offset(jump.start_ip);
+        let rel = new_offset(target as usize) as i32 - start_ip as i32;
+        chunk.reencode_jump_offset(new_offset(jump.offset_pos), rel)?;
+    }
+    Ok(())
+}
+
+fn bad_file<S: Into<String>>(msg: S) -> CompileError {
+    CompileError::new(CompileErrorKind::Bytecode, msg)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::compile::*;
+    use crate::prelude::*;
+    use crate::reader::*;
+    use crate::test_util::*;
+
+    /// Compile and run text in vm, return the .slc bytes and the value of the last form.
+    fn write_slc(vm: &mut Vm, text: &str) -> (Vec<u8>, String) {
+        let mut reader_state = ReaderState::new();
+        let exps = read_all(vm, &mut reader_state, text).unwrap();
+        let mut writer = SlcWriter::new();
+        let mut last = String::new();
+        for exp in exps {
+            let mut linenum = 1;
+            let mut line = Some(&mut linenum);
+            let mut state = CompileState::new_state(vm, "test", 1, None);
+            state.chunk.dbg_args = Some(Vec::new());
+            compile_top_level(vm, &mut state, exp, &mut line).unwrap();
+            let chunk = Arc::new(state.chunk.clone());
+            writer.add_top_level(vm, &chunk, &state).unwrap();
+            vm.execute(chunk).unwrap();
+            last = vm.get_stack(0).display_value(vm);
+        }
+        (writer.to_bytes(), last)
+    }
+
+    /// Load bytes in vm and return the value of the last top level chunk.
+    fn run_slc(vm: &mut Vm, bytes: &[u8]) -> String {
+        let (chunks, sticky) = read_slc(vm, bytes).unwrap();
+        for chunk in chunks {
+            vm.execute(chunk).unwrap();
+        }
+        let last = vm.get_stack(0).display_value(vm);
+        for h in sticky {
+            vm.heap_unsticky(h);
+        }
+        last
+    }
+
+    const SCRIPT: &str = "(defn fact (n) (if (< n 2) 1 (* n (fact (- n 1)))))
+        (def scale 3)
+        (defn go (l) (let ((acc '())) (for x l (set! acc (cons (* scale (fact x)) acc))) acc))
+        (go '(1 2 3 4))";
+
+    #[test]
+    fn test_round_trip() {
+        let mut vm = new_vm();
+        let (bytes, expected) = write_slc(&mut vm, SCRIPT);
+        assert_eq!(expected, "(72 18 6 3)");
+        assert!(is_slc(&bytes));
+
+        let mut vm = Vm::new();
+        assert_eq!(run_slc(&mut vm, &bytes), expected);
+    }
+
+    #[test]
+    fn test_relink_moves_jumps() {
+        let mut vm = new_vm();
+        let (bytes, expected) = write_slc(&mut vm, SCRIPT);
+
+        // Globals the compiling VM never had push the script's slots past u16, changing the
+        // size of the instructions that reference them.
+        let mut vm = Vm::new();
+        for i in 0..70_000 {
+            let name = vm.intern(&format!("filler-{}", i));
+            vm.reserve_index(name);
+        }
+        assert_eq!(run_slc(&mut vm, &bytes), expected);
+    }
+
+    #[test]
+    fn test_prelude_lambda_constant() {
+        let mut vm = new_vm();
+        // The expansion is the prelude's for macro itself, its chunk calls gensym by slot.
+        let (bytes, expected) = write_slc(
+            &mut vm,
+            "(defmacro prelude-for () for)
+             (def f (prelude-for))
+             (car (f 'x ''(1) 'x))",
+        );
+        assert_eq!(expected, "loop");
+
+        // Put gensym in another slot than the compiling VM had it in.
+        let mut vm = Vm::new();
+        for i in 0..10 {
+            let name = vm.intern(&format!("filler-{}", i));
+            vm.reserve_index(name);
+        }
+        load_prelude(&mut vm).unwrap();
+        assert_eq!(run_slc(&mut vm, &bytes), expected);
+    }
+
+    #[test]
+    fn test_loaded_lambda_not_written() {
+        let mut vm = new_vm();
+        let (bytes, _) = write_slc(&mut vm, "(defn f () 1)");
+        let mut vm = new_vm();
+        run_slc(&mut vm, &bytes);
+        eval(&mut vm, "(defmacro loaded-f () f)");
+        let mut reader_state = ReaderState::new();
+        let exp = read_all(&mut vm, &mut reader_state, "(def g (loaded-f))").unwrap()[0];
+        let mut state = CompileState::new_state(&mut vm, "test", 1, None);
+        compile_top_level(&mut vm, &mut state, exp, &mut None).unwrap();
+        let chunk = Arc::new(state.chunk.clone());
+        let e = SlcWriter::new()
+            .add_top_level(&vm, &chunk, &state)
+            .err()
+            .unwrap();
+        assert!(matches!(e.kind, CompileErrorKind::Bytecode));
+    }
+
+    #[test]
+    fn test_bad_version() {
+        let mut vm = Vm::new();
+        let mut bytes = SLC_MAGIC.to_vec();
+        bytes.extend_from_slice(&(SLC_VERSION + 1).to_le_bytes());
+        let e = read_slc(&mut vm, &bytes).err().unwrap();
+        assert!(matches!(e.kind, CompileErrorKind::Bytecode));
+    }
+}
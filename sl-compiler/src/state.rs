@@ -1,15 +1,24 @@
 use std::cell::RefCell;
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 use std::rc::Rc;
+use std::sync::atomic::{AtomicU64, Ordering};
 
 use slvm::chunk::*;
+use slvm::error::*;
 use slvm::interner::*;
 use slvm::value::*;
 use slvm::vm::*;
+use slvm::Handle;
+
+use crate::warning::*;
 
 #[derive(Clone, Debug)]
 pub struct SymbolsInt {
     pub syms: HashMap<Interned, usize>,
+    /// Local macros (macrolet) visible in this scope.
+    pub macros: HashMap<Interned, Value>,
+    /// Lint info for the named locals in this scope.
+    pub bindings: HashMap<Interned, Rc<Binding>>,
     count: usize,
 }
 
@@ -18,6 +27,18 @@ impl SymbolsInt {
         self.syms.insert(sym, self.count);
         self.count += 1;
     }
+
+    /// Take the next index without a name (a value that is destructured into named locals).
+    pub fn add_anon(&mut self) -> usize {
+        let count = self.count;
+        self.count += 1;
+        count
+    }
+
+    /// Number of indexes taken, named or not.
+    pub fn count(&self) -> usize {
+        self.count
+    }
 }
 
 // symbol name, idx/reg for scope, idx/reg for outer scope
@@ -34,6 +55,8 @@ impl Symbols {
     pub fn with_outer(outer: Option<Rc<RefCell<Symbols>>>) -> Symbols {
         let data = Rc::new(RefCell::new(SymbolsInt {
             syms: HashMap::new(),
+            macros: HashMap::new(),
+            bindings: HashMap::new(),
             count: 0,
         }));
         Symbols {
@@ -47,6 +70,8 @@ impl Symbols {
     pub fn with_let(source: Rc<RefCell<Symbols>>, result: usize) -> Symbols {
         let data = Rc::new(RefCell::new(SymbolsInt {
             syms: HashMap::new(),
+            macros: HashMap::new(),
+            bindings: HashMap::new(),
             count: 0,
         }));
         {
@@ -54,6 +79,12 @@ impl Symbols {
             for (key, val) in source.borrow().data.borrow().syms.iter() {
                 datad.syms.insert(*key, *val);
             }
+            for (key, val) in source.borrow().data.borrow().macros.iter() {
+                datad.macros.insert(*key, *val);
+            }
+            for (key, val) in source.borrow().data.borrow().bindings.iter() {
+                datad.bindings.insert(*key, val.clone());
+            }
             if result > 0 {
                 datad.count = result - 1;
             }
@@ -66,6 +97,14 @@ impl Symbols {
         }
     }
 
+    /// A scope that sees none of the locals around it (an inlined fn body), its locals start at
+    /// index count.
+    pub fn isolated(count: usize) -> Symbols {
+        let symbols = Symbols::with_outer(None);
+        symbols.data.borrow_mut().count = count;
+        symbols
+    }
+
     pub fn is_empty(&self) -> bool {
         self.data.borrow().syms.is_empty()
     }
@@ -109,15 +148,28 @@ impl Symbols {
 
     pub fn insert(&mut self, key: Interned) -> usize {
         let mut data = self.data.borrow_mut();
+        data.macros.remove(&key);
         let count = data.count;
         data.syms.insert(key, count);
         data.count += 1;
         count
     }
 
+    /// Name idx (an index taken with add_anon) key, a let* local is named once its value is
+    /// compiled so the value still sees what key meant before.
+    pub fn name_index(&mut self, key: Interned, idx: usize) {
+        let mut data = self.data.borrow_mut();
+        data.macros.remove(&key);
+        data.syms.insert(key, idx);
+    }
+
     pub fn insert_capture(&self, vm: &mut Vm, key: Interned) -> Option<usize> {
         let data_d = self.data.borrow();
         if let Some(idx) = data_d.syms.get(&key) {
+            // Captured by an inner fn so it is read.
+            if let Some(binding) = data_d.bindings.get(&key) {
+                binding.mark_read();
+            }
             Some(*idx)
         } else {
             if let Some(outer) = &self.outer {
@@ -136,6 +188,61 @@ impl Symbols {
         }
     }
 
+    /// Add a local macro to this scope.
+    pub fn insert_macro(&mut self, key: Interned, mac: Value) {
+        self.data.borrow_mut().macros.insert(key, mac);
+    }
+
+    /// Find the local macro key in this or an outer scope.  A local binding of key in a closer
+    /// scope hides the macro.
+    pub fn get_macro(&self, key: Interned) -> Option<Value> {
+        {
+            let data = self.data.borrow();
+            if let Some(mac) = data.macros.get(&key) {
+                return Some(*mac);
+            }
+            if data.syms.contains_key(&key) {
+                return None;
+            }
+        }
+        let mut loop_outer = self.outer.clone();
+        while let Some(outer) = loop_outer {
+            let outer = outer.borrow();
+            let data = outer.data.borrow();
+            if let Some(mac) = data.macros.get(&key) {
+                return Some(*mac);
+            }
+            if data.syms.contains_key(&key) {
+                return None;
+            }
+            drop(data);
+            loop_outer = outer.outer.clone();
+        }
+        None
+    }
+
+    /// The local macros visible in this scope (not hidden by a closer local of the same name).
+    pub fn visible_macros(&self) -> HashMap<Interned, Value> {
+        let mut scopes = vec![self.data.clone()];
+        let mut loop_outer = self.outer.clone();
+        while let Some(outer) = loop_outer {
+            let outer = outer.borrow();
+            scopes.push(outer.data.clone());
+            loop_outer = outer.outer.clone();
+        }
+        let mut macros = HashMap::new();
+        for scope in scopes.iter().rev() {
+            let scope = scope.borrow();
+            for sym in scope.syms.keys() {
+                macros.remove(sym);
+            }
+            for (name, mac) in scope.macros.iter() {
+                macros.insert(*name, *mac);
+            }
+        }
+        macros
+    }
+
     pub fn len_captures(&self) -> usize {
         self.captures.borrow().len()
     }
@@ -147,6 +254,22 @@ pub struct Specials {
     pub do_: Interned,
     pub fn_: Interned,
     pub mac_: Interned,
+    pub macrolet: Interned,
+    pub while_: Interned,
+    pub loop_: Interned,
+    pub dotimes: Interned,
+    pub dotimes_i: Interned,
+    pub for_each: Interned,
+    pub cond: Interned,
+    pub case: Interned,
+    pub match_: Interned,
+    pub ns: Interned,
+    pub in_ns: Interned,
+    pub import: Interned,
+    pub declare: Interned,
+    pub ignore_undefined: Interned,
+    pub inline: Interned,
+    pub eval_when: Interned,
     pub if_: Interned,
     pub add: Interned,
     pub sub: Interned,
@@ -194,6 +317,25 @@ pub struct Specials {
     pub on_error: Interned,
 
     pub rest: Interned,
+    pub key: Interned,
+    pub optional: Interned,
+    pub else_: Interned,
+    pub arrow: Interned,
+    pub wildcard: Interned,
+    /// Keyword marking a match guard (:when).
+    pub guard: Interned,
+    /// Keyword marking a multi-arity fn (:arities).
+    pub arities: Interned,
+    /// Keywords naming the phases of eval-when.
+    pub compile_phase: Interned,
+    pub load_phase: Interned,
+    pub execute_phase: Interned,
+    /// Keywords returned by type, used for runtime type checks.
+    pub int_type: Interned,
+    pub pair_type: Interned,
+    pub vector_type: Interned,
+    /// The builtin compiled case forms call to find char, keyword and symbol keys.
+    pub case_index: Interned,
 }
 
 impl Specials {
@@ -204,6 +346,22 @@ impl Specials {
             do_: vm.intern_static("do"),
             fn_: vm.intern_static("fn"),
             mac_: vm.intern_static("macro"),
+            macrolet: vm.intern_static("macrolet"),
+            while_: vm.intern_static("while"),
+            loop_: vm.intern_static("loop"),
+            dotimes: vm.intern_static("dotimes"),
+            dotimes_i: vm.intern_static("dotimes-i"),
+            for_each: vm.intern_static("for-each"),
+            cond: vm.intern_static("cond"),
+            case: vm.intern_static("case"),
+            match_: vm.intern_static("match"),
+            ns: vm.intern_static("ns"),
+            in_ns: vm.intern_static("in-ns"),
+            import: vm.intern_static("import"),
+            declare: vm.intern_static("declare"),
+            ignore_undefined: vm.intern_static("ignore-undefined"),
+            inline: vm.intern_static("inline"),
+            eval_when: vm.intern_static("eval-when"),
             if_: vm.intern_static("if"),
             add: vm.intern_static("+"),
             sub: vm.intern_static("-"),
@@ -251,18 +409,198 @@ impl Specials {
             on_error: vm.intern_static("on-error"),
 
             rest: vm.intern_static("&rest"),
+            key: vm.intern_static("&key"),
+            optional: vm.intern_static("&optional"),
+            else_: vm.intern_static("else"),
+            arrow: vm.intern_static("=>"),
+            wildcard: vm.intern_static("_"),
+            guard: vm.intern_static("when"),
+            arities: vm.intern_static("arities"),
+            compile_phase: vm.intern_static("compile"),
+            load_phase: vm.intern_static("load"),
+            execute_phase: vm.intern_static("execute"),
+            int_type: vm.intern_static("Int"),
+            pair_type: vm.intern_static("Pair"),
+            vector_type: vm.intern_static("Vector"),
+            case_index: vm.intern_static("case-index"),
         }
     }
 }
 
+/// The operands of an instruction that references a global slot, enough to encode it again.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum GlobalRefOp {
+    /// REFI with the destination register.
+    Refi(u16),
+    /// CALLG with the number of args and result register.
+    Callg(u16, u16),
+    /// TCALLG with the number of args.
+    Tcallg(u16),
+}
+
+/// A global slot encoded in a chunk's code (from start to end).  Slots are only valid in the VM
+/// that compiled the chunk, these let serialized code be relinked by name in another VM.
+#[derive(Copy, Clone, Debug)]
+pub struct GlobalRef {
+    pub start: usize,
+    pub end: usize,
+    pub name: Interned,
+    pub op: GlobalRefOp,
+}
+
+/// The offset operand of a jump, relative to start_ip (the end of the operand).
+#[derive(Copy, Clone, Debug)]
+pub struct JumpOperand {
+    pub offset_pos: usize,
+    pub start_ip: usize,
+    pub rel: i32,
+}
+
+/// What writing a compiled lambda's chunk needs besides the chunk.  A global slot can be
+/// encoded in a different number of bytes when relinked so the jumps are kept to move them.
+#[derive(Clone, Debug, Default)]
+pub struct ChunkRefs {
+    pub global_refs: Vec<GlobalRef>,
+    pub jumps: Vec<JumpOperand>,
+}
+
+/// Heap property holding the id of a compiled lambda's chunk, the key of its ChunkRefs.
+const CHUNK_ID: &str = ":chunk-id";
+
+static NEXT_CHUNK_ID: AtomicU64 = AtomicU64::new(1);
+
+thread_local! {
+    /// The ChunkRefs of every lambda compiled on this thread by chunk id.  Kept for the life of
+    /// the thread since a lambda compiled by any form (the prelude, an eval-when, a macro's
+    /// expansion) can end up in a later form's constants.
+    static CHUNK_REFS: RefCell<HashMap<u64, ChunkRefs>> = RefCell::new(HashMap::new());
+}
+
+/// Give the compiled lambda a new chunk id and return it.
+pub fn new_chunk_id(vm: &mut Vm, lambda: Value) -> u64 {
+    let id = NEXT_CHUNK_ID.fetch_add(1, Ordering::Relaxed);
+    if let Some(h) = lambda.get_handle() {
+        vm.set_heap_property(h, CHUNK_ID, Value::UInt(id));
+    }
+    id
+}
+
+/// Record the refs of the lambda with chunk id for writing it out later.
+pub fn record_chunk_refs(id: u64, refs: ChunkRefs) {
+    CHUNK_REFS.with(|chunk_refs| chunk_refs.borrow_mut().insert(id, refs));
+}
+
+/// The refs recorded for the lambda with chunk id, None if it was not compiled on this thread.
+pub fn chunk_refs(id: u64) -> Option<ChunkRefs> {
+    CHUNK_REFS.with(|chunk_refs| chunk_refs.borrow().get(&id).cloned())
+}
+
+/// The chunk id of a lambda compiled by new_chunk_id, None for one loaded from a .slc file.
+pub fn chunk_id(vm: &Vm, lambda: Handle) -> Option<u64> {
+    match vm.get_heap_property(lambda, CHUNK_ID) {
+        Some(Value::UInt(id)) => Some(id),
+        _ => None,
+    }
+}
+
+/// An instruction as it was emitted (code from pos to end), recorded for the peephole pass.
+#[derive(Clone, Debug)]
+pub struct Instr {
+    pub pos: usize,
+    pub end: usize,
+    /// None for an instruction the peephole pass leaves alone (a global reference).
+    pub op: Option<u8>,
+    pub args: Vec<u16>,
+    pub jump: Option<JumpOperand>,
+}
+
+/// Where recur jumps to inside a loop form.
+#[derive(Clone, Debug)]
+pub struct LoopTarget {
+    /// Code offset of the top of the loop body.
+    pub start: usize,
+    /// Registers of the loop bindings, recur moves its args into these.
+    pub regs: Vec<usize>,
+    /// Defers active when the loop started.
+    pub defers: usize,
+    /// Registers counting the defers pushed since the top of this loop's body and of each loop
+    /// around it in the chunk, this loop's last.  Used to pop them when their number depends on
+    /// the path taken.
+    pub defer_regs: Vec<usize>,
+}
+
+/// Where the code being compiled runs, decides which eval-when bodies are compiled.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum CompileMode {
+    /// Run by this process as it is compiled (slosh, eval, sl-compiler without -o), eval-when
+    /// bodies with :execute are compiled.
+    Eval,
+    /// Written out to be loaded by another process (sl-compiler -o), eval-when bodies with
+    /// :compile are run as they are compiled and ones with :load are compiled.
+    File,
+}
+
+/// Compile time settings from (declare declaration*) forms.  They last for the rest of the file so
+/// the states of its top level forms and the fns in them share one.
+#[derive(Default)]
+pub struct Declarations {
+    /// Globals from (declare (ignore-undefined name*)).
+    pub ignore_undefined: HashSet<Interned>,
+    /// Globals from (declare (inline name*)).
+    pub inline: HashSet<Interned>,
+}
+
+pub type DeclarationsRef = Rc<RefCell<Declarations>>;
+
+impl Declarations {
+    pub fn new_ref() -> DeclarationsRef {
+        Rc::new(RefCell::new(Declarations::default()))
+    }
+}
+
 pub struct CompileState {
     pub symbols: Rc<RefCell<Symbols>>,
     pub constants: HashMap<Value, usize>,
     pub chunk: Chunk,
     pub specials: Specials,
     pub max_regs: usize,
+    /// Registers holding an operand until the op reading it is emitted, see alloc_temp.
+    temps: Vec<usize>,
     pub tail: bool,
+    /// Number of defers active at this point of the chunk.
     pub defers: usize,
+    /// False when the active defers depend on the path taken (a defer in one branch of an if
+    /// for instance), then a recur in a loop pops them by the loop's count at runtime.
+    pub defers_known: bool,
+    /// The innermost loop form being compiled in this chunk, if any.
+    pub loop_target: Option<LoopTarget>,
+    /// True when tail only means the end of the innermost loop's body (the loop is not in tail
+    /// position), recur can jump back from there but a call is not a tail call.
+    pub loop_tail: bool,
+    pub global_refs: Vec<GlobalRef>,
+    /// The jumps in the finished chunk, set by the peephole pass.
+    pub jumps: Vec<JumpOperand>,
+    /// Forms with a constant value, filled in by fold_constants.
+    pub folds: HashMap<Value, Value>,
+    /// Warnings for the file being compiled, shared with the states of the fns in it.
+    pub warnings: WarningsRef,
+    /// Declarations for the file being compiled, shared like warnings.
+    pub declarations: DeclarationsRef,
+    /// Macro calls expanded by lower and their expansions, compile uses them instead of running
+    /// the macros again.  Shared with the states of the fns in the form.
+    pub expansions: Rc<RefCell<HashMap<Value, Value>>>,
+    /// True while compiling a loop body, code later in the loop can run before a form.
+    pub in_loop: bool,
+    /// The instructions in chunk, for the peephole pass.
+    pub instrs: Vec<Instr>,
+    /// Index in instrs of each jump by the position of its offset operand.
+    jump_instrs: HashMap<usize, usize>,
+    /// Run the peephole pass on the finished chunk.
+    pub peephole: bool,
+    pub mode: CompileMode,
+    /// True while the form being compiled is at the top level of the form compile_top_level was
+    /// given (that form, or one in a do, eval-when or macro expansion that is).
+    pub top_level: bool,
 }
 
 impl CompileState {
@@ -273,8 +611,24 @@ impl CompileState {
             chunk: Chunk::new("no_file", 1),
             specials: Specials::new(vm),
             max_regs: 0,
+            temps: Vec::new(),
             tail: false,
             defers: 0,
+            defers_known: true,
+            loop_target: None,
+            loop_tail: false,
+            global_refs: Vec::new(),
+            jumps: Vec::new(),
+            folds: HashMap::new(),
+            warnings: Warnings::new_ref(),
+            declarations: Declarations::new_ref(),
+            expansions: Rc::new(RefCell::new(HashMap::new())),
+            in_loop: false,
+            instrs: Vec::new(),
+            jump_instrs: HashMap::new(),
+            peephole: true,
+            mode: CompileMode::Eval,
+            top_level: false,
         }
     }
 
@@ -291,8 +645,24 @@ impl CompileState {
             chunk: Chunk::new(file_name, first_line),
             specials: Specials::new(vm),
             max_regs: 0,
+            temps: Vec::new(),
             tail: false,
             defers: 0,
+            defers_known: true,
+            loop_target: None,
+            loop_tail: false,
+            global_refs: Vec::new(),
+            jumps: Vec::new(),
+            folds: HashMap::new(),
+            warnings: Warnings::new_ref(),
+            declarations: Declarations::new_ref(),
+            expansions: Rc::new(RefCell::new(HashMap::new())),
+            in_loop: false,
+            instrs: Vec::new(),
+            jump_instrs: HashMap::new(),
+            peephole: true,
+            mode: CompileMode::Eval,
+            top_level: false,
         }
     }
 
@@ -303,13 +673,174 @@ impl CompileState {
             chunk: Chunk::new(state.chunk.file_name, line),
             specials: Specials::new(vm),
             max_regs: state.max_regs,
+            temps: Vec::new(),
             tail: state.tail,
             defers: state.defers,
+            defers_known: state.defers_known,
+            // A new chunk, can not jump into the loop from here.
+            loop_target: None,
+            loop_tail: false,
+            global_refs: Vec::new(),
+            jumps: Vec::new(),
+            folds: HashMap::new(),
+            warnings: state.warnings.clone(),
+            declarations: state.declarations.clone(),
+            expansions: state.expansions.clone(),
+            in_loop: state.in_loop,
+            instrs: Vec::new(),
+            jump_instrs: HashMap::new(),
+            peephole: state.peephole,
+            mode: state.mode,
+            top_level: false,
+        }
+    }
+
+    /// The number of defers a tail call has to pop before leaving the chunk, None if that is
+    /// not known at compile time.
+    pub fn tail_defers(&self) -> Option<usize> {
+        if self.defers_known {
+            Some(self.defers)
+        } else {
+            None
+        }
+    }
+
+    /// True if a call here can be a tail call.  Not with a defer active, it has to run after
+    /// the callee returns and a tail call would leave this chunk first.
+    pub fn tail_call(&self) -> bool {
+        self.tail && !self.loop_tail && self.defers == 0
+    }
+
+    /// Set the defers after a conditional form from the defers at each of its exits.
+    pub fn merge_defers(&mut self, exits: &[usize]) {
+        if let Some(max) = exits.iter().max() {
+            if exits.iter().any(|d| d != max) {
+                self.defers_known = false;
+            }
+            self.defers = *max;
+        }
+    }
+
+    /// Add a warning for the form at line.
+    pub fn warn(&self, kind: WarningKind, message: String, line: Option<u32>) {
+        self.warnings.borrow_mut().warn(Warning {
+            kind,
+            message,
+            file_name: self.chunk.file_name,
+            line,
+        });
+    }
+
+    /// Warn (when the file is finished) if global is not defined, used says how it was used.
+    pub fn warn_undefined(&self, vm: &Vm, global: Interned, used: &str, line: Option<u32>) {
+        if let Some(slot) = vm.global_intern_slot(global) {
+            if !matches!(vm.get_global(slot), Value::Undefined) {
+                return;
+            }
+        }
+        if self
+            .declarations
+            .borrow()
+            .ignore_undefined
+            .contains(&global)
+        {
+            return;
+        }
+        self.warnings.borrow_mut().warn_undefined(
+            global,
+            Warning {
+                kind: WarningKind::Undefined,
+                message: format!("{} undefined global {}", used, vm.get_interned(global)),
+                file_name: self.chunk.file_name,
+                line,
+            },
+        );
+    }
+
+    /// Track the local name just added to symbols (a fn arg if param) for the lints.
+    pub fn add_binding(
+        &self,
+        vm: &Vm,
+        symbols: &Symbols,
+        name: Interned,
+        param: bool,
+        line: Option<u32>,
+    ) {
+        let binding = Rc::new(Binding::new(name, param, self.chunk.file_name, line));
+        let shadowed = symbols
+            .data
+            .borrow_mut()
+            .bindings
+            .insert(name, binding.clone());
+        if let Some(shadowed) = shadowed {
+            if self.warnings.borrow().lint() && !vm.get_interned(name).starts_with("#:") {
+                let what = if shadowed.param {
+                    "an argument"
+                } else {
+                    "a local"
+                };
+                self.warn(
+                    WarningKind::Shadow,
+                    format!("{} shadows {}", vm.get_interned(name), what),
+                    line,
+                );
+            }
+        }
+        self.warnings.borrow_mut().add_binding(binding);
+    }
+
+    /// Record a read of the local name.
+    pub fn read_local(&self, name: Interned) {
+        if let Some(binding) = self.symbols.borrow().data.borrow().bindings.get(&name) {
+            binding.mark_read();
+        }
+    }
+
+    /// Record a set! of the local name, lints an arg that is not read after.
+    pub fn set_local(&self, name: Interned, line: Option<u32>) {
+        if self.in_loop {
+            // A read earlier in the loop can see the value.
+            return;
+        }
+        if let Some(binding) = self.symbols.borrow().data.borrow().bindings.get(&name) {
+            if binding.param {
+                binding.mark_set(line);
+            }
+        }
+    }
+
+    /// The register for a temporary at or above floor.  Compiling into a register uses the
+    /// ones above it as scratch so a temp goes above the live ones, the registers of temps that
+    /// were released are handed out again.  hold_temp marks it live once it is set.
+    pub fn alloc_temp(&mut self, floor: usize) -> usize {
+        let reg = self
+            .temps
+            .iter()
+            .map(|live| live + 1)
+            .fold(floor, usize::max);
+        if self.max_regs < reg {
+            self.max_regs = reg;
         }
+        reg
+    }
+
+    /// The temporary in reg is set, it stays live until release_temps.
+    pub fn hold_temp(&mut self, reg: usize) {
+        self.temps.push(reg);
+    }
+
+    /// Mark the live temporaries, release_temps(mark) frees the ones held after it.
+    pub fn temps_mark(&self) -> usize {
+        self.temps.len()
+    }
+
+    /// Free the temporaries held since mark, the op reading them has been emitted.
+    pub fn release_temps(&mut self, mark: usize) {
+        self.temps.truncate(mark);
     }
 
     pub fn reserved_regs(&self) -> usize {
-        self.symbols.borrow().len() + 1
+        self.symbols.borrow().data.borrow().count() + 1
     }
 
     pub fn get_symbol(&self, sym: Interned) -> Option<usize> {
@@ -325,4 +856,139 @@ impl CompileState {
             const_i
         }
     }
+
+    fn add_instr(&mut self, pos: usize, op: Option<u8>, args: Vec<u16>) {
+        self.instrs.push(Instr {
+            pos,
+            end: self.chunk.code.len(),
+            op,
+            args,
+            jump: None,
+        });
+    }
+
+    pub fn encode0(&mut self, op: u8, line: Option<u32>) -> VMResult<()> {
+        let pos = self.chunk.code.len();
+        self.chunk.encode0(op, line)?;
+        self.add_instr(pos, Some(op), Vec::new());
+        Ok(())
+    }
+
+    pub fn encode1(&mut self, op: u8, arg1: u16, line: Option<u32>) -> VMResult<()> {
+        let pos = self.chunk.code.len();
+        self.chunk.encode1(op, arg1, line)?;
+        self.add_instr(pos, Some(op), vec![arg1]);
+        Ok(())
+    }
+
+    pub fn encode2(&mut self, op: u8, arg1: u16, arg2: u16, line: Option<u32>) -> VMResult<()> {
+        let pos = self.chunk.code.len();
+        self.chunk.encode2(op, arg1, arg2, line)?;
+        self.add_instr(pos, Some(op), vec![arg1, arg2]);
+        Ok(())
+    }
+
+    pub fn encode3(
+        &mut self,
+        op: u8,
+        arg1: u16,
+        arg2: u16,
+        arg3: u16,
+        line: Option<u32>,
+    ) -> VMResult<()> {
+        let pos = self.chunk.code.len();
+        self.chunk.encode3(op, arg1, arg2, arg3, line)?;
+        self.add_instr(pos, Some(op), vec![arg1, arg2, arg3]);
+        Ok(())
+    }
+
+    /// Remove and return the instruction log.
+    pub fn take_instrs(&mut self) -> Vec<Instr> {
+        self.jump_instrs.clear();
+        std::mem::take(&mut self.instrs)
+    }
+
+    /// Encode the offset operand of the jump instruction just encoded.
+    pub fn encode_jump_offset(&mut self, offset: i32) -> VMResult<()> {
+        let offset_pos = self.chunk.code.len();
+        self.chunk.encode_jump_offset(offset)?;
+        let start_ip = self.chunk.code.len();
+        if let Some(instr) = self.instrs.last_mut() {
+            instr.end = start_ip;
+            instr.jump = Some(JumpOperand {
+                offset_pos,
+                start_ip,
+                rel: offset,
+            });
+            self.jump_instrs.insert(offset_pos, self.instrs.len() - 1);
+        }
+        Ok(())
+    }
+
+    /// Set the offset of the jump whose offset operand is at offset_pos.
+    pub fn reencode_jump_offset(&mut self, offset_pos: usize, offset: i32) -> VMResult<()> {
+        self.chunk.reencode_jump_offset(offset_pos, offset)?;
+        if let Some(idx) = self.jump_instrs.get(&offset_pos) {
+            if let Some(jump) = self.instrs[*idx].jump.as_mut() {
+                jump.rel = offset;
+            }
+        }
+        Ok(())
+    }
+
+    /// Encode a REFI of the global name into result.
+    pub fn encode_refi(
+        &mut self,
+        vm: &mut Vm,
+        result: u16,
+        name: Interned,
+        line: Option<u32>,
+    ) -> VMResult<()> {
+        let slot = vm.reserve_index(name);
+        let start = self.chunk.code.len();
+        self.chunk.encode_refi(result, slot, line)?;
+        self.add_global_ref(start, name, GlobalRefOp::Refi(result));
+        Ok(())
+    }
+
+    /// Encode a call to the global name.
+    pub fn encode_callg(
+        &mut self,
+        vm: &mut Vm,
+        name: Interned,
+        num_args: u16,
+        result: u16,
+        line: Option<u32>,
+    ) -> VMResult<()> {
+        let slot = vm.reserve_index(name);
+        let start = self.chunk.code.len();
+        self.chunk.encode_callg(slot, num_args, result, line)?;
+        self.add_global_ref(start, name, GlobalRefOp::Callg(num_args, result));
+        Ok(())
+    }
+
+    /// Encode a tail call to the global name.
+    pub fn encode_tcallg(
+        &mut self,
+        vm: &mut Vm,
+        name: Interned,
+        num_args: u16,
+        line: Option<u32>,
+    ) -> VMResult<()> {
+        let slot = vm.reserve_index(name);
+        let start = self.chunk.code.len();
+        self.chunk.encode_tcallg(slot, num_args, line)?;
+        self.add_global_ref(start, name, GlobalRefOp::Tcallg(num_args));
+        Ok(())
+    }
+
+    fn add_global_ref(&mut self, start: usize, name: Interned, op: GlobalRefOp) {
+        self.add_instr(start, None, Vec::new());
+        self.global_refs.push(GlobalRef {
+            start,
+            end: self.chunk.code.len(),
+            name,
+            op,
+        });
+    }
 }
@@ -0,0 +1,30 @@
+//! Helpers shared by the unit tests of the compiler modules.
+
+use slvm::vm::*;
+
+use crate::error::*;
+use crate::prelude::*;
+
+/// A vm with the prelude loaded.
+pub fn new_vm() -> Vm {
+    let mut vm = Vm::new();
+    load_prelude(&mut vm).unwrap();
+    vm
+}
+
+/// Compile and run text, return the value of its last form for display.  Panics naming text if
+/// it fails to compile or run.
+pub fn eval(vm: &mut Vm, text: &str) -> String {
+    match eval_str(vm, "test", text) {
+        Ok(val) => val.display_value(vm),
+        Err(e) => panic!("{}: {}", text, e),
+    }
+}
+
+/// The error compiling or running text gives, panics if it evaluates.
+pub fn compile_err(vm: &mut Vm, text: &str) -> CompileError {
+    match eval_str(vm, "test", text) {
+        Ok(val) => panic!("{}: expected an error, got {}", text, val.display_value(vm)),
+        Err(e) => e,
+    }
+}
@@ -0,0 +1,366 @@
+use std::cell::{Cell, RefCell};
+use std::collections::HashSet;
+use std::fmt;
+use std::rc::Rc;
+
+use slvm::interner::*;
+use slvm::value::*;
+use slvm::vm::*;
+
+/// The category of a compile warning, code() is the stable name used in messages.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum WarningKind {
+    /// A global that is not defined is called, referenced or set!.
+    Undefined,
+    /// A global lambda is called with the wrong number of arguments.
+    ArgCount,
+    /// Lint, a local is never read.
+    UnusedLocal,
+    /// Lint, a fn arg is never read.
+    UnusedParam,
+    /// Lint, a local hides another local or arg of the same fn.
+    Shadow,
+    /// Lint, a fn arg is set! and not read after.
+    UnreadSet,
+    /// A global declared inline can not be inlined or is redefined after calls to it were.
+    Inline,
+}
+
+impl WarningKind {
+    pub fn code(self) -> &'static str {
+        match self {
+            WarningKind::Undefined => "undefined",
+            WarningKind::ArgCount => "arg-count",
+            WarningKind::UnusedLocal => "unused-local",
+            WarningKind::UnusedParam => "unused-param",
+            WarningKind::Shadow => "shadow",
+            WarningKind::UnreadSet => "unread-set",
+            WarningKind::Inline => "inline",
+        }
+    }
+}
+
+/// A warning from the compiler with the source position of the form that caused it.
+#[derive(Clone, Debug)]
+pub struct Warning {
+    pub kind: WarningKind,
+    pub message: String,
+    pub file_name: &'static str,
+    pub line: Option<u32>,
+}
+
+impl fmt::Display for Warning {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}:", self.file_name)?;
+        if let Some(line) = self.line {
+            write!(f, "{}:", line)?;
+        }
+        write!(f, " warning[{}]: {}", self.kind.code(), self.message)
+    }
+}
+
+/// A named local (fn arg or let, loop, match, etc binding) tracked for the lints.
+#[derive(Debug)]
+pub struct Binding {
+    pub name: Interned,
+    /// True for a fn arg.
+    pub param: bool,
+    pub file_name: &'static str,
+    pub line: Option<u32>,
+    read: Cell<bool>,
+    /// Set by a set! of this arg, cleared when it is read.
+    unread_set: Cell<bool>,
+    set_line: Cell<Option<u32>>,
+}
+
+impl Binding {
+    pub fn new(name: Interned, param: bool, file_name: &'static str, line: Option<u32>) -> Self {
+        Binding {
+            name,
+            param,
+            file_name,
+            line,
+            read: Cell::new(false),
+            unread_set: Cell::new(false),
+            set_line: Cell::new(None),
+        }
+    }
+
+    pub fn mark_read(&self) {
+        self.read.set(true);
+        self.unread_set.set(false);
+    }
+
+    pub fn mark_set(&self, line: Option<u32>) {
+        self.unread_set.set(true);
+        self.set_line.set(line);
+    }
+
+    /// The lint warning for this binding if it is unused or set and never read, names starting
+    /// with _ (and gensyms) are not reported.
+    fn lint(&self, vm: &Vm) -> Option<Warning> {
+        let name = vm.get_interned(self.name);
+        if name.starts_with('_') || name.starts_with("#:") {
+            return None;
+        }
+        let (kind, message, line) = if !self.read.get() {
+            if self.param {
+                (
+                    WarningKind::UnusedParam,
+                    format!("argument {} is never used", name),
+                    self.line,
+                )
+            } else {
+                (
+                    WarningKind::UnusedLocal,
+                    format!("local {} is never used", name),
+                    self.line,
+                )
+            }
+        } else if self.unread_set.get() {
+            (
+                WarningKind::UnreadSet,
+                format!("argument {} is set! and never read after", name),
+                self.set_line.get(),
+            )
+        } else {
+            return None;
+        };
+        Some(Warning {
+            kind,
+            message,
+            file_name: self.file_name,
+            line,
+        })
+    }
+}
+
+/// The warnings from compiling a file (or a REPL form), shared by the states of its top level
+/// forms and the fns in them.
+///
+/// Undefined globals are held as pending until finish() since a later form in the file may
+/// define them (a forward reference).
+#[derive(Default)]
+pub struct Warnings {
+    warnings: Vec<Warning>,
+    pending: Vec<(Interned, Warning)>,
+    /// Globals def'ed so far in this file.
+    defined: HashSet<Interned>,
+    /// Report the lints (unused locals and args, shadowing, set! of an arg never read).
+    lint: bool,
+    /// Locals to lint when the file is finished.
+    bindings: Vec<Rc<Binding>>,
+}
+
+pub type WarningsRef = Rc<RefCell<Warnings>>;
+
+impl Warnings {
+    pub fn new_ref() -> WarningsRef {
+        Rc::new(RefCell::new(Warnings::default()))
+    }
+
+    /// Warnings that also report the lints.
+    pub fn with_lints() -> WarningsRef {
+        Rc::new(RefCell::new(Warnings {
+            lint: true,
+            ..Warnings::default()
+        }))
+    }
+
+    pub fn lint(&self) -> bool {
+        self.lint
+    }
+
+    /// Lint binding when the file is finished.
+    pub fn add_binding(&mut self, binding: Rc<Binding>) {
+        if self.lint {
+            self.bindings.push(binding);
+        }
+    }
+
+    pub fn warn(&mut self, warning: Warning) {
+        self.warnings.push(warning);
+    }
+
+    /// Warn that global is not defined unless a form in this file def'ed it.
+    pub fn warn_undefined(&mut self, global: Interned, warning: Warning) {
+        if !self.defined.contains(&global) {
+            self.pending.push((global, warning));
+        }
+    }
+
+    /// Record a def of global in this file.
+    pub fn defined(&mut self, global: Interned) {
+        self.defined.insert(global);
+    }
+
+    /// Drop the pending undefined warnings for global, it was declared ignore-undefined after
+    /// forms that used it.
+    pub fn forget_undefined(&mut self, global: Interned) {
+        self.pending.retain(|(pending, _)| *pending != global);
+    }
+
+    /// End of the file, keep the undefined warnings for globals that are still not defined (by a
+    /// def in the file or at runtime) and add the lints for the locals.
+    pub fn finish(&mut self, vm: &Vm) {
+        for (global, warning) in std::mem::take(&mut self.pending) {
+            let defined = self.defined.contains(&global)
+                || matches!(
+                    vm.global_intern_slot(global).map(|slot| vm.get_global(slot)),
+                    Some(val) if !matches!(val, Value::Undefined)
+                );
+            if !defined {
+                self.warnings.push(warning);
+            }
+        }
+        for binding in std::mem::take(&mut self.bindings) {
+            if let Some(warning) = binding.lint(vm) {
+                self.warnings.push(warning);
+            }
+        }
+        self.warnings.sort_by_key(|w| w.line);
+    }
+
+    /// Remove and return the warnings, call finish() first to include undefined globals.
+    pub fn take(&mut self) -> Vec<Warning> {
+        std::mem::take(&mut self.warnings)
+    }
+}
+
+/// Finish warnings and print them to stderr, returns how many there were.
+pub fn print_warnings(vm: &Vm, warnings: &WarningsRef) -> usize {
+    let mut warnings = warnings.borrow_mut();
+    warnings.finish(vm);
+    let warnings = warnings.take();
+    for warning in &warnings {
+        eprintln!("{}", warning);
+    }
+    warnings.len()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::prelude::*;
+    use crate::test_util::*;
+
+    /// The messages of the warnings of kind from linting text.
+    fn warnings_of(vm: &mut Vm, kind: WarningKind, text: &str) -> Vec<String> {
+        lint_str(vm, "test", text)
+            .unwrap()
+            .into_iter()
+            .filter(|w| w.kind == kind)
+            .map(|w| w.message)
+            .collect()
+    }
+
+    #[test]
+    fn test_undefined() {
+        let mut vm = new_vm();
+        assert_eq!(
+            warnings_of(
+                &mut vm,
+                WarningKind::Undefined,
+                "(defn f () (no-fn 1))
+                 (defn g () no-val)
+                 (defn h () (set! no-set 1))",
+            ),
+            vec![
+                "call to undefined global no-fn",
+                "reference to undefined global no-val",
+                "set! of undefined global no-set",
+            ]
+        );
+        // Defined by a later form in the file or before the file.
+        assert!(warnings_of(
+            &mut vm,
+            WarningKind::Undefined,
+            "(defn early () (later 1) later)
+             (defn later (x) x)
+             (defn again () (f))",
+        )
+        .is_empty());
+    }
+
+    #[test]
+    fn test_ignore_undefined() {
+        let mut vm = new_vm();
+        // Declared before or after the use, only the declared names are ignored.
+        assert_eq!(
+            warnings_of(
+                &mut vm,
+                WarningKind::Undefined,
+                "(declare (ignore-undefined ext-a))
+                 (defn f () (ext-a) (ext-b) ext-c)
+                 (declare (ignore-undefined ext-b))",
+            ),
+            vec!["reference to undefined global ext-c"]
+        );
+        // Only for the file that declared it.
+        assert_eq!(
+            warnings_of(&mut vm, WarningKind::Undefined, "(defn g () (ext-a))"),
+            vec!["call to undefined global ext-a"]
+        );
+    }
+
+    #[test]
+    fn test_unused() {
+        let mut vm = new_vm();
+        let text = "(defn f (a b _c) (let ((x 1) (_y 2) (z 3)) (+ a z)))";
+        assert_eq!(
+            warnings_of(&mut vm, WarningKind::UnusedLocal, text),
+            vec!["local x is never used"]
+        );
+        assert_eq!(
+            warnings_of(&mut vm, WarningKind::UnusedParam, text),
+            vec!["argument b is never used"]
+        );
+        // Captured by a fn is used.
+        assert!(lint_str(&mut vm, "test", "(defn g (a) (fn () a))")
+            .unwrap()
+            .is_empty());
+    }
+
+    #[test]
+    fn test_shadow() {
+        let mut vm = new_vm();
+        assert_eq!(
+            warnings_of(
+                &mut vm,
+                WarningKind::Shadow,
+                "(defn f (a) (let ((a (+ a 1)) (b 2)) (let ((b a)) b)))",
+            ),
+            vec!["a shadows an argument", "b shadows a local"]
+        );
+        // A let of an earlier fn or a global is not a shadow.
+        assert!(warnings_of(
+            &mut vm,
+            WarningKind::Shadow,
+            "(def g 1) (defn h (x) x) (defn k () (let ((g 2) (x 3)) (+ g x)))",
+        )
+        .is_empty());
+    }
+
+    #[test]
+    fn test_unread_set() {
+        let mut vm = new_vm();
+        assert_eq!(
+            warnings_of(
+                &mut vm,
+                WarningKind::UnreadSet,
+                "(defn f (a) (+ a 1) (set! a 2) nil)
+                 (defn g (a) (set! a 2) a)",
+            ),
+            vec!["argument a is set! and never read after"]
+        );
+        // Set before going around the loop again and read at its head.
+        assert!(warnings_of(
+            &mut vm,
+            WarningKind::UnreadSet,
+            "(defn w (n) (while (> n 0) (set! n (- n 1))))
+             (defn l (n) (loop ((i 0)) (if (> n 0) (do (set! n (- n 1)) (recur (+ i 1))) i)))
+             (defn r (n acc) (if (= n 0) acc (do (set! acc (+ acc n)) (recur (- n 1) acc))))",
+        )
+        .is_empty());
+    }
+}
@@ -0,0 +1,63 @@
+use std::env;
+use std::ffi::OsString;
+
+pub struct Config {
+    pub prelude: bool,
+}
+
+const HELP: &str = r#"slosh - Simple Lisp Shell
+Run the slosh REPL.
+
+USAGE:
+    slosh [FLAGS] [OPTIONS]
+
+FLAGS:
+    -v, --version  Print the version of slosh then exit.
+    -h, --help     Print help (this) and exit.
+
+OPTIONS:
+    -n, --no-prelude   Do not load the bundled prelude macros (defn, when, for, etc)."#;
+
+fn help(_name: &str) {
+    println!("{}", HELP);
+}
+
+fn version() {
+    println!("{} {}", env!("CARGO_PKG_NAME"), env!("CARGO_PKG_VERSION"));
+}
+
+pub fn get_config() -> Option<Config> {
+    let mut args: Vec<OsString> = env::args_os().collect();
+
+    args.reverse();
+    // Pop off the executable name.
+    let exe_name = args
+        .pop()
+        .and_then(|a| a.into_string().ok())
+        .unwrap_or_else(|| "slosh".to_string());
+
+    let mut prelude = true;
+    while let Some(argument) = args.pop() {
+        match argument.into_string().as_deref() {
+            Ok("-n") | Ok("--no-prelude") => prelude = false,
+            Ok("-v") | Ok("--version") => {
+                version();
+                return None;
+            }
+            Ok("-h") | Ok("--help") => {
+                help(&exe_name);
+                return None;
+            }
+            Ok(arg) => {
+                println!("Unknown argument {}.", arg);
+                help(&exe_name);
+                return None;
+            }
+            Err(_) => {
+                help(&exe_name);
+                return None;
+            }
+        }
+    }
+    Some(Config { prelude })
+}
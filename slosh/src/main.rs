@@ -5,18 +5,27 @@ use std::io::{BufReader, ErrorKind};
 use std::sync::Arc;
 
 use slvm::error::*;
-use slvm::opcodes::*;
 use slvm::value::*;
 use slvm::vm::*;
 
+use sl_compiler::backquote::*;
 use sl_compiler::compile::*;
+use sl_compiler::error::*;
+use sl_compiler::namespace::*;
+use sl_compiler::prelude::*;
 use sl_compiler::reader::*;
+use sl_compiler::roots::*;
+use sl_compiler::serialize::*;
 use sl_compiler::state::*;
+use sl_compiler::warning::*;
 
 use sl_liner::{Context, Prompt};
 use slvm::Chunk;
 use unicode_reader::Graphemes;
 
+pub mod config;
+use config::*;
+
 pub mod debug;
 use debug::*;
 
@@ -79,7 +88,9 @@ fn load_one_expression(
     exp: Value,
     name: &'static str,
     mut line: &mut Option<&mut u32>,
-) -> VMResult<Arc<Chunk>> {
+    warnings: &WarningsRef,
+    declarations: &DeclarationsRef,
+) -> CompileResult<Arc<Chunk>> {
     if let Value::Pair(h) = exp {
         let (_, _) = vm.get_pair(h);
         if let (Some(line), Some(Value::UInt(dline))) =
@@ -90,30 +101,13 @@ fn load_one_expression(
     }
     let mut state = CompileState::new_state(vm, name, line_num(line), None);
     state.chunk.dbg_args = Some(Vec::new());
-    if let Err(e) = pass1(vm, &mut state, exp) {
-        println!(
-            "Compile error (pass one), {}, line {}: {}",
-            name,
-            line_num(line),
-            e
-        );
-        return Err(e);
-    }
-    if let Err(e) = compile(vm, &mut state, exp, 0, line) {
-        println!(
-            "Compile error, {} line {}: {} exp: {}",
-            name,
-            line_num(line),
-            e,
-            exp.display_value(vm)
-        );
-        return Err(e);
-    }
-    if let Err(e) = state.chunk.encode0(RET, Some(line_num(line))) {
-        println!("Compile error, {} line {}: {}", name, line_num(line), e);
+    state.warnings = warnings.clone();
+    state.declarations = declarations.clone();
+    if let Err(e) = compile_top_level(vm, &mut state, exp, line) {
+        let e = e.with_line(name, Some(line_num(line)));
+        println!("Compile error, {}", e);
         return Err(e);
     }
-    state.chunk.extra_regs = state.max_regs;
     Ok(Arc::new(state.chunk))
 }
 
@@ -133,6 +127,17 @@ fn load(vm: &mut Vm, registers: &[Value]) -> VMResult<Value> {
         }
         _ => return Err(VMError::new_vm("load: Not a string.")),
     };
+    // A file switching namespaces (ns or in-ns) should not change the namespace of the loader.
+    let ns = current_namespace(vm);
+    let res = load_file(vm, name);
+    set_namespace(vm, ns);
+    res
+}
+
+fn load_file(vm: &mut Vm, name: &'static str) -> VMResult<Value> {
+    if name.ends_with(".slc") {
+        return load_compiled(vm, name);
+    }
     let file = std::fs::File::open(name)?;
     let mut chars: CharIter = Box::new(
         Graphemes::from(BufReader::new(file))
@@ -150,20 +155,18 @@ fn load(vm: &mut Vm, registers: &[Value]) -> VMResult<Value> {
     let mut linenum = 1;
     let mut line = Some(&mut linenum);
     let mut last = Value::Nil;
+    let warnings = Warnings::new_ref();
+    let declarations = Declarations::new_ref();
     while let Ok((exp, nchars)) = read_form(vm, &mut reader_state, chars) {
         chars = nchars;
-        if let Some(handle) = exp.get_handle() {
-            vm.heap_sticky(handle);
-        }
-        //vm.pause_gc();
-
-        let chunk = load_one_expression(vm, exp, name, &mut line);
-
-        //vm.unpause_gc();
-        if let Some(handle) = exp.get_handle() {
-            vm.heap_unsticky(handle);
-        }
-        vm.execute(chunk?)?;
+        // The form and what compiling it allocates stay rooted until its chunk has run.
+        let mark = roots_mark();
+        root(vm, exp);
+        let res = load_one_expression(vm, exp, name, &mut line, &warnings, &declarations)
+            .map_err(VMError::from)
+            .and_then(|chunk| vm.execute(chunk));
+        release_roots(vm, mark);
+        res?;
         /*            if let Err(err) = vm.execute(chunk) {
             println!("ERROR: {}", err.display(&vm));
             if let Some(err_frame) = vm.err_frame() {
@@ -179,9 +182,28 @@ fn load(vm: &mut Vm, registers: &[Value]) -> VMResult<Value> {
         }*/
         last = vm.get_stack(0);
     }
+    print_warnings(vm, &warnings);
     Ok(last)
 }
 
+/// Load and run a file written by sl-compiler --output.
+fn load_compiled(vm: &mut Vm, name: &'static str) -> VMResult<Value> {
+    let bytes = std::fs::read(name)?;
+    let (chunks, sticky) = read_slc(vm, &bytes).map_err(|e| VMError::from(e.with_file(name)))?;
+    let mut last = Ok(Value::Nil);
+    for chunk in chunks {
+        if let Err(err) = vm.execute(chunk) {
+            last = Err(err);
+            break;
+        }
+        last = Ok(vm.get_stack(0));
+    }
+    for handle in sticky {
+        vm.heap_unsticky(handle);
+    }
+    last
+}
+
 fn vec_slice(vm: &mut Vm, registers: &[Value]) -> VMResult<Value> {
     let (vector, start, end) = match registers.len() {
         2 => {
@@ -289,8 +311,44 @@ fn set_prop(vm: &mut Vm, registers: &[Value]) -> VMResult<Value> {
     }
 }
 
+/// Read input, asking for continuation lines while the reader reports an incomplete form
+/// (open paren, string or block comment).  Returns None if the form was abandoned.
+fn read_with_continuation(
+    con: &mut Context,
+    vm: &mut Vm,
+    mut input: String,
+) -> Option<(String, Result<Vec<Value>, ReadError>)> {
+    loop {
+        let mut reader_state = ReaderState::new();
+        match read_all(vm, &mut reader_state, &input) {
+            Err(err) if err.is_incomplete() => {
+                match con.read_line(Prompt::from(CONTINUE_PROMPT), None) {
+                    Ok(more) => {
+                        input.push('\n');
+                        input.push_str(&more);
+                    }
+                    Err(err) => match err.kind() {
+                        ErrorKind::UnexpectedEof | ErrorKind::Interrupted => return None,
+                        _ => {
+                            eprintln!("Error on input: {}", err);
+                            return None;
+                        }
+                    },
+                }
+            }
+            exps => return Some((input, exps)),
+        }
+    }
+}
+
 const PROMPT_FN: &str = "prompt";
+const CONTINUE_PROMPT: &str = "  ...> ";
 fn main() {
+    let config = if let Some(c) = get_config() {
+        c
+    } else {
+        return;
+    };
     let mut con = Context::new();
 
     if let Err(e) = con.history.set_file_name_and_load_history("history") {
@@ -305,7 +363,24 @@ fn main() {
     vm.set_global("vec->list", Value::Builtin(CallFunc { func: vec_to_list }));
     vm.set_global("get-prop", Value::Builtin(CallFunc { func: get_prop }));
     vm.set_global("set-prop", Value::Builtin(CallFunc { func: set_prop }));
+    vm.set_global(
+        "macroexpand-1",
+        Value::Builtin(CallFunc {
+            func: macroexpand_1,
+        }),
+    );
+    vm.set_global(
+        "macroexpand",
+        Value::Builtin(CallFunc { func: macroexpand }),
+    );
+    vm.set_global("gensym", Value::Builtin(CallFunc { func: gensym }));
+    vm.set_global("case-index", Value::Builtin(CallFunc { func: case_index }));
     //vm.set_global("eval", Value::Builtin(CallFunc { func: eval }));
+    if config.prelude {
+        if let Err(e) = load_prelude(&mut vm) {
+            eprintln!("Error loading prelude: {}", e);
+        }
+    }
     loop {
         let res = match con.read_line(Prompt::from("slosh> "), None) {
             Ok(input) => input,
@@ -327,13 +402,21 @@ fn main() {
             continue;
         }
 
+        let (res, exps) = if let Some(read) = read_with_continuation(&mut con, &mut vm, res) {
+            read
+        } else {
+            continue;
+        };
         con.history.push(&res).expect("Failed to push history.");
-        let mut reader_state = ReaderState::new();
-        let exps = read_all(&mut vm, &mut reader_state, &res);
         match exps {
             Ok(exps) => {
                 let mut linenum = 1;
                 let mut line = Some(&mut linenum);
+                // Running a form can GC, keep the forms not compiled yet alive.
+                let mark = roots_mark();
+                for exp in &exps {
+                    root(&mut vm, *exp);
+                }
                 for exp in exps {
                     /*if let Value::Pair(h) = exp {
                         let (_, _) = vm.get_pair(h);
@@ -343,15 +426,13 @@ fn main() {
                     }*/
                     let mut state =
                         CompileState::new_state(&mut vm, PROMPT_FN, line_num(&line), None);
-                    if let Err(e) = pass1(&mut vm, &mut state, exp) {
-                        println!("Compile error, line {}: {}", line_num(&line), e);
-                    }
-                    if let Err(e) = compile(&mut vm, &mut state, exp, 0, &mut line) {
-                        println!("Compile error, line {}: {}", line_num(&line), e);
-                    }
-                    if let Err(e) = state.chunk.encode0(RET, Some(line_num(&line))) {
-                        println!("Compile error, line {}: {}", line_num(&line), e);
+                    if let Err(e) = compile_top_level(&mut vm, &mut state, exp, &mut line) {
+                        let e = e.with_line(PROMPT_FN, Some(line_num(&line)));
+                        println!("Compile error, {}", e);
+                        print_warnings(&vm, &state.warnings);
+                        continue;
                     }
+                    print_warnings(&vm, &state.warnings);
                     let chunk = Arc::new(state.chunk.clone());
                     if let Err(err) = vm.execute(chunk) {
                         println!("ERROR: {}", err.display(&vm));
@@ -370,6 +451,7 @@ fn main() {
                         println!("{}", value_dsp_str(&mut vm, reg));
                     }
                 }
+                release_roots(&mut vm, mark);
             }
             Err(err) => println!("Reader error: {}", err),
         }